@@ -0,0 +1,110 @@
+// audit.rs
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Kind of write recorded in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOp {
+    /// The key was created or its value, TTL or type changed.
+    Set,
+    /// The key was deleted, evicted or moved away.
+    Delete,
+    /// Every key was removed.
+    Clear,
+}
+
+/// A write recorded in the audit log.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AuditEntry<K = String> {
+    pub op: AuditOp,
+    /// Key written to, None for `Clear`.
+    pub key: Option<K>,
+    /// When the write was applied, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+}
+
+/// The last `capacity` writes applied to a store, oldest first.
+pub struct AuditLog<K = String> {
+    capacity: usize,
+    entries: Mutex<VecDeque<AuditEntry<K>>>,
+}
+
+impl<K: Clone> AuditLog<K> {
+    pub fn new(capacity: usize) -> Self {
+        AuditLog {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Records a write, dropping the oldest one if the log is full.
+    pub fn record(&self, op: AuditOp, key: Option<K>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(AuditEntry {
+            op,
+            key,
+            timestamp_ms,
+        });
+    }
+
+    /// Recorded writes, oldest first.
+    pub fn entries(&self) -> Vec<AuditEntry<K>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_log_keeps_the_last_writes() {
+        let audit_log = AuditLog::new(3);
+        audit_log.record(AuditOp::Set, Some("key1"));
+        audit_log.record(AuditOp::Set, Some("key2"));
+        audit_log.record(AuditOp::Delete, Some("key1"));
+        audit_log.record(AuditOp::Clear, None);
+
+        let entries = audit_log.entries();
+        let ops: Vec<(AuditOp, Option<&str>)> =
+            entries.iter().map(|entry| (entry.op, entry.key)).collect();
+        assert_eq!(
+            ops,
+            vec![
+                (AuditOp::Set, Some("key2")),
+                (AuditOp::Delete, Some("key1")),
+                (AuditOp::Clear, None),
+            ]
+        );
+        assert!(entries
+            .windows(2)
+            .all(|pair| pair[0].timestamp_ms <= pair[1].timestamp_ms));
+    }
+
+    #[test]
+    fn test_audit_log_with_zero_capacity() {
+        let audit_log = AuditLog::new(0);
+        audit_log.record(AuditOp::Set, Some("key1"));
+        assert!(audit_log.entries().is_empty());
+    }
+}
@@ -0,0 +1,286 @@
+// config.rs
+
+use crate::hash_algo::HashAlgo;
+use crate::shard_manager::ShardManager;
+use crate::wal::WalOptions;
+use serde::Deserialize;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1:8080";
+const DEFAULT_SWEEP_INTERVAL_SECS: u64 = 60;
+const DEFAULT_MAX_KEY_BYTES: usize = 1024;
+
+/// Startup settings of the server, read from a TOML file such as:
+///
+/// ```toml
+/// shard_count = 8
+/// bind_address = "0.0.0.0:8080"
+/// max_entries_per_shard = 10000
+/// hash_algo = "xxhash"
+/// max_total_bytes = 104857600
+/// max_total_keys = 1000000
+/// default_ttl_seconds = 3600
+/// sweep_interval_secs = 30
+/// max_value_bytes = 1048576
+/// max_key_bytes = 256
+/// compress_responses = true
+/// wal_batch_size = 100
+/// wal_flush_ms = 50
+/// wal_retries = 3
+/// wal_retry_delay_ms = 10
+/// snapshot_interval_secs = 300
+/// write_batch_size = 64
+/// audit_log_size = 1000
+/// case_insensitive_keys = true
+/// ```
+///
+/// Every field is optional and falls back to its built-in default when missing.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub shard_count: usize,
+    pub bind_address: String,
+    pub max_entries_per_shard: Option<usize>,
+    /// Hash function placing keys on the shards, `"default"`, `"fnv"` or `"xxhash"`. Only the
+    /// latter two place a key on the same shard whatever the build of the server.
+    pub hash_algo: HashAlgo,
+    /// Largest summed size of the keys and values in the store, in bytes, the least recently
+    /// used entries being evicted past it. Unlimited when unset.
+    pub max_total_bytes: Option<usize>,
+    /// Largest number of keys in the store, writes creating keys past it being rejected with
+    /// 507. Unlimited when unset.
+    pub max_total_keys: Option<usize>,
+    /// TTL of the keys written without one, in seconds. Keys don't expire by default.
+    pub default_ttl_seconds: Option<u64>,
+    /// How often expired entries are swept out of the store, 0 disabling the sweeps.
+    pub sweep_interval_secs: u64,
+    /// Largest value accepted by writes, in bytes. Unlimited when unset.
+    pub max_value_bytes: Option<usize>,
+    /// Longest key accepted by writes, in bytes.
+    pub max_key_bytes: usize,
+    /// Whether responses are gzip-encoded for clients accepting it. Off by default, since it
+    /// only pays off for large bodies such as exports and scans.
+    pub compress_responses: bool,
+    /// Number of writes the WAL buffers before syncing them to disk, 1 syncing every write.
+    pub wal_batch_size: usize,
+    /// Longest time a write stays buffered in the WAL, in milliseconds, 0 leaving it to
+    /// `wal_batch_size`.
+    pub wal_flush_ms: u64,
+    /// Number of times a failed write to the WAL is retried before it's undone and the request
+    /// fails.
+    pub wal_retries: usize,
+    /// Wait before the first retry of a failed write to the WAL, in milliseconds, doubled
+    /// before each following one.
+    pub wal_retry_delay_ms: u64,
+    /// How often the store is snapshotted to the data file, truncating the WAL, 0 only
+    /// snapshotting on shutdown.
+    pub snapshot_interval_secs: u64,
+    /// Largest number of `POST /api` writes applied under a single lock by the write queue, 0
+    /// disabling the queue so each write takes the lock itself.
+    pub write_batch_size: usize,
+    /// Number of recent writes kept for `GET /api/admin/audit`, 0 disabling the audit log.
+    pub audit_log_size: usize,
+    /// Whether keys are lowercased before they're hashed and stored, so `Key1` and `key1` name
+    /// the same entry. Off by default.
+    pub case_insensitive_keys: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            shard_count: crate::SHARD_COUNT,
+            bind_address: DEFAULT_BIND_ADDRESS.to_string(),
+            max_entries_per_shard: None,
+            hash_algo: HashAlgo::Default,
+            max_total_bytes: None,
+            max_total_keys: None,
+            default_ttl_seconds: None,
+            sweep_interval_secs: DEFAULT_SWEEP_INTERVAL_SECS,
+            max_value_bytes: None,
+            max_key_bytes: DEFAULT_MAX_KEY_BYTES,
+            compress_responses: false,
+            wal_batch_size: 1,
+            wal_flush_ms: 0,
+            wal_retries: 3,
+            wal_retry_delay_ms: 10,
+            snapshot_interval_secs: 0,
+            write_batch_size: 0,
+            audit_log_size: 0,
+            case_insensitive_keys: false,
+        }
+    }
+}
+
+impl Config {
+    /// Parses a config from the contents of a TOML file.
+    ///
+    /// Fails with `InvalidData` if the TOML is malformed or `shard_count` is zero.
+    pub fn from_toml(contents: &str) -> io::Result<Self> {
+        let config: Config = toml::from_str(contents)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if config.shard_count == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "shard_count must be at least 1",
+            ));
+        }
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    /// How the WAL batches the writes it syncs to disk.
+    pub fn wal_options(&self) -> WalOptions {
+        WalOptions {
+            batch_size: self.wal_batch_size.max(1),
+            flush_interval: (self.wal_flush_ms > 0)
+                .then(|| Duration::from_millis(self.wal_flush_ms)),
+            retries: self.wal_retries,
+            retry_delay: Duration::from_millis(self.wal_retry_delay_ms),
+        }
+    }
+
+    /// TTL given to the keys written without one, None if they don't expire.
+    pub fn default_ttl(&self) -> Option<Duration> {
+        self.default_ttl_seconds
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// Creates an empty shard manager with the configured shard count, per-shard capacity, hash
+    /// algorithm, byte budget, key limit, default TTL, key casing and audit log.
+    pub fn build_shard_manager(&self) -> ShardManager {
+        let mut shard_manager = ShardManager::with_max_entries_and_hash_algo(
+            self.shard_count,
+            self.max_entries_per_shard,
+            self.hash_algo,
+        );
+        shard_manager.set_max_bytes(self.max_total_bytes);
+        shard_manager.set_max_keys(self.max_total_keys);
+        shard_manager.set_default_ttl(self.default_ttl());
+        shard_manager.set_case_insensitive_keys(self.case_insensitive_keys);
+        shard_manager.enable_audit(self.audit_log_size);
+        shard_manager
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_from_toml() {
+        let config = Config::from_toml(
+            r#"
+            shard_count = 8
+            bind_address = "0.0.0.0:9090"
+            max_entries_per_shard = 2
+            hash_algo = "fnv"
+            max_total_bytes = 4096
+            max_total_keys = 100
+            default_ttl_seconds = 3600
+            sweep_interval_secs = 5
+            max_value_bytes = 1024
+            max_key_bytes = 64
+            compress_responses = true
+            wal_batch_size = 100
+            wal_flush_ms = 50
+            wal_retries = 5
+            wal_retry_delay_ms = 20
+            snapshot_interval_secs = 300
+            write_batch_size = 64
+            audit_log_size = 1000
+            case_insensitive_keys = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.shard_count, 8);
+        assert_eq!(config.bind_address, "0.0.0.0:9090");
+        assert_eq!(config.max_entries_per_shard, Some(2));
+        assert_eq!(config.hash_algo, HashAlgo::Fnv);
+        assert_eq!(config.max_total_bytes, Some(4096));
+        assert_eq!(config.max_total_keys, Some(100));
+        assert_eq!(config.default_ttl(), Some(Duration::from_secs(3600)));
+        assert_eq!(config.sweep_interval_secs, 5);
+        assert_eq!(config.max_value_bytes, Some(1024));
+        assert_eq!(config.max_key_bytes, 64);
+        assert!(config.compress_responses);
+        assert_eq!(
+            config.wal_options(),
+            WalOptions {
+                batch_size: 100,
+                flush_interval: Some(Duration::from_millis(50)),
+                retries: 5,
+                retry_delay: Duration::from_millis(20),
+            }
+        );
+        assert_eq!(config.snapshot_interval_secs, 300);
+        assert_eq!(config.write_batch_size, 64);
+        assert_eq!(config.audit_log_size, 1000);
+        assert!(config.case_insensitive_keys);
+    }
+
+    #[test]
+    fn test_config_missing_fields_use_defaults() {
+        let config = Config::from_toml("shard_count = 2").unwrap();
+        assert_eq!(
+            config,
+            Config {
+                shard_count: 2,
+                ..Config::default()
+            }
+        );
+
+        assert_eq!(Config::from_toml("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_config_rejects_invalid_contents() {
+        let err = Config::from_toml("shard_count = 0").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = Config::from_toml("shard_count = \"many\"").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_build_shard_manager_applies_config() {
+        let config = Config::from_toml(
+            r#"
+            shard_count = 3
+            max_entries_per_shard = 2
+            "#,
+        )
+        .unwrap();
+        let shard_manager = config.build_shard_manager();
+        assert_eq!(shard_manager.shard_count(), 3);
+
+        for i in 0..50 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        assert!(shard_manager.shard_sizes().iter().all(|&size| size <= 2));
+
+        let config = Config::from_toml("hash_algo = \"xxhash\"").unwrap();
+        assert_eq!(config.build_shard_manager().hash_algo(), HashAlgo::XxHash);
+
+        let config = Config::from_toml("max_total_bytes = 100").unwrap();
+        let shard_manager = config.build_shard_manager();
+        for i in 0..50 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        assert!(shard_manager.total_bytes() <= 100);
+
+        let config = Config::from_toml("case_insensitive_keys = true").unwrap();
+        let shard_manager = config.build_shard_manager();
+        assert_eq!(shard_manager.fold_key("Key1".to_string()), "key1");
+    }
+}
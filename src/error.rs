@@ -0,0 +1,144 @@
+// error.rs
+
+use crate::shard_manager::{StoreFull, WriteError};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+
+/// Error returned by the endpoints. It's rendered as
+/// `{"error": {"code": ..., "message": ...}}` with the matching status code.
+#[derive(Debug, PartialEq)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    NotFound(String),
+    Conflict(String),
+    PayloadTooLarge(String),
+    TooManyRequests(String),
+    ServiceUnavailable(String),
+    Internal(String),
+    InsufficientStorage(String),
+}
+
+/// JSON body of an error response.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ErrorBody {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ErrorDetail {
+    pub code: String,
+    pub message: String,
+}
+
+impl ApiError {
+    /// Machine-readable name of the error, stable across message wording changes.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::ServiceUnavailable(_) => "service_unavailable",
+            ApiError::Internal(_) => "internal_error",
+            ApiError::InsufficientStorage(_) => "insufficient_storage",
+        }
+    }
+
+    /// Code and message of the error, as found in the response body.
+    pub fn detail(&self) -> ErrorDetail {
+        ErrorDetail {
+            code: self.code().to_string(),
+            message: self.to_string(),
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::TooManyRequests(message)
+            | ApiError::ServiceUnavailable(message)
+            | ApiError::Internal(message)
+            | ApiError::InsufficientStorage(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::InsufficientStorage(_) => StatusCode::INSUFFICIENT_STORAGE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: self.detail(),
+        })
+    }
+}
+
+/// I/O errors, eg. a write the WAL couldn't record, fail the request with 500.
+impl From<io::Error> for ApiError {
+    fn from(err: io::Error) -> Self {
+        ApiError::Internal(err.to_string())
+    }
+}
+
+/// Writes creating a key while the store holds `max_total_keys` keys fail with 507.
+impl From<StoreFull> for ApiError {
+    fn from(_: StoreFull) -> Self {
+        ApiError::InsufficientStorage(
+            "The store holds its maximum number of keys, only existing keys can be updated"
+                .to_string(),
+        )
+    }
+}
+
+/// A write refused for lack of room fails with 507, one the WAL couldn't record with 500.
+impl From<WriteError> for ApiError {
+    fn from(err: WriteError) -> Self {
+        match err {
+            WriteError::StoreFull => StoreFull.into(),
+            WriteError::Wal(err) => err.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_error_response() {
+        let error = ApiError::NotFound("Key not found: key1".to_string());
+        assert_eq!(error.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(error.code(), "not_found");
+        assert_eq!(error.to_string(), "Key not found: key1");
+
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
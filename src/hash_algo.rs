@@ -0,0 +1,76 @@
+// hash_algo.rs
+
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use twox_hash::XxHash64;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Hash function used to place keys and virtual nodes on the ring.
+///
+/// `Default` is the standard library's hasher, whose output may change between Rust
+/// versions. `Fnv` and `XxHash` are fixed algorithms, so a key always lands on the same
+/// shard whatever the build.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgo {
+    #[default]
+    Default,
+    Fnv,
+    XxHash,
+}
+
+impl HashAlgo {
+    pub fn hash<T: Hash + ?Sized>(&self, t: &T) -> u64 {
+        match self {
+            HashAlgo::Default => finish(t, DefaultHasher::new()),
+            HashAlgo::Fnv => finish(t, FnvHasher(FNV_OFFSET_BASIS)),
+            HashAlgo::XxHash => finish(t, XxHash64::with_seed(0)),
+        }
+    }
+}
+
+fn finish<T: Hash + ?Sized, H: Hasher>(t: &T, mut hasher: H) -> u64 {
+    t.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// 64-bit FNV-1a.
+struct FnvHasher(u64);
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fnv_known_values() {
+        let mut hasher = FnvHasher(FNV_OFFSET_BASIS);
+        assert_eq!(hasher.finish(), 0xcbf2_9ce4_8422_2325);
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_hash_algos_are_deterministic() {
+        for algo in [HashAlgo::Default, HashAlgo::Fnv, HashAlgo::XxHash] {
+            assert_eq!(algo.hash("key1"), algo.hash("key1"));
+            assert_ne!(algo.hash("key1"), algo.hash("key2"));
+        }
+        assert_ne!(HashAlgo::Fnv.hash("key1"), HashAlgo::XxHash.hash("key1"));
+    }
+}
@@ -4,7 +4,10 @@
 //! being sharded across multiple nodes for scalability and performance.
 //!
 //! For the sharding, we use a consistent hashing scheme. It distributes keys more or less evenly across the available nodes.
-//! And it's relatively simple to implement and understand.
+//! And it's relatively simple to implement and understand. Each shard owns several virtual nodes on a hash ring, and a key
+//! belongs to the first virtual node clockwise from its hash, so adding or removing a shard only moves a fraction of the keys.
+//! A shard added with `ShardManager::add_weighted_shard` owns proportionally more virtual
+//! nodes, and so more keys, to make the most of shards with more capacity.
 //!  
 //! The project is structured to run a local server that clients can interact with using
 //! HTTP requests. It leverages Actix-Web as the web framework to handle routing and server
@@ -12,236 +15,8661 @@
 //!
 //! ## Features
 //!
-//! - Add key-value pairs to the store.
-//! - Retrieve values by their keys.
+//! - Add key-value pairs to the store, optionally expiring after a TTL, one at a time or in batches.
+//!   Expired entries are swept out of the store in the background.
+//! - Store plain strings or JSON documents, the latter being validated on write.
+//! - Retrieve values by their keys, one at a time or in batches.
+//! - Update values of existing keys.
 //! - Delete keys from the store.
-//! - Data sharding across configurable number of shards.
+//! - Atomically increment integer values, to use the store for counters.
+//! - Data sharding across configurable number of shards, with a choice of hash algorithm
+//!   (FNV or xxHash) for key placement that stays the same across builds.
+//! - Concurrent reads and writes: requests share a read lock on the store, only resharding and
+//!   snapshots take it exclusively. Each shard also has its own read-write lock, so requests
+//!   touching different shards don't wait on each other either, and stats only take the
+//!   shards' read locks.
+//! - `ShardManager` can be used on its own as a library, generic over the types of the keys
+//!   and values it stores, eg. `ShardManager<u64, String>` or `ShardManager<String, i64>`. Key
+//!   and value types implement `ByteSize`, which strings and primitives already do. The server
+//!   stores `String` keys and values.
 //!
 //! ## Usage
 //!
 //! Run the server using `cargo run`, and interact with it via HTTP requests to the
-//! exposed endpoints. The server will by default run on `http://127.0.0.1:8080`.
+//! exposed endpoints. The server will by default run on `http://127.0.0.1:8080`, another address
+//! can be set with the `HASHMAPRS_BIND` environment variable.
+//!
+//! The number of shards defaults to 4 and can be changed with the `HASHMAPRS_SHARD_COUNT`
+//! environment variable.
+//!
+//! `HASHMAPRS_CONFIG` can point to a TOML file setting `shard_count`, `bind_address`,
+//! `max_entries_per_shard`, `hash_algo`, `max_total_bytes`, `max_total_keys`, `default_ttl_seconds`,
+//! `sweep_interval_secs`, `max_value_bytes`, `max_key_bytes`, `compress_responses`,
+//! `wal_batch_size`, `wal_flush_ms`, `wal_retries`, `wal_retry_delay_ms`,
+//! `snapshot_interval_secs`, `write_batch_size`, `audit_log_size` and `case_insensitive_keys`.
+//! Missing settings keep their defaults, and `HASHMAPRS_SHARD_COUNT` takes precedence over the
+//! file's shard count. `hash_algo` is one of `default`, `fnv` or `xxhash`, only the latter two
+//! placing keys the same way across builds. Values longer than `max_value_bytes` are rejected with 413, and empty
+//! keys or keys longer than `max_key_bytes` (1024 by default) with 400. With
+//! `compress_responses = true`, responses are gzip-encoded for clients sending
+//! `Accept-Encoding: gzip`. With `max_total_bytes` set, writes taking the summed size of the
+//! keys and values past it evict the least recently used entries of the largest shard. With
+//! `max_total_keys` set, writes creating a key once the store holds that many are rejected with
+//! 507, while existing keys can still be updated. With
+//! `case_insensitive_keys = true`, every key sent to the API is lowercased before it's hashed
+//! and stored, so `Key1` and `key1` name the same entry. Keys are then returned lowercased, and
+//! keys saved before the setting was turned on keep their case.
+//!
+//! With `write_batch_size` set, `POST /api` writes go through a queue instead of each taking
+//! the store's lock: a background task applies them in batches of up to that many writes, and
+//! each request is answered once its write is applied.
+//!
+//! When `HASHMAPRS_API_KEY` is set, every request except `/health` must carry that key in the
+//! `X-API-Key` header, and is rejected with 401 otherwise.
+//!
+//! `HASHMAPRS_RATE_LIMIT` caps the number of requests each client address may send per window,
+//! 60 seconds unless set with `HASHMAPRS_RATE_LIMIT_WINDOW_SECS`. Requests over the limit are
+//! rejected with 429.
+//!
+//! Browsers only get to call the API from the same origin, unless `HASHMAPRS_CORS_ORIGINS`
+//! lists the other origins allowed, comma-separated, or is `*` to allow any.
+//!
+//! Request bodies larger than 2 MiB are rejected with 413 before being parsed, another limit
+//! can be set in bytes with `HASHMAPRS_MAX_BODY_BYTES`.
+//!
+//! Requests are served by one worker thread per CPU, unless `HASHMAPRS_WORKERS` sets another
+//! number of workers.
+//!
+//! Each request is logged to stderr along with its status and latency, eg.
+//! `[INFO] GET /api/key1 200 0.084ms`. `HASHMAPRS_LOG_LEVEL` sets the level logged, `info` by
+//! default, `warn` or `off` silencing the request lines.
+//!
+//! On Ctrl-C or SIGTERM, the server stops accepting connections and lets in-flight requests
+//! complete before exiting.
+//!
+//! Failed requests return a JSON body of the form
+//! `{"error": {"code": "not_found", "message": "Key not found: key1"}}` along with the matching
+//! status code.
+//!
+//! When `HASHMAPRS_DATA_FILE` is set, the store is loaded from that file on startup and saved
+//! back to it once the server has shut down. The loaded keys are placed on the shards of the
+//! config, with its hash algorithm and limits, so a store resharded at runtime comes back with
+//! the configured shard count.
+//!
+//! When `HASHMAPRS_WAL_FILE` is set, every write is also appended to that write-ahead log, and
+//! the writes it holds are replayed on startup, so they survive a crash. Writes are synced to
+//! disk in batches of `wal_batch_size` (1 by default, syncing every write), and at least every
+//! `wal_flush_ms` milliseconds when set. A failed write to the log is retried `wal_retries`
+//! times (3 by default), waiting `wal_retry_delay_ms` milliseconds (10 by default) before the
+//! first retry and twice as long before each following one, other writes being logged in the
+//! meantime. If it still fails, the write is undone and the request gets a 500.
+//!
+//! With both files set, the store is snapshotted to the data file every
+//! `snapshot_interval_secs` seconds and on shutdown, each snapshot truncating the WAL. Startup
+//! then loads the snapshot and only replays the writes made after it.
+//!
+//! When `HASHMAPRS_SEED_FILE` points to a JSON object mapping keys to values, its pairs are
+//! preloaded into the store before the server accepts connections. Unlike the data file, the
+//! seed file is only read: keys the store already holds keep their value, and writes are
+//! never saved back to it.
 //!
 //! ## Endpoints
 //!
-//! - `POST /api`: Add a new key-value pair.
-//! - `GET /api/{key}`: Retrieve the value associated with the given key.
+//! - `GET /health`: Liveness probe, returns `{"status": "ok", "shards": <count>}` without
+//!   locking the store.
+//! - `GET /health/deep`: Writes, reads back and removes a reserved key in every shard, and
+//!   returns `{"status": "ok", "shards": [{"shard": <index>, "ok": true}, ...]}`. If any shard
+//!   fails, eg. because a writer panicked while holding its lock, the status is `"failing"`
+//!   and 503 is returned.
+//! - `GET /metrics`: The same counters as `/api/metrics` along with the number of entries of each
+//!   shard and how many write locks had to wait for it, in the Prometheus text format. Shards
+//!   whose lock contention keeps growing are a sign more shards would help.
+//! - `POST /api`: Add a new key-value pair. With `?nx=true`, the pair is only inserted if the
+//!   key is absent, and 409 is returned otherwise.
+//!   With `"value_type": "json"`, the value must be valid JSON, and is later returned as is
+//!   instead of as a JSON string.
+//!   An `"encoding"` field, or an `X-Value-Encoding` header, tags the value with the encoding
+//!   it's in, eg. `gzip`. The tag is stored as is and sent back in the `X-Value-Encoding`
+//!   header of `GET /api/{key}`, the value itself never being decoded. Overwriting the value
+//!   drops the tag.
+//!   The expiry is set either in seconds from now with `ttl_seconds`, or as a Unix timestamp
+//!   with `expires_at`, which takes precedence and must be in the future. Without either, the
+//!   key gets the configured `default_ttl_seconds` if any, and `ttl_seconds: 0` opts out of it.
+//! - `X-Shard-Override: <index>`: Sent along `POST /api`, stores the key in the given shard
+//!   instead of the one it hashes to, and keeps it there for later reads and writes. Along
+//!   `GET` or `DELETE /api/{key}`, only looks the key up in that shard. Overrides live in
+//!   memory only.
+//! - `GET /api/{key}`: Retrieve the value associated with the given key. With `?default=...`, a
+//!   missing key returns that default instead of 404. The `X-Version` header holds the version
+//!   of the value, which starts at 1 and goes up by one on every write to the key. When the
+//!   `If-None-Match` header holds the current version, 304 is returned without the value.
+//!   The `ETag` header holds a hash of the value, which also gets 304 when sent back in
+//!   `If-None-Match`. The `Last-Modified` header holds the time of the last write to the key,
+//!   as an HTTP date. With an `Accept` header listing `text/plain` ahead of
+//!   `application/json`, the raw value is returned as plain text instead of a JSON string.
+//!   With `?transform=upper` or `?transform=lower`, the value is returned in upper or lower
+//!   case, the stored value staying as is. Other transforms, and transforms of `json` values,
+//!   are rejected with 400.
+//! - `POST /api/raw/{key}`: Store the request body as is as the value of the given key, eg.
+//!   sent as `text/plain`, saving the JSON overhead on small values. Returns 201 with an empty
+//!   body if the key is new, 200 if it was overwritten, the new version being sent in the
+//!   `X-Version` header.
+//! - `GET /api/raw/{key}`: Retrieve the value of the given key as the `text/plain` body, along
+//!   with its `X-Version` header. Errors keep their JSON body.
+//! - `POST /api/batch/set`: Add several key-value pairs at once, none being stored if one of
+//!   them is invalid. With `?dry_run=true`, the pairs are only validated, and the keys that
+//!   would be accepted or rejected are returned.
+//! - `POST /api/batch/get`: Retrieve the values of several keys at once.
+//! - `POST /api/batch/exists`: Check several keys for presence at once, as a JSON object
+//!   mapping each key to a boolean.
+//! - `POST /api/batch/delete`: Remove several keys at once and retrieve how many existed.
+//! - `POST /api/batch/incr`: Atomically increment several counters at once, given a JSON object
+//!   mapping each key to the amount to add. Returns the new value of each counter under
+//!   `values`, and the error of each counter that couldn't be incremented, eg. because it holds
+//!   a value that isn't an integer, under `errors`.
+//! - `POST /api/batch/touch`: Reset the expiry of several keys at once, given
+//!   `{"keys": [...], "ttl_seconds": <seconds>}`, 0 removing it. Returns the keys that exist
+//!   and were touched, missing keys being left out.
+//! - `GET /api/mget?keys=a,b,c`: Retrieve the values of the comma-separated keys, as a JSON
+//!   object omitting missing keys.
+//! - `GET /api/random`: Retrieve a key-value pair picked at random, every key being as likely
+//!   to come up. 404 if the store is empty.
+//! - `GET /api/schema`: Retrieve an OpenAPI document describing every endpoint, its method and
+//!   the shapes of its request and response bodies, eg. to generate a client.
+//! - `GET /api/changed?since=<unix_ts>`: Retrieve the keys, sorted, written to after the
+//!   given Unix timestamp in seconds, eg. to sync a copy of the store incrementally. Keys
+//!   loaded from a snapshot count as written when the server started.
+//! - `POST /api/import`: Add every pair of a JSON object mapping keys to values, skipping
+//!   existing keys when called with `?overwrite=false`.
+//! - `GET /api/export`: Retrieve the whole store as a JSON object mapping keys to values. This
+//!   route, like `/api/scan`, `/api/mget`, `/api/random`, `/api/changed`, `/api/schema` and
+//!   `/api/metrics`, takes precedence over `GET /api/{key}`, so keys named `export`, `scan`,
+//!   `mget`, `random`, `changed`, `schema` or `metrics` can't be read on their own.
+//! - `GET /api/export/stream`: Retrieve the whole store as newline-delimited JSON, one
+//!   `{"key": ..., "value": ...}` object per line, streamed a shard at a time so it's never
+//!   copied whole in memory.
+//! - `GET /api/scan?cursor=<n>&limit=<m>`: Retrieve a page of key-value pairs along with the
+//!   cursor of the next page, null on the last one.
+//! - `GET /api/metrics`: Retrieve the number of gets, sets, deletes, hits and misses served
+//!   since startup. A hit is a lookup that found its key, a miss one that didn't. `hit_ratio`
+//!   holds hits / (hits + misses), 0 before the first lookup.
+//! - `GET /api/stats/count`: Retrieve the total number of keys in the store.
+//! - `GET /api/stats/shards`: Retrieve the number of keys held by each shard.
+//! - `GET /api/stats/shard-ops`: Retrieve the number of gets, sets and deletes served by each
+//!   shard.
+//! - `GET /api/stats/prefix-count?prefix=<prefix>`: Retrieve the number of keys starting with the
+//!   given prefix.
+//! - `GET /api/stats/memory`: Retrieve the estimated bytes used by each shard under `shards`
+//!   and by the whole store under `total`, counting the keys and values along with a fixed
+//!   overhead per entry.
+//! - `GET /api/ttl/{key}`: Retrieve the number of seconds left before the given key expires, -1
+//!   if it never does.
+//! - `GET /api/meta/{key}`: Retrieve the `value_length` in bytes, `shard_index` and
+//!   `ttl_remaining` in seconds, null if it never expires, of the given key.
+//! - `POST /api/incr/{key}`: Atomically increment the integer stored at the given key.
+//! - `POST /api/decr/{key}`: Atomically decrement the integer stored at the given key.
+//! - `POST /api/cas/{key}`: Set the value of the given key only if it currently holds an expected value.
+//! - `POST /api/delete-if/{key}`: Delete the given key only if it currently holds the `expected`
+//!   value, 409 if it holds another one.
+//! - `POST /api/rename`: Move the value of the `from` key to the `to` key.
+//! - `POST /api/swap`: Exchange the values of the `a` and `b` keys atomically. A missing key
+//!   takes the other one's value, the other one being deleted, and 404 is returned if both are
+//!   missing.
+//! - `POST /api/getset/{key}`: Set the value of the given key and retrieve the one it replaced.
+//! - `POST /api/append/{key}`: Append a JSON string to the value of the given key, creating it
+//!   if it's missing, and retrieve the new length.
+//! - `POST /api/touch/{key}`: Reset the expiry of the given key to `ttl_seconds` from now,
+//!   without changing its value. 0 removes the expiry.
+//! - `HEAD /api/{key}`: Check whether the given key exists, without returning its value.
+//! - `PUT /api/{key}`: Update the value of an existing key.
+//! - `PATCH /api/{key}`: Apply a JSON Merge Patch (RFC 7386) to the JSON value of an existing
+//!   key, returning the patched value. 400 if the current value isn't valid JSON.
 //! - `DELETE /api/{key}`: Remove the key-value pair from the store.
+//! - `POST /api/admin/drain`: Put the server in read-only drain mode for maintenance, writes are
+//!   rejected with 503 until it restarts.
+//! - `POST /api/admin/reshard`: Change the number of shards to the given `shard_count`,
+//!   redistributing every key. Requests wait until every key is moved, unless called with
+//!   `?background=true`: a background task then moves the keys changing shard a chunk at a
+//!   time, and requests are served between chunks. A background reshard stops once the server
+//!   drains, the keys it didn't reach being moved when they're next used. Returns 202, or 409
+//!   if a background reshard is still running.
+//! - `GET /api/admin/reshard/status`: Retrieve the progress of the background reshard, whether
+//!   it's `running`, its `from` and `to` shard counts, the `total_keys` to check, the
+//!   `checked_keys` and `moved_keys` so far and the `percent` of the keys checked.
+//! - `GET /api/admin/verify`: List the keys stored in a shard they don't hash to, which can't be
+//!   reached anymore. Empty unless placement is broken.
+//! - `GET /api/admin/ring`: List the virtual nodes of the hash ring sorted by `position`,
+//!   each with the index of the `shard` owning it. A key goes to the owner of the first node
+//!   at or after its hash.
+//! - `GET /api/admin/audit`: List the last writes applied, oldest first, with their op (`set`,
+//!   `delete` or `clear`), key and timestamp. Empty unless `audit_log_size` is set.
+//! - `POST /api/{namespace}`: Add a key-value pair to the given namespace, an isolated keyspace
+//!   with its own shards created on first use. Namespaces live in memory only and their writes
+//!   aren't in the audit log, so they're disabled, with a 400, when `HASHMAPRS_DATA_FILE` or
+//!   `HASHMAPRS_WAL_FILE` is set. A namespace named after a fixed route such as `batch`,
+//!   `stats`, `raw`, `rename` or `swap` is rejected with 400 too. Their keys count against the
+//!   store's `max_total_keys` and `max_total_bytes`, writes past either being rejected with
+//!   507.
+//! - `GET /api/{namespace}/{key}`: Retrieve the value of the given key in the given namespace.
+//! - `DELETE /api/{namespace}/{key}`: Remove the given key from the given namespace.
+//! - `DELETE /api/prefix/{prefix}`: Remove every key starting with the given prefix and
+//!   retrieve how many were removed.
+//! - `DELETE /api/all`: Remove every key-value pair from the store. This route takes precedence
+//!   over `DELETE /api/{key}`, so a key named `all` can't be deleted on its own.
 //!
 //!
 
-mod shard;
-mod shard_manager;
+mod audit;
+mod config;
+mod error;
+mod hash_algo;
+mod logger;
+mod metrics;
+mod namespaces;
+mod rate_limiter;
+mod shard;
+mod shard_manager;
+mod wal;
+mod write_batcher;
+
+pub use audit::{AuditEntry, AuditOp};
+pub use config::Config;
+use error::{ApiError, ErrorDetail};
+pub use hash_algo::HashAlgo;
+use metrics::Metrics;
+use namespaces::Namespaces;
+use rate_limiter::RateLimiter;
+use shard::Entry;
+pub use shard::{ByteSize, IncrError, ValueType};
+pub use shard_manager::{
+    DeleteIfResult, MigratedKeys, ShardManager, StoreFull, ValueTags, WriteError,
+};
+use wal::Wal;
+use write_batcher::WriteBatcher;
+
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{Payload, Server, ServiceRequest, ServiceResponse},
+    error::JsonPayloadError,
+    http::{
+        header::{self, ContentType, HeaderValue},
+        Method,
+    },
+    middleware::{from_fn, Compress, Condition, Next},
+    web, App, FromRequest, HttpRequest, HttpResponse, HttpServer, ResponseError, Result,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::future::{ready, Future, Ready};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Number of shards used when `HASHMAPRS_SHARD_COUNT` is unset or invalid.
+const SHARD_COUNT: usize = 4;
+const SHARD_COUNT_ENV_VAR: &str = "HASHMAPRS_SHARD_COUNT";
+const DATA_FILE_ENV_VAR: &str = "HASHMAPRS_DATA_FILE";
+const WAL_FILE_ENV_VAR: &str = "HASHMAPRS_WAL_FILE";
+const SEED_FILE_ENV_VAR: &str = "HASHMAPRS_SEED_FILE";
+const CONFIG_ENV_VAR: &str = "HASHMAPRS_CONFIG";
+const BIND_ENV_VAR: &str = "HASHMAPRS_BIND";
+const API_KEY_ENV_VAR: &str = "HASHMAPRS_API_KEY";
+const API_KEY_HEADER: &str = "X-API-Key";
+const VERSION_HEADER: &str = "X-Version";
+const SHARD_OVERRIDE_HEADER: &str = "X-Shard-Override";
+const VALUE_ENCODING_HEADER: &str = "X-Value-Encoding";
+const CORS_EXPOSED_HEADERS: &str = "ETag, X-Version, X-Value-Encoding";
+const CORS_ORIGINS_ENV_VAR: &str = "HASHMAPRS_CORS_ORIGINS";
+const RATE_LIMIT_ENV_VAR: &str = "HASHMAPRS_RATE_LIMIT";
+const RATE_LIMIT_WINDOW_ENV_VAR: &str = "HASHMAPRS_RATE_LIMIT_WINDOW_SECS";
+const LOG_LEVEL_ENV_VAR: &str = "HASHMAPRS_LOG_LEVEL";
+const WORKERS_ENV_VAR: &str = "HASHMAPRS_WORKERS";
+const MAX_BODY_BYTES_ENV_VAR: &str = "HASHMAPRS_MAX_BODY_BYTES";
+/// Largest JSON body accepted when `HASHMAPRS_MAX_BODY_BYTES` is unset or invalid, the same
+/// as Actix's default.
+const MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+/// Window of the rate limit when `HASHMAPRS_RATE_LIMIT_WINDOW_SECS` is unset or invalid.
+const RATE_LIMIT_WINDOW_SECS: u64 = 60;
+/// Number of keys a background reshard checks each time it takes the store lock.
+const RESHARD_CHUNK_KEYS: usize = 1000;
+/// First segments of the fixed routes under `/api`, which take precedence over the namespace
+/// routes so namespaces can't be named after them.
+const RESERVED_NAMESPACES: &[&str] = &[
+    "admin",
+    "all",
+    "append",
+    "batch",
+    "cas",
+    "changed",
+    "decr",
+    "delete-if",
+    "export",
+    "getset",
+    "import",
+    "incr",
+    "meta",
+    "metrics",
+    "mget",
+    "prefix",
+    "random",
+    "raw",
+    "rename",
+    "scan",
+    "schema",
+    "stats",
+    "swap",
+    "touch",
+    "ttl",
+];
+
+/// Key clients must send in the `X-API-Key` header, authentication being disabled when None.
+struct ApiKey(Option<String>);
+
+/// Per client address rate limit, disabled when None.
+struct RateLimit(Option<RateLimiter>);
+
+/// Origins browsers may call the API from. Empty means same-origin only, and `*` allows any
+/// origin.
+struct CorsOrigins(Vec<String>);
+
+impl CorsOrigins {
+    fn allows(&self, origin: &str) -> bool {
+        self.0
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == origin)
+    }
+}
+
+/// Read-only maintenance mode. While draining, every write is rejected with 503 Service
+/// Unavailable and reads keep being served.
+#[derive(Default)]
+struct DrainMode {
+    draining: AtomicBool,
+}
+
+impl DrainMode {
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    fn enable(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Fails with 503 Service Unavailable while draining.
+    fn check_writable(&self) -> Result<(), ApiError> {
+        if self.is_draining() {
+            return Err(ApiError::ServiceUnavailable(
+                "Server is draining, writes are rejected".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Rename {
+    from: String,
+    to: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Swap {
+    a: String,
+    b: String,
+}
+
+#[derive(Deserialize)]
+struct SetOptions {
+    #[serde(default)]
+    nx: bool,
+}
+
+/// Options of a single-key write, read from its query string and headers.
+struct WriteOptions {
+    /// Only stores the value if the key is absent, see `SetOptions`.
+    nx: bool,
+    /// Shard named by the `X-Shard-Override` header, see `shard_override`.
+    shard_override: Option<usize>,
+    /// `X-Value-Encoding` header, see `value_encoding`.
+    encoding: Option<HeaderValue>,
+}
+
+impl FromRequest for WriteOptions {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, ApiError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            web::Query::<SetOptions>::from_query(req.query_string())
+                .map_err(|err| ApiError::BadRequest(err.to_string()))
+                .and_then(|options| {
+                    Ok(WriteOptions {
+                        nx: options.nx,
+                        shard_override: shard_override(req)?,
+                        encoding: req.headers().get(VALUE_ENCODING_HEADER).cloned(),
+                    })
+                }),
+        )
+    }
+}
+
+#[derive(Deserialize)]
+struct GetOptions {
+    default: Option<String>,
+    transform: Option<Transform>,
+}
+
+/// Case change applied to a value as it's read, the stored value being left as is.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Transform {
+    Upper,
+    Lower,
+}
+
+impl Transform {
+    fn apply(self, value: &str) -> String {
+        match self {
+            Transform::Upper => value.to_uppercase(),
+            Transform::Lower => value.to_lowercase(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BatchSetOptions {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Outcome of a dry-run batch set: the keys that would be stored, and why the others wouldn't.
+#[derive(Deserialize, Serialize)]
+struct BatchValidation {
+    accepted: Vec<String>,
+    rejected: Vec<RejectedPair>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct RejectedPair {
+    key: String,
+    error: ErrorDetail,
+}
+
+/// Outcome of a batch increment: the new value of each counter, and why the others weren't
+/// incremented.
+#[derive(Deserialize, Serialize)]
+struct BatchIncrements {
+    values: HashMap<String, i64>,
+    errors: HashMap<String, ErrorDetail>,
+}
+
+#[derive(Deserialize)]
+struct MGetOptions {
+    #[serde(default)]
+    keys: String,
+}
+
+#[derive(Deserialize)]
+struct PrefixOptions {
+    #[serde(default)]
+    prefix: String,
+}
+
+#[derive(Deserialize)]
+struct ScanOptions {
+    #[serde(default)]
+    cursor: usize,
+    #[serde(default = "default_scan_limit")]
+    limit: usize,
+}
+
+fn default_scan_limit() -> usize {
+    100
+}
+
+#[derive(Deserialize)]
+struct ChangedOptions {
+    since: u64,
+}
+
+/// Virtual node of the hash ring, see `get_ring`.
+#[derive(Deserialize, Serialize)]
+struct RingNode {
+    position: u64,
+    shard: usize,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ScanPage {
+    pairs: Vec<KeyValuePair>,
+    next_cursor: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ImportOptions {
+    #[serde(default = "default_overwrite")]
+    overwrite: bool,
+}
+
+fn default_overwrite() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize)]
+struct ImportResult {
+    imported: usize,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Reshard {
+    shard_count: usize,
+}
+
+#[derive(Deserialize)]
+struct ReshardOptions {
+    #[serde(default)]
+    background: bool,
+}
+
+/// Progress of a background reshard, served by `/api/admin/reshard/status`.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+struct ReshardStatus {
+    /// Whether keys are still being moved.
+    running: bool,
+    /// Number of shards when the reshard started.
+    from: usize,
+    /// Number of shards the reshard is heading to.
+    to: usize,
+    /// Number of keys the store held when the reshard started, each checked once.
+    total_keys: usize,
+    /// Number of keys checked so far.
+    checked_keys: usize,
+    /// Number of keys moved to another shard so far, the others already being in theirs.
+    moved_keys: usize,
+    /// Share of the keys checked so far, 100 once complete.
+    percent: u8,
+}
+
+impl ReshardStatus {
+    /// Status of a reshard that just started, with `total_keys` keys to check.
+    fn new(from: usize, to: usize, total_keys: usize) -> Self {
+        ReshardStatus {
+            running: true,
+            from,
+            to,
+            total_keys,
+            checked_keys: 0,
+            moved_keys: 0,
+            percent: 0,
+        }
+    }
+
+    /// Status of a reshard that completed, eg. to report when none was ever started.
+    fn complete(from: usize, to: usize) -> Self {
+        ReshardStatus {
+            running: false,
+            percent: 100,
+            ..ReshardStatus::new(from, to, 0)
+        }
+    }
+
+    /// Counts the keys checked and moved by a step of the reshard.
+    fn advance(&mut self, migrated: MigratedKeys) {
+        self.checked_keys += migrated.checked;
+        self.moved_keys += migrated.moved;
+        let percent = (self.checked_keys * 100)
+            .checked_div(self.total_keys)
+            .unwrap_or(100);
+        // Never 100 until complete, the last keys can take a while to move
+        self.percent = percent.min(99) as u8;
+    }
+}
+
+/// Status of the last background reshard, None until one is started.
+#[derive(Default)]
+struct ReshardProgress {
+    status: Mutex<Option<ReshardStatus>>,
+}
+
+impl ReshardProgress {
+    fn lock(&self) -> MutexGuard<'_, Option<ReshardStatus>> {
+        self.status.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn is_running(&self) -> bool {
+        self.lock().is_some_and(|status| status.running)
+    }
+
+    /// Records the start of a reshard from `from` to `to` shards, `start` placing the keys on
+    /// the new shards and returning how many it has to check. Fails with 409 Conflict, without
+    /// calling it, if another one is still running.
+    fn start(
+        &self,
+        from: usize,
+        to: usize,
+        start: impl FnOnce() -> usize,
+    ) -> Result<ReshardStatus, ApiError> {
+        let mut status = self.lock();
+        if status.is_some_and(|status| status.running) {
+            return Err(ApiError::Conflict(
+                "A reshard is already running".to_string(),
+            ));
+        }
+        let started = ReshardStatus::new(from, to, start());
+        *status = Some(started);
+        Ok(started)
+    }
+
+    /// Records the keys checked and moved by a step of the running reshard.
+    fn advance(&self, migrated: MigratedKeys) {
+        if let Some(status) = self.lock().as_mut() {
+            status.advance(migrated);
+        }
+    }
+
+    /// Records the end of the running reshard, `complete` telling whether every key was
+    /// checked or it stopped early.
+    fn finish(&self, complete: bool) {
+        if let Some(status) = self.lock().as_mut() {
+            status.running = false;
+            if complete {
+                status.percent = 100;
+            }
+        }
+    }
+}
+
+/// Data served by `/health`, kept apart from the store so the probe never waits on its locks.
+struct HealthState {
+    shard_count: AtomicUsize,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct KeyMeta {
+    value_length: usize,
+    shard_index: usize,
+    ttl_remaining: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct HealthStatus {
+    status: String,
+    shards: usize,
+}
+
+/// Estimated memory footprint of the store, in bytes.
+#[derive(Deserialize, Serialize)]
+struct MemoryStats {
+    shards: Vec<usize>,
+    total: usize,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ShardHealth {
+    shard: usize,
+    ok: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+struct DeepHealthStatus {
+    status: String,
+    shards: Vec<ShardHealth>,
+}
+
+/// Takes the read lock of the store, recovering it if a panicking handler poisoned it.
+fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Takes the write lock of the store, recovering it if poisoned like `read_lock`.
+fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Rejects keys that are empty or longer than the configured `max_key_bytes` with 400 Bad
+/// Request.
+fn validate_key(key: &str, config: &Config) -> Result<(), ApiError> {
+    if key.is_empty() {
+        return Err(ApiError::BadRequest("Key must not be empty".to_string()));
+    }
+    if key.len() > config.max_key_bytes {
+        return Err(ApiError::BadRequest(format!(
+            "Key is {} bytes long, the limit is {} bytes",
+            key.len(),
+            config.max_key_bytes
+        )));
+    }
+    Ok(())
+}
+
+/// Rejects values tagged as JSON that don't parse as JSON with 400 Bad Request.
+fn validate_value_type(value: &str, value_type: ValueType) -> Result<(), ApiError> {
+    if value_type == ValueType::Json {
+        serde_json::from_str::<serde_json::Value>(value)
+            .map_err(|err| ApiError::BadRequest(format!("Value is not valid JSON: {}", err)))?;
+    }
+    Ok(())
+}
+
+/// Rejects encodings that are empty or can't be sent back as a header value with 400 Bad
+/// Request.
+fn validate_encoding(encoding: &str) -> Result<(), ApiError> {
+    if encoding.trim().is_empty() || HeaderValue::from_str(encoding).is_err() {
+        return Err(ApiError::BadRequest(format!(
+            "Invalid encoding: {:?}",
+            encoding
+        )));
+    }
+    Ok(())
+}
+
+/// Runs every check a write applies to the pair, returning the first one that fails.
+fn validate_pair(pair: &KeyValuePair, config: &Config) -> Result<(), ApiError> {
+    validate_key(&pair.key, config)?;
+    check_value_size(pair.value.len(), config)?;
+    if let Some(encoding) = &pair.encoding {
+        validate_encoding(encoding)?;
+    }
+    validate_value_type(&pair.value, pair.value_type.unwrap_or_default())
+}
+
+/// Rejects values longer than the configured `max_value_bytes` with 413 Payload Too Large.
+fn check_value_size(len: usize, config: &Config) -> Result<(), ApiError> {
+    match config.max_value_bytes {
+        Some(max_value_bytes) if len > max_value_bytes => Err(ApiError::PayloadTooLarge(format!(
+            "Value is {} bytes long, the limit is {} bytes",
+            len, max_value_bytes
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct KeyValuePair {
+    key: String,
+    value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl_seconds: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value_type: Option<ValueType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
+}
+
+impl KeyValuePair {
+    /// Time left before the pair expires, taken from `expires_at` if set, `ttl_seconds`
+    /// otherwise, None leaving it to the default TTL. An `expires_at` that isn't in the future
+    /// is rejected with 400 Bad Request.
+    fn ttl(&self) -> Result<Option<Duration>, ApiError> {
+        let Some(expires_at) = self.expires_at else {
+            return Ok(self.ttl_seconds.map(Duration::from_secs));
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        match Duration::from_secs(expires_at).checked_sub(now) {
+            Some(ttl) if !ttl.is_zero() => Ok(Some(ttl)),
+            _ => Err(ApiError::BadRequest(format!(
+                "expires_at is in the past: {}",
+                expires_at
+            ))),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct AddedKeyValue {
+    key: String,
+    value: String,
+    shard: usize,
+    created: bool,
+    version: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ValueUpdate {
+    value: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Touch {
+    ttl_seconds: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct BatchTouch {
+    keys: Vec<String>,
+    ttl_seconds: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+struct IncrementBy {
+    by: i64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct CompareAndSwap {
+    expected: Option<String>,
+    new: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct DeleteIf {
+    expected: String,
+}
+
+impl Default for IncrementBy {
+    fn default() -> Self {
+        IncrementBy { by: 1 }
+    }
+}
+
+/// Gets a value using the provided key
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+/// * `default` - Optional query parameter, returned instead of a NotFound when the key is missing.
+/// * `transform` - Optional query parameter, `upper` or `lower` to return the value in upper or
+///   lower case. The stored value is left untouched.
+///
+/// # Returns
+///
+/// The value with OK code if the key exists, the default with OK code if it doesn't and one is
+/// given, None with NotFound code otherwise. BadRequest code if `transform` is neither `upper`
+/// nor `lower`, or is given for a value stored with the `json` type. Values stored with the
+/// `json` type are returned as is rather than as a JSON string. The version of a stored value
+/// is sent in the `X-Version` header and a hash of its content in the `ETag` header,
+/// NotModified code being returned instead of the value if either matches the `If-None-Match`
+/// request header. The time of the last write to the key is sent in the `Last-Modified`
+/// header. With an `X-Shard-Override` header, the key is only looked up in that shard.
+/// The value is sent as is with the `text/plain` content type when the `Accept` header
+/// prefers it.
+async fn get_value(
+    req: HttpRequest,
+    path: web::Path<String>,
+    options: web::Query<GetOptions>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, ApiError> {
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+    let shard_override = shard_override(&req)?;
+    let entry = {
+        let shard_manager = read_lock(&shard_manager);
+        if in_override_shard(&shard_manager, &key, shard_override) {
+            shard_manager.get_entry(&key)
+        } else {
+            None
+        }
+    };
+    metrics.record_get(entry.is_some());
+    let GetOptions { default, transform } = options.into_inner();
+    let transformed = |value: String| match transform {
+        Some(transform) => transform.apply(&value),
+        None => value,
+    };
+
+    match entry {
+        Some(mut entry) => {
+            if transform.is_some() && entry.value_type == ValueType::Json {
+                return Err(ApiError::BadRequest(format!(
+                    "Cannot transform the JSON value of key: {}",
+                    key
+                )));
+            }
+            entry.value = transformed(entry.value);
+            Ok(entry_response(&req, entry))
+        }
+        None => match default.map(transformed) {
+            Some(default) if wants_plain_text(&req) => Ok(HttpResponse::Ok()
+                .content_type(ContentType::plaintext())
+                .body(default)),
+            Some(default) => Ok(HttpResponse::Ok().json(default)),
+            None => Err(ApiError::NotFound(format!("Key not found: {}", key))),
+        },
+    }
+}
+
+/// Whether the `Accept` header of the request lists `text/plain` ahead of `application/json`,
+/// quality values being ignored.
+fn wants_plain_text(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|accept| {
+            accept
+                .split(',')
+                .map(|media_range| media_range.split(';').next().unwrap_or("").trim())
+                .find(|media_type| {
+                    media_type.eq_ignore_ascii_case("text/plain")
+                        || media_type.eq_ignore_ascii_case("application/json")
+                })
+        })
+        .is_some_and(|media_type| media_type.eq_ignore_ascii_case("text/plain"))
+}
+
+/// Shard index named by the `X-Shard-Override` header of the request, if any. A value that
+/// isn't a number is rejected with 400 Bad Request.
+fn shard_override(req: &HttpRequest) -> Result<Option<usize>, ApiError> {
+    let Some(value) = req.headers().get(SHARD_OVERRIDE_HEADER) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .map(Some)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid {} header", SHARD_OVERRIDE_HEADER)))
+}
+
+/// Encoding to tag a written value with, the `encoding` field of the pair taking precedence
+/// over the `X-Value-Encoding` header.
+fn value_encoding(
+    header: Option<&HeaderValue>,
+    encoding: Option<String>,
+) -> Result<Option<String>, ApiError> {
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => match header {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| {
+                    ApiError::BadRequest(format!("Invalid {} header", VALUE_ENCODING_HEADER))
+                })?
+                .to_string(),
+            None => return Ok(None),
+        },
+    };
+    validate_encoding(&encoding)?;
+    Ok(Some(encoding))
+}
+
+/// Whether the key lives in the shard named by the `X-Shard-Override` header, always true
+/// without one.
+fn in_override_shard(
+    shard_manager: &ShardManager,
+    key: &str,
+    shard_override: Option<usize>,
+) -> bool {
+    shard_override.is_none_or(|shard_index| shard_manager.get_shard_index(key) == shard_index)
+}
+
+/// Pins the key to the shard named by the `X-Shard-Override` header, if any. The write is
+/// checked first, so one that's going to be rejected leaves the key where it was.
+fn pin_override(
+    shard_manager: &ShardManager,
+    key: &str,
+    options: &WriteOptions,
+) -> Result<(), ApiError> {
+    let Some(shard_index) = options.shard_override else {
+        return Ok(());
+    };
+    if shard_index >= shard_manager.shard_count() {
+        return Err(ApiError::BadRequest(format!(
+            "No such shard: {}",
+            shard_index
+        )));
+    }
+    if options.nx && shard_manager.contains(key) {
+        return Err(ApiError::Conflict(format!("Key already exists: {}", key)));
+    }
+    if !shard_manager.has_room_for_key(key) {
+        return Err(StoreFull.into());
+    }
+    shard_manager.pin(key.to_string(), shard_index);
+    Ok(())
+}
+
+/// Expiry of a key written with an explicit TTL, a TTL of 0 meaning it never expires.
+fn explicit_expiry(ttl: Duration) -> Option<Duration> {
+    Some(ttl).filter(|ttl| !ttl.is_zero())
+}
+
+/// Expiry of a key written with the requested TTL, the store's default TTL applying without one.
+fn write_expiry(shard_manager: &ShardManager, ttl: Option<Duration>) -> Option<Duration> {
+    ttl.map_or(shard_manager.default_ttl(), explicit_expiry)
+}
+
+/// Serves a stored value along with its version, ETag and modification time, or just those if
+/// the client already holds the value.
+fn entry_response(req: &HttpRequest, entry: Entry) -> HttpResponse {
+    let etag = value_etag(&entry.value);
+    let last_modified = header::LastModified(entry.modified_at.into());
+    if matches_if_none_match(req, entry.version, &etag) {
+        return HttpResponse::NotModified()
+            .insert_header((VERSION_HEADER, entry.version))
+            .insert_header((header::ETAG, etag))
+            .insert_header(last_modified)
+            .finish();
+    }
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header((VERSION_HEADER, entry.version));
+    response.insert_header((header::ETAG, etag));
+    response.insert_header(last_modified);
+    if let Some(encoding) = entry.encoding {
+        response.insert_header((VALUE_ENCODING_HEADER, encoding));
+    }
+    if wants_plain_text(req) {
+        return response
+            .content_type(ContentType::plaintext())
+            .body(entry.value);
+    }
+    match entry.value_type {
+        ValueType::Json => response.content_type(ContentType::json()).body(entry.value),
+        ValueType::String => response.json(entry.value),
+    }
+}
+
+/// Strong ETag of a value, quoted as the header requires. It's derived from the content only,
+/// with a hash that stays the same across builds, so equal values always share an ETag.
+fn value_etag(value: &str) -> String {
+    format!("\"{:016x}\"", HashAlgo::XxHash.hash(value))
+}
+
+/// Whether the `If-None-Match` header of the request lists `etag`, or `version` with or
+/// without quotes.
+fn matches_if_none_match(req: &HttpRequest, version: u64, etag: &str) -> bool {
+    let version = version.to_string();
+    req.headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|candidates| {
+            candidates
+                .split(',')
+                .map(str::trim)
+                .any(|candidate| candidate == etag || candidate.trim_matches('"') == version)
+        })
+}
+
+/// Gets the values of several keys in a single request
+///
+/// # Arguments
+///
+/// * `keys` - A JSON array of the keys to look up.
+///
+/// # Returns
+///
+/// A JSON object mapping every key that exists to its value, missing keys are omitted
+async fn get_many_values(
+    keys: web::Json<Vec<String>>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, ApiError> {
+    let shard_manager = read_lock(&shard_manager);
+    let keys: Vec<String> = keys
+        .into_inner()
+        .into_iter()
+        .map(|key| shard_manager.fold_key(key))
+        .collect();
+    let values = shard_manager.get_many(&keys);
+    let hits = values.len() as u64;
+    metrics.record_gets(hits, keys.len() as u64 - hits);
+
+    Ok(HttpResponse::Ok().json(values))
+}
+
+/// Checks several keys for presence in a single request
+///
+/// # Arguments
+///
+/// * `keys` - A JSON array of the keys to check.
+///
+/// # Returns
+///
+/// A JSON object mapping every key to true if it exists, false otherwise
+async fn exists_many_keys(
+    keys: web::Json<Vec<String>>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    let shard_manager = read_lock(&shard_manager);
+    let keys: Vec<String> = keys
+        .into_inner()
+        .into_iter()
+        .map(|key| shard_manager.fold_key(key))
+        .collect();
+    Ok(HttpResponse::Ok().json(shard_manager.contains_many(&keys)))
+}
+
+/// Gets the values of several keys listed in the query string, a cacheable alternative to
+/// the batch get
+///
+/// # Arguments
+///
+/// * `keys` - The keys to look up, separated by commas, eg. `?keys=a,b,c`. Keys may be
+///   URL-encoded.
+///
+/// # Returns
+///
+/// A JSON object mapping every key that exists to its value, missing keys are omitted. A key
+/// listed several times is looked up and counted in the metrics once
+async fn mget_values(
+    options: web::Query<MGetOptions>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, ApiError> {
+    let shard_manager = read_lock(&shard_manager);
+    let mut listed = HashSet::new();
+    let keys = options
+        .keys
+        .split(',')
+        .filter(|key| !key.is_empty())
+        .map(|key| shard_manager.fold_key(key.to_string()))
+        .filter(|key| listed.insert(key.clone()))
+        .collect::<Vec<_>>();
+    let values = shard_manager.get_many(&keys);
+    let hits = values.len() as u64;
+    metrics.record_gets(hits, keys.len() as u64 - hits);
+
+    Ok(HttpResponse::Ok().json(values))
+}
+
+/// Picks a key at random, every key being as likely to come up, eg. to sample the store
+///
+/// # Returns
+///
+/// The key-value pair with OK code, NotFound code if the store is empty
+async fn get_random_entry(
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    match read_lock(&shard_manager).random_entry() {
+        Some((key, value)) => Ok(HttpResponse::Ok().json(KeyValuePair {
+            key,
+            value,
+            ttl_seconds: None,
+            expires_at: None,
+            value_type: None,
+            encoding: None,
+        })),
+        None => Err(ApiError::NotFound("The store is empty".to_string())),
+    }
+}
+
+/// Lists the keys written to after a point in time, eg. to sync a copy of the store
+///
+/// # Arguments
+///
+/// * `since` - Query parameter holding the point in time, as a Unix timestamp in seconds.
+///
+/// # Returns
+///
+/// The sorted keys modified after `since` with OK code
+async fn get_changed_keys(
+    options: web::Query<ChangedOptions>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    let since = UNIX_EPOCH + Duration::from_secs(options.since);
+    let mut keys = read_lock(&shard_manager).changed_since(since);
+    keys.sort();
+
+    Ok(HttpResponse::Ok().json(keys))
+}
+
+/// Counts the keys stored across all shards
+///
+/// # Returns
+///
+/// The total number of keys with OK code
+async fn get_count(
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(read_lock(&shard_manager).len()))
+}
+
+/// Counts the keys starting with a prefix, eg. `user:` for hierarchical keys
+///
+/// # Arguments
+///
+/// * `prefix` - Query parameter holding the prefix, every key matching when it's empty.
+///
+/// # Returns
+///
+/// The number of keys starting with the prefix with OK code
+async fn get_prefix_count(
+    options: web::Query<PrefixOptions>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    let shard_manager = read_lock(&shard_manager);
+    let prefix = shard_manager.fold_key(options.into_inner().prefix);
+    Ok(HttpResponse::Ok().json(shard_manager.count_prefix(&prefix)))
+}
+
+/// Reports how keys are distributed across shards
+///
+/// # Returns
+///
+/// A JSON array where index i holds the number of keys in shard i, with OK code
+async fn get_shard_sizes(
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(read_lock(&shard_manager).shard_sizes()))
+}
+
+/// Reports the number of gets, sets and deletes each shard has served, to spot hot shards
+///
+/// # Returns
+///
+/// A JSON array where index i holds the `gets`, `sets` and `deletes` counters of shard i, with
+/// OK code
+async fn get_shard_ops(
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(read_lock(&shard_manager).shard_ops()))
+}
+
+/// Estimates the memory used by the store, for capacity planning
+///
+/// # Returns
+///
+/// A JSON object with the estimated bytes used by each shard under `shards` and by the whole
+/// store under `total`, with OK code. Estimates count the keys and values along with a fixed
+/// overhead per entry
+async fn get_memory_stats(
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    let shard_manager = read_lock(&shard_manager);
+    Ok(HttpResponse::Ok().json(MemoryStats {
+        shards: shard_manager.estimated_shard_bytes(),
+        total: shard_manager.estimated_bytes(),
+    }))
+}
+
+/// Gets the remaining lifetime of a key
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+///
+/// # Returns
+///
+/// The remaining seconds, or -1 if the key never expires, with OK code. NotFound code if the
+/// key doesn't exist
+async fn get_ttl(
+    path: web::Path<String>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+
+    match read_lock(&shard_manager).ttl(&key) {
+        Some(ttl) => Ok(HttpResponse::Ok().json(ttl)),
+        None => Err(ApiError::NotFound(format!("Key not found: {}", key))),
+    }
+}
+
+/// Describes a key without transferring its value, for debugging
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+///
+/// # Returns
+///
+/// A JSON object with the length of the value in bytes, the index of the shard holding the key
+/// and the seconds left before it expires, null if it never does, with OK code. NotFound code
+/// if the key doesn't exist
+async fn get_key_meta(
+    path: web::Path<String>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+    let shard_manager = read_lock(&shard_manager);
+
+    let value_length = shard_manager
+        .value_len(&key)
+        .ok_or_else(|| ApiError::NotFound(format!("Key not found: {}", key)))?;
+    Ok(HttpResponse::Ok().json(KeyMeta {
+        value_length,
+        shard_index: shard_manager.get_shard_index(&key),
+        ttl_remaining: shard_manager.ttl(&key).filter(|&ttl| ttl >= 0),
+    }))
+}
+
+/// Checks whether a key exists without transferring its value
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+///
+/// # Returns
+///
+/// OK code if the key exists, NotFound code if it doesn't
+async fn head_value(
+    path: web::Path<String>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+
+    if read_lock(&shard_manager).contains(&key) {
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Err(ApiError::NotFound(format!("Key not found: {}", key)))
+    }
+}
+
+/// Adds a key-value pair to the shard manager and returns the shard index.
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+/// * `value` - A string slice that holds the value.
+/// * `ttl_seconds` - An optional number of seconds after which the key expires, 0 meaning
+///   never. The default TTL applies when neither it nor `expires_at` is given.
+/// * `expires_at` - An optional Unix timestamp at which the key expires, taking precedence over
+///   `ttl_seconds`. BadRequest code if it's already past.
+/// * `value_type` - Optional, `"string"` (the default) or `"json"`. JSON values are rejected
+///   with BadRequest code if they don't parse.
+/// * `encoding` - Optional encoding the value is tagged with, eg. `"gzip"`, also accepted as
+///   an `X-Value-Encoding` header. It's stored as is and sent back in the same header on reads.
+/// * `nx` - Optional query parameter, when true the pair is only inserted if the key is absent.
+///
+/// When the write queue is enabled, writes without `nx` are handed to it and applied in a
+/// batch with other writes, the response being sent once the write is applied.
+///
+/// An `X-Shard-Override: <index>` header pins the key to that shard instead of the one it
+/// hashes to, BadRequest code being returned if there is no such shard.
+///
+/// # Returns
+///
+/// A JSON object with the key, the value, the shard index where the pair was stored, whether
+/// the key was created (false if an existing value was overwritten) and its new version. Conflict code
+/// if `nx` is set and the key already exists.
+///
+async fn add_key_value(
+    item: web::Json<KeyValuePair>,
+    options: WriteOptions,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    write_batcher: Option<web::Data<WriteBatcher>>,
+    metrics: web::Data<Metrics>,
+    drain_mode: web::Data<DrainMode>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let ttl = item.ttl()?;
+    let KeyValuePair {
+        key,
+        value,
+        value_type,
+        encoding,
+        ..
+    } = item.into_inner();
+    let key = read_lock(&shard_manager).fold_key(key);
+    let value_type = value_type.unwrap_or_default();
+    validate_key(&key, &config)?;
+    check_value_size(value.len(), &config)?;
+    validate_value_type(&value, value_type)?;
+    let encoding = value_encoding(options.encoding.as_ref(), encoding)?;
+    metrics.record_sets(1);
+
+    // Conditional inserts check the key under the lock, so only plain writes are queued
+    if let Some(write_batcher) = write_batcher.filter(|_| !options.nx) {
+        pin_override(&read_lock(&shard_manager), &key, &options)?;
+        let applied = write_batcher
+            .set(key.clone(), value.clone(), ttl, value_type, encoding)
+            .await
+            .ok_or_else(|| {
+                ApiError::ServiceUnavailable("The write queue is closed".to_string())
+            })??;
+        return Ok(HttpResponse::Ok().json(AddedKeyValue {
+            key,
+            value,
+            shard: applied.shard,
+            created: applied.created,
+            version: applied.version,
+        }));
+    }
+
+    let locked_shard_manager = read_lock(&shard_manager);
+    pin_override(&locked_shard_manager, &key, &options)?;
+    if options.nx {
+        let created = locked_shard_manager.set_if_absent_tagged(
+            key.clone(),
+            value.clone(),
+            write_expiry(&locked_shard_manager, ttl),
+            ValueTags {
+                value_type,
+                encoding,
+            },
+        )?;
+        if !created {
+            return Err(ApiError::Conflict(format!("Key already exists: {}", key)));
+        }
+        return Ok(HttpResponse::Ok().json(AddedKeyValue {
+            shard: locked_shard_manager.get_shard_index(&key),
+            version: locked_shard_manager.version(&key).unwrap_or_default(),
+            key,
+            value,
+            created,
+        }));
+    }
+
+    let (shard, created) = locked_shard_manager.set_tagged(
+        key.clone(),
+        value.clone(),
+        write_expiry(&locked_shard_manager, ttl),
+        ValueTags {
+            value_type,
+            encoding,
+        },
+    )?;
+    Ok(HttpResponse::Ok().json(AddedKeyValue {
+        version: locked_shard_manager.version(&key).unwrap_or_default(),
+        key,
+        value,
+        shard,
+        created,
+    }))
+}
+
+/// Retrieves the value of a key as the raw response body, without the JSON encoding of
+/// `GET /api/{key}`
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+///
+/// # Returns
+///
+/// The value as `text/plain` with OK code, its version in the `X-Version` header and its
+/// encoding, if tagged with one, in `X-Value-Encoding`. NotFound code if the key doesn't exist
+async fn get_raw_value(
+    path: web::Path<String>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, ApiError> {
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+    let entry = read_lock(&shard_manager).get_entry(&key);
+    metrics.record_get(entry.is_some());
+
+    match entry {
+        Some(entry) => {
+            let mut response = HttpResponse::Ok();
+            response.insert_header((VERSION_HEADER, entry.version));
+            if let Some(encoding) = entry.encoding {
+                response.insert_header((VALUE_ENCODING_HEADER, encoding));
+            }
+            Ok(response
+                .content_type(ContentType::plaintext())
+                .body(entry.value))
+        }
+        None => Err(ApiError::NotFound(format!("Key not found: {}", key))),
+    }
+}
+
+/// Stores the raw request body as the value of a key, without the JSON envelope of `POST /api`
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+/// * `body` - The value, sent as is, eg. with the `text/plain` content type.
+/// * `X-Value-Encoding` - Optional header, the encoding to tag the value with.
+///
+/// # Returns
+///
+/// An empty body with Created code if the key is new, OK code if its value was replaced, the
+/// new version being sent in the `X-Version` header. BadRequest code if the body isn't valid
+/// UTF-8 or the encoding is invalid, PayloadTooLarge code if it's longer than `max_value_bytes`
+async fn add_raw_value(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Bytes,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    metrics: web::Data<Metrics>,
+    drain_mode: web::Data<DrainMode>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+    validate_key(&key, &config)?;
+    check_value_size(body.len(), &config)?;
+    let value = String::from_utf8(body.to_vec())
+        .map_err(|_| ApiError::BadRequest("The value must be valid UTF-8".to_string()))?;
+    let encoding = value_encoding(req.headers().get(VALUE_ENCODING_HEADER), None)?;
+    metrics.record_sets(1);
+
+    let locked_shard_manager = read_lock(&shard_manager);
+    let (_, created) = locked_shard_manager.set_tagged(
+        key.clone(),
+        value,
+        write_expiry(&locked_shard_manager, None),
+        ValueTags {
+            encoding,
+            ..ValueTags::default()
+        },
+    )?;
+    let mut response = if created {
+        HttpResponse::Created()
+    } else {
+        HttpResponse::Ok()
+    };
+    Ok(response
+        .insert_header((
+            VERSION_HEADER,
+            locked_shard_manager.version(&key).unwrap_or_default(),
+        ))
+        .finish())
+}
+
+/// Adds several key-value pairs in a single request. The batch is all or nothing: if any
+/// pair fails validation, none is stored.
+///
+/// `ttl_seconds` and `expires_at` are not supported for batch inserts, every pair is stored
+/// without expiry.
+///
+/// # Arguments
+///
+/// * `pairs` - A JSON array of key-value pairs.
+/// * `dry_run` - Optional query parameter, when true the pairs are only validated.
+///
+/// # Returns
+///
+/// A JSON object mapping each shard index to the number of pairs stored in it, or the error
+/// of the first invalid pair. With `dry_run`, a JSON object listing the `accepted` keys and
+/// the `rejected` ones along with their error, the store being left untouched.
+async fn add_many_key_values(
+    pairs: web::Json<Vec<KeyValuePair>>,
+    options: web::Query<BatchSetOptions>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    metrics: web::Data<Metrics>,
+    drain_mode: web::Data<DrainMode>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let pairs = {
+        let shard_manager = read_lock(&shard_manager);
+        pairs
+            .into_inner()
+            .into_iter()
+            .map(|pair| KeyValuePair {
+                key: shard_manager.fold_key(pair.key),
+                ..pair
+            })
+            .collect::<Vec<_>>()
+    };
+
+    if options.dry_run {
+        let mut validation = BatchValidation {
+            accepted: Vec::new(),
+            rejected: Vec::new(),
+        };
+        for pair in pairs {
+            match validate_pair(&pair, &config) {
+                Ok(()) => validation.accepted.push(pair.key),
+                Err(err) => validation.rejected.push(RejectedPair {
+                    key: pair.key,
+                    error: err.detail(),
+                }),
+            }
+        }
+        return Ok(HttpResponse::Ok().json(validation));
+    }
+
+    for pair in &pairs {
+        validate_pair(pair, &config)?;
+    }
+    metrics.record_sets(pairs.len() as u64);
+
+    let pairs = pairs
+        .into_iter()
+        .map(|pair| {
+            let tags = ValueTags {
+                value_type: pair.value_type.unwrap_or_default(),
+                encoding: pair.encoding,
+            };
+            (pair.key, pair.value, tags)
+        })
+        .collect::<Vec<_>>();
+
+    let counts = read_lock(&shard_manager).set_many_tagged(pairs)?;
+    Ok(HttpResponse::Ok().json(counts))
+}
+
+/// Dumps the whole store, eg. for backups or to migrate it to another instance.
+///
+/// # Returns
+///
+/// A JSON object mapping every key to its value, with OK code
+async fn export_key_values(
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(read_lock(&shard_manager).export()))
+}
+
+/// Streams the whole store as newline-delimited JSON, one `{"key": ..., "value": ...}` object
+/// per line, so only one shard is copied at a time rather than the whole store.
+///
+/// # Returns
+///
+/// The pairs with OK code, sent in chunks of one shard each
+async fn stream_export_key_values(
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .body(ExportStream {
+            shard_manager: shard_manager.get_ref().clone(),
+            next_shard: 0,
+        }))
+}
+
+/// Body of `GET /api/export/stream`, producing a chunk of lines per non-empty shard. Writes
+/// made while the body is being sent show up in it only if their shard wasn't sent yet.
+struct ExportStream {
+    shard_manager: Arc<RwLock<ShardManager>>,
+    next_shard: usize,
+}
+
+impl MessageBody for ExportStream {
+    type Error = serde_json::Error;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<web::Bytes, Self::Error>>> {
+        loop {
+            let pairs = {
+                let shard_manager = read_lock(&self.shard_manager);
+                if self.next_shard >= shard_manager.shard_count() {
+                    return Poll::Ready(None);
+                }
+                shard_manager.shard_entries(self.next_shard)
+            };
+            self.next_shard += 1;
+            if pairs.is_empty() {
+                continue;
+            }
+
+            let mut chunk = Vec::new();
+            for (key, value) in pairs {
+                let pair = KeyValuePair {
+                    key,
+                    value,
+                    ttl_seconds: None,
+                    expires_at: None,
+                    value_type: None,
+                    encoding: None,
+                };
+                if let Err(err) = serde_json::to_writer(&mut chunk, &pair) {
+                    return Poll::Ready(Some(Err(err)));
+                }
+                chunk.push(b'\n');
+            }
+            return Poll::Ready(Some(Ok(web::Bytes::from(chunk))));
+        }
+    }
+}
+
+/// Reports the number of operations served since startup
+///
+/// # Returns
+///
+/// A JSON object with the gets, sets, deletes, hits and misses counters, along with the hit
+/// ratio, with OK code
+async fn get_metrics(metrics: web::Data<Metrics>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(metrics.snapshot()))
+}
+
+/// Reports the operation counters, the number of entries of each shard and how often its
+/// write lock had to wait, for Prometheus to scrape
+///
+/// # Returns
+///
+/// The metrics in the Prometheus text exposition format, with OK code
+async fn get_prometheus_metrics(
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, ApiError> {
+    let (shard_sizes, lock_contention) = {
+        let shard_manager = read_lock(&shard_manager);
+        (shard_manager.shard_sizes(), shard_manager.lock_contention())
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(
+            metrics
+                .snapshot()
+                .to_prometheus(&shard_sizes, &lock_contention),
+        ))
+}
+
+/// Walks the store one page at a time, a lighter alternative to exporting it all at once
+///
+/// # Arguments
+///
+/// * `cursor` - Query parameter, position of the first pair to return. Defaults to 0.
+/// * `limit` - Query parameter, maximum number of pairs to return. Defaults to 100.
+///
+/// # Returns
+///
+/// A JSON object with the page of pairs and the cursor of the next page, null once the whole
+/// store has been walked, with OK code
+async fn scan_key_values(
+    options: web::Query<ScanOptions>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    let (pairs, next_cursor) = read_lock(&shard_manager).scan(options.cursor, options.limit.max(1));
+
+    Ok(HttpResponse::Ok().json(ScanPage {
+        pairs: pairs
+            .into_iter()
+            .map(|(key, value)| KeyValuePair {
+                key,
+                value,
+                ttl_seconds: None,
+                expires_at: None,
+                value_type: None,
+                encoding: None,
+            })
+            .collect(),
+        next_cursor,
+    }))
+}
+
+/// Inserts a full dataset, eg. to bootstrap a new instance from another one's export.
+///
+/// # Arguments
+///
+/// * `data` - A JSON object mapping keys to values.
+/// * `overwrite` - Query parameter, when false keys that already exist are skipped. Defaults to true.
+///
+/// # Returns
+///
+/// The number of imported pairs, with OK code
+async fn import_key_values(
+    data: web::Json<HashMap<String, String>>,
+    options: web::Query<ImportOptions>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    drain_mode: web::Data<DrainMode>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let shard_manager = read_lock(&shard_manager);
+    let data = data
+        .into_inner()
+        .into_iter()
+        .map(|(key, value)| (shard_manager.fold_key(key), value))
+        .collect();
+    let imported = shard_manager.import(data, options.overwrite)?;
+
+    Ok(HttpResponse::Ok().json(ImportResult { imported }))
+}
+
+/// Updates the value of an existing key, without creating it if it's missing.
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+/// * `value` - A string slice that holds the new value.
+///
+/// # Returns
+///
+/// The updated key-value pair with OK code and its new version in the `X-Version` header if
+/// the key exists, NotFound code if it doesn't. The key keeps its value type, so a `json` key
+/// only accepts valid JSON.
+async fn update_key_value(
+    path: web::Path<String>,
+    item: web::Json<ValueUpdate>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    metrics: web::Data<Metrics>,
+    drain_mode: web::Data<DrainMode>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+    let value = item.into_inner().value;
+    validate_key(&key, &config)?;
+    check_value_size(value.len(), &config)?;
+    metrics.record_sets(1);
+
+    let locked_shard_manager = read_lock(&shard_manager);
+    if let Some(value_type) = locked_shard_manager.value_type(&key) {
+        validate_value_type(&value, value_type)?;
+    }
+    match locked_shard_manager.update(&key, value.clone())? {
+        Some(_) => Ok(HttpResponse::Ok()
+            .insert_header((
+                VERSION_HEADER,
+                locked_shard_manager.version(&key).unwrap_or_default(),
+            ))
+            .json(KeyValuePair {
+                key,
+                value,
+                ttl_seconds: None,
+                expires_at: None,
+                value_type: None,
+                encoding: None,
+            })),
+        None => Err(ApiError::NotFound(format!("Key not found: {}", key))),
+    }
+}
+
+/// Applies a JSON Merge Patch (RFC 7386) to the JSON value of a key: objects are merged
+/// recursively, `null` members remove the matching field and any other value replaces it.
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+/// * `patch` - The JSON body to merge into the current value.
+///
+/// # Returns
+///
+/// The patched value with OK code and its new version in the `X-Version` header, NotFound
+/// code if the key doesn't exist, BadRequest code if its current value isn't valid JSON
+async fn patch_key_value(
+    path: web::Path<String>,
+    patch: web::Json<serde_json::Value>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    metrics: web::Data<Metrics>,
+    drain_mode: web::Data<DrainMode>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+    validate_key(&key, &config)?;
+    metrics.record_sets(1);
+
+    let locked_shard_manager = read_lock(&shard_manager);
+    let mut document = serde_json::Value::Null;
+    let patched: Option<Result<String, ApiError>> =
+        locked_shard_manager.update_with(&key, |current| {
+            document = serde_json::from_str(&current).map_err(|err| {
+                ApiError::BadRequest(format!("Value of key: {} is not valid JSON: {}", key, err))
+            })?;
+            merge_patch(&mut document, patch.into_inner());
+            let value = document.to_string();
+            check_value_size(value.len(), &config)?;
+            Ok(value)
+        })?;
+    patched.ok_or_else(|| ApiError::NotFound(format!("Key not found: {}", key)))??;
+    Ok(HttpResponse::Ok()
+        .insert_header((
+            VERSION_HEADER,
+            locked_shard_manager.version(&key).unwrap_or_default(),
+        ))
+        .json(document))
+}
+
+/// Merges `patch` into `target` as described by RFC 7386.
+fn merge_patch(target: &mut serde_json::Value, patch: serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+    let serde_json::Value::Object(target) = target else {
+        return;
+    };
+    for (name, value) in patch {
+        if value.is_null() {
+            target.remove(&name);
+        } else {
+            merge_patch(target.entry(name).or_insert(serde_json::Value::Null), value);
+        }
+    }
+}
+
+/// Atomically increments the integer stored at a key, a missing key counting as 0.
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+/// * `by` - An optional amount to add, read from a `{"by": <i64>}` JSON body. Defaults to 1.
+///
+/// # Returns
+///
+/// The new value with OK code, BadRequest code if the body is invalid or the current value
+/// isn't an integer
+async fn increment_value(
+    path: web::Path<String>,
+    body: web::Bytes,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    drain_mode: web::Data<DrainMode>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+    validate_key(&key, &config)?;
+    let by = parse_increment_by(&body)?;
+
+    match read_lock(&shard_manager).increment(&key, by)? {
+        Ok(value) => Ok(HttpResponse::Ok().json(value)),
+        Err(err) => Err(ApiError::BadRequest(format!(
+            "Cannot increment key: {}, {}",
+            key, err
+        ))),
+    }
+}
+
+/// Atomically increments several counters in a single request. Each counter is incremented on
+/// its own, so one that fails doesn't prevent the others from being incremented.
+///
+/// # Arguments
+///
+/// * `deltas` - A JSON object mapping each key to the amount to add to it, which may be
+///   negative.
+///
+/// # Returns
+///
+/// A JSON object with OK code, holding the new value of every counter incremented under
+/// `values`, and the error of every other one, eg. holding a value that isn't an integer,
+/// under `errors`
+async fn increment_many_values(
+    deltas: web::Json<HashMap<String, i64>>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    drain_mode: web::Data<DrainMode>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let mut increments = BatchIncrements {
+        values: HashMap::new(),
+        errors: HashMap::new(),
+    };
+
+    let shard_manager = read_lock(&shard_manager);
+    for (key, by) in deltas.into_inner() {
+        let key = shard_manager.fold_key(key);
+        let incremented = validate_key(&key, &config).and_then(|()| {
+            shard_manager.increment(&key, by)?.map_err(|err| {
+                ApiError::BadRequest(format!("Cannot increment key: {}, {}", key, err))
+            })
+        });
+        match incremented {
+            Ok(value) => {
+                increments.values.insert(key, value);
+            }
+            Err(err) => {
+                increments.errors.insert(key, err.detail());
+            }
+        }
+    }
+    Ok(HttpResponse::Ok().json(increments))
+}
+
+/// Atomically decrements the integer stored at a key, a missing key counting as 0.
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+/// * `by` - An optional amount to subtract, read from a `{"by": <i64>}` JSON body. Defaults
+///   to 1.
+///
+/// # Returns
+///
+/// The new value with OK code, BadRequest code if the body is invalid or the current value
+/// isn't an integer
+async fn decrement_value(
+    path: web::Path<String>,
+    body: web::Bytes,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    drain_mode: web::Data<DrainMode>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+    validate_key(&key, &config)?;
+    let by = parse_increment_by(&body)?;
+    let by = by
+        .checked_neg()
+        .ok_or_else(|| ApiError::BadRequest(format!("Cannot decrement by: {}", by)))?;
+
+    match read_lock(&shard_manager).increment(&key, by)? {
+        Ok(value) => Ok(HttpResponse::Ok().json(value)),
+        Err(err) => Err(ApiError::BadRequest(format!(
+            "Cannot decrement key: {}, {}",
+            key, err
+        ))),
+    }
+}
+
+/// Reads the amount of the counter endpoints from an optional `{"by": <i64>}` body, 1 when
+/// the body is empty.
+fn parse_increment_by(body: &[u8]) -> Result<i64, ApiError> {
+    if body.is_empty() {
+        return Ok(IncrementBy::default().by);
+    }
+    serde_json::from_slice::<IncrementBy>(body)
+        .map(|increment| increment.by)
+        .map_err(|err| ApiError::BadRequest(format!("Invalid body: {}", err)))
+}
+
+/// Sets a key to a new value only if it currently holds the expected one, for safe
+/// read-modify-write cycles.
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+/// * `expected` - The value the key must currently hold, `null` meaning the key must be absent.
+/// * `new` - The value to store.
+///
+/// # Returns
+///
+/// OK code if the value was swapped, Conflict code if the current value didn't match
+async fn compare_and_swap(
+    path: web::Path<String>,
+    item: web::Json<CompareAndSwap>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    drain_mode: web::Data<DrainMode>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+    let CompareAndSwap { expected, new } = item.into_inner();
+
+    if read_lock(&shard_manager).compare_and_swap(&key, expected, new)? {
+        Ok(HttpResponse::Ok().json(format!("Swapped value of key: {}", key)))
+    } else {
+        Err(ApiError::Conflict(format!(
+            "Value of key: {} doesn't match the expected one",
+            key
+        )))
+    }
+}
+
+/// Deletes a key only if it currently holds the expected value, so a value changed since it
+/// was read isn't lost
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+/// * `expected` - The value the key must currently hold.
+///
+/// # Returns
+///
+/// OK code if the key was deleted, Conflict code if its value didn't match, NotFound code if
+/// it doesn't exist
+async fn delete_key_if(
+    path: web::Path<String>,
+    item: web::Json<DeleteIf>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    metrics: web::Data<Metrics>,
+    drain_mode: web::Data<DrainMode>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+    metrics.record_deletes(1);
+
+    match read_lock(&shard_manager).delete_if(&key, &item.into_inner().expected)? {
+        DeleteIfResult::Deleted => Ok(HttpResponse::Ok().json(format!("Deleted key: {}", key))),
+        DeleteIfResult::Mismatch => Err(ApiError::Conflict(format!(
+            "Value of key: {} doesn't match the expected one",
+            key
+        ))),
+        DeleteIfResult::NotFound => Err(ApiError::NotFound(format!("Key not found: {}", key))),
+    }
+}
+
+/// Delete a key from the hashmap
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+///
+/// # Returns
+///
+/// OK code if the key existed and was removed, NotFound code if it doesn't exist. With an
+/// `X-Shard-Override` header, the key is only looked up in that shard.
+async fn delete_key(
+    req: HttpRequest,
+    path: web::Path<String>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    metrics: web::Data<Metrics>,
+    drain_mode: web::Data<DrainMode>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+    let shard_override = shard_override(&req)?;
+    metrics.record_deletes(1);
+
+    let locked_shard_manager = read_lock(&shard_manager);
+    let deleted = if in_override_shard(&locked_shard_manager, &key, shard_override) {
+        locked_shard_manager.delete(&key)?
+    } else {
+        None
+    };
+    match deleted {
+        Some(_) => Ok(HttpResponse::Ok().json(format!("Deleted key: {}", key))),
+        None => Err(ApiError::NotFound(format!("Key not found: {}", key))),
+    }
+}
+
+/// Adds a key-value pair to a namespace, creating the namespace if it doesn't exist yet.
+/// Keys of different namespaces never collide.
+///
+/// # Arguments
+///
+/// * `namespace` - A string slice that holds the namespace.
+/// * `key` - A string slice that holds the key.
+/// * `value` - A string slice that holds the value.
+/// * `ttl_seconds` - An optional number of seconds after which the key expires, 0 meaning
+///   never. The default TTL applies when neither it nor `expires_at` is given.
+/// * `expires_at` - An optional Unix timestamp at which the key expires, taking precedence over
+///   `ttl_seconds`. BadRequest code if it's already past.
+/// * `value_type` - Optional, `"string"` (the default) or `"json"`.
+/// * `encoding` - Optional encoding the value is tagged with, also accepted as an
+///   `X-Value-Encoding` header.
+///
+/// # Returns
+///
+/// A JSON object with the key, the value, the shard index of the namespace where the pair was
+/// stored, whether the key was created and its new version, InsufficientStorage code if the
+/// store and its namespaces are full. BadRequest code if the namespace is named after a fixed
+/// route, or if the store is persisted to a data file or a WAL, which namespaces aren't
+async fn add_namespaced_key_value(
+    req: HttpRequest,
+    path: web::Path<String>,
+    item: web::Json<KeyValuePair>,
+    namespaces: web::Data<Namespaces>,
+    metrics: web::Data<Metrics>,
+    drain_mode: web::Data<DrainMode>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let namespace = path.into_inner();
+    if namespaces.is_disabled() {
+        return Err(ApiError::BadRequest(
+            "Namespaces live in memory only, so they're disabled when the store is persisted"
+                .to_string(),
+        ));
+    }
+    if RESERVED_NAMESPACES.contains(&namespace.as_str()) {
+        return Err(ApiError::BadRequest(format!(
+            "Namespace: {} is the name of a fixed route",
+            namespace
+        )));
+    }
+    let pair = item.into_inner();
+    validate_pair(&pair, &config)?;
+    let ttl = pair.ttl()?;
+    metrics.record_sets(1);
+
+    let shard_manager = namespaces.get_or_create(&namespace, || config.build_shard_manager());
+    let KeyValuePair {
+        key,
+        value,
+        value_type,
+        encoding,
+        ..
+    } = pair;
+    let key = shard_manager.fold_key(key);
+    let encoding = value_encoding(req.headers().get(VALUE_ENCODING_HEADER), encoding)?;
+    let writes = namespaces.lock_writes();
+    check_namespace_budget(&req, &namespaces, &shard_manager, &key, &value, &config)?;
+    let (shard, created) = shard_manager.set_tagged(
+        key.clone(),
+        value.clone(),
+        write_expiry(&shard_manager, ttl),
+        ValueTags {
+            value_type: value_type.unwrap_or_default(),
+            encoding,
+        },
+    )?;
+    drop(writes);
+    Ok(HttpResponse::Ok().json(AddedKeyValue {
+        version: shard_manager.version(&key).unwrap_or_default(),
+        key,
+        value,
+        shard,
+        created,
+    }))
+}
+
+/// Rejects a write to a namespace with 507 Insufficient Storage if it would take the main
+/// store and the namespaces together past `max_total_keys` or `max_total_bytes`, which they
+/// share rather than each namespace getting its own. The main store is the one served to the
+/// request, if any. Must be called under `Namespaces::lock_writes` along with the write.
+fn check_namespace_budget(
+    req: &HttpRequest,
+    namespaces: &Namespaces,
+    namespace: &ShardManager,
+    key: &str,
+    value: &str,
+    config: &Config,
+) -> Result<(), ApiError> {
+    let (store_keys, store_bytes) = match req.app_data::<web::Data<Arc<RwLock<ShardManager>>>>() {
+        Some(store) => {
+            let store = read_lock(store);
+            (store.len(), store.total_bytes())
+        }
+        None => (0, 0),
+    };
+    let current_len = namespace.value_len(key);
+    if let Some(max_keys) = config.max_total_keys {
+        if current_len.is_none() && store_keys + namespaces.total_keys() >= max_keys {
+            return Err(StoreFull.into());
+        }
+    }
+    if let Some(max_bytes) = config.max_total_bytes {
+        let freed = current_len.map_or(0, |len| key.len() + len);
+        let total = (store_bytes + namespaces.total_bytes()).saturating_sub(freed);
+        if total + key.len() + value.len() > max_bytes {
+            return Err(ApiError::InsufficientStorage(format!(
+                "The store has no room left for key: {}",
+                key
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Gets the value of a key in a namespace
+///
+/// # Arguments
+///
+/// * `namespace` - A string slice that holds the namespace.
+/// * `key` - A string slice that holds the key.
+///
+/// # Returns
+///
+/// The value with OK code and its version in the `X-Version` header, handled like
+/// `GET /api/{key}`. NotFound code if the key or the namespace doesn't exist
+async fn get_namespaced_value(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    namespaces: web::Data<Namespaces>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, ApiError> {
+    let (namespace, key) = path.into_inner();
+    let entry = namespaces
+        .get(&namespace)
+        .and_then(|shard_manager| shard_manager.get_entry(&shard_manager.fold_key(key.clone())));
+    metrics.record_get(entry.is_some());
+
+    match entry {
+        Some(entry) => Ok(entry_response(&req, entry)),
+        None => Err(ApiError::NotFound(format!(
+            "Key not found: {} in namespace: {}",
+            key, namespace
+        ))),
+    }
+}
+
+/// Deletes a key from a namespace
+///
+/// # Arguments
+///
+/// * `namespace` - A string slice that holds the namespace.
+/// * `key` - A string slice that holds the key.
+///
+/// # Returns
+///
+/// OK code if the key existed and was removed, NotFound code if it doesn't exist
+async fn delete_namespaced_key(
+    path: web::Path<(String, String)>,
+    namespaces: web::Data<Namespaces>,
+    metrics: web::Data<Metrics>,
+    drain_mode: web::Data<DrainMode>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let (namespace, key) = path.into_inner();
+    metrics.record_deletes(1);
+
+    let deleted = match namespaces.get(&namespace) {
+        Some(shard_manager) => shard_manager.delete(&shard_manager.fold_key(key.clone()))?,
+        None => None,
+    };
+    match deleted {
+        Some(_) => Ok(HttpResponse::Ok().json(format!(
+            "Deleted key: {} from namespace: {}",
+            key, namespace
+        ))),
+        None => Err(ApiError::NotFound(format!(
+            "Key not found: {} in namespace: {}",
+            key, namespace
+        ))),
+    }
+}
+
+/// Deletes several keys in a single request
+///
+/// # Arguments
+///
+/// * `keys` - A JSON array of the keys to delete.
+///
+/// # Returns
+///
+/// The number of keys actually removed with OK code, missing keys not being counted
+async fn delete_many_keys(
+    keys: web::Json<Vec<String>>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    metrics: web::Data<Metrics>,
+    drain_mode: web::Data<DrainMode>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    metrics.record_deletes(keys.len() as u64);
+
+    let shard_manager = read_lock(&shard_manager);
+    let keys: Vec<String> = keys
+        .into_inner()
+        .into_iter()
+        .map(|key| shard_manager.fold_key(key))
+        .collect();
+    let removed = shard_manager.delete_many(&keys)?;
+    Ok(HttpResponse::Ok().json(removed))
+}
+
+/// Deletes every key starting with a prefix, eg. to retire a family of keys such as `session:`
+///
+/// # Arguments
+///
+/// * `prefix` - A string slice that holds the prefix.
+///
+/// # Returns
+///
+/// The number of keys removed with OK code
+async fn delete_prefix(
+    prefix: web::Path<String>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    metrics: web::Data<Metrics>,
+    drain_mode: web::Data<DrainMode>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let shard_manager = read_lock(&shard_manager);
+    let prefix = shard_manager.fold_key(prefix.into_inner());
+    let removed = shard_manager.delete_prefix(&prefix)?;
+    metrics.record_deletes(removed as u64);
+
+    Ok(HttpResponse::Ok().json(removed))
+}
+
+/// Sets a key to a new value and returns the one it replaced, in a single step
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+/// * `value` - A string slice that holds the new value.
+///
+/// # Returns
+///
+/// The previous value, or null if the key didn't exist, with OK code
+async fn get_set_value(
+    path: web::Path<String>,
+    item: web::Json<ValueUpdate>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    drain_mode: web::Data<DrainMode>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+    let value = item.into_inner().value;
+    validate_key(&key, &config)?;
+    check_value_size(value.len(), &config)?;
+
+    let previous = read_lock(&shard_manager).get_set(key, value)?;
+    Ok(HttpResponse::Ok().json(previous))
+}
+
+/// Appends a string to the value of a key, creating the key if it's missing
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+/// * `suffix` - A JSON string to append to the current value.
+///
+/// # Returns
+///
+/// The length of the new value in bytes with OK code, PayloadTooLarge code if it would go over
+/// `max_value_bytes`
+async fn append_value(
+    path: web::Path<String>,
+    suffix: web::Json<String>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    drain_mode: web::Data<DrainMode>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+    validate_key(&key, &config)?;
+
+    let locked_shard_manager = read_lock(&shard_manager);
+    let current_len = locked_shard_manager.value_len(&key).unwrap_or(0);
+    check_value_size(current_len + suffix.len(), &config)?;
+    let len = locked_shard_manager.append(&key, &suffix)?;
+    Ok(HttpResponse::Ok().json(len))
+}
+
+/// Resets the expiry of a key without rewriting its value, eg. to keep a session alive
+///
+/// # Arguments
+///
+/// * `key` - A string slice that holds the key.
+/// * `ttl_seconds` - The number of seconds from now after which the key expires, 0 meaning
+///   never.
+///
+/// # Returns
+///
+/// OK code if the key exists, NotFound code if it doesn't
+async fn touch_key(
+    path: web::Path<String>,
+    item: web::Json<Touch>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    drain_mode: web::Data<DrainMode>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let key = read_lock(&shard_manager).fold_key(path.into_inner());
+    let ttl = explicit_expiry(Duration::from_secs(item.ttl_seconds));
+
+    if read_lock(&shard_manager).touch(&key, ttl)? {
+        Ok(HttpResponse::Ok().json(format!("Touched key: {}", key)))
+    } else {
+        Err(ApiError::NotFound(format!("Key not found: {}", key)))
+    }
+}
+
+/// Resets the expiry of several keys in a single request, without rewriting their values
+///
+/// # Arguments
+///
+/// * `keys` - A JSON array of the keys to touch.
+/// * `ttl_seconds` - The number of seconds from now after which the keys expire, 0 meaning
+///   never.
+///
+/// # Returns
+///
+/// The keys that exist and were touched, in the order they were given, with OK code. Missing
+/// keys are left out
+async fn touch_many_keys(
+    item: web::Json<BatchTouch>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    drain_mode: web::Data<DrainMode>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let BatchTouch { keys, ttl_seconds } = item.into_inner();
+    let ttl = explicit_expiry(Duration::from_secs(ttl_seconds));
+
+    let shard_manager = read_lock(&shard_manager);
+    let mut touched = Vec::new();
+    for key in keys {
+        let key = shard_manager.fold_key(key);
+        if shard_manager.touch(&key, ttl)? {
+            touched.push(key);
+        }
+    }
+    Ok(HttpResponse::Ok().json(touched))
+}
+
+/// Moves the value of a key to another key, overwriting the destination if it exists
+///
+/// # Arguments
+///
+/// * `from` - A string slice that holds the key to move.
+/// * `to` - A string slice that holds the key to move it to.
+///
+/// # Returns
+///
+/// OK code if the key was renamed, NotFound code if `from` doesn't exist
+async fn rename_key(
+    item: web::Json<Rename>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    drain_mode: web::Data<DrainMode>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let Rename { from, to } = item.into_inner();
+    let shard_manager = read_lock(&shard_manager);
+    let (from, to) = (shard_manager.fold_key(from), shard_manager.fold_key(to));
+    validate_key(&to, &config)?;
+
+    if shard_manager.rename(&from, &to)? {
+        Ok(HttpResponse::Ok().json(format!("Renamed key: {} to {}", from, to)))
+    } else {
+        Err(ApiError::NotFound(format!("Key not found: {}", from)))
+    }
+}
+
+/// Exchanges the values of two keys atomically, along with their TTLs. When one of the keys
+/// is missing, it takes the other key's value and the other key is deleted
+///
+/// # Arguments
+///
+/// * `a` - A string slice that holds the first key.
+/// * `b` - A string slice that holds the second key.
+///
+/// # Returns
+///
+/// OK code if the values were swapped, NotFound code if both keys are missing
+async fn swap_keys(
+    item: web::Json<Swap>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    drain_mode: web::Data<DrainMode>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let Swap { a, b } = item.into_inner();
+    let shard_manager = read_lock(&shard_manager);
+    let (a, b) = (shard_manager.fold_key(a), shard_manager.fold_key(b));
+    validate_key(&a, &config)?;
+    validate_key(&b, &config)?;
+
+    if shard_manager.swap(&a, &b)? {
+        Ok(HttpResponse::Ok().json(format!("Swapped keys: {} and {}", a, b)))
+    } else {
+        Err(ApiError::NotFound(format!(
+            "Keys not found: {} and {}",
+            a, b
+        )))
+    }
+}
+
+/// Removes every key from the store
+///
+/// # Returns
+///
+/// A message with the number of removed keys, with OK code
+async fn clear_all(
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    drain_mode: web::Data<DrainMode>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let removed = read_lock(&shard_manager).clear()?;
+    Ok(HttpResponse::Ok().json(format!("Deleted {} keys", removed)))
+}
+
+/// Changes the number of shards at runtime, redistributing every key to its new shard.
+/// Requests wait until the keys have been moved, unless the reshard runs in the background.
+///
+/// # Arguments
+///
+/// * `shard_count` - The new number of shards, at least 1.
+/// * `background` - Optional query parameter, when true the shards are added or removed at
+///   once but the keys are moved by a background task, a chunk of keys at a time, requests
+///   being served in between. Only the keys of the ring segments that change owner move, and
+///   a key a request uses is moved first. Unlike the blocking reshard, shard weights are kept.
+///
+/// # Returns
+///
+/// OK code, or Accepted code along with the status of the reshard when it runs in the
+/// background. BadRequest code if `shard_count` is 0, Conflict code if a background reshard
+/// is still running
+async fn reshard(
+    item: web::Json<Reshard>,
+    options: web::Query<ReshardOptions>,
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    health_state: web::Data<HealthState>,
+    reshard_progress: web::Data<ReshardProgress>,
+    drain_mode: web::Data<DrainMode>,
+) -> Result<HttpResponse, ApiError> {
+    drain_mode.check_writable()?;
+    let shard_count = item.shard_count;
+    if shard_count == 0 {
+        return Err(ApiError::BadRequest(
+            "shard_count must be at least 1".to_string(),
+        ));
+    }
+
+    if options.background {
+        let mut locked_shard_manager = write_lock(&shard_manager);
+        let current = locked_shard_manager.shard_count();
+        let status = reshard_progress.start(current, shard_count, || {
+            locked_shard_manager.start_migration(shard_count)
+        })?;
+        drop(locked_shard_manager);
+        health_state
+            .shard_count
+            .store(shard_count, Ordering::SeqCst);
+        spawn_background_reshard(
+            shard_manager.get_ref().clone(),
+            reshard_progress,
+            drain_mode,
+        );
+        return Ok(HttpResponse::Accepted().json(status));
+    }
+
+    if reshard_progress.is_running() {
+        return Err(ApiError::Conflict(
+            "A reshard is already running".to_string(),
+        ));
+    }
+    write_lock(&shard_manager).reshard(shard_count);
+    health_state
+        .shard_count
+        .store(shard_count, Ordering::SeqCst);
+    Ok(HttpResponse::Ok().json(format!("Resharded to {} shards", shard_count)))
+}
+
+/// Spawns the task of a background reshard, moving the keys to the shards
+/// `ShardManager::start_migration` placed them on, `RESHARD_CHUNK_KEYS` at a time. The store is
+/// only read-locked for each chunk, so requests get served while keys are moved, and the ones
+/// they use are moved right away. The task stops early, leaving the keys it didn't reach to be
+/// moved when they're used, once the server starts draining. Must be called from a Tokio
+/// runtime.
+fn spawn_background_reshard(
+    shard_manager: Arc<RwLock<ShardManager>>,
+    reshard_progress: web::Data<ReshardProgress>,
+    drain_mode: web::Data<DrainMode>,
+) {
+    tokio::spawn(async move {
+        loop {
+            if drain_mode.is_draining() {
+                reshard_progress.finish(false);
+                return;
+            }
+            let migrated = read_lock(&shard_manager).migrate_keys(RESHARD_CHUNK_KEYS);
+            match migrated {
+                Some(migrated) => reshard_progress.advance(migrated),
+                None => break,
+            }
+            tokio::task::yield_now().await;
+        }
+        write_lock(&shard_manager).finish_migration();
+        reshard_progress.finish(true);
+    });
+}
+
+/// Reports the progress of the last background reshard
+///
+/// # Returns
+///
+/// A JSON object with OK code, telling whether the reshard is `running`, the shard count it
+/// started `from` and the one it goes `to`, the `total_keys` it has to check, the
+/// `checked_keys` and `moved_keys` so far and the `percent` of the keys checked. A complete
+/// reshard to the current shard count when none was started
+async fn get_reshard_status(
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+    reshard_progress: web::Data<ReshardProgress>,
+) -> Result<HttpResponse, ApiError> {
+    let status = *reshard_progress.lock();
+    let status = match status {
+        Some(status) => status,
+        None => {
+            let shard_count = read_lock(&shard_manager).shard_count();
+            ReshardStatus::complete(shard_count, shard_count)
+        }
+    };
+    Ok(HttpResponse::Ok().json(status))
+}
+
+/// Checks that every key is stored in the shard it hashes to. A misplaced key can't be read,
+/// updated or deleted anymore
+///
+/// # Returns
+///
+/// The list of misplaced keys, empty when every key is reachable, with OK code
+async fn verify_placement(
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(read_lock(&shard_manager).verify_placement()))
+}
+
+/// Lists the virtual nodes of the hash ring, to see how keys are placed. A key goes to the
+/// shard owning the first node whose position is at or after the hash of the key, wrapping
+/// around past the last one
+///
+/// # Returns
+///
+/// A JSON array of the nodes sorted by position, each with its `position` and the index of
+/// the `shard` owning it, with OK code
+async fn get_ring(
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    let ring = read_lock(&shard_manager)
+        .ring()
+        .into_iter()
+        .map(|(position, shard)| RingNode { position, shard })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(ring))
+}
+
+/// Lists the last writes applied to the store, up to the configured `audit_log_size`
+///
+/// # Returns
+///
+/// A JSON array of the writes, oldest first, each with its op (`set`, `delete` or `clear`), its
+/// key and its Unix timestamp in milliseconds, with OK code. Empty if the audit log is
+/// disabled
+async fn get_audit_log(
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(read_lock(&shard_manager).audit_log()))
+}
+
+/// Puts the server in drain mode: from then on writes are rejected until the server
+/// restarts, while reads keep working
+///
+/// # Returns
+///
+/// OK code
+async fn enable_drain(drain_mode: web::Data<DrainMode>) -> Result<HttpResponse, ApiError> {
+    drain_mode.enable();
+    Ok(HttpResponse::Ok().json("Draining, writes are now rejected"))
+}
+
+/// Describes the API for tooling, eg. to generate a client
+///
+/// # Returns
+///
+/// An OpenAPI document listing every endpoint with its method, parameters and the shapes of its
+/// request and response bodies, with OK code
+async fn get_schema() -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(api_schema()))
+}
+
+/// OpenAPI 3.0 document of the API served by `GET /api/schema`. Path parameters are derived
+/// from the `{...}` segments of each path.
+fn api_schema() -> serde_json::Value {
+    use serde_json::{json, Map, Value};
+
+    let string = || json!({ "type": "string" });
+    let integer = || json!({ "type": "integer" });
+    let boolean = || json!({ "type": "boolean" });
+    let array_of = |items: Value| json!({ "type": "array", "items": items });
+    let map_of = |values: Value| json!({ "type": "object", "additionalProperties": values });
+    let object = |properties: Value, required: &[&str]| {
+        let required = required.to_vec();
+        json!({ "type": "object", "properties": properties, "required": required })
+    };
+    let key_value = || json!({ "$ref": "#/components/schemas/KeyValuePair" });
+    let key_list = || array_of(string());
+    let counters = || {
+        object(
+            json!({ "gets": integer(), "sets": integer(), "deletes": integer() }),
+            &["gets", "sets", "deletes"],
+        )
+    };
+
+    /// One endpoint of the API, its query parameters being separated by commas.
+    struct Endpoint {
+        method: &'static str,
+        path: &'static str,
+        summary: &'static str,
+        query: &'static str,
+        request: Option<Value>,
+        response: Value,
+    }
+    let endpoint = |method, path, summary, query, request, response| Endpoint {
+        method,
+        path,
+        summary,
+        query,
+        request,
+        response,
+    };
+
+    let endpoints = vec![
+        endpoint(
+            "get",
+            "/health",
+            "Liveness probe",
+            "",
+            None,
+            object(
+                json!({ "status": string(), "shards": integer() }),
+                &["status", "shards"],
+            ),
+        ),
+        endpoint(
+            "get",
+            "/health/deep",
+            "Checks every shard serves writes and reads, 503 if one doesn't",
+            "",
+            None,
+            object(
+                json!({
+                    "status": string(),
+                    "shards": array_of(object(
+                        json!({ "shard": integer(), "ok": boolean() }),
+                        &["shard", "ok"],
+                    )),
+                }),
+                &["status", "shards"],
+            ),
+        ),
+        endpoint(
+            "get",
+            "/metrics",
+            "Counters in Prometheus text format",
+            "",
+            None,
+            string(),
+        ),
+        endpoint(
+            "get",
+            "/api/schema",
+            "This document",
+            "",
+            None,
+            json!({ "type": "object" }),
+        ),
+        endpoint(
+            "get",
+            "/api/export",
+            "Every key-value pair",
+            "",
+            None,
+            map_of(string()),
+        ),
+        endpoint(
+            "get",
+            "/api/export/stream",
+            "Every key-value pair as newline-delimited JSON",
+            "",
+            None,
+            key_value(),
+        ),
+        endpoint(
+            "get",
+            "/api/metrics",
+            "Request counters",
+            "",
+            None,
+            object(
+                json!({
+                    "gets": integer(),
+                    "sets": integer(),
+                    "deletes": integer(),
+                    "hits": integer(),
+                    "misses": integer(),
+                    "hit_ratio": { "type": "number" },
+                }),
+                &["gets", "sets", "deletes", "hits", "misses", "hit_ratio"],
+            ),
+        ),
+        endpoint(
+            "get",
+            "/api/scan",
+            "Page of key-value pairs",
+            "cursor,limit",
+            None,
+            object(
+                json!({ "pairs": array_of(key_value()), "next_cursor": integer() }),
+                &["pairs", "next_cursor"],
+            ),
+        ),
+        endpoint(
+            "get",
+            "/api/mget",
+            "Values of several keys",
+            "keys",
+            None,
+            map_of(string()),
+        ),
+        endpoint(
+            "get",
+            "/api/random",
+            "Key-value pair picked at random",
+            "",
+            None,
+            key_value(),
+        ),
+        endpoint(
+            "get",
+            "/api/changed",
+            "Keys modified since a timestamp",
+            "since",
+            None,
+            key_list(),
+        ),
+        endpoint(
+            "get",
+            "/api/{key}",
+            "Value of a key",
+            "default,transform",
+            None,
+            string(),
+        ),
+        endpoint(
+            "head",
+            "/api/{key}",
+            "Whether a key exists",
+            "",
+            None,
+            json!({}),
+        ),
+        endpoint(
+            "post",
+            "/api",
+            "Add a key-value pair",
+            "nx",
+            Some(key_value()),
+            object(
+                json!({
+                    "key": string(),
+                    "value": string(),
+                    "shard": integer(),
+                    "created": boolean(),
+                    "version": integer(),
+                }),
+                &["key", "value", "shard", "created", "version"],
+            ),
+        ),
+        endpoint(
+            "post",
+            "/api/batch/get",
+            "Values of several keys",
+            "",
+            Some(key_list()),
+            map_of(string()),
+        ),
+        endpoint(
+            "post",
+            "/api/batch/exists",
+            "Presence of several keys",
+            "",
+            Some(key_list()),
+            map_of(boolean()),
+        ),
+        endpoint(
+            "post",
+            "/api/batch/set",
+            "Add several key-value pairs",
+            "dry_run",
+            Some(array_of(key_value())),
+            array_of(integer()),
+        ),
+        endpoint(
+            "post",
+            "/api/batch/delete",
+            "Delete several keys",
+            "",
+            Some(key_list()),
+            integer(),
+        ),
+        endpoint(
+            "post",
+            "/api/batch/incr",
+            "Increment several counters",
+            "",
+            Some(map_of(integer())),
+            object(
+                json!({
+                    "values": map_of(integer()),
+                    "errors": map_of(object(
+                        json!({ "code": string(), "message": string() }),
+                        &["code", "message"],
+                    )),
+                }),
+                &["values", "errors"],
+            ),
+        ),
+        endpoint(
+            "post",
+            "/api/batch/touch",
+            "Reset the expiry of several keys",
+            "",
+            Some(object(
+                json!({ "keys": key_list(), "ttl_seconds": integer() }),
+                &["keys", "ttl_seconds"],
+            )),
+            key_list(),
+        ),
+        endpoint(
+            "post",
+            "/api/import",
+            "Add every pair of an object",
+            "overwrite",
+            Some(map_of(string())),
+            object(json!({ "imported": integer() }), &["imported"]),
+        ),
+        endpoint(
+            "get",
+            "/api/stats/count",
+            "Number of keys",
+            "",
+            None,
+            integer(),
+        ),
+        endpoint(
+            "get",
+            "/api/stats/shards",
+            "Number of keys per shard",
+            "",
+            None,
+            array_of(integer()),
+        ),
+        endpoint(
+            "get",
+            "/api/stats/shard-ops",
+            "Operations per shard",
+            "",
+            None,
+            array_of(counters()),
+        ),
+        endpoint(
+            "get",
+            "/api/stats/prefix-count",
+            "Number of keys starting with a prefix",
+            "prefix",
+            None,
+            integer(),
+        ),
+        endpoint(
+            "get",
+            "/api/stats/memory",
+            "Estimated bytes used per shard and in total",
+            "",
+            None,
+            object(
+                json!({ "shards": array_of(integer()), "total": integer() }),
+                &["shards", "total"],
+            ),
+        ),
+        endpoint(
+            "get",
+            "/api/ttl/{key}",
+            "Seconds left before a key expires",
+            "",
+            None,
+            integer(),
+        ),
+        endpoint(
+            "get",
+            "/api/meta/{key}",
+            "Metadata of a key",
+            "",
+            None,
+            object(
+                json!({
+                    "value_length": integer(),
+                    "shard_index": integer(),
+                    "ttl_remaining": integer(),
+                }),
+                &["value_length", "shard_index", "ttl_remaining"],
+            ),
+        ),
+        endpoint(
+            "post",
+            "/api/incr/{key}",
+            "Increment an integer",
+            "",
+            Some(object(json!({ "by": integer() }), &[])),
+            integer(),
+        ),
+        endpoint(
+            "post",
+            "/api/decr/{key}",
+            "Decrement an integer",
+            "",
+            Some(object(json!({ "by": integer() }), &[])),
+            integer(),
+        ),
+        endpoint(
+            "post",
+            "/api/cas/{key}",
+            "Compare and swap a value",
+            "",
+            Some(object(
+                json!({ "expected": string(), "new": string() }),
+                &["expected", "new"],
+            )),
+            boolean(),
+        ),
+        endpoint(
+            "post",
+            "/api/delete-if/{key}",
+            "Delete a key holding a value",
+            "",
+            Some(object(json!({ "expected": string() }), &["expected"])),
+            string(),
+        ),
+        endpoint(
+            "post",
+            "/api/rename",
+            "Rename a key",
+            "",
+            Some(object(
+                json!({ "from": string(), "to": string() }),
+                &["from", "to"],
+            )),
+            string(),
+        ),
+        endpoint(
+            "post",
+            "/api/swap",
+            "Exchange the values of two keys",
+            "",
+            Some(object(json!({ "a": string(), "b": string() }), &["a", "b"])),
+            string(),
+        ),
+        endpoint(
+            "post",
+            "/api/getset/{key}",
+            "Set a value, returning the previous one",
+            "",
+            Some(object(json!({ "value": string() }), &["value"])),
+            string(),
+        ),
+        endpoint(
+            "get",
+            "/api/raw/{key}",
+            "Value of a key as plain text",
+            "",
+            None,
+            string(),
+        ),
+        endpoint(
+            "post",
+            "/api/raw/{key}",
+            "Set the value of a key to the plain text body",
+            "",
+            Some(string()),
+            string(),
+        ),
+        endpoint(
+            "post",
+            "/api/append/{key}",
+            "Append to a value",
+            "",
+            Some(string()),
+            integer(),
+        ),
+        endpoint(
+            "post",
+            "/api/touch/{key}",
+            "Reset the expiry of a key",
+            "",
+            Some(object(
+                json!({ "ttl_seconds": integer() }),
+                &["ttl_seconds"],
+            )),
+            string(),
+        ),
+        endpoint(
+            "put",
+            "/api/{key}",
+            "Update the value of a key",
+            "",
+            Some(object(json!({ "value": string() }), &["value"])),
+            string(),
+        ),
+        endpoint(
+            "patch",
+            "/api/{key}",
+            "Merge a JSON Merge Patch into a JSON value",
+            "",
+            Some(json!({ "type": "object" })),
+            json!({ "type": "object" }),
+        ),
+        endpoint(
+            "delete",
+            "/api/all",
+            "Delete every key",
+            "",
+            None,
+            integer(),
+        ),
+        endpoint(
+            "delete",
+            "/api/prefix/{prefix}",
+            "Delete keys by prefix",
+            "",
+            None,
+            integer(),
+        ),
+        endpoint(
+            "post",
+            "/api/admin/drain",
+            "Reject writes until restart",
+            "",
+            None,
+            string(),
+        ),
+        endpoint(
+            "post",
+            "/api/admin/reshard",
+            "Change the number of shards",
+            "background",
+            Some(object(
+                json!({ "shard_count": integer() }),
+                &["shard_count"],
+            )),
+            string(),
+        ),
+        endpoint(
+            "get",
+            "/api/admin/reshard/status",
+            "Progress of the background reshard",
+            "",
+            None,
+            object(
+                json!({
+                    "running": boolean(),
+                    "from": integer(),
+                    "to": integer(),
+                    "shard_count": integer(),
+                    "percent": integer(),
+                }),
+                &["running", "from", "to", "shard_count", "percent"],
+            ),
+        ),
+        endpoint(
+            "get",
+            "/api/admin/verify",
+            "Keys stored in the wrong shard",
+            "",
+            None,
+            key_list(),
+        ),
+        endpoint(
+            "get",
+            "/api/admin/ring",
+            "Virtual nodes of the hash ring",
+            "",
+            None,
+            array_of(object(
+                json!({ "position": integer(), "shard": integer() }),
+                &["position", "shard"],
+            )),
+        ),
+        endpoint(
+            "get",
+            "/api/admin/audit",
+            "Recent writes",
+            "",
+            None,
+            array_of(object(
+                json!({ "op": string(), "key": string(), "timestamp_ms": integer() }),
+                &["op", "key", "timestamp_ms"],
+            )),
+        ),
+        endpoint("delete", "/api/{key}", "Delete a key", "", None, string()),
+        endpoint(
+            "post",
+            "/api/{namespace}",
+            "Add a key-value pair to a namespace",
+            "",
+            Some(key_value()),
+            json!({ "type": "object" }),
+        ),
+        endpoint(
+            "get",
+            "/api/{namespace}/{key}",
+            "Value of a namespaced key",
+            "",
+            None,
+            string(),
+        ),
+        endpoint(
+            "delete",
+            "/api/{namespace}/{key}",
+            "Delete a namespaced key",
+            "",
+            None,
+            string(),
+        ),
+    ];
+
+    let mut paths = Map::new();
+    for Endpoint {
+        method,
+        path,
+        summary,
+        query,
+        request,
+        response,
+    } in endpoints
+    {
+        let path_parameters = path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+            .map(
+                |name| json!({ "name": name, "in": "path", "required": true, "schema": string() }),
+            );
+        let query_parameters = query
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .map(|name| json!({ "name": name, "in": "query", "schema": string() }));
+        let media_type = if path.starts_with("/api/raw/") {
+            "text/plain"
+        } else {
+            "application/json"
+        };
+        let mut operation = json!({
+            "summary": summary,
+            "parameters": path_parameters.chain(query_parameters).collect::<Vec<_>>(),
+            "responses": {
+                "200": {
+                    "description": "Success",
+                    "content": { media_type: { "schema": response } },
+                },
+                "default": {
+                    "description": "Error",
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/Error" },
+                        },
+                    },
+                },
+            },
+        });
+        if let Some(request) = request {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": { media_type: { "schema": request } },
+            });
+        }
+        if let Value::Object(operations) = paths
+            .entry(path)
+            .or_insert_with(|| Value::Object(Map::new()))
+        {
+            operations.insert(method.to_string(), operation);
+        }
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": { "title": "HashmapRS", "version": env!("CARGO_PKG_VERSION") },
+        "paths": paths,
+        "components": {
+            "schemas": {
+                "KeyValuePair": object(
+                    json!({
+                        "key": string(),
+                        "value": string(),
+                        "ttl_seconds": integer(),
+                        "expires_at": integer(),
+                        "value_type": { "type": "string", "enum": ["string", "json"] },
+                        "encoding": string(),
+                    }),
+                    &["key", "value"],
+                ),
+                "Error": object(
+                    json!({
+                        "error": object(
+                            json!({ "code": string(), "message": string() }),
+                            &["code", "message"],
+                        ),
+                    }),
+                    &["error"],
+                ),
+            },
+        },
+    })
+}
+
+/// Liveness probe for load balancers and orchestrators
+///
+/// # Returns
+///
+/// The status and the number of shards, with OK code
+async fn health(health_state: web::Data<HealthState>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(HealthStatus {
+        status: "ok".to_string(),
+        shards: health_state.shard_count.load(Ordering::SeqCst),
+    }))
+}
+
+/// Deeper probe checking every shard still serves writes and reads, by writing, reading back
+/// and removing a reserved key in each of them
+///
+/// # Returns
+///
+/// The status and whether each shard passed, with OK code if they all did and
+/// ServiceUnavailable code otherwise
+async fn deep_health(
+    shard_manager: web::Data<Arc<RwLock<ShardManager>>>,
+) -> Result<HttpResponse, ApiError> {
+    let shards: Vec<ShardHealth> = read_lock(&shard_manager)
+        .check_shards()
+        .into_iter()
+        .enumerate()
+        .map(|(shard, ok)| ShardHealth { shard, ok })
+        .collect();
+    if shards.iter().all(|shard| shard.ok) {
+        return Ok(HttpResponse::Ok().json(DeepHealthStatus {
+            status: "ok".to_string(),
+            shards,
+        }));
+    }
+    Ok(HttpResponse::ServiceUnavailable().json(DeepHealthStatus {
+        status: "failing".to_string(),
+        shards,
+    }))
+}
+
+/// Logs the method, path, status and latency of every request at info level once its response
+/// is ready, leaving the response untouched.
+async fn log_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    let started = Instant::now();
+    let method = req.method().clone();
+    let path = req.path().to_string();
+
+    let res = next.call(req).await;
+    let status = match &res {
+        Ok(res) => res.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    log::info!(
+        "{} {} {} {:.3}ms",
+        method,
+        path,
+        status.as_u16(),
+        started.elapsed().as_secs_f64() * 1000.0
+    );
+    res
+}
+
+/// Rejects requests that don't carry the configured API key with 401 Unauthorized. `/health`
+/// stays open so probes don't need the key.
+async fn require_api_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    let expected = req
+        .app_data::<web::Data<ApiKey>>()
+        .and_then(|api_key| api_key.0.clone());
+
+    if let Some(expected) = expected {
+        let provided = req
+            .headers()
+            .get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok());
+        if req.path() != "/health" && provided != Some(expected.as_str()) {
+            let response =
+                ApiError::Unauthorized("Missing or invalid API key".to_string()).error_response();
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// Rejects requests with 429 Too Many Requests once their client address went over the rate
+/// limit. Requests whose peer address is unknown aren't limited.
+async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    let rate_limiter = req
+        .app_data::<web::Data<RateLimit>>()
+        .and_then(|rate_limit| rate_limit.0.as_ref());
+
+    if let (Some(rate_limiter), Some(peer_addr)) = (rate_limiter, req.peer_addr()) {
+        if !rate_limiter.check(peer_addr.ip()) {
+            let response =
+                ApiError::TooManyRequests("Rate limit exceeded, retry later".to_string())
+                    .error_response();
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// Adds the CORS headers letting browsers on the allowed origins read the responses, and
+/// answers preflight requests itself. Requests from other origins get no CORS headers, so
+/// browsers keep them same-origin.
+async fn cors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    let allowed_origin = req.headers().get(header::ORIGIN).cloned().filter(|origin| {
+        let origins = req.app_data::<web::Data<CorsOrigins>>();
+        matches!((origins, origin.to_str()), (Some(origins), Ok(origin)) if origins.allows(origin))
+    });
+
+    let is_preflight = req.method() == Method::OPTIONS
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    if is_preflight {
+        let mut response = HttpResponse::NoContent();
+        response.insert_header((header::VARY, "Origin"));
+        if let Some(origin) = allowed_origin {
+            response
+                .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin))
+                .insert_header((
+                    header::ACCESS_CONTROL_ALLOW_METHODS,
+                    "GET, HEAD, POST, PUT, PATCH, DELETE",
+                ))
+                .insert_header((
+                    header::ACCESS_CONTROL_ALLOW_HEADERS,
+                    format!(
+                        "Content-Type, If-None-Match, {}, {}, {}",
+                        API_KEY_HEADER, SHARD_OVERRIDE_HEADER, VALUE_ENCODING_HEADER
+                    ),
+                ))
+                .insert_header((header::ACCESS_CONTROL_MAX_AGE, "3600"));
+        }
+        return Ok(req.into_response(response.finish()).map_into_right_body());
+    }
+
+    let mut res = next.call(req).await?;
+    if let Some(origin) = allowed_origin {
+        let headers = res.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.insert(
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            HeaderValue::from_static(CORS_EXPOSED_HEADERS),
+        );
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+    Ok(res.map_into_left_body())
+}
+
+/// Turns JSON body errors into structured errors, 413 for oversized bodies and 400 otherwise.
+fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match err {
+        JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => {
+            ApiError::PayloadTooLarge(err.to_string()).into()
+        }
+        _ => ApiError::BadRequest(err.to_string()).into(),
+    }
+}
+
+/// Parses JSON bodies of up to `limit` bytes, larger ones being rejected with 413 before they
+/// reach the handler.
+fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(limit)
+        .error_handler(json_error_handler)
+}
+
+/// Reads the largest JSON body accepted, in bytes, from `HASHMAPRS_MAX_BODY_BYTES`.
+///
+/// # Returns
+///
+/// The parsed limit, or `MAX_BODY_BYTES` if the variable is unset, unparseable or zero
+fn max_body_bytes_from_env() -> usize {
+    std::env::var(MAX_BODY_BYTES_ENV_VAR)
+        .ok()
+        .and_then(|max_body_bytes| max_body_bytes.trim().parse::<usize>().ok())
+        .filter(|&max_body_bytes| max_body_bytes > 0)
+        .unwrap_or(MAX_BODY_BYTES)
+}
+
+/// Reads the API key from `HASHMAPRS_API_KEY`, an unset or empty variable disabling
+/// authentication.
+fn api_key_from_env() -> Option<String> {
+    std::env::var(API_KEY_ENV_VAR)
+        .ok()
+        .filter(|api_key| !api_key.is_empty())
+}
+
+/// Reads the rate limit from `HASHMAPRS_RATE_LIMIT`, the number of requests a client address
+/// may send per window, and the window from `HASHMAPRS_RATE_LIMIT_WINDOW_SECS`.
+///
+/// # Returns
+///
+/// The rate limiter, or None if the limit is unset, unparseable or zero
+fn rate_limiter_from_env() -> Option<RateLimiter> {
+    let max_requests = std::env::var(RATE_LIMIT_ENV_VAR)
+        .ok()
+        .and_then(|max_requests| max_requests.parse::<usize>().ok())
+        .filter(|&max_requests| max_requests > 0)?;
+    let window_secs = std::env::var(RATE_LIMIT_WINDOW_ENV_VAR)
+        .ok()
+        .and_then(|window_secs| window_secs.parse::<u64>().ok())
+        .filter(|&window_secs| window_secs > 0)
+        .unwrap_or(RATE_LIMIT_WINDOW_SECS);
+    Some(RateLimiter::new(
+        max_requests,
+        Duration::from_secs(window_secs),
+    ))
+}
+
+/// Reads the comma-separated origins allowed by CORS from `HASHMAPRS_CORS_ORIGINS`, an unset
+/// variable allowing none.
+fn cors_origins_from_env() -> Vec<String> {
+    std::env::var(CORS_ORIGINS_ENV_VAR)
+        .map(|origins| {
+            origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Reads the log level from `HASHMAPRS_LOG_LEVEL`, one of `off`, `error`, `warn`, `info`,
+/// `debug` or `trace`.
+///
+/// # Returns
+///
+/// The parsed level, or `info` if the variable is unset or unparseable
+fn log_level_from_env() -> log::LevelFilter {
+    std::env::var(LOG_LEVEL_ENV_VAR)
+        .ok()
+        .and_then(|level| level.trim().parse().ok())
+        .unwrap_or(log::LevelFilter::Info)
+}
+
+/// Logs to stderr at the level set by `HASHMAPRS_LOG_LEVEL`, `info` by default, which
+/// includes a line per request served.
+pub fn init_logger() {
+    logger::init(log_level_from_env());
+}
+
+/// Reads the number of worker threads serving requests from `HASHMAPRS_WORKERS`.
+///
+/// # Returns
+///
+/// The parsed count, or None to keep Actix's default of one worker per CPU if the variable
+/// is unset, unparseable or zero
+fn workers_from_env() -> Option<usize> {
+    std::env::var(WORKERS_ENV_VAR)
+        .ok()
+        .and_then(|workers| workers.trim().parse::<usize>().ok())
+        .filter(|&workers| workers > 0)
+}
+
+/// Reads the shard count from the `HASHMAPRS_SHARD_COUNT` environment variable.
+///
+/// # Returns
+///
+/// The parsed shard count, or `SHARD_COUNT` if the variable is unset, unparseable or zero
+fn shard_count_from_env() -> usize {
+    parse_shard_count(std::env::var(SHARD_COUNT_ENV_VAR).ok().as_deref())
+}
+
+/// Parses a shard count, falling back to `SHARD_COUNT` if it's missing, unparseable or zero.
+fn parse_shard_count(value: Option<&str>) -> usize {
+    value
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .filter(|&count| count > 0)
+        .unwrap_or(SHARD_COUNT)
+}
+
+/// Reads the path of the file the store is persisted to from `HASHMAPRS_DATA_FILE`.
+pub fn data_file_from_env() -> Option<PathBuf> {
+    std::env::var_os(DATA_FILE_ENV_VAR).map(PathBuf::from)
+}
+
+/// Reads the path of the write-ahead log from `HASHMAPRS_WAL_FILE`.
+pub fn wal_file_from_env() -> Option<PathBuf> {
+    std::env::var_os(WAL_FILE_ENV_VAR).map(PathBuf::from)
+}
+
+/// Reads the path of the JSON file the store is seeded from on startup from
+/// `HASHMAPRS_SEED_FILE`.
+pub fn seed_file_from_env() -> Option<PathBuf> {
+    std::env::var_os(SEED_FILE_ENV_VAR).map(PathBuf::from)
+}
+
+/// Files the store is recovered from on startup and persisted to, see `load_shard_manager`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StoreFiles {
+    /// File the store is loaded from, and saved and snapshotted to.
+    pub data_file: Option<PathBuf>,
+    /// Write-ahead log replayed on startup and appended every write.
+    pub wal_file: Option<PathBuf>,
+    /// JSON file of the pairs preloaded into the store.
+    pub seed_file: Option<PathBuf>,
+}
+
+impl StoreFiles {
+    /// Reads the paths from `HASHMAPRS_DATA_FILE`, `HASHMAPRS_WAL_FILE` and
+    /// `HASHMAPRS_SEED_FILE`.
+    pub fn from_env() -> Self {
+        StoreFiles {
+            data_file: data_file_from_env(),
+            wal_file: wal_file_from_env(),
+            seed_file: seed_file_from_env(),
+        }
+    }
+}
+
+/// Loads the server config from the TOML file named by `HASHMAPRS_CONFIG`, see
+/// `load_config_from`.
+pub fn load_config() -> std::io::Result<Config> {
+    load_config_from(
+        std::env::var_os(CONFIG_ENV_VAR)
+            .map(PathBuf::from)
+            .as_deref(),
+    )
+}
+
+/// Loads the server config from the TOML file at `path`.
+///
+/// # Returns
+///
+/// The config read from the file, or the built-in defaults if no path is given or the file
+/// doesn't exist. `HASHMAPRS_SHARD_COUNT` and `HASHMAPRS_BIND`, when set, override the shard
+/// count and the bind address.
+pub fn load_config_from(path: Option<&Path>) -> std::io::Result<Config> {
+    let mut config = match path {
+        Some(path) if path.exists() => Config::from_file(path)?,
+        _ => Config::default(),
+    };
+    if std::env::var_os(SHARD_COUNT_ENV_VAR).is_some() {
+        config.shard_count = shard_count_from_env();
+    }
+    if let Some(bind_address) = std::env::var(BIND_ENV_VAR)
+        .ok()
+        .filter(|bind_address| !bind_address.trim().is_empty())
+    {
+        config.bind_address = bind_address.trim().to_string();
+    }
+    Ok(config)
+}
+
+/// Builds the shard manager served by `run`.
+///
+/// # Returns
+///
+/// A store built from the given config, holding the entries saved in the data file of `files`
+/// if it's set and exists. The config's shard count, hash algorithm and limits apply to the
+/// loaded entries too. When a WAL is set, the writes it holds are replayed on top, and every
+/// write from then on is appended to it. When a seed file is set, its keys missing from the
+/// store are added, an error being returned if it can't be read or holds more keys than
+/// `max_total_keys` leaves room for
+pub fn load_shard_manager(
+    config: &Config,
+    files: &StoreFiles,
+) -> std::io::Result<Arc<RwLock<ShardManager>>> {
+    let mut shard_manager = config.build_shard_manager();
+    if let Some(path) = files.data_file.as_deref().filter(|path| path.exists()) {
+        shard_manager.load_entries_from_file(path)?;
+    }
+    let wal_path = files.wal_file.as_deref();
+    if let Some(path) = wal_path.filter(|path| path.exists()) {
+        shard_manager.replay_wal(path)?;
+    }
+    // Seeded before the WAL is attached, so the seed isn't copied to the log on every startup
+    if let Some(path) = files.seed_file.as_deref() {
+        let seeded = shard_manager.seed_from_file(path)?;
+        log::info!("Seeded {} keys from {}", seeded, path.display());
+    }
+    if let Some(path) = wal_path {
+        shard_manager.attach_wal(Wal::open(path, config.wal_options())?);
+    }
+    // Restarted once the store is recovered, so only the writes served from now on are recorded
+    shard_manager.enable_audit(config.audit_log_size);
+    Ok(Arc::new(RwLock::new(shard_manager)))
+}
+
+/// Spawns a task removing the expired entries of the store every `interval`, so keys that
+/// are never read again don't keep using memory. Must be called from a Tokio runtime.
+pub fn spawn_ttl_sweeper(
+    shard_manager: Arc<RwLock<ShardManager>>,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes right away
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            read_lock(&shard_manager).sweep_expired();
+        }
+    })
+}
+
+/// Spawns a task syncing the writes buffered by the WAL to disk every `interval`, so a quiet
+/// store doesn't leave its last writes unsynced. Must be called from a Tokio runtime.
+pub fn spawn_wal_flusher(
+    shard_manager: Arc<RwLock<ShardManager>>,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(err) = read_lock(&shard_manager).flush_wal() {
+                log::error!("Failed to flush the WAL: {}", err);
+            }
+        }
+    })
+}
+
+/// Spawns a task snapshotting the store to `path` every `interval`, which keeps the WAL
+/// short and the startup replay fast. Must be called from a Tokio runtime.
+pub fn spawn_snapshotter(
+    shard_manager: Arc<RwLock<ShardManager>>,
+    path: PathBuf,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(err) = write_lock(&shard_manager).snapshot(&path) {
+                log::error!(
+                    "Failed to snapshot the store to {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    })
+}
+
+pub fn run(listener: TcpListener, config: &Config) -> std::io::Result<Server> {
+    let files = StoreFiles::from_env();
+    let shard_manager = load_shard_manager(config, &files)?;
+    run_with_shard_manager(listener, shard_manager, &files, config)
+}
+
+/// Binds a listener to `addr`, eg. `127.0.0.1:8080`, and serves the store on it with `run`.
+///
+/// # Returns
+///
+/// The server along with the address it listens on, which tells the actual port when binding
+/// to port 0
+pub fn run_with_addr(addr: &str, config: &Config) -> std::io::Result<(Server, SocketAddr)> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    Ok((run(listener, config)?, local_addr))
+}
+
+/// Same as `run`, but serves the given shard manager so the caller keeps a handle on it,
+/// eg. to save it on shutdown. `files` are the ones it was loaded from, its data file being
+/// the one snapshotted to.
+pub fn run_with_shard_manager(
+    listener: TcpListener,
+    shard_manager: Arc<RwLock<ShardManager>>,
+    files: &StoreFiles,
+    config: &Config,
+) -> std::io::Result<Server> {
+    if config.sweep_interval_secs > 0 {
+        spawn_ttl_sweeper(
+            shard_manager.clone(),
+            Duration::from_secs(config.sweep_interval_secs),
+        );
+    }
+    if config.wal_flush_ms > 0 {
+        spawn_wal_flusher(
+            shard_manager.clone(),
+            Duration::from_millis(config.wal_flush_ms),
+        );
+    }
+    if let Some(path) = files
+        .data_file
+        .as_ref()
+        .filter(|_| config.snapshot_interval_secs > 0)
+    {
+        spawn_snapshotter(
+            shard_manager.clone(),
+            path.clone(),
+            Duration::from_secs(config.snapshot_interval_secs),
+        );
+    }
+
+    let write_batcher = (config.write_batch_size > 0).then(|| {
+        web::Data::new(WriteBatcher::spawn(
+            shard_manager.clone(),
+            config.write_batch_size,
+        ))
+    });
+    let drain_mode = web::Data::new(DrainMode::default());
+    let metrics = web::Data::new(Metrics::default());
+    let config = web::Data::new(config.clone());
+    let api_key = web::Data::new(ApiKey(api_key_from_env()));
+    let cors_origins = web::Data::new(CorsOrigins(cors_origins_from_env()));
+    let rate_limit_data = web::Data::new(RateLimit(rate_limiter_from_env()));
+    let namespaces = web::Data::new(if files.data_file.is_some() || files.wal_file.is_some() {
+        Namespaces::disabled()
+    } else {
+        Namespaces::default()
+    });
+    let max_body_bytes = max_body_bytes_from_env();
+    let reshard_progress = web::Data::new(ReshardProgress::default());
+    let health_state = web::Data::new(HealthState {
+        shard_count: AtomicUsize::new(read_lock(&shard_manager).shard_count()),
+    });
+
+    let mut server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(shard_manager.clone()))
+            .app_data(drain_mode.clone())
+            .app_data(health_state.clone())
+            .app_data(reshard_progress.clone())
+            .app_data(api_key.clone())
+            .app_data(cors_origins.clone())
+            .app_data(rate_limit_data.clone())
+            .app_data(namespaces.clone())
+            .app_data(metrics.clone())
+            .app_data(config.clone())
+            .configure(|cfg| {
+                if let Some(write_batcher) = &write_batcher {
+                    cfg.app_data(write_batcher.clone());
+                }
+            })
+            .app_data(json_config(max_body_bytes))
+            .app_data(web::PayloadConfig::new(max_body_bytes))
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(|err, _req| ApiError::BadRequest(err.to_string()).into()),
+            )
+            .wrap(Condition::new(
+                config.compress_responses,
+                Compress::default(),
+            ))
+            .wrap(from_fn(require_api_key))
+            .wrap(from_fn(rate_limit))
+            // Registered after the others so it runs first, preflights carrying no API key
+            .wrap(from_fn(cors))
+            // Outermost, so the latency covers every other middleware
+            .wrap(from_fn(log_request))
+            .route("/health", web::get().to(health))
+            .route("/health/deep", web::get().to(deep_health))
+            .route("/metrics", web::get().to(get_prometheus_metrics))
+            .route("/api/export", web::get().to(export_key_values))
+            .route(
+                "/api/export/stream",
+                web::get().to(stream_export_key_values),
+            )
+            .route("/api/metrics", web::get().to(get_metrics))
+            .route("/api/scan", web::get().to(scan_key_values))
+            .route("/api/mget", web::get().to(mget_values))
+            .route("/api/random", web::get().to(get_random_entry))
+            .route("/api/changed", web::get().to(get_changed_keys))
+            .route("/api/schema", web::get().to(get_schema))
+            .route("/api/{key}", web::get().to(get_value))
+            .route("/api/{key}", web::head().to(head_value))
+            .route("/api", web::post().to(add_key_value))
+            .route("/api/batch/get", web::post().to(get_many_values))
+            .route("/api/batch/exists", web::post().to(exists_many_keys))
+            .route("/api/batch/set", web::post().to(add_many_key_values))
+            .route("/api/batch/delete", web::post().to(delete_many_keys))
+            .route("/api/batch/incr", web::post().to(increment_many_values))
+            .route("/api/batch/touch", web::post().to(touch_many_keys))
+            .route("/api/import", web::post().to(import_key_values))
+            .route("/api/stats/count", web::get().to(get_count))
+            .route("/api/stats/shards", web::get().to(get_shard_sizes))
+            .route("/api/stats/shard-ops", web::get().to(get_shard_ops))
+            .route("/api/stats/prefix-count", web::get().to(get_prefix_count))
+            .route("/api/stats/memory", web::get().to(get_memory_stats))
+            .route("/api/ttl/{key}", web::get().to(get_ttl))
+            .route("/api/meta/{key}", web::get().to(get_key_meta))
+            .route("/api/raw/{key}", web::get().to(get_raw_value))
+            .route("/api/raw/{key}", web::post().to(add_raw_value))
+            .route("/api/incr/{key}", web::post().to(increment_value))
+            .route("/api/decr/{key}", web::post().to(decrement_value))
+            .route("/api/cas/{key}", web::post().to(compare_and_swap))
+            .route("/api/delete-if/{key}", web::post().to(delete_key_if))
+            .route("/api/rename", web::post().to(rename_key))
+            .route("/api/swap", web::post().to(swap_keys))
+            .route("/api/getset/{key}", web::post().to(get_set_value))
+            .route("/api/append/{key}", web::post().to(append_value))
+            .route("/api/touch/{key}", web::post().to(touch_key))
+            .route("/api/{key}", web::put().to(update_key_value))
+            .route("/api/{key}", web::patch().to(patch_key_value))
+            .route("/api/all", web::delete().to(clear_all))
+            .route("/api/prefix/{prefix}", web::delete().to(delete_prefix))
+            .route("/api/admin/drain", web::post().to(enable_drain))
+            .route("/api/admin/reshard", web::post().to(reshard))
+            .route(
+                "/api/admin/reshard/status",
+                web::get().to(get_reshard_status),
+            )
+            .route("/api/admin/verify", web::get().to(verify_placement))
+            .route("/api/admin/ring", web::get().to(get_ring))
+            .route("/api/admin/audit", web::get().to(get_audit_log))
+            .route("/api/{key}", web::delete().to(delete_key))
+            // Registered last so that every fixed route above takes precedence
+            .route("/api/{namespace}", web::post().to(add_namespaced_key_value))
+            .route(
+                "/api/{namespace}/{key}",
+                web::get().to(get_namespaced_value),
+            )
+            .route(
+                "/api/{namespace}/{key}",
+                web::delete().to(delete_namespaced_key),
+            )
+    })
+    // Signals are handled by `serve_until_shutdown`, so the store can be saved after the
+    // server stops
+    .disable_signals();
+    if let Some(workers) = workers_from_env() {
+        server = server.workers(workers);
+    }
+
+    Ok(server.listen(listener)?.run())
+}
+
+/// Runs the server until `shutdown` resolves, then stops it gracefully: it stops accepting
+/// connections and lets in-flight requests complete before returning.
+pub async fn serve_until_shutdown(
+    server: Server,
+    shutdown: impl Future<Output = ()>,
+) -> std::io::Result<()> {
+    let handle = server.handle();
+    let mut server_task = tokio::spawn(server);
+
+    tokio::select! {
+        result = &mut server_task => return result.map_err(std::io::Error::other)?,
+        _ = shutdown => {}
+    }
+
+    handle.stop(true).await;
+    server_task.await.map_err(std::io::Error::other)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorBody;
+    use crate::metrics::MetricsSnapshot;
+    use crate::shard::ShardOps;
+    use crate::wal::WalOptions;
+    use actix_web::{
+        http::{Method, StatusCode},
+        test as actix_test, web, App,
+    };
+    use flate2::read::GzDecoder;
+    use std::collections::HashMap;
+    use std::io::Read;
+    use std::sync::Barrier;
+    use std::thread;
+
+    /// Empty store of string values, like the one served by `run`.
+    fn new_shard_manager() -> Arc<RwLock<ShardManager>> {
+        Arc::new(RwLock::new(ShardManager::new(SHARD_COUNT)))
+    }
+
+    #[tokio::test]
+    async fn test_get_value_existing() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get().uri("/api/key1").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = actix_test::read_body(resp).await;
+        assert_eq!(body, r#""value1""#);
+    }
+
+    #[tokio::test]
+    async fn test_raw_value_round_trip() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/raw/{key}", web::get().to(get_raw_value))
+                .route("/api/raw/{key}", web::post().to(add_raw_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/raw/key1")
+            .insert_header(ContentType::plaintext())
+            .set_payload(r#"value "1""#)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(resp.headers().get(VERSION_HEADER).unwrap(), "1");
+        assert!(actix_test::read_body(resp).await.is_empty());
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/raw/key1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        assert_eq!(actix_test::read_body(resp).await, r#"value "1""#);
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some(r#"value "1""#.to_string())
+        );
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/raw/key1")
+            .set_payload("")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(VERSION_HEADER).unwrap(), "2");
+        let req = actix_test::TestRequest::get()
+            .uri("/api/raw/key1")
+            .to_request();
+        assert_eq!(actix_test::call_and_read_body(&app, req).await, "");
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/raw/key2")
+            .set_payload(vec![0xff, 0xfe])
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/raw/key2")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_add_raw_value_with_encoding() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/raw/{key}", web::get().to(get_raw_value))
+                .route("/api/raw/{key}", web::post().to(add_raw_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/raw/key1")
+            .insert_header((VALUE_ENCODING_HEADER, "br"))
+            .set_payload("G7sAAA")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(
+            shard_manager
+                .read()
+                .unwrap()
+                .get_entry("key1")
+                .unwrap()
+                .encoding
+                .as_deref(),
+            Some("br")
+        );
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/raw/key1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get(VALUE_ENCODING_HEADER).unwrap(), "br");
+
+        // Overwriting the value without the header drops its encoding
+        let req = actix_test::TestRequest::post()
+            .uri("/api/raw/key1")
+            .set_payload("value1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = actix_test::TestRequest::get()
+            .uri("/api/raw/key1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert!(resp.headers().get(VALUE_ENCODING_HEADER).is_none());
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/raw/key2")
+            .insert_header((VALUE_ENCODING_HEADER, ""))
+            .set_payload("value2")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_get_value_transform() {
+        let shard_manager = new_shard_manager();
+        {
+            let shard_manager = shard_manager.write().unwrap();
+            shard_manager
+                .set("key1".to_string(), "Hello World".to_string())
+                .unwrap();
+            shard_manager
+                .set("json".to_string(), r#"{"a": 1}"#.to_string())
+                .unwrap();
+            shard_manager.set_value_type("json", ValueType::Json);
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/key1?transform=upper")
+            .to_request();
+        let body: String = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, "HELLO WORLD");
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/key1?transform=lower")
+            .to_request();
+        let body: String = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, "hello world");
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/missing?default=Foo&transform=upper")
+            .to_request();
+        let body: String = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, "FOO");
+
+        for uri in ["/api/key1?transform=reverse", "/api/json?transform=upper"] {
+            let req = actix_test::TestRequest::get().uri(uri).to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+
+        // The stored value is left as is
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("Hello World".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_value_accept_plain_text() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value \"1\"".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/key1")
+            .insert_header((header::ACCEPT, "text/plain"))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        assert_eq!(actix_test::read_body(resp).await, r#"value "1""#);
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/missing?default=foo")
+            .insert_header((header::ACCEPT, "text/plain; q=0.9, */*"))
+            .to_request();
+        assert_eq!(actix_test::call_and_read_body(&app, req).await, "foo");
+
+        for accept in [
+            None,
+            Some("application/json"),
+            Some("application/json, text/plain"),
+        ] {
+            let mut req = actix_test::TestRequest::get().uri("/api/key1");
+            if let Some(accept) = accept {
+                req = req.insert_header((header::ACCEPT, accept));
+            }
+            let resp = actix_test::call_service(&app, req.to_request()).await;
+            assert_eq!(
+                resp.headers().get(header::CONTENT_TYPE).unwrap(),
+                &HeaderValue::from_static("application/json")
+            );
+            let expected = serde_json::to_string("value \"1\"").unwrap();
+            assert_eq!(actix_test::read_body(resp).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_value_with_default() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/key1?default=foo")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = actix_test::read_body(resp).await;
+        assert_eq!(body, r#""value1""#);
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/missing?default=foo")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = actix_test::read_body(resp).await;
+        assert_eq!(body, r#""foo""#);
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/missing")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(!shard_manager.read().unwrap().contains("missing"));
+    }
+
+    #[tokio::test]
+    async fn test_get_key_meta() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set_with_ttl(
+                "key1".to_string(),
+                "héllo".to_string(),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key2".to_string(), "value2".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .route("/api/meta/{key}", web::get().to(get_key_meta)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/meta/key1")
+            .to_request();
+        let body: KeyMeta = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            body,
+            KeyMeta {
+                value_length: 6,
+                shard_index: shard_manager.read().unwrap().get_shard_index("key1"),
+                ttl_remaining: Some(60),
+            }
+        );
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/meta/key2")
+            .to_request();
+        let body: KeyMeta = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.value_length, 6);
+        assert_eq!(body.ttl_remaining, None);
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/meta/missing")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get_ttl() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        shard_manager
+            .write()
+            .unwrap()
+            .set_with_ttl(
+                "key2".to_string(),
+                "value2".to_string(),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .route("/api/ttl/{key}", web::get().to(get_ttl)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/ttl/key2")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: i64 = actix_test::read_body_json(resp).await;
+        assert_eq!(body, 60);
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/ttl/key1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: i64 = actix_test::read_body_json(resp).await;
+        assert_eq!(body, -1);
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/ttl/missing")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get_value_non_existing() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/non_existent_key")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: ErrorBody = actix_test::read_body_json(resp).await;
+        assert_eq!(body.error.code, "not_found");
+        assert_eq!(body.error.message, "Key not found: non_existent_key");
+    }
+
+    #[tokio::test]
+    async fn test_get_many_values() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key2".to_string(), "value2".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/batch/get", web::post().to(get_many_values)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/batch/get")
+            .set_json(vec!["key1", "key2", "non_existent_key"])
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: HashMap<String, String> = actix_test::read_body_json(resp).await;
+        assert_eq!(body.len(), 2);
+        assert_eq!(body.get("key1"), Some(&"value1".to_string()));
+        assert_eq!(body.get("key2"), Some(&"value2".to_string()));
+        assert!(!body.contains_key("non_existent_key"));
+    }
+
+    #[tokio::test]
+    async fn test_exists_many_keys() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key2".to_string(), "value2".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .route("/api/batch/exists", web::post().to(exists_many_keys)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/batch/exists")
+            .set_json(vec!["key1", "missing1", "key2", "missing2"])
+            .to_request();
+        let body: HashMap<String, bool> = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            body,
+            HashMap::from([
+                ("key1".to_string(), true),
+                ("key2".to_string(), true),
+                ("missing1".to_string(), false),
+                ("missing2".to_string(), false),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mget_values() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key 2".to_string(), "value2".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/mget", web::get().to(mget_values)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/mget?keys=key1,key%202,non_existent_key")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: HashMap<String, String> = actix_test::read_body_json(resp).await;
+        assert_eq!(body.len(), 2);
+        assert_eq!(body.get("key1"), Some(&"value1".to_string()));
+        assert_eq!(body.get("key 2"), Some(&"value2".to_string()));
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/mget?keys=non_existent_key")
+            .to_request();
+        let body: HashMap<String, String> = actix_test::call_and_read_body_json(&app, req).await;
+        assert!(body.is_empty());
+
+        for uri in ["/api/mget?keys=", "/api/mget"] {
+            let req = actix_test::TestRequest::get().uri(uri).to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body: HashMap<String, String> = actix_test::read_body_json(resp).await;
+            assert!(body.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mget_values_counts_duplicate_keys_once() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        let metrics = web::Data::new(Metrics::default());
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(metrics.clone())
+                .route("/api/mget", web::get().to(mget_values)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/mget?keys=key1,key1,missing,missing")
+            .to_request();
+        let body: HashMap<String, String> = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.len(), 1);
+        let snapshot = metrics.snapshot();
+        assert_eq!((snapshot.hits, snapshot.misses), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_get_random_entry() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .route("/api/random", web::get().to(get_random_entry)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/random")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        for i in 0..10 {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        for _ in 0..20 {
+            let req = actix_test::TestRequest::get()
+                .uri("/api/random")
+                .to_request();
+            let body: KeyValuePair = actix_test::call_and_read_body_json(&app, req).await;
+            assert_eq!(
+                shard_manager.read().unwrap().get(&body.key),
+                Some(body.value)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_schema() {
+        let app =
+            actix_test::init_service(App::new().route("/api/schema", web::get().to(get_schema)))
+                .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/schema")
+            .to_request();
+        let schema: serde_json::Value = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(schema["openapi"], "3.0.3");
+        let paths = &schema["paths"];
+        assert!(paths["/api"]["post"]["requestBody"].is_object());
+        for method in ["get", "head", "put", "patch", "delete"] {
+            assert!(paths["/api/{key}"][method].is_object(), "{}", method);
+        }
+        assert!(paths["/api/{key}"]["post"].is_null());
+        assert_eq!(paths["/api/{key}"]["get"]["parameters"][0]["name"], "key");
+        assert_eq!(paths["/api/{key}"]["get"]["parameters"][1]["in"], "query");
+        assert!(paths["/api/{namespace}/{key}"]["get"].is_object());
+        assert!(
+            paths["/api/raw/{key}"]["post"]["requestBody"]["content"]["text/plain"].is_object()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_changed_keys() {
+        let shard_manager = new_shard_manager();
+        for key in ["b", "a"] {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(key.to_string(), "value".to_string())
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .route("/api/changed", web::get().to(get_changed_keys)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/changed?since=0")
+            .to_request();
+        let keys: Vec<String> = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(keys, vec!["a".to_string(), "b".to_string()]);
+
+        let later = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let req = actix_test::TestRequest::get()
+            .uri(&format!("/api/changed?since={}", later))
+            .to_request();
+        let keys: Vec<String> = actix_test::call_and_read_body_json(&app, req).await;
+        assert!(keys.is_empty());
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/changed")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_stream_export_key_values() {
+        let shard_manager = new_shard_manager();
+        for i in 0..50 {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("key{}", i), format!("value \"{}\"\n", i))
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .route(
+                    "/api/export/stream",
+                    web::get().to(stream_export_key_values),
+                ),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/export/stream")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/x-ndjson"
+        );
+        let body = actix_test::read_body(resp).await;
+        let lines: Vec<&str> = std::str::from_utf8(&body).unwrap().lines().collect();
+        assert_eq!(lines.len(), 50);
+        let data: HashMap<String, String> = lines
+            .into_iter()
+            .map(|line| {
+                let pair: KeyValuePair = serde_json::from_str(line).unwrap();
+                (pair.key, pair.value)
+            })
+            .collect();
+        assert_eq!(data, shard_manager.read().unwrap().export());
+
+        // An empty store streams an empty body
+        shard_manager.read().unwrap().clear().unwrap();
+        let req = actix_test::TestRequest::get()
+            .uri("/api/export/stream")
+            .to_request();
+        let body = actix_test::call_and_read_body(&app, req).await;
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_count() {
+        let shard_manager = new_shard_manager();
+        for i in 0..7 {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/stats/count", web::get().to(get_count)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/stats/count")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = actix_test::read_body(resp).await;
+        assert_eq!(body, "7");
+    }
+
+    #[tokio::test]
+    async fn test_get_prefix_count() {
+        let shard_manager = new_shard_manager();
+        for i in 0..6 {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("foo:{}", i), format!("value{}", i))
+                .unwrap();
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("bar:{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        shard_manager
+            .write()
+            .unwrap()
+            .set("foo".to_string(), "value".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .route("/api/stats/prefix-count", web::get().to(get_prefix_count)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/stats/prefix-count?prefix=foo:")
+            .to_request();
+        let body: usize = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, 6);
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/stats/prefix-count?prefix=baz")
+            .to_request();
+        let body: usize = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_shard_sizes() {
+        let shard_manager = new_shard_manager();
+        for i in 0..25 {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/stats/shards", web::get().to(get_shard_sizes)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/stats/shards")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: Vec<usize> = actix_test::read_body_json(resp).await;
+        assert_eq!(body.len(), SHARD_COUNT);
+        assert_eq!(body.iter().sum::<usize>(), 25);
+    }
+
+    #[tokio::test]
+    async fn test_get_memory_stats() {
+        let shard_manager = new_shard_manager();
+        for i in 0..10 {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("key{}", i), "x".repeat(1000))
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .route("/api/stats/memory", web::get().to(get_memory_stats)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/stats/memory")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: MemoryStats = actix_test::read_body_json(resp).await;
+        assert_eq!(body.shards.len(), SHARD_COUNT);
+        assert_eq!(body.shards.iter().sum::<usize>(), body.total);
+        // 10 values of 1000 bytes, keys and per-entry overhead on top
+        assert!((10_040..=15_000).contains(&body.total), "{}", body.total);
+    }
+
+    #[tokio::test]
+    async fn test_get_shard_ops() {
+        let shard_manager = new_shard_manager();
+        let shard = {
+            let locked_shard_manager = shard_manager.write().unwrap();
+            locked_shard_manager
+                .set("key1".to_string(), "value1".to_string())
+                .unwrap();
+            locked_shard_manager.get("key1");
+            locked_shard_manager.delete("key1").unwrap();
+            locked_shard_manager.get_shard_index("key1")
+        };
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .route("/api/stats/shard-ops", web::get().to(get_shard_ops)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/stats/shard-ops")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: Vec<ShardOps> = actix_test::read_body_json(resp).await;
+        assert_eq!(body.len(), SHARD_COUNT);
+        for (index, ops) in body.iter().enumerate() {
+            let expected = if index == shard { 1 } else { 0 };
+            assert_eq!(
+                *ops,
+                ShardOps {
+                    gets: expected,
+                    sets: expected,
+                    deletes: expected,
+                }
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_head_value_existing() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/{key}", web::head().to(head_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::default()
+            .method(Method::HEAD)
+            .uri("/api/key1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = actix_test::read_body(resp).await;
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_head_value_non_existing() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/{key}", web::head().to(head_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::default()
+            .method(Method::HEAD)
+            .uri("/api/non_existent_key")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_writes_share_the_store_lock() {
+        let shard_manager = new_shard_manager();
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api", web::post().to(add_key_value))
+                .route("/api/{key}", web::delete().to(delete_key)),
+        )
+        .await;
+
+        // Writes only lock the shard of their key, so they go through while another thread
+        // holds the read lock of the store
+        let (locked, wait_for_lock) = std::sync::mpsc::channel();
+        let (release, wait_for_release) = std::sync::mpsc::channel::<()>();
+        let reader = {
+            let shard_manager = shard_manager.clone();
+            thread::spawn(move || {
+                let _locked_shard_manager = shard_manager.read().unwrap();
+                locked.send(()).unwrap();
+                let _ = wait_for_release.recv();
+            })
+        };
+        wait_for_lock.recv().unwrap();
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(serde_json::json!({"key": "key1", "value": "value1"}))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = actix_test::TestRequest::delete()
+            .uri("/api/key1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        release.send(()).unwrap();
+        reader.join().unwrap();
+        assert!(shard_manager.read().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_add_key_value() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api", web::post().to(add_key_value)),
+        )
+        .await;
+
+        let kv = KeyValuePair {
+            key: "k".to_string(),
+            value: "v".to_string(),
+            ttl_seconds: None,
+            expires_at: None,
+            value_type: None,
+            encoding: None,
+        };
+
+        // Calculate the expected shard index
+        let expected_shard_index = shard_manager.read().unwrap().get_shard_index(&kv.key);
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: AddedKeyValue = actix_test::read_body_json(resp).await;
+        assert_eq!(body.key, "k");
+        assert_eq!(body.value, "v");
+        assert_eq!(body.shard, expected_shard_index);
+        assert!(body.created);
+        assert_eq!(body.version, 1);
+
+        // Setting the key again overwrites it
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: AddedKeyValue = actix_test::read_body_json(resp).await;
+        assert_eq!(body.shard, expected_shard_index);
+        assert!(!body.created);
+        assert_eq!(body.version, 2);
+    }
+
+    #[tokio::test]
+    async fn test_add_key_value_with_encoding() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/{key}", web::get().to(get_value))
+                .route("/api", web::post().to(add_key_value)),
+        )
+        .await;
+
+        let kv = KeyValuePair {
+            key: "gzipped".to_string(),
+            value: "H4sIAAAAAAAA".to_string(),
+            ttl_seconds: None,
+            expires_at: None,
+            value_type: None,
+            encoding: Some("gzip".to_string()),
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let kv = KeyValuePair {
+            key: "encoded".to_string(),
+            encoding: None,
+            ..kv
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .insert_header((VALUE_ENCODING_HEADER, "br"))
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        for (key, encoding) in [("gzipped", "gzip"), ("encoded", "br")] {
+            let req = actix_test::TestRequest::get()
+                .uri(&format!("/api/{}", key))
+                .to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().get(VALUE_ENCODING_HEADER).unwrap(), encoding);
+            let body: String = actix_test::read_body_json(resp).await;
+            assert_eq!(body, "H4sIAAAAAAAA");
+        }
+
+        // Overwriting the value without an encoding drops it
+        let kv = KeyValuePair {
+            key: "gzipped".to_string(),
+            ..kv
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(&kv)
+            .to_request();
+        actix_test::call_service(&app, req).await;
+        let req = actix_test::TestRequest::get()
+            .uri("/api/gzipped")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert!(resp.headers().get(VALUE_ENCODING_HEADER).is_none());
+
+        let kv = KeyValuePair {
+            encoding: Some("".to_string()),
+            ..kv
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_case_insensitive_keys() {
+        for case_insensitive in [true, false] {
+            let shard_manager = new_shard_manager();
+            shard_manager
+                .write()
+                .unwrap()
+                .set_case_insensitive_keys(case_insensitive);
+
+            let app = actix_test::init_service(
+                App::new()
+                    .app_data(web::Data::new(shard_manager.clone()))
+                    .app_data(web::Data::new(Metrics::default()))
+                    .app_data(web::Data::new(DrainMode::default()))
+                    .app_data(web::Data::new(Config::default()))
+                    .route("/api/{key}", web::get().to(get_value))
+                    .route("/api/{key}", web::delete().to(delete_key))
+                    .route("/api", web::post().to(add_key_value)),
+            )
+            .await;
+
+            let kv = KeyValuePair {
+                key: "Key1".to_string(),
+                value: "value1".to_string(),
+                ttl_seconds: None,
+                expires_at: None,
+                value_type: None,
+                encoding: None,
+            };
+            let req = actix_test::TestRequest::post()
+                .uri("/api")
+                .set_json(&kv)
+                .to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            let req = actix_test::TestRequest::get().uri("/api/key1").to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            if case_insensitive {
+                assert_eq!(resp.status(), StatusCode::OK);
+                let body: String = actix_test::read_body_json(resp).await;
+                assert_eq!(body, "value1");
+            } else {
+                assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+            }
+
+            let req = actix_test::TestRequest::delete()
+                .uri("/api/KEY1")
+                .to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            let expected = if case_insensitive {
+                StatusCode::OK
+            } else {
+                StatusCode::NOT_FOUND
+            };
+            assert_eq!(resp.status(), expected);
+            assert_eq!(
+                shard_manager.read().unwrap().len(),
+                usize::from(!case_insensitive)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_value_last_modified() {
+        let shard_manager = new_shard_manager();
+        let before = SystemTime::now();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get().uri("/api/key1").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let last_modified: header::HttpDate = resp
+            .headers()
+            .get(header::LAST_MODIFIED)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        // HTTP dates have a resolution of one second
+        let last_modified = SystemTime::from(last_modified);
+        assert!(last_modified + Duration::from_secs(1) > before);
+        assert!(last_modified <= SystemTime::now());
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/missing")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert!(resp.headers().get(header::LAST_MODIFIED).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_value_if_none_match() {
+        let shard_manager = new_shard_manager();
+        for value in ["value1", "value2"] {
+            shard_manager
+                .write()
+                .unwrap()
+                .set("key1".to_string(), value.to_string())
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        for if_none_match in ["2", "\"2\"", "\"1\", \"2\""] {
+            let req = actix_test::TestRequest::get()
+                .uri("/api/key1")
+                .insert_header((header::IF_NONE_MATCH, if_none_match))
+                .to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(resp.headers().get(VERSION_HEADER).unwrap(), "2");
+            assert!(actix_test::read_body(resp).await.is_empty());
+        }
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/key1")
+            .insert_header((header::IF_NONE_MATCH, "1"))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(VERSION_HEADER).unwrap(), "2");
+        let body: String = actix_test::read_body_json(resp).await;
+        assert_eq!(body, "value2");
+    }
+
+    #[tokio::test]
+    async fn test_get_value_etag() {
+        let shard_manager = new_shard_manager();
+        for key in ["key1", "key2"] {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(key.to_string(), "value".to_string())
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        // Equal values share an ETag, whatever their key or how often they're read
+        let mut etags = Vec::new();
+        for key in ["key1", "key2", "key1"] {
+            let req = actix_test::TestRequest::get()
+                .uri(&format!("/api/{}", key))
+                .to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            etags.push(resp.headers().get(header::ETAG).unwrap().clone());
+        }
+        assert!(etags.iter().all(|etag| *etag == value_etag("value")));
+        assert_ne!(value_etag("value"), value_etag("other"));
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/key1")
+            .insert_header((header::IF_NONE_MATCH, value_etag("value")))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert!(actix_test::read_body(resp).await.is_empty());
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/key1")
+            .insert_header((header::IF_NONE_MATCH, value_etag("other")))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_value_versions() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/{key}", web::get().to(get_value))
+                .route("/api/{key}", web::put().to(update_key_value))
+                .route("/api", web::post().to(add_key_value)),
+        )
+        .await;
+
+        for version in 1..=3 {
+            let kv = KeyValuePair {
+                key: "key1".to_string(),
+                value: format!("value{}", version),
+                ttl_seconds: None,
+                expires_at: None,
+                value_type: None,
+                encoding: None,
+            };
+            let req = actix_test::TestRequest::post()
+                .uri("/api")
+                .set_json(&kv)
+                .to_request();
+            let body: AddedKeyValue = actix_test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body.version, version);
+
+            let req = actix_test::TestRequest::get().uri("/api/key1").to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(
+                resp.headers().get(VERSION_HEADER).unwrap(),
+                version.to_string().as_str()
+            );
+        }
+
+        let req = actix_test::TestRequest::put()
+            .uri("/api/key1")
+            .set_json(ValueUpdate {
+                value: "value4".to_string(),
+            })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(VERSION_HEADER).unwrap(), "4");
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let mut document = serde_json::json!({"a": "b", "c": {"d": "e", "f": "g"}});
+        merge_patch(
+            &mut document,
+            serde_json::json!({"a": "z", "c": {"f": null}}),
+        );
+        assert_eq!(document, serde_json::json!({"a": "z", "c": {"d": "e"}}));
+
+        // Non-object patches and targets are replaced as a whole
+        let mut document = serde_json::json!({"a": [1, 2]});
+        merge_patch(
+            &mut document,
+            serde_json::json!({"a": [3], "b": {"c": null}}),
+        );
+        assert_eq!(document, serde_json::json!({"a": [3], "b": {}}));
+        merge_patch(&mut document, serde_json::json!("text"));
+        assert_eq!(document, serde_json::json!("text"));
+        merge_patch(&mut document, serde_json::json!({"a": 1}));
+        assert_eq!(document, serde_json::json!({"a": 1}));
+    }
+
+    #[tokio::test]
+    async fn test_patch_key_value() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .read()
+            .unwrap()
+            .set(
+                "user".to_string(),
+                r#"{"name":"Ada","address":{"city":"London","zip":"N1"},"age":36}"#.to_string(),
+            )
+            .unwrap();
+        shard_manager
+            .read()
+            .unwrap()
+            .set("text".to_string(), "not json".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/{key}", web::patch().to(patch_key_value)),
+        )
+        .await;
+
+        // Patches a nested object, adds a field and removes another
+        let req = actix_test::TestRequest::patch()
+            .uri("/api/user")
+            .set_json(serde_json::json!({
+                "address": {"city": "Paris"},
+                "email": "ada@example.com",
+                "age": null
+            }))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(VERSION_HEADER).unwrap(), "2");
+        let body: serde_json::Value = actix_test::read_body_json(resp).await;
+        let expected = serde_json::json!({
+            "name": "Ada",
+            "address": {"city": "Paris", "zip": "N1"},
+            "email": "ada@example.com"
+        });
+        assert_eq!(body, expected);
+        let stored = shard_manager.read().unwrap().get("user").unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&stored).unwrap(),
+            expected
+        );
+
+        let req = actix_test::TestRequest::patch()
+            .uri("/api/missing")
+            .set_json(serde_json::json!({"a": 1}))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = actix_test::TestRequest::patch()
+            .uri("/api/text")
+            .set_json(serde_json::json!({"a": 1}))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            shard_manager.read().unwrap().get("text"),
+            Some("not json".to_string())
+        );
+    }
+
+    // Writes to /dev/full always fail with "No space left on device"
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_add_key_value_wal_failure() {
+        let mut shard_manager: ShardManager = ShardManager::new(4);
+        let options = WalOptions {
+            retries: 1,
+            retry_delay: Duration::from_millis(1),
+            ..WalOptions::default()
+        };
+        shard_manager.attach_wal(Wal::open(Path::new("/dev/full"), options).unwrap());
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(Arc::new(RwLock::new(shard_manager))))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api", web::post().to(add_key_value))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(serde_json::json!({ "key": "k", "value": "v" }))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body: ErrorBody = actix_test::read_body_json(resp).await;
+        assert_eq!(body.error.code, "internal_error");
+        // The write that couldn't be logged isn't applied either
+        let req = actix_test::TestRequest::get().uri("/api/k").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_requests_succeed_after_lock_poisoned() {
+        let shard_manager = new_shard_manager();
+        let poisoning = shard_manager.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoning.write().unwrap();
+            panic!("handler panicking while holding the lock");
+        })
+        .join();
+        assert!(shard_manager.is_poisoned());
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/{key}", web::get().to(get_value))
+                .route("/api", web::post().to(add_key_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(serde_json::json!({ "key": "key1", "value": "value1" }))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = actix_test::TestRequest::get().uri("/api/key1").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let value: String = actix_test::read_body_json(resp).await;
+        assert_eq!(value, "value1");
+    }
+
+    #[tokio::test]
+    async fn test_add_key_value_store_full() {
+        let config = Config {
+            max_total_keys: Some(2),
+            ..Config::default()
+        };
+        let shard_manager = Arc::new(RwLock::new(config.build_shard_manager()));
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(config))
+                .route("/api", web::post().to(add_key_value)),
+        )
+        .await;
+
+        for key in ["k1", "k2"] {
+            let req = actix_test::TestRequest::post()
+                .uri("/api")
+                .set_json(serde_json::json!({ "key": key, "value": "v" }))
+                .to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(serde_json::json!({ "key": "k3", "value": "v" }))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::INSUFFICIENT_STORAGE);
+        let body: ErrorBody = actix_test::read_body_json(resp).await;
+        assert_eq!(body.error.code, "insufficient_storage");
+        assert_eq!(shard_manager.read().unwrap().get("k3"), None);
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(serde_json::json!({ "key": "k1", "value": "updated" }))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            shard_manager.read().unwrap().get("k1"),
+            Some("updated".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_key_value_with_ttl() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/{key}", web::get().to(get_value))
+                .route("/api", web::post().to(add_key_value)),
+        )
+        .await;
+
+        let kv = KeyValuePair {
+            key: "k".to_string(),
+            value: "v".to_string(),
+            ttl_seconds: Some(1),
+            expires_at: None,
+            value_type: None,
+            encoding: None,
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = actix_test::TestRequest::get().uri("/api/k").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        let req = actix_test::TestRequest::get().uri("/api/k").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_add_key_value_with_write_batching() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(WriteBatcher::spawn(
+                    shard_manager.clone(),
+                    8,
+                )))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api", web::post().to(add_key_value)),
+        )
+        .await;
+
+        for i in 0..20 {
+            let kv = KeyValuePair {
+                key: format!("key{}", i),
+                value: format!("value{}", i),
+                ttl_seconds: None,
+                expires_at: None,
+                value_type: None,
+                encoding: None,
+            };
+            let req = actix_test::TestRequest::post()
+                .uri("/api")
+                .set_json(&kv)
+                .to_request();
+            let body: AddedKeyValue = actix_test::call_and_read_body_json(&app, req).await;
+            assert!(body.created);
+            assert_eq!(
+                body.shard,
+                shard_manager.read().unwrap().get_shard_index(&kv.key)
+            );
+        }
+
+        let shard_manager = shard_manager.read().unwrap();
+        assert_eq!(shard_manager.len(), 20);
+        assert_eq!(shard_manager.get("key7"), Some("value7".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_add_key_value_default_ttl() {
+        let config = Config {
+            default_ttl_seconds: Some(60),
+            ..Config::default()
+        };
+        let shard_manager = Arc::new(RwLock::new(config.build_shard_manager()));
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(config))
+                .route("/api", web::post().to(add_key_value)),
+        )
+        .await;
+
+        for (key, ttl_seconds) in [
+            ("default", None),
+            ("explicit", Some(600)),
+            ("forever", Some(0)),
+        ] {
+            let kv = KeyValuePair {
+                key: key.to_string(),
+                value: "value".to_string(),
+                ttl_seconds,
+                expires_at: None,
+                value_type: None,
+                encoding: None,
+            };
+            let req = actix_test::TestRequest::post()
+                .uri("/api")
+                .set_json(&kv)
+                .to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let shard_manager = shard_manager.read().unwrap();
+        let ttl = shard_manager.ttl("default").unwrap();
+        assert!(ttl > 55 && ttl <= 60);
+        let ttl = shard_manager.ttl("explicit").unwrap();
+        assert!(ttl > 595 && ttl <= 600);
+        assert_eq!(shard_manager.ttl("forever"), Some(-1));
+    }
+
+    #[tokio::test]
+    async fn test_shard_override_header() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api", web::post().to(add_key_value))
+                .route("/api/{key}", web::get().to(get_value))
+                .route("/api/{key}", web::delete().to(delete_key)),
+        )
+        .await;
+
+        let hashed_index = shard_manager.read().unwrap().get_shard_index("key1");
+        let pinned_index = (hashed_index + 1) % SHARD_COUNT;
+        let kv = KeyValuePair {
+            key: "key1".to_string(),
+            value: "value1".to_string(),
+            ttl_seconds: None,
+            expires_at: None,
+            value_type: None,
+            encoding: None,
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .insert_header((SHARD_OVERRIDE_HEADER, pinned_index.to_string()))
+            .set_json(&kv)
+            .to_request();
+        let body: AddedKeyValue = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.shard, pinned_index);
+        assert_eq!(shard_manager.read().unwrap().shard_sizes()[pinned_index], 1);
+
+        // Found with or without the header, but not in another shard
+        let req = actix_test::TestRequest::get().uri("/api/key1").to_request();
+        let body: String = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, "value1");
+        let req = actix_test::TestRequest::get()
+            .uri("/api/key1")
+            .insert_header((SHARD_OVERRIDE_HEADER, pinned_index.to_string()))
+            .to_request();
+        let body: String = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, "value1");
+        let req = actix_test::TestRequest::get()
+            .uri("/api/key1")
+            .insert_header((SHARD_OVERRIDE_HEADER, hashed_index.to_string()))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        for (header, status) in [
+            ("99", StatusCode::BAD_REQUEST),
+            ("one", StatusCode::BAD_REQUEST),
+        ] {
+            let req = actix_test::TestRequest::post()
+                .uri("/api")
+                .insert_header((SHARD_OVERRIDE_HEADER, header))
+                .set_json(&kv)
+                .to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        // A rejected write doesn't move the key
+        let req = actix_test::TestRequest::post()
+            .uri("/api?nx=true")
+            .insert_header((SHARD_OVERRIDE_HEADER, hashed_index.to_string()))
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            shard_manager.read().unwrap().get_shard_index("key1"),
+            pinned_index
+        );
+
+        let req = actix_test::TestRequest::delete()
+            .uri("/api/key1")
+            .insert_header((SHARD_OVERRIDE_HEADER, pinned_index.to_string()))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(shard_manager.read().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_add_key_value_expires_at() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api", web::post().to(add_key_value)),
+        )
+        .await;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let kv = KeyValuePair {
+            key: "k".to_string(),
+            value: "v".to_string(),
+            ttl_seconds: Some(10),
+            expires_at: Some(now + 100),
+            value_type: None,
+            encoding: None,
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // expires_at wins over ttl_seconds
+        let ttl = shard_manager.read().unwrap().ttl("k").unwrap();
+        assert!(ttl > 90 && ttl <= 100);
+
+        let kv = KeyValuePair {
+            key: "k2".to_string(),
+            value: "v".to_string(),
+            ttl_seconds: None,
+            expires_at: Some(now - 1),
+            value_type: None,
+            encoding: None,
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(shard_manager.read().unwrap().get("k2"), None);
+    }
+
+    #[tokio::test]
+    async fn test_add_key_value_json() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/{key}", web::get().to(get_value))
+                .route("/api", web::post().to(add_key_value)),
+        )
+        .await;
+
+        let kv = KeyValuePair {
+            key: "user".to_string(),
+            value: r#"{"name": "ada", "tags": [1, 2]}"#.to_string(),
+            ttl_seconds: None,
+            expires_at: None,
+            value_type: Some(ValueType::Json),
+            encoding: None,
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = actix_test::TestRequest::get().uri("/api/user").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+        let body: serde_json::Value = actix_test::read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!({"name": "ada", "tags": [1, 2]}));
+
+        let kv = KeyValuePair {
+            key: "broken".to_string(),
+            value: r#"{"name": "#.to_string(),
+            ttl_seconds: None,
+            expires_at: None,
+            value_type: Some(ValueType::Json),
+            encoding: None,
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: ErrorBody = actix_test::read_body_json(resp).await;
+        assert!(body.error.message.starts_with("Value is not valid JSON"));
+        assert!(!shard_manager.read().unwrap().contains("broken"));
+    }
+
+    #[tokio::test]
+    async fn test_add_many_key_values() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/batch/set", web::post().to(add_many_key_values)),
+        )
+        .await;
+
+        let pairs: Vec<KeyValuePair> = (0..10)
+            .map(|i| KeyValuePair {
+                key: format!("key{}", i),
+                value: format!("value{}", i),
+                ttl_seconds: None,
+                expires_at: None,
+                value_type: None,
+                encoding: None,
+            })
+            .collect();
+        let req = actix_test::TestRequest::post()
+            .uri("/api/batch/set")
+            .set_json(&pairs)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: HashMap<usize, usize> = actix_test::read_body_json(resp).await;
+        assert_eq!(body.values().sum::<usize>(), 10);
+        for pair in &pairs {
+            assert_eq!(
+                shard_manager.read().unwrap().get(&pair.key),
+                Some(pair.value.clone())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_many_key_values_dry_run() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config {
+                    max_key_bytes: 8,
+                    ..Config::default()
+                }))
+                .route("/api/batch/set", web::post().to(add_many_key_values)),
+        )
+        .await;
+
+        let pairs: Vec<KeyValuePair> = ["key1", "key2", "too_long_key"]
+            .into_iter()
+            .map(|key| KeyValuePair {
+                key: key.to_string(),
+                value: "value".to_string(),
+                ttl_seconds: None,
+                expires_at: None,
+                value_type: None,
+                encoding: None,
+            })
+            .collect();
+        let req = actix_test::TestRequest::post()
+            .uri("/api/batch/set?dry_run=true")
+            .set_json(&pairs)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: BatchValidation = actix_test::read_body_json(resp).await;
+        assert_eq!(body.accepted, ["key1", "key2"]);
+        assert_eq!(body.rejected.len(), 1);
+        assert_eq!(body.rejected[0].key, "too_long_key");
+        assert_eq!(body.rejected[0].error.code, "bad_request");
+        assert!(shard_manager.read().unwrap().is_empty());
+
+        // Without dry run the invalid pair fails the whole batch
+        let req = actix_test::TestRequest::post()
+            .uri("/api/batch/set")
+            .set_json(&pairs)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(shard_manager.read().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_add_many_key_values_store_full() {
+        let config = Config {
+            max_total_keys: Some(3),
+            ..Config::default()
+        };
+        let shard_manager = Arc::new(RwLock::new(config.build_shard_manager()));
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "old".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(config))
+                .route("/api/batch/set", web::post().to(add_many_key_values)),
+        )
+        .await;
+
+        // Three new keys on top of the stored one don't fit, so none are stored
+        let pairs = serde_json::json!([
+            { "key": "key1", "value": "value1" },
+            { "key": "key2", "value": "value2" },
+            { "key": "key3", "value": "value3" },
+            { "key": "key4", "value": "value4" },
+        ]);
+        let req = actix_test::TestRequest::post()
+            .uri("/api/batch/set")
+            .set_json(&pairs)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(shard_manager.read().unwrap().len(), 1);
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("old".to_string())
+        );
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/batch/set")
+            .set_json(&pairs.as_array().unwrap()[..3])
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(shard_manager.read().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_import_key_values() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key0".to_string(), "old".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .route("/api/import", web::post().to(import_key_values)),
+        )
+        .await;
+
+        let data: HashMap<String, String> = (0..5)
+            .map(|i| (format!("key{}", i), format!("value{}", i)))
+            .collect();
+        let req = actix_test::TestRequest::post()
+            .uri("/api/import")
+            .set_json(&data)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: ImportResult = actix_test::read_body_json(resp).await;
+        assert_eq!(body.imported, 5);
+        for (key, value) in &data {
+            assert_eq!(shard_manager.read().unwrap().get(key), Some(value.clone()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_key_values_without_overwrite() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key0".to_string(), "old".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .route("/api/import", web::post().to(import_key_values)),
+        )
+        .await;
+
+        let data: HashMap<String, String> = (0..5)
+            .map(|i| (format!("key{}", i), format!("value{}", i)))
+            .collect();
+        let req = actix_test::TestRequest::post()
+            .uri("/api/import?overwrite=false")
+            .set_json(&data)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: ImportResult = actix_test::read_body_json(resp).await;
+        assert_eq!(body.imported, 4);
+        assert_eq!(
+            shard_manager.read().unwrap().get("key0"),
+            Some("old".to_string())
+        );
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("value1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_key_values() {
+        let shard_manager = new_shard_manager();
+        let data: HashMap<String, String> = (0..10)
+            .map(|i| (format!("key{}", i), format!("value{}", i)))
+            .collect();
+        for (key, value) in &data {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(key.clone(), value.clone())
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/api/export", web::get().to(export_key_values))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/export")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: HashMap<String, String> = actix_test::read_body_json(resp).await;
+        assert_eq!(body, data);
+    }
+
+    #[tokio::test]
+    async fn test_export_key_values_gzip() {
+        let shard_manager = new_shard_manager();
+        for i in 0..100 {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .wrap(Condition::new(true, Compress::default()))
+                .route("/api/export", web::get().to(export_key_values)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/export")
+            .insert_header((header::ACCEPT_ENCODING, "gzip"))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+
+        let body = actix_test::read_body(resp).await;
+        let mut decompressed = String::new();
+        GzDecoder::new(&body[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        let body: HashMap<String, String> = serde_json::from_str(&decompressed).unwrap();
+        assert_eq!(body, shard_manager.read().unwrap().export());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_counters() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/metrics", web::get().to(get_metrics))
+                .route("/api/{key}", web::get().to(get_value))
+                .route("/api", web::post().to(add_key_value))
+                .route("/api/batch/get", web::post().to(get_many_values))
+                .route("/api/batch/set", web::post().to(add_many_key_values))
+                .route("/api/{key}", web::delete().to(delete_key)),
+        )
+        .await;
+
+        let pairs: Vec<KeyValuePair> = (0..2)
+            .map(|i| KeyValuePair {
+                key: format!("key{}", i),
+                value: format!("value{}", i),
+                ttl_seconds: None,
+                expires_at: None,
+                value_type: None,
+                encoding: None,
+            })
+            .collect();
+        let requests = [
+            actix_test::TestRequest::post()
+                .uri("/api")
+                .set_json(&pairs[0]),
+            actix_test::TestRequest::post()
+                .uri("/api/batch/set")
+                .set_json(&pairs),
+            actix_test::TestRequest::get().uri("/api/key0"),
+            actix_test::TestRequest::get().uri("/api/missing"),
+            actix_test::TestRequest::post()
+                .uri("/api/batch/get")
+                .set_json(vec!["key1", "missing"]),
+            actix_test::TestRequest::delete().uri("/api/key0"),
+            actix_test::TestRequest::get().uri("/api/key0"),
+        ];
+        for req in requests {
+            actix_test::call_service(&app, req.to_request()).await;
+        }
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/metrics")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: MetricsSnapshot = actix_test::read_body_json(resp).await;
+        assert_eq!(
+            body,
+            MetricsSnapshot {
+                gets: 5,
+                sets: 3,
+                deletes: 1,
+                hits: 2,
+                misses: 3,
+                hit_ratio: 0.4,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prometheus_metrics() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .route("/metrics", web::get().to(get_prometheus_metrics))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get().uri("/api/key1").to_request();
+        actix_test::call_service(&app, req).await;
+
+        let req = actix_test::TestRequest::get().uri("/metrics").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp
+            .headers()
+            .get("content-type")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+
+        let body = actix_test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("hashmaprs_gets_total 1\n"));
+        assert!(body.contains("hashmaprs_hits_total 1\n"));
+        assert!(body.contains("hashmaprs_shard_entries{shard=\"0\"}"));
+        assert!(body.contains("hashmaprs_shard_lock_contention_total{shard=\"0\"}"));
+        // Every line is either a comment or a `name{labels} value` sample
+        for line in body.lines().filter(|line| !line.starts_with('#')) {
+            let (name, value) = line.rsplit_once(' ').unwrap();
+            assert!(name.starts_with("hashmaprs_"));
+            assert!(value.parse::<f64>().is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_key_value_nx() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api", web::post().to(add_key_value)),
+        )
+        .await;
+
+        let kv = KeyValuePair {
+            key: "key1".to_string(),
+            value: "value1".to_string(),
+            ttl_seconds: None,
+            expires_at: None,
+            value_type: None,
+            encoding: None,
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api?nx=true")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: AddedKeyValue = actix_test::read_body_json(resp).await;
+        assert!(body.created);
+        assert_eq!(
+            body.shard,
+            shard_manager.read().unwrap().get_shard_index("key1")
+        );
+
+        let kv = KeyValuePair {
+            value: "value2".to_string(),
+            ..kv
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api?nx=true")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("value1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_key_value_size_limit() {
+        let shard_manager = new_shard_manager();
+        let config = Config {
+            max_value_bytes: Some(8),
+            ..Config::default()
+        };
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(config))
+                .route("/api", web::post().to(add_key_value))
+                .route("/api/{key}", web::put().to(update_key_value)),
+        )
+        .await;
+
+        let kv = KeyValuePair {
+            key: "key1".to_string(),
+            value: "x".repeat(9),
+            ttl_seconds: None,
+            expires_at: None,
+            value_type: None,
+            encoding: None,
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!shard_manager.read().unwrap().contains("key1"));
+
+        let kv = KeyValuePair {
+            value: "x".repeat(8),
+            ..kv
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = actix_test::TestRequest::put()
+            .uri("/api/key1")
+            .set_json(&ValueUpdate {
+                value: "x".repeat(9),
+            })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("x".repeat(8))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_key_value_key_validation() {
+        let shard_manager = new_shard_manager();
+        let config = Config {
+            max_key_bytes: 8,
+            ..Config::default()
+        };
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(config))
+                .route("/api", web::post().to(add_key_value))
+                .route("/api/incr/{key}", web::post().to(increment_value)),
+        )
+        .await;
+
+        for (key, status) in [
+            ("", StatusCode::BAD_REQUEST),
+            ("123456789", StatusCode::BAD_REQUEST),
+            ("12345678", StatusCode::OK),
+        ] {
+            let kv = KeyValuePair {
+                key: key.to_string(),
+                value: "value".to_string(),
+                ttl_seconds: None,
+                expires_at: None,
+                value_type: None,
+                encoding: None,
+            };
+            let req = actix_test::TestRequest::post()
+                .uri("/api")
+                .set_json(&kv)
+                .to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+        }
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/incr/123456789")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: ErrorBody = actix_test::read_body_json(resp).await;
+        assert_eq!(body.error.code, "bad_request");
+        assert_eq!(
+            body.error.message,
+            "Key is 9 bytes long, the limit is 8 bytes"
+        );
+
+        assert_eq!(shard_manager.read().unwrap().len(), 1);
+        assert!(shard_manager.read().unwrap().contains("12345678"));
+    }
+
+    #[tokio::test]
+    async fn test_scan_key_values() {
+        let shard_manager = new_shard_manager();
+        for i in 0..25 {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .route("/api/scan", web::get().to(scan_key_values)),
+        )
+        .await;
+
+        let mut seen = HashMap::new();
+        let mut cursor = Some(0);
+        while let Some(current) = cursor {
+            let req = actix_test::TestRequest::get()
+                .uri(&format!("/api/scan?cursor={}&limit=10", current))
+                .to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            let page: ScanPage = actix_test::read_body_json(resp).await;
+            assert!(page.pairs.len() <= 10);
+            for pair in page.pairs {
+                assert!(seen.insert(pair.key, pair.value).is_none());
+            }
+            cursor = page.next_cursor;
+        }
+
+        assert_eq!(seen.len(), 25);
+        for i in 0..25 {
+            assert_eq!(seen[&format!("key{}", i)], format!("value{}", i));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rename_key() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/rename", web::post().to(rename_key)),
+        )
+        .await;
+
+        let from_index = shard_manager.read().unwrap().get_shard_index("from");
+        for same_shard in [true, false] {
+            shard_manager
+                .write()
+                .unwrap()
+                .set("from".to_string(), "value".to_string())
+                .unwrap();
+            let to = (0..)
+                .map(|i| format!("to{}", i))
+                .find(|to| {
+                    (shard_manager.read().unwrap().get_shard_index(to) == from_index) == same_shard
+                })
+                .unwrap();
+
+            let req = actix_test::TestRequest::post()
+                .uri("/api/rename")
+                .set_json(&Rename {
+                    from: "from".to_string(),
+                    to: to.clone(),
+                })
+                .to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(!shard_manager.read().unwrap().contains("from"));
+            assert_eq!(
+                shard_manager.read().unwrap().get(&to),
+                Some("value".to_string())
+            );
+        }
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/rename")
+            .set_json(&Rename {
+                from: "missing".to_string(),
+                to: "to".to_string(),
+            })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_swap_keys() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("a".to_string(), "1".to_string())
+            .unwrap();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("b".to_string(), "2".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/swap", web::post().to(swap_keys)),
+        )
+        .await;
+
+        let swap = |a: &str, b: &str| {
+            actix_test::TestRequest::post()
+                .uri("/api/swap")
+                .set_json(&Swap {
+                    a: a.to_string(),
+                    b: b.to_string(),
+                })
+                .to_request()
+        };
+
+        let resp = actix_test::call_service(&app, swap("a", "b")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            shard_manager.read().unwrap().get("a"),
+            Some("2".to_string())
+        );
+        assert_eq!(
+            shard_manager.read().unwrap().get("b"),
+            Some("1".to_string())
+        );
+
+        // The missing key takes the value, the other one is deleted
+        let resp = actix_test::call_service(&app, swap("a", "c")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!shard_manager.read().unwrap().contains("a"));
+        assert_eq!(
+            shard_manager.read().unwrap().get("c"),
+            Some("2".to_string())
+        );
+
+        let resp = actix_test::call_service(&app, swap("missing", "a")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get_set_value() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/getset/{key}", web::post().to(get_set_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/getset/key1")
+            .set_json(&ValueUpdate {
+                value: "value1".to_string(),
+            })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = actix_test::read_body(resp).await;
+        assert_eq!(body, "null");
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/getset/key1")
+            .set_json(&ValueUpdate {
+                value: "value2".to_string(),
+            })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = actix_test::read_body(resp).await;
+        assert_eq!(body, r#""value1""#);
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("value2".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_touch_key() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set_with_ttl(
+                "session".to_string(),
+                "user1".to_string(),
+                Duration::from_millis(100),
+            )
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .route("/api/touch/{key}", web::post().to(touch_key)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/touch/session")
+            .set_json(Touch { ttl_seconds: 60 })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Still alive past its original expiry, with the same value
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        {
+            let locked_shard_manager = shard_manager.read().unwrap();
+            assert_eq!(
+                locked_shard_manager.get("session"),
+                Some("user1".to_string())
+            );
+            assert!(locked_shard_manager.ttl("session").unwrap() > 55);
+        }
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/touch/missing")
+            .set_json(Touch { ttl_seconds: 60 })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_append_value() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("log".to_string(), "line1\n".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config {
+                    max_value_bytes: Some(16),
+                    ..Config::default()
+                }))
+                .route("/api/append/{key}", web::post().to(append_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/append/log")
+            .set_json("line2\n")
+            .to_request();
+        let len: usize = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(len, 12);
+        assert_eq!(
+            shard_manager.read().unwrap().get("log"),
+            Some("line1\nline2\n".to_string())
+        );
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/append/fresh")
+            .set_json("abc")
+            .to_request();
+        let len: usize = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(len, 3);
+        assert_eq!(
+            shard_manager.read().unwrap().get("fresh"),
+            Some("abc".to_string())
+        );
+
+        // The new value would be 18 bytes long
+        let req = actix_test::TestRequest::post()
+            .uri("/api/append/log")
+            .set_json("line3\n")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            shard_manager.read().unwrap().get("log"),
+            Some("line1\nline2\n".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_key_value_existing() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/{key}", web::put().to(update_key_value)),
+        )
+        .await;
+
+        let update = ValueUpdate {
+            value: "value2".to_string(),
+        };
+        let req = actix_test::TestRequest::put()
+            .uri("/api/key1")
+            .set_json(&update)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = actix_test::read_body(resp).await;
+        assert_eq!(body, r#"{"key":"key1","value":"value2"}"#);
+
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("value2".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_key_value_non_existing() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/{key}", web::put().to(update_key_value)),
+        )
+        .await;
+
+        let update = ValueUpdate {
+            value: "value".to_string(),
+        };
+        let req = actix_test::TestRequest::put()
+            .uri("/api/non_existent_key")
+            .set_json(&update)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        assert_eq!(shard_manager.read().unwrap().get("non_existent_key"), None);
+    }
+
+    #[tokio::test]
+    async fn test_increment_value() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/incr/{key}", web::post().to(increment_value)),
+        )
+        .await;
+
+        // A missing key starts from 0 and the default increment is 1
+        let req = actix_test::TestRequest::post()
+            .uri("/api/incr/counter")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = actix_test::read_body(resp).await;
+        assert_eq!(body, "1");
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/incr/counter")
+            .set_json(IncrementBy { by: 5 })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = actix_test::read_body(resp).await;
+        assert_eq!(body, "6");
+
+        assert_eq!(
+            shard_manager.read().unwrap().get("counter"),
+            Some("6".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_increment_value_non_numeric() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/incr/{key}", web::post().to(increment_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/incr/key1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: ErrorBody = actix_test::read_body_json(resp).await;
+        assert_eq!(body.error.code, "bad_request");
+        assert!(body.error.message.starts_with("Cannot increment key: key1"));
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("value1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_increment_many_values() {
+        let shard_manager = new_shard_manager();
+        {
+            let shard_manager = shard_manager.write().unwrap();
+            shard_manager
+                .set("hits".to_string(), "10".to_string())
+                .unwrap();
+            shard_manager
+                .set("name".to_string(), "value1".to_string())
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/batch/incr", web::post().to(increment_many_values)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/batch/incr")
+            .set_json(serde_json::json!({ "hits": 5, "name": 1, "misses": -2 }))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: BatchIncrements = actix_test::read_body_json(resp).await;
+        assert_eq!(
+            body.values,
+            HashMap::from([("hits".to_string(), 15), ("misses".to_string(), -2)])
+        );
+        assert_eq!(body.errors.len(), 1);
+        assert_eq!(body.errors["name"].code, "bad_request");
+        assert!(body.errors["name"]
+            .message
+            .starts_with("Cannot increment key: name"));
+
+        let shard_manager = shard_manager.read().unwrap();
+        assert_eq!(shard_manager.get("hits"), Some("15".to_string()));
+        assert_eq!(shard_manager.get("misses"), Some("-2".to_string()));
+        assert_eq!(shard_manager.get("name"), Some("value1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_touch_many_keys() {
+        let shard_manager = new_shard_manager();
+        {
+            let shard_manager = shard_manager.write().unwrap();
+            for key in ["key1", "key2"] {
+                shard_manager
+                    .set_with_ttl(key.to_string(), "value".to_string(), Duration::from_secs(5))
+                    .unwrap();
+            }
+            shard_manager
+                .set("key3".to_string(), "value".to_string())
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .route("/api/batch/touch", web::post().to(touch_many_keys)),
+        )
+        .await;
+
+        let touch = BatchTouch {
+            keys: vec![
+                "key1".to_string(),
+                "missing".to_string(),
+                "key3".to_string(),
+            ],
+            ttl_seconds: 100,
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api/batch/touch")
+            .set_json(&touch)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Vec<String> = actix_test::read_body_json(resp).await;
+        assert_eq!(body, vec!["key1", "key3"]);
+
+        let shard_manager = shard_manager.read().unwrap();
+        for key in ["key1", "key3"] {
+            let ttl = shard_manager.ttl(key).unwrap();
+            assert!((99..=100).contains(&ttl), "{}: {}", key, ttl);
+        }
+        // Keys left out of the batch keep their expiry
+        assert!(shard_manager.ttl("key2").unwrap() <= 5);
+        assert!(!shard_manager.contains("missing"));
+    }
+
+    #[tokio::test]
+    async fn test_decrement_value() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("counter".to_string(), "10".to_string())
+            .unwrap();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/decr/{key}", web::post().to(decrement_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/decr/counter")
+            .to_request();
+        let body: i64 = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, 9);
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/decr/counter")
+            .set_json(IncrementBy { by: 4 })
+            .to_request();
+        let body: i64 = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, 5);
+        assert_eq!(
+            shard_manager.read().unwrap().get("counter"),
+            Some("5".to_string())
+        );
+
+        // A missing key starts from 0
+        let req = actix_test::TestRequest::post()
+            .uri("/api/decr/missing")
+            .set_json(IncrementBy { by: 3 })
+            .to_request();
+        let body: i64 = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, -3);
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/decr/key1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: ErrorBody = actix_test::read_body_json(resp).await;
+        assert!(body.error.message.starts_with("Cannot decrement key: key1"));
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("value1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compare_and_swap() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .route("/api/cas/{key}", web::post().to(compare_and_swap)),
+        )
+        .await;
+
+        // Mismatching expected value
+        let req = actix_test::TestRequest::post()
+            .uri("/api/cas/key1")
+            .set_json(CompareAndSwap {
+                expected: Some("other".to_string()),
+                new: "value2".to_string(),
+            })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("value1".to_string())
+        );
+
+        // Matching expected value
+        let req = actix_test::TestRequest::post()
+            .uri("/api/cas/key1")
+            .set_json(CompareAndSwap {
+                expected: Some("value1".to_string()),
+                new: "value2".to_string(),
+            })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("value2".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_key_if() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .route("/api/delete-if/{key}", web::post().to(delete_key_if)),
+        )
+        .await;
+
+        let delete_if = |expected: &str| {
+            actix_test::TestRequest::post()
+                .uri("/api/delete-if/key1")
+                .set_json(DeleteIf {
+                    expected: expected.to_string(),
+                })
+                .to_request()
+        };
+
+        let resp = actix_test::call_service(&app, delete_if("other")).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("value1".to_string())
+        );
+
+        let resp = actix_test::call_service(&app, delete_if("value1")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!shard_manager.read().unwrap().contains("key1"));
+
+        let resp = actix_test::call_service(&app, delete_if("value1")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_compare_and_swap_absent_key() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .route("/api/cas/{key}", web::post().to(compare_and_swap)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/cas/key1")
+            .set_json(CompareAndSwap {
+                expected: None,
+                new: "value1".to_string(),
+            })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("value1".to_string())
+        );
+
+        // The key now exists, so expecting it to be absent fails
+        let req = actix_test::TestRequest::post()
+            .uri("/api/cas/key1")
+            .set_json(CompareAndSwap {
+                expected: None,
+                new: "value2".to_string(),
+            })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("value1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_key() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .route("/api/{key}", web::delete().to(delete_key)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::delete()
+            .uri("/api/key1")
+            .to_request();
+
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = actix_test::read_body(resp).await;
+        assert_eq!(body, r#""Deleted key: key1""#);
+
+        assert_eq!(shard_manager.read().unwrap().get("key1"), None);
+    }
+
+    #[tokio::test]
+    async fn test_delete_key_non_existing() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .route("/api/{key}", web::delete().to(delete_key)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::delete()
+            .uri("/api/non_existent_key")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_namespaces_are_isolated() {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(new_shard_manager()))
+                .app_data(web::Data::new(Namespaces::default()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/{namespace}", web::post().to(add_namespaced_key_value))
+                .route(
+                    "/api/{namespace}/{key}",
+                    web::get().to(get_namespaced_value),
+                )
+                .route(
+                    "/api/{namespace}/{key}",
+                    web::delete().to(delete_namespaced_key),
+                ),
+        )
+        .await;
+
+        for namespace in ["users", "orders"] {
+            let kv = KeyValuePair {
+                key: "key1".to_string(),
+                value: format!("{} value", namespace),
+                ttl_seconds: None,
+                expires_at: None,
+                value_type: None,
+                encoding: None,
+            };
+            let req = actix_test::TestRequest::post()
+                .uri(&format!("/api/{}", namespace))
+                .set_json(&kv)
+                .to_request();
+            let body: AddedKeyValue = actix_test::call_and_read_body_json(&app, req).await;
+            assert!(body.created);
+        }
+
+        for namespace in ["users", "orders"] {
+            let req = actix_test::TestRequest::get()
+                .uri(&format!("/api/{}/key1", namespace))
+                .to_request();
+            let body: String = actix_test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body, format!("{} value", namespace));
+        }
+
+        let req = actix_test::TestRequest::delete()
+            .uri("/api/users/key1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/users/key1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let req = actix_test::TestRequest::get()
+            .uri("/api/orders/key1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = actix_test::TestRequest::get()
+            .uri("/api/unknown/key1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_namespaces_share_the_store_limits() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .read()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Namespaces::default()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config {
+                    max_total_keys: Some(3),
+                    max_total_bytes: Some(40),
+                    ..Config::default()
+                }))
+                .route("/api/{namespace}", web::post().to(add_namespaced_key_value)),
+        )
+        .await;
+
+        let post = |namespace: &str, key: &str, value: &str| {
+            actix_test::TestRequest::post()
+                .uri(&format!("/api/{}", namespace))
+                .set_json(serde_json::json!({"key": key, "value": value}))
+                .to_request()
+        };
+        for namespace in ["users", "orders"] {
+            let resp = actix_test::call_service(&app, post(namespace, "key1", "value1")).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        // The store and the two namespaces hold 3 keys, so a new namespace gets no room
+        let resp = actix_test::call_service(&app, post("carts", "key1", "value1")).await;
+        assert_eq!(resp.status(), StatusCode::INSUFFICIENT_STORAGE);
+        let body: ErrorBody = actix_test::read_body_json(resp).await;
+        assert_eq!(body.error.code, "insufficient_storage");
+
+        // Existing keys can still be updated, as long as the bytes fit
+        let resp = actix_test::call_service(&app, post("users", "key1", "value2")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = actix_test::call_service(&app, post("users", "key1", &"x".repeat(30))).await;
+        assert_eq!(resp.status(), StatusCode::INSUFFICIENT_STORAGE);
+    }
+
+    #[tokio::test]
+    async fn test_namespaces_rejected() {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(Namespaces::default()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/{namespace}", web::post().to(add_namespaced_key_value)),
+        )
+        .await;
+        let post = |namespace: &str| {
+            actix_test::TestRequest::post()
+                .uri(&format!("/api/{}", namespace))
+                .set_json(serde_json::json!({"key": "key1", "value": "value1"}))
+                .to_request()
+        };
+
+        // Shadowed by the fixed routes, so their keys could never be read back
+        for namespace in ["batch", "admin", "stats", "mget"] {
+            let resp = actix_test::call_service(&app, post(namespace)).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+        let resp = actix_test::call_service(&app, post("users")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Disabled for a persisted store, as namespaces would be lost on restart
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(Namespaces::disabled()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/{namespace}", web::post().to(add_namespaced_key_value)),
+        )
+        .await;
+        let resp = actix_test::call_service(&app, post("users")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_delete_many_keys() {
+        let shard_manager = new_shard_manager();
+        for i in 0..5 {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .route("/api/batch/delete", web::post().to(delete_many_keys)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/batch/delete")
+            .set_json(["key0", "key3", "non_existent_key", "other_missing_key"])
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let removed: usize = actix_test::read_body_json(resp).await;
+        assert_eq!(removed, 2);
+        assert_eq!(shard_manager.read().unwrap().len(), 3);
+        assert!(!shard_manager.read().unwrap().contains("key0"));
+        assert!(!shard_manager.read().unwrap().contains("key3"));
+    }
+
+    #[tokio::test]
+    async fn test_delete_prefix() {
+        let shard_manager = new_shard_manager();
+        for i in 0..5 {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("tmp:{}", i), format!("value{}", i))
+                .unwrap();
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .route("/api/prefix/{prefix}", web::delete().to(delete_prefix)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::delete()
+            .uri("/api/prefix/tmp:")
+            .to_request();
+        let removed: usize = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(removed, 5);
+
+        let shard_manager = shard_manager.read().unwrap();
+        assert_eq!(shard_manager.len(), 5);
+        for i in 0..5 {
+            assert!(!shard_manager.contains(&format!("tmp:{}", i)));
+            assert_eq!(
+                shard_manager.get(&format!("key{}", i)),
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clear_all() {
+        let shard_manager = new_shard_manager();
+        for i in 0..5 {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .route("/api/all", web::delete().to(clear_all))
+                .route("/api/stats/count", web::get().to(get_count)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::delete()
+            .uri("/api/all")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = actix_test::read_body(resp).await;
+        assert_eq!(body, r#""Deleted 5 keys""#);
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/stats/count")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        let body = actix_test::read_body(resp).await;
+        assert_eq!(body, "0");
+    }
+
+    #[tokio::test]
+    async fn test_drain_mode_rejects_writes_and_serves_reads() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api/{key}", web::get().to(get_value))
+                .route("/api", web::post().to(add_key_value))
+                .route("/api/admin/drain", web::post().to(enable_drain))
+                .route("/api/{key}", web::put().to(update_key_value))
+                .route("/api/all", web::delete().to(clear_all))
+                .route("/api/{key}", web::delete().to(delete_key)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/admin/drain")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let kv = KeyValuePair {
+            key: "key2".to_string(),
+            value: "value2".to_string(),
+            ttl_seconds: None,
+            expires_at: None,
+            value_type: None,
+            encoding: None,
+        };
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let req = actix_test::TestRequest::put()
+            .uri("/api/key1")
+            .set_json(&ValueUpdate {
+                value: "new".to_string(),
+            })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let req = actix_test::TestRequest::delete()
+            .uri("/api/key1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let req = actix_test::TestRequest::delete()
+            .uri("/api/all")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let req = actix_test::TestRequest::get().uri("/api/key1").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = actix_test::read_body(resp).await;
+        assert_eq!(body, r#""value1""#);
+        assert!(!shard_manager.read().unwrap().contains("key2"));
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(HealthState {
+                    shard_count: AtomicUsize::new(SHARD_COUNT),
+                }))
+                .route("/health", web::get().to(health)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get().uri("/health").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: HealthStatus = actix_test::read_body_json(resp).await;
+        assert_eq!(body.status, "ok");
+        assert_eq!(body.shards, SHARD_COUNT);
+    }
+
+    #[tokio::test]
+    async fn test_deep_health() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .route("/health/deep", web::get().to(deep_health)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/health/deep")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: DeepHealthStatus = actix_test::read_body_json(resp).await;
+        assert_eq!(body.status, "ok");
+        assert_eq!(body.shards.len(), SHARD_COUNT);
+        for (index, shard) in body.shards.iter().enumerate() {
+            assert_eq!(shard.shard, index);
+            assert!(shard.ok);
+        }
+        // The probe leaves nothing behind
+        assert_eq!(shard_manager.read().unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_ring() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .route("/api/admin/ring", web::get().to(get_ring)),
+        )
+        .await;
 
-use actix_web::{
-    dev::Server, http::StatusCode, web, App, HttpResponse, HttpServer, Responder, Result,
-};
-use serde::{Deserialize, Serialize};
-use shard_manager::ShardManager;
-use std::net::TcpListener;
-use std::sync::{Arc, Mutex};
+        let req = actix_test::TestRequest::get()
+            .uri("/api/admin/ring")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let ring: Vec<RingNode> = actix_test::read_body_json(resp).await;
+        assert!(!ring.is_empty());
+        assert!(ring
+            .windows(2)
+            .all(|nodes| nodes[0].position < nodes[1].position));
+        for shard_index in 0..SHARD_COUNT {
+            assert!(
+                ring.iter().any(|node| node.shard == shard_index),
+                "{}",
+                shard_index
+            );
+        }
+        assert!(ring.iter().all(|node| node.shard < SHARD_COUNT));
+    }
 
-const SHARD_COUNT: usize = 4;
+    #[tokio::test]
+    async fn test_get_audit_log() {
+        let shard_manager = new_shard_manager();
+        shard_manager.write().unwrap().enable_audit(10);
 
-#[derive(Deserialize, Serialize)]
-struct KeyValuePair {
-    key: String,
-    value: String,
-}
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .route("/api", web::post().to(add_key_value))
+                .route("/api/admin/audit", web::get().to(get_audit_log))
+                .route("/api/{key}", web::delete().to(delete_key)),
+        )
+        .await;
 
-/// Gets a value using the provided key
-///
-/// # Arguments
-///
-/// * `key` - A string slice that holds the key.
-///
-/// # Returns
-///
-/// The value with OK code if the key exists, None with NotFound code if it doesn't
-async fn get_value(
-    path: web::Path<String>,
-    shard_manager: web::Data<Arc<Mutex<ShardManager>>>,
-) -> impl Responder {
-    let key = path.into_inner();
-    let shard_manager = shard_manager.lock().unwrap(); // Lock the mutex
-    let value = shard_manager.get(&key);
+        for key in ["key1", "key2"] {
+            let req = actix_test::TestRequest::post()
+                .uri("/api")
+                .set_json(&KeyValuePair {
+                    key: key.to_string(),
+                    value: "value".to_string(),
+                    ttl_seconds: None,
+                    expires_at: None,
+                    value_type: None,
+                    encoding: None,
+                })
+                .to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let req = actix_test::TestRequest::delete()
+            .uri("/api/key1")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
 
-    match value {
-        Some(value) => HttpResponse::Ok().json(value),
-        None => HttpResponse::NotFound().finish(),
+        let req = actix_test::TestRequest::get()
+            .uri("/api/admin/audit")
+            .to_request();
+        let body: Vec<AuditEntry> = actix_test::call_and_read_body_json(&app, req).await;
+        let ops: Vec<(AuditOp, Option<&str>)> = body
+            .iter()
+            .map(|entry| (entry.op, entry.key.as_deref()))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                (AuditOp::Set, Some("key1")),
+                (AuditOp::Set, Some("key2")),
+                (AuditOp::Delete, Some("key1")),
+            ]
+        );
     }
-}
 
-/// Adds a key-value pair to the shard manager and returns the shard index.
-///
-/// # Arguments
-///
-/// * `key` - A string slice that holds the key.
-/// * `value` - A string slice that holds the value.
-///
-/// # Returns
-///
-/// The shard index where the key-value pair was stored.
-///
-async fn add_key_value(
-    item: web::Json<KeyValuePair>,
-    shard_manager: web::Data<Arc<Mutex<ShardManager>>>,
-) -> Result<HttpResponse> {
-    let key = &item.key;
-    let value = &item.value;
+    #[tokio::test]
+    async fn test_reshard() {
+        let shard_manager = Arc::new(RwLock::new(ShardManager::new(4)));
+        for i in 0..100 {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
 
-    let mut locked_shard_manager = shard_manager.lock().unwrap();
-    let shard_index = locked_shard_manager.set(key.clone(), value.clone());
-    Ok(HttpResponse::Ok().json(format!(
-        "Added key: {}, with value: {} to shard: {}",
-        key, value, shard_index
-    )))
-}
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(HealthState {
+                    shard_count: AtomicUsize::new(4),
+                }))
+                .app_data(web::Data::new(ReshardProgress::default()))
+                .route("/health", web::get().to(health))
+                .route("/api/admin/reshard", web::post().to(reshard))
+                .route("/api/admin/verify", web::get().to(verify_placement))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
 
-/// Delete a key from the hashmap
-///
-/// # Arguments
-///
-/// * `key` - A string slice that holds the key.
-///
-/// # Returns
-///
-/// OK code
-async fn delete_key(
-    path: web::Path<String>,
-    shard_manager: web::Data<Arc<Mutex<ShardManager>>>,
-) -> impl Responder {
-    let key = path.into_inner();
+        let req = actix_test::TestRequest::post()
+            .uri("/api/admin/reshard")
+            .set_json(Reshard { shard_count: 8 })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(shard_manager.read().unwrap().shard_count(), 8);
 
-    let mut locked_shard_manager = shard_manager.lock().unwrap();
-    locked_shard_manager.delete(&key);
-    // TODO: Delete the key from the sharded HashMap
-    HttpResponse::Ok().json(format!("Deleted key: {}", key))
-}
+        for i in 0..100 {
+            let req = actix_test::TestRequest::get()
+                .uri(&format!("/api/key{}", i))
+                .to_request();
+            let body: String = actix_test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body, format!("value{}", i));
+        }
 
-pub fn run(listener: TcpListener) -> std::io::Result<Server> {
-    let shard_manager = Arc::new(Mutex::new(ShardManager::new(SHARD_COUNT)));
+        let req = actix_test::TestRequest::get()
+            .uri("/api/admin/verify")
+            .to_request();
+        let body: Vec<String> = actix_test::call_and_read_body_json(&app, req).await;
+        assert!(body.is_empty());
 
-    let server = HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(shard_manager.clone()))
-            .route("/api/{key}", web::get().to(get_value))
-            .route("/api", web::post().to(add_key_value))
-            .route("/api/{key}", web::delete().to(delete_key))
-    })
-    .listen(listener)?
-    .run();
+        let req = actix_test::TestRequest::get().uri("/health").to_request();
+        let body: HealthStatus = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.shards, 8);
 
-    Ok(server)
-}
+        let req = actix_test::TestRequest::post()
+            .uri("/api/admin/reshard")
+            .set_json(Reshard { shard_count: 0 })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(shard_manager.read().unwrap().shard_count(), 8);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use actix_web::{test, web, App};
-    use tokio;
+    #[tokio::test]
+    async fn test_reshard_in_background() {
+        let shard_manager = Arc::new(RwLock::new(ShardManager::new(4)));
+        for i in 0..1000 {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(HealthState {
+                    shard_count: AtomicUsize::new(4),
+                }))
+                .app_data(web::Data::new(ReshardProgress::default()))
+                .route("/api/admin/reshard", web::post().to(reshard))
+                .route(
+                    "/api/admin/reshard/status",
+                    web::get().to(get_reshard_status),
+                )
+                .route("/api/admin/verify", web::get().to(verify_placement))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/admin/reshard/status")
+            .to_request();
+        let status: ReshardStatus = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(status, ReshardStatus::complete(4, 4));
+
+        let req = actix_test::TestRequest::post()
+            .uri("/api/admin/reshard?background=true")
+            .set_json(Reshard { shard_count: 8 })
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let status: ReshardStatus = actix_test::read_body_json(resp).await;
+        assert_eq!((status.from, status.to), (4, 8));
+        assert_eq!(status.total_keys, 1000);
+
+        let mut status = status;
+        for _ in 0..1000 {
+            let req = actix_test::TestRequest::get()
+                .uri("/api/admin/reshard/status")
+                .to_request();
+            status = actix_test::call_and_read_body_json(&app, req).await;
+            if !status.running {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!status.running);
+        assert_eq!(status.checked_keys, 1000);
+        // Only the keys of the segments the new shards own move
+        assert!(status.moved_keys > 0 && status.moved_keys < 1000);
+        assert_eq!(status.percent, 100);
+        assert_eq!(shard_manager.read().unwrap().shard_count(), 8);
+
+        for i in 0..1000 {
+            let req = actix_test::TestRequest::get()
+                .uri(&format!("/api/key{}", i))
+                .to_request();
+            let body: String = actix_test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body, format!("value{}", i));
+        }
+        let req = actix_test::TestRequest::get()
+            .uri("/api/admin/verify")
+            .to_request();
+        let body: Vec<String> = actix_test::call_and_read_body_json(&app, req).await;
+        assert!(body.is_empty());
+    }
 
     #[tokio::test]
-    async fn test_get_value_existing() {
-        let shard_manager = Arc::new(Mutex::new(ShardManager::new(SHARD_COUNT)));
-        let mut locked_shard_manager = shard_manager.lock().unwrap();
-        locked_shard_manager.set("key1".to_string(), "value1".to_string());
-        drop(locked_shard_manager); // Release the lock
+    async fn test_background_reshard_stops_when_draining() {
+        let shard_manager = Arc::new(RwLock::new(ShardManager::new(4)));
+        for i in 0..1000 {
+            shard_manager
+                .write()
+                .unwrap()
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        let reshard_progress = web::Data::new(ReshardProgress::default());
+        reshard_progress
+            .start(4, 8, || shard_manager.write().unwrap().start_migration(8))
+            .unwrap();
+        let drain_mode = web::Data::new(DrainMode::default());
+        drain_mode.enable();
+
+        spawn_background_reshard(shard_manager.clone(), reshard_progress.clone(), drain_mode);
+        for _ in 0..100 {
+            if !reshard_progress.is_running() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let status = reshard_progress.lock().unwrap();
+        assert!(!status.running);
+        assert!(status.percent < 100);
+
+        // The keys it didn't reach are still found, and moved once used
+        let shard_manager = shard_manager.read().unwrap();
+        assert!(shard_manager.is_migrating());
+        for i in 0..1000 {
+            assert_eq!(
+                shard_manager.get(&format!("key{}", i)),
+                Some(format!("value{}", i))
+            );
+        }
+        assert!(shard_manager.verify_placement().is_empty());
+    }
+
+    #[test]
+    fn test_reshard_status_percent() {
+        let mut status = ReshardStatus::new(4, 8, 200);
+        assert_eq!(status.percent, 0);
+        status.advance(MigratedKeys {
+            checked: 100,
+            moved: 30,
+        });
+        assert_eq!((status.percent, status.moved_keys), (50, 30));
+        status.advance(MigratedKeys {
+            checked: 100,
+            moved: 20,
+        });
+        assert_eq!((status.percent, status.moved_keys), (99, 50));
+        assert!(status.running);
+
+        let done = ReshardStatus::complete(8, 2);
+        assert_eq!(done.percent, 100);
+        assert!(!done.running);
+    }
+
+    #[tokio::test]
+    async fn test_ttl_sweeper_removes_unread_expired_keys() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set_with_ttl(
+                "key1".to_string(),
+                "value1".to_string(),
+                Duration::from_millis(10),
+            )
+            .unwrap();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key2".to_string(), "value2".to_string())
+            .unwrap();
+        assert_eq!(shard_manager.read().unwrap().len(), 2);
+
+        let sweeper = spawn_ttl_sweeper(shard_manager.clone(), Duration::from_millis(20));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        sweeper.abort();
+
+        // The sweeper already dropped the expired key, so there is nothing left to sweep
+        assert_eq!(shard_manager.write().unwrap().sweep_expired(), 0);
+        assert_eq!(shard_manager.read().unwrap().len(), 1);
+    }
 
-        let app = test::init_service(
+    #[tokio::test]
+    async fn test_api_key_valid() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(ApiKey(Some("secret".to_string()))))
+                .wrap(from_fn(require_api_key))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/key1")
+            .insert_header((API_KEY_HEADER, "secret"))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = actix_test::read_body(resp).await;
+        assert_eq!(body, r#""value1""#);
+    }
+
+    #[tokio::test]
+    async fn test_api_key_invalid() {
+        let shard_manager = new_shard_manager();
+
+        let app = actix_test::init_service(
             App::new()
                 .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(ApiKey(Some("secret".to_string()))))
+                .app_data(web::Data::new(HealthState {
+                    shard_count: AtomicUsize::new(SHARD_COUNT),
+                }))
+                .wrap(from_fn(require_api_key))
+                .route("/health", web::get().to(health))
                 .route("/api/{key}", web::get().to(get_value)),
         )
         .await;
 
-        let req = test::TestRequest::get().uri("/api/key1").to_request();
-        let resp = test::call_service(&app, req).await;
+        let req = actix_test::TestRequest::get()
+            .uri("/api/key1")
+            .insert_header((API_KEY_HEADER, "wrong"))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = actix_test::TestRequest::get().uri("/api/key1").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
 
+        // Probes don't need the key
+        let req = actix_test::TestRequest::get().uri("/health").to_request();
+        let resp = actix_test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
+    }
 
-        let body = test::read_body(resp).await;
-        assert_eq!(body, r#""value1""#);
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(HealthState {
+                    shard_count: AtomicUsize::new(SHARD_COUNT),
+                }))
+                .app_data(web::Data::new(RateLimit(Some(RateLimiter::new(
+                    3,
+                    Duration::from_secs(60),
+                )))))
+                .wrap(from_fn(rate_limit))
+                .route("/health", web::get().to(health)),
+        )
+        .await;
+
+        let peer: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let mut statuses = Vec::new();
+        for _ in 0..5 {
+            let req = actix_test::TestRequest::get()
+                .uri("/health")
+                .peer_addr(peer)
+                .to_request();
+            statuses.push(actix_test::call_service(&app, req).await.status());
+        }
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::TOO_MANY_REQUESTS,
+            ]
+        );
+
+        // Other clients have their own budget
+        let req = actix_test::TestRequest::get()
+            .uri("/health")
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
     }
 
     #[tokio::test]
-    async fn test_get_value_non_existing() {
-        let shard_manager = Arc::new(Mutex::new(ShardManager::new(SHARD_COUNT)));
+    async fn test_cors() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
 
-        let app = test::init_service(
+        let app = actix_test::init_service(
             App::new()
                 .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(ApiKey(Some("secret".to_string()))))
+                .app_data(web::Data::new(CorsOrigins(vec![
+                    "https://app.example.com".to_string()
+                ])))
+                .wrap(from_fn(require_api_key))
+                .wrap(from_fn(cors))
                 .route("/api/{key}", web::get().to(get_value)),
         )
         .await;
 
-        let req = test::TestRequest::get()
-            .uri("/api/non_existent_key")
+        let req = actix_test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri("/api/key1")
+            .insert_header((header::ORIGIN, "https://app.example.com"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "PATCH"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, API_KEY_HEADER))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let headers = resp.headers();
+        assert_eq!(
+            headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "https://app.example.com"
+        );
+        let allowed_methods = headers
+            .get(header::ACCESS_CONTROL_ALLOW_METHODS)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(allowed_methods.contains("GET"));
+        assert!(allowed_methods.contains("PATCH"));
+        let allowed_headers = headers
+            .get(header::ACCESS_CONTROL_ALLOW_HEADERS)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        for name in [
+            API_KEY_HEADER,
+            "If-None-Match",
+            SHARD_OVERRIDE_HEADER,
+            VALUE_ENCODING_HEADER,
+        ] {
+            assert!(allowed_headers.contains(name));
+        }
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/key1")
+            .insert_header((header::ORIGIN, "https://app.example.com"))
+            .insert_header((API_KEY_HEADER, "secret"))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "https://app.example.com"
+        );
+        let exposed = resp
+            .headers()
+            .get(header::ACCESS_CONTROL_EXPOSE_HEADERS)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(exposed.contains("ETag"));
+        assert!(exposed.contains(VERSION_HEADER));
+
+        let req = actix_test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri("/api/key1")
+            .insert_header((header::ORIGIN, "https://evil.example.com"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "GET"))
             .to_request();
-        let resp = test::call_service(&app, req).await;
+        let resp = actix_test::call_service(&app, req).await;
+        assert!(!resp
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn test_api_key_disabled() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(ApiKey(None)))
+                .wrap(from_fn(require_api_key))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get().uri("/api/key1").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_run_with_addr_on_ephemeral_port() {
+        let (server, address) = run_with_addr("127.0.0.1:0", &Config::default()).unwrap();
+        assert_ne!(address.port(), 0);
+
+        let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(serve_until_shutdown(server, async {
+            let _ = shutdown_receiver.await;
+        }));
+
+        let response = reqwest::get(format!("http://{}/health", address))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        shutdown_sender.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_with_workers_from_env() {
+        std::env::set_var(WORKERS_ENV_VAR, "0");
+        assert_eq!(workers_from_env(), None);
+        std::env::set_var(WORKERS_ENV_VAR, "many");
+        assert_eq!(workers_from_env(), None);
+
+        std::env::set_var(WORKERS_ENV_VAR, "2");
+        assert_eq!(workers_from_env(), Some(2));
+        let (server, address) = run_with_addr("127.0.0.1:0", &Config::default()).unwrap();
+        std::env::remove_var(WORKERS_ENV_VAR);
+        assert_eq!(workers_from_env(), None);
+
+        let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(serve_until_shutdown(server, async {
+            let _ = shutdown_receiver.await;
+        }));
+
+        let response = reqwest::get(format!("http://{}/health", address))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        shutdown_sender.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serve_until_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        let server = run_with_shard_manager(
+            listener,
+            shard_manager.clone(),
+            &StoreFiles::default(),
+            &Config::default(),
+        )
+        .unwrap();
+
+        let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(serve_until_shutdown(server, async {
+            let _ = shutdown_receiver.await;
+        }));
+
+        let body = reqwest::get(format!("http://{}/api/key1", address))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, r#""value1""#);
+
+        shutdown_sender.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(10), serving)
+            .await
+            .expect("the server didn't shut down")
+            .unwrap()
+            .unwrap();
+        assert!(reqwest::get(format!("http://{}/health", address))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_many_simultaneous_readers() {
+        let shard_manager = new_shard_manager();
+        shard_manager
+            .write()
+            .unwrap()
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        let readers = 8;
+        let barrier = Arc::new(Barrier::new(readers));
+        let handles: Vec<_> = (0..readers)
+            .map(|_| {
+                let shard_manager = shard_manager.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let locked_shard_manager = shard_manager.read().unwrap();
+                    // Every reader waits here while holding the lock, which only completes if
+                    // they can all hold it at the same time
+                    barrier.wait();
+                    locked_shard_manager.get("key1")
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), Some("value1".to_string()));
+        }
+    }
+
+    /// Logger keeping the lines logged by the tests.
+    struct CapturingLogger(std::sync::Mutex<Vec<String>>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURING_LOGGER: CapturingLogger = CapturingLogger(std::sync::Mutex::new(Vec::new()));
 
+    #[tokio::test]
+    async fn test_log_request() {
+        let _ = log::set_logger(&CAPTURING_LOGGER);
+        log::set_max_level(log::LevelFilter::Info);
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(new_shard_manager()))
+                .app_data(web::Data::new(Metrics::default()))
+                .wrap(from_fn(log_request))
+                .route("/api/{key}", web::get().to(get_value)),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/api/logged_key?default=x")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = actix_test::TestRequest::get()
+            .uri("/api/missing_logged_key")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let lines = CAPTURING_LOGGER.0.lock().unwrap();
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("GET /api/logged_key 200 ") && line.ends_with("ms")));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("GET /api/missing_logged_key 404 ")));
     }
 
     #[tokio::test]
-    async fn test_add_key_value() {
-        let shard_manager = Arc::new(Mutex::new(ShardManager::new(SHARD_COUNT)));
+    async fn test_json_body_limit() {
+        let shard_manager = new_shard_manager();
+        let metrics = web::Data::new(Metrics::default());
 
-        let app = test::init_service(
+        let app = actix_test::init_service(
             App::new()
                 .app_data(web::Data::new(shard_manager.clone()))
+                .app_data(metrics.clone())
+                .app_data(web::Data::new(DrainMode::default()))
+                .app_data(web::Data::new(Config::default()))
+                .app_data(json_config(64))
                 .route("/api", web::post().to(add_key_value)),
         )
         .await;
 
         let kv = KeyValuePair {
-            key: "k".to_string(),
-            value: "v".to_string(),
+            key: "key1".to_string(),
+            value: "x".repeat(100),
+            ttl_seconds: None,
+            expires_at: None,
+            value_type: None,
+            encoding: None,
         };
+        let req = actix_test::TestRequest::post()
+            .uri("/api")
+            .set_json(&kv)
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: ErrorBody = actix_test::read_body_json(resp).await;
+        assert_eq!(body.error.code, "payload_too_large");
+        // Rejected before the handler ran
+        assert_eq!(metrics.snapshot().sets, 0);
+        assert!(shard_manager.read().unwrap().is_empty());
 
-        // Calculate the expected shard index
-        let expected_shard_index = {
-            let mut locked_shard_manager = shard_manager.lock().unwrap();
-            locked_shard_manager.get_shard_index(&kv.key)
+        let kv = KeyValuePair {
+            value: "x".repeat(10),
+            ..kv
         };
-
-        let req = test::TestRequest::post()
+        let req = actix_test::TestRequest::post()
             .uri("/api")
             .set_json(&kv)
             .to_request();
-        let resp = test::call_service(&app, req).await;
-
+        let resp = actix_test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(metrics.snapshot().sets, 1);
+    }
+
+    #[test]
+    fn test_max_body_bytes_from_env() {
+        std::env::set_var(MAX_BODY_BYTES_ENV_VAR, "1024");
+        assert_eq!(max_body_bytes_from_env(), 1024);
+
+        std::env::set_var(MAX_BODY_BYTES_ENV_VAR, "0");
+        assert_eq!(max_body_bytes_from_env(), MAX_BODY_BYTES);
+
+        std::env::remove_var(MAX_BODY_BYTES_ENV_VAR);
+        assert_eq!(max_body_bytes_from_env(), MAX_BODY_BYTES);
+    }
+
+    #[test]
+    fn test_log_level_from_env() {
+        std::env::set_var(LOG_LEVEL_ENV_VAR, "debug");
+        assert_eq!(log_level_from_env(), log::LevelFilter::Debug);
+
+        std::env::set_var(LOG_LEVEL_ENV_VAR, "loud");
+        assert_eq!(log_level_from_env(), log::LevelFilter::Info);
+
+        std::env::remove_var(LOG_LEVEL_ENV_VAR);
+        assert_eq!(log_level_from_env(), log::LevelFilter::Info);
+    }
+
+    #[test]
+    fn test_parse_shard_count() {
+        let shard_manager: ShardManager = ShardManager::new(parse_shard_count(Some("8")));
+        assert_eq!(shard_manager.shard_count(), 8);
+
+        assert_eq!(parse_shard_count(Some(" 8 ")), 8);
+        assert_eq!(parse_shard_count(Some("0")), SHARD_COUNT);
+        assert_eq!(parse_shard_count(Some("not_a_number")), SHARD_COUNT);
+        assert_eq!(parse_shard_count(None), SHARD_COUNT);
+    }
+
+    #[test]
+    fn test_load_shard_manager_from_data_file() {
+        let path = std::env::temp_dir().join(format!(
+            "hashmaprs_load_shard_manager_{}.json",
+            std::process::id()
+        ));
+        let saved = ShardManager::new(SHARD_COUNT);
+        saved.set("key1".to_string(), "value1".to_string()).unwrap();
+        saved.save_to_file(&path).unwrap();
+
+        let files = StoreFiles {
+            data_file: Some(path.clone()),
+            ..StoreFiles::default()
+        };
+        let shard_manager = load_shard_manager(&Config::default(), &files).unwrap();
+        std::fs::remove_file(&path).unwrap();
 
-        let body = test::read_body(resp).await;
-        let expected_response = format!(
-            r#""Added key: k, with value: v to shard: {}""#,
-            expected_shard_index
+        assert_eq!(
+            shard_manager.read().unwrap().get("key1"),
+            Some("value1".to_string())
         );
-        assert_eq!(body, expected_response);
     }
 
-    #[tokio::test]
-    async fn test_delete_key() {
-        let shard_manager = Arc::new(Mutex::new(ShardManager::new(SHARD_COUNT)));
-        let mut locked_shard_manager = shard_manager.lock().unwrap();
-        locked_shard_manager.set("key1".to_string(), "value1".to_string());
-        drop(locked_shard_manager); // Release the lock
+    #[test]
+    fn test_load_shard_manager_applies_config_to_data_file() {
+        let path = std::env::temp_dir().join(format!(
+            "hashmaprs_load_shard_manager_config_{}.json",
+            std::process::id()
+        ));
+        let saved = ShardManager::new(2);
+        for i in 0..50 {
+            saved
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        saved.save_to_file(&path).unwrap();
 
-        let app = test::init_service(
-            App::new()
-                .app_data(web::Data::new(shard_manager.clone()))
-                .route("/api/{key}", web::delete().to(delete_key)),
-        )
-        .await;
+        let config = Config {
+            shard_count: 3,
+            max_entries_per_shard: Some(2),
+            hash_algo: HashAlgo::Fnv,
+            ..Config::default()
+        };
+        let files = StoreFiles {
+            data_file: Some(path.clone()),
+            ..StoreFiles::default()
+        };
+        let shard_manager = load_shard_manager(&config, &files).unwrap();
+        std::fs::remove_file(&path).unwrap();
 
-        let req = test::TestRequest::delete().uri("/api/key1").to_request();
+        let shard_manager = shard_manager.read().unwrap();
+        assert_eq!(shard_manager.shard_count(), 3);
+        assert_eq!(shard_manager.hash_algo(), HashAlgo::Fnv);
+        assert!(shard_manager.shard_sizes().iter().all(|&size| size <= 2));
 
-        let resp = test::call_service(&app, req).await;
+        // The cap still holds for the writes served after the restart
+        for i in 50..100 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        assert!(shard_manager.shard_sizes().iter().all(|&size| size <= 2));
+    }
 
-        assert_eq!(resp.status(), StatusCode::OK);
+    #[test]
+    fn test_load_shard_manager_from_seed_file() {
+        let path =
+            std::env::temp_dir().join(format!("hashmaprs_seed_file_{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"key1": "value1", "key2": "value2"}"#).unwrap();
 
-        let body = test::read_body(resp).await;
-        assert_eq!(body, r#""Deleted key: key1""#);
+        let files = StoreFiles {
+            seed_file: Some(path.clone()),
+            ..StoreFiles::default()
+        };
+        let shard_manager = load_shard_manager(&Config::default(), &files).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let shard_manager = shard_manager.read().unwrap();
+        assert_eq!(shard_manager.len(), 2);
+        assert_eq!(shard_manager.get("key1"), Some("value1".to_string()));
+        assert_eq!(shard_manager.get("key2"), Some("value2".to_string()));
+    }
+
+    #[test]
+    fn test_load_shard_manager_replays_wal() {
+        let path =
+            std::env::temp_dir().join(format!("hashmaprs_load_wal_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = Config {
+            wal_batch_size: 50,
+            ..Config::default()
+        };
+
+        let files = StoreFiles {
+            wal_file: Some(path.clone()),
+            ..StoreFiles::default()
+        };
+        let shard_manager = load_shard_manager(&config, &files).unwrap();
+        for i in 0..120 {
+            shard_manager
+                .read()
+                .unwrap()
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        shard_manager.read().unwrap().flush_wal().unwrap();
+
+        let recovered = load_shard_manager(&config, &files).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(recovered.read().unwrap().len(), 120);
+        assert_eq!(
+            recovered.read().unwrap().get("key119"),
+            Some("value119".to_string())
+        );
+    }
+
+    #[test]
+    fn test_load_config_from_file() {
+        let path =
+            std::env::temp_dir().join(format!("hashmaprs_load_config_{}.toml", std::process::id()));
+        std::fs::write(&path, "shard_count = 6\nmax_entries_per_shard = 10\n").unwrap();
+
+        let config = load_config_from(Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.max_entries_per_shard, Some(10));
+        assert_eq!(config.bind_address, Config::default().bind_address);
+
+        let shard_manager = load_shard_manager(&config, &StoreFiles::default()).unwrap();
+        assert_eq!(
+            shard_manager.read().unwrap().shard_count(),
+            config.shard_count
+        );
     }
 }
@@ -1,10 +1,52 @@
-use hashmaprs::run;
+use hashmaprs::{
+    init_logger, load_config, load_shard_manager, run_with_shard_manager, serve_until_shutdown,
+    StoreFiles,
+};
 use std::net::TcpListener;
+use std::sync::PoisonError;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:8080")?;
-    println!("Running server on http://127.0.0.1:8080");
+    init_logger();
+    let config = load_config()?;
+    let listener = TcpListener::bind(&config.bind_address)?;
+    log::info!("Running server on http://{}", config.bind_address);
 
-    run(listener)?.await
+    let files = StoreFiles::from_env();
+    let shard_manager = load_shard_manager(&config, &files)?;
+    let server = run_with_shard_manager(listener, shard_manager.clone(), &files, &config)?;
+
+    serve_until_shutdown(server, wait_for_shutdown_signal()).await?;
+    shard_manager
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .flush_wal()?;
+
+    if let Some(path) = files.data_file {
+        shard_manager
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .snapshot(&path)?;
+        log::info!("Saved store to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Resolves when the process receives Ctrl-C, or SIGTERM on Unix.
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
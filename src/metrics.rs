@@ -0,0 +1,197 @@
+// metrics.rs
+
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts of the operations served since startup, shared by every worker.
+#[derive(Default)]
+pub struct Metrics {
+    gets: AtomicU64,
+    sets: AtomicU64,
+    deletes: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Point-in-time copy of the counters, as served by the metrics endpoints.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct MetricsSnapshot {
+    pub gets: u64,
+    pub sets: u64,
+    pub deletes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// Share of the lookups that found their key, 0 before the first lookup.
+    pub hit_ratio: f64,
+}
+
+impl Metrics {
+    /// Records `hits + misses` key lookups, `hits` of which found their key.
+    pub fn record_gets(&self, hits: u64, misses: u64) {
+        self.gets.fetch_add(hits + misses, Ordering::Relaxed);
+        self.hits.fetch_add(hits, Ordering::Relaxed);
+        self.misses.fetch_add(misses, Ordering::Relaxed);
+    }
+
+    pub fn record_get(&self, hit: bool) {
+        if hit {
+            self.record_gets(1, 0);
+        } else {
+            self.record_gets(0, 1);
+        }
+    }
+
+    pub fn record_sets(&self, count: u64) {
+        self.sets.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn record_deletes(&self, count: u64) {
+        self.deletes.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            gets: self.gets.load(Ordering::Relaxed),
+            sets: self.sets.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            hits,
+            misses,
+            hit_ratio: hit_ratio(hits, misses),
+        }
+    }
+}
+
+fn hit_ratio(hits: u64, misses: u64) -> f64 {
+    if hits + misses == 0 {
+        return 0.0;
+    }
+    hits as f64 / (hits + misses) as f64
+}
+
+impl MetricsSnapshot {
+    /// Renders the counters, along with the number of entries of each shard and how often its
+    /// write lock had to wait, in the Prometheus text exposition format.
+    pub fn to_prometheus(&self, shard_sizes: &[usize], lock_contention: &[u64]) -> String {
+        let counters = [
+            ("gets", "Key lookups served.", self.gets),
+            ("sets", "Key writes served.", self.sets),
+            ("deletes", "Key deletions served.", self.deletes),
+            ("hits", "Key lookups that found their key.", self.hits),
+            (
+                "misses",
+                "Key lookups that didn't find their key.",
+                self.misses,
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, help, value) in counters {
+            writeln!(output, "# HELP hashmaprs_{}_total {}", name, help).unwrap();
+            writeln!(output, "# TYPE hashmaprs_{}_total counter", name).unwrap();
+            writeln!(output, "hashmaprs_{}_total {}", name, value).unwrap();
+        }
+
+        writeln!(
+            output,
+            "# HELP hashmaprs_shard_entries Number of entries held by each shard."
+        )
+        .unwrap();
+        writeln!(output, "# TYPE hashmaprs_shard_entries gauge").unwrap();
+        for (shard, entries) in shard_sizes.iter().enumerate() {
+            writeln!(
+                output,
+                "hashmaprs_shard_entries{{shard=\"{}\"}} {}",
+                shard, entries
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            output,
+            "# HELP hashmaprs_shard_lock_contention_total Write lock waits of each shard."
+        )
+        .unwrap();
+        writeln!(
+            output,
+            "# TYPE hashmaprs_shard_lock_contention_total counter"
+        )
+        .unwrap();
+        for (shard, waits) in lock_contention.iter().enumerate() {
+            writeln!(
+                output,
+                "hashmaprs_shard_lock_contention_total{{shard=\"{}\"}} {}",
+                shard, waits
+            )
+            .unwrap();
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_record() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.snapshot(), MetricsSnapshot::default());
+
+        metrics.record_get(true);
+        metrics.record_get(false);
+        metrics.record_gets(3, 2);
+        metrics.record_sets(4);
+        metrics.record_deletes(1);
+
+        assert_eq!(
+            metrics.snapshot(),
+            MetricsSnapshot {
+                gets: 7,
+                sets: 4,
+                deletes: 1,
+                hits: 4,
+                misses: 3,
+                hit_ratio: 4.0 / 7.0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_metrics_hit_ratio() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.snapshot().hit_ratio, 0.0);
+
+        metrics.record_gets(3, 0);
+        assert!((metrics.snapshot().hit_ratio - 1.0).abs() < 1e-9);
+
+        metrics.record_get(false);
+        metrics.record_gets(2, 4);
+        assert!((metrics.snapshot().hit_ratio - 0.5).abs() < 1e-9);
+
+        metrics.record_get(false);
+        assert!((metrics.snapshot().hit_ratio - 5.0 / 11.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_metrics_to_prometheus() {
+        let snapshot = MetricsSnapshot {
+            gets: 42,
+            hits: 40,
+            misses: 2,
+            ..MetricsSnapshot::default()
+        };
+        let output = snapshot.to_prometheus(&[10, 0], &[3, 0]);
+
+        assert!(output.contains("# TYPE hashmaprs_gets_total counter\nhashmaprs_gets_total 42\n"));
+        assert!(output.contains("hashmaprs_hits_total 40\n"));
+        assert!(output.contains("hashmaprs_sets_total 0\n"));
+        assert!(output.contains("# TYPE hashmaprs_shard_entries gauge\n"));
+        assert!(output.contains("hashmaprs_shard_entries{shard=\"0\"} 10\n"));
+        assert!(output.contains("hashmaprs_shard_entries{shard=\"1\"} 0\n"));
+        assert!(output.contains("# TYPE hashmaprs_shard_lock_contention_total counter\n"));
+        assert!(output.contains("hashmaprs_shard_lock_contention_total{shard=\"0\"} 3\n"));
+    }
+}
@@ -0,0 +1,122 @@
+// namespaces.rs
+
+use crate::shard_manager::ShardManager;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+
+/// Isolated keyspaces, each backed by its own shard manager. A namespace is created the first
+/// time a key is written to it.
+#[derive(Default)]
+pub struct Namespaces {
+    managers: RwLock<HashMap<String, Arc<ShardManager>>>,
+    /// Held by a write from the check of the shared limits until it's applied.
+    writes: Mutex<()>,
+    disabled: bool,
+}
+
+impl Namespaces {
+    /// Namespaces that can't be created, for a store persisted to disk: they live in memory
+    /// only, so their keys would be lost on restart.
+    pub fn disabled() -> Self {
+        Namespaces {
+            disabled: true,
+            ..Namespaces::default()
+        }
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    /// Serializes the writes to namespaces, so two writes can't both see room for their key
+    /// under the limits shared with the store and overshoot them together. To be held from the
+    /// check until the write is applied.
+    pub fn lock_writes(&self) -> MutexGuard<'_, ()> {
+        self.writes.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Shard manager of the namespace, None if nothing was ever written to it.
+    pub fn get(&self, namespace: &str) -> Option<Arc<ShardManager>> {
+        self.managers
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(namespace)
+            .cloned()
+    }
+
+    /// Shard manager of the namespace, built with `create` if the namespace doesn't exist yet.
+    pub fn get_or_create<F: FnOnce() -> ShardManager>(
+        &self,
+        namespace: &str,
+        create: F,
+    ) -> Arc<ShardManager> {
+        if let Some(shard_manager) = self.get(namespace) {
+            return shard_manager;
+        }
+
+        // Another request may have created it between the two locks
+        self.managers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(namespace.to_string())
+            .or_insert_with(|| Arc::new(create()))
+            .clone()
+    }
+
+    /// Number of keys held across every namespace.
+    pub fn total_keys(&self) -> usize {
+        self.managers
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .map(|shard_manager| shard_manager.len())
+            .sum()
+    }
+
+    /// Summed size of the keys and values held across every namespace, in bytes.
+    pub fn total_bytes(&self) -> usize {
+        self.managers
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .map(|shard_manager| shard_manager.total_bytes())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_namespaces_are_isolated() {
+        let namespaces = Namespaces::default();
+        assert!(namespaces.get("users").is_none());
+
+        namespaces
+            .get_or_create("users", || ShardManager::new(2))
+            .set("key1".to_string(), "user".to_string())
+            .unwrap();
+        namespaces
+            .get_or_create("orders", || ShardManager::new(2))
+            .set("key1".to_string(), "order".to_string())
+            .unwrap();
+
+        let users = namespaces.get("users").unwrap();
+        assert_eq!(users.get("key1"), Some("user".to_string()));
+        assert_eq!(
+            namespaces.get("orders").unwrap().get("key1"),
+            Some("order".to_string())
+        );
+
+        // An existing namespace is reused rather than rebuilt
+        let users_again = namespaces.get_or_create("users", || panic!("users already exists"));
+        assert!(Arc::ptr_eq(&users, &users_again));
+
+        assert_eq!(namespaces.total_keys(), 2);
+        assert_eq!(
+            namespaces.total_bytes(),
+            "key1user".len() + "key1order".len()
+        );
+    }
+}
@@ -0,0 +1,111 @@
+// rate_limiter.rs
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Limits how many requests each client address may send within a sliding window.
+pub struct RateLimiter {
+    max_requests: usize,
+    window: Duration,
+    requests: Mutex<Requests>,
+}
+
+struct Requests {
+    /// Instants of the requests each address sent within the last window, oldest first.
+    by_addr: HashMap<IpAddr, VecDeque<Instant>>,
+    /// When the addresses that went quiet were last forgotten.
+    swept_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(max_requests: usize, window: Duration) -> Self {
+        RateLimiter {
+            max_requests,
+            window,
+            requests: Mutex::new(Requests {
+                by_addr: HashMap::new(),
+                swept_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Records a request from `addr`, returning false if the address already sent
+    /// `max_requests` requests within the window. Rejected requests aren't recorded.
+    pub fn check(&self, addr: IpAddr) -> bool {
+        self.check_at(addr, Instant::now())
+    }
+
+    fn check_at(&self, addr: IpAddr, now: Instant) -> bool {
+        let mut requests = self.requests.lock().unwrap_or_else(PoisonError::into_inner);
+        // Forget the addresses that went quiet once per window, so the map doesn't grow with
+        // every client seen without going over every address on each request
+        if now.duration_since(requests.swept_at) >= self.window {
+            requests
+                .by_addr
+                .retain(|_, instants| !self.expire(instants, now));
+            requests.swept_at = now;
+        }
+
+        let instants = requests.by_addr.entry(addr).or_default();
+        self.expire(instants, now);
+        if instants.len() >= self.max_requests {
+            return false;
+        }
+        instants.push_back(now);
+        true
+    }
+
+    /// Drops the instants that left the window, returning whether none is left.
+    fn expire(&self, instants: &mut VecDeque<Instant>, now: Instant) -> bool {
+        while matches!(instants.front(), Some(&instant) if now.duration_since(instant) >= self.window)
+        {
+            instants.pop_front();
+        }
+        instants.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_rate_limiter_sliding_window() {
+        let rate_limiter = RateLimiter::new(2, Duration::from_secs(10));
+        let addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other_addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let start = Instant::now();
+
+        assert!(rate_limiter.check_at(addr, start));
+        assert!(rate_limiter.check_at(addr, start + Duration::from_secs(4)));
+        assert!(!rate_limiter.check_at(addr, start + Duration::from_secs(6)));
+        assert!(rate_limiter.check_at(other_addr, start + Duration::from_secs(6)));
+
+        // The first request left the window, the second one is still in it
+        assert!(rate_limiter.check_at(addr, start + Duration::from_secs(10)));
+        assert!(!rate_limiter.check_at(addr, start + Duration::from_secs(11)));
+        assert!(rate_limiter.check_at(addr, start + Duration::from_secs(14)));
+    }
+
+    #[test]
+    fn test_rate_limiter_forgets_quiet_addresses() {
+        let rate_limiter = RateLimiter::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+        for i in 0..5 {
+            assert!(rate_limiter.check_at(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)), start));
+        }
+        let addresses =
+            |rate_limiter: &RateLimiter| rate_limiter.requests.lock().unwrap().by_addr.len();
+        assert_eq!(addresses(&rate_limiter), 5);
+
+        // Quiet addresses are kept until a window passed since the last sweep
+        let addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5));
+        assert!(rate_limiter.check_at(addr, start + Duration::from_secs(5)));
+        assert_eq!(addresses(&rate_limiter), 6);
+        assert!(rate_limiter.check_at(addr, start + Duration::from_secs(12)));
+        assert_eq!(addresses(&rate_limiter), 1);
+    }
+}
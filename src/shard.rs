@@ -1,26 +1,684 @@
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::Hash;
+use std::mem;
+use std::time::{Duration, Instant, SystemTime};
 
-pub struct Shard {
-    data: HashMap<String, String>,
+/// How a stored value should be interpreted. `Json` values are known to hold valid JSON.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    #[default]
+    String,
+    Json,
 }
 
-impl Shard {
+impl ValueType {
+    pub fn is_string(&self) -> bool {
+        *self == ValueType::String
+    }
+}
+
+/// Memory taken up by a key or value, as counted against a byte budget.
+pub trait ByteSize {
+    fn byte_size(&self) -> usize;
+}
+
+impl ByteSize for String {
+    fn byte_size(&self) -> usize {
+        self.len()
+    }
+}
+
+macro_rules! impl_byte_size_for_primitives {
+    ($($ty:ty),*) => {
+        $(impl ByteSize for $ty {
+            fn byte_size(&self) -> usize {
+                std::mem::size_of::<$ty>()
+            }
+        })*
+    };
+}
+
+impl_byte_size_for_primitives!(
+    i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize, f32, f64, bool, char
+);
+
+/// A stored value along with the instant it expires at, if any.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry<V = String> {
+    pub value: V,
+    pub expires_at: Option<Instant>,
+    pub value_type: ValueType,
+    /// Encoding the client tagged the value with, eg. `gzip`, kept as is and never applied.
+    pub encoding: Option<String>,
+    /// Starts at 1 when the key is created and goes up by one on every write to it.
+    pub version: u64,
+    /// Wall-clock time of the last write to the value.
+    pub modified_at: SystemTime,
+    /// Tick of the last access, used to find the least recently used entry.
+    last_used: u64,
+}
+
+impl<V> Entry<V> {
+    /// Creates an entry at version 1, typed as a plain string, eg. to restore it with
+    /// `Shard::insert_entry`.
+    pub fn new(value: V, expires_at: Option<Instant>) -> Self {
+        Entry {
+            value,
+            expires_at,
+            value_type: ValueType::String,
+            encoding: None,
+            version: 1,
+            modified_at: SystemTime::now(),
+            last_used: 0,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= Instant::now())
+    }
+}
+
+/// Why an increment couldn't be applied.
+#[derive(Debug, PartialEq)]
+pub enum IncrError {
+    /// The current value isn't a valid 64-bit integer.
+    NotAnInteger,
+    /// The result doesn't fit in a 64-bit integer.
+    Overflow,
+}
+
+impl fmt::Display for IncrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IncrError::NotAnInteger => write!(f, "value is not an integer"),
+            IncrError::Overflow => write!(f, "increment would overflow"),
+        }
+    }
+}
+
+impl std::error::Error for IncrError {}
+
+/// Number of reads, writes and deletions a shard has served.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ShardOps {
+    pub gets: u64,
+    pub sets: u64,
+    pub deletes: u64,
+}
+
+/// Entries of one shard, mapping keys of type `K` to values of type `V`. Counters, string
+/// appends and compare-and-swap are only available for `String` keys and values.
+pub struct Shard<K = String, V = String> {
+    data: HashMap<K, Entry<V>>,
+    /// Keys ordered by their last access tick, least recently used first.
+    recency: BTreeMap<u64, K>,
+    tick: u64,
+    max_entries: Option<usize>,
+    /// Summed size of the keys and values held, expired ones included until they're removed.
+    bytes: usize,
+    ops: ShardOps,
+    /// Keys evicted to make room for new ones since `take_evicted` was last called.
+    evicted: Vec<K>,
+}
+
+impl<K, V> Shard<K, V> {
+    /// Number of entries held, counting the expired ones that weren't removed yet. Unlike
+    /// `len`, it doesn't walk the entries.
+    pub fn stored_len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl<K: Hash + Eq + Clone + ByteSize, V: Clone + ByteSize> Shard<K, V> {
     pub fn new() -> Self {
         Shard {
             data: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            max_entries: None,
+            bytes: 0,
+            ops: ShardOps::default(),
+            evicted: Vec::new(),
+        }
+    }
+
+    /// Creates a shard holding at most `max_entries` entries, evicting the least recently
+    /// used one when a new key would go over the limit.
+    pub fn with_max_entries(max_entries: usize) -> Self {
+        Shard {
+            max_entries: Some(max_entries),
+            ..Self::new()
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Marks the key as the most recently used one.
+    fn touch<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let tick = self.next_tick();
+        let Some((stored_key, entry)) = self.data.get_key_value(key) else {
+            return;
+        };
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(tick, stored_key.clone());
+        if let Some(entry) = self.data.get_mut(key) {
+            entry.last_used = tick;
+        }
+    }
+
+    /// Removes the entry of the key, returned along with the key as it was stored.
+    fn remove<Q>(&mut self, key: &Q) -> Option<(K, Entry<V>)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (key, entry) = self.data.remove_entry(key)?;
+        self.recency.remove(&entry.last_used);
+        self.bytes -= key.byte_size() + entry.value.byte_size();
+        Some((key, entry))
+    }
+
+    /// Removes the key if its TTL has elapsed, returning whether it was removed.
+    fn remove_if_expired<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let expired = matches!(self.data.get(key), Some(entry) if entry.is_expired());
+        if expired {
+            self.remove(key);
+        }
+        expired
+    }
+
+    /// Evicts least recently used entries until there is room for one more, keeping their keys
+    /// for `take_evicted`.
+    fn make_room(&mut self) {
+        if let Some(max_entries) = self.max_entries {
+            while self.data.len() >= max_entries {
+                match self.evict_lru() {
+                    Some(key) => self.evicted.push(key),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Keys of the entries evicted to make room for new ones since the last call, oldest
+    /// first.
+    pub fn take_evicted(&mut self) -> Vec<K> {
+        mem::take(&mut self.evicted)
+    }
+
+    /// Stores the value typed as a plain string, returning true if the key was created and
+    /// false if it was overwritten.
+    fn insert(&mut self, key: K, value: V, expires_at: Option<Instant>) -> bool {
+        self.remove_if_expired(&key);
+        if let Some(entry) = self.data.get_mut(&key) {
+            self.bytes = self.bytes - entry.value.byte_size() + value.byte_size();
+            entry.value = value;
+            entry.expires_at = expires_at;
+            entry.value_type = ValueType::String;
+            entry.encoding = None;
+            entry.version += 1;
+            entry.modified_at = SystemTime::now();
+            self.touch(&key);
+            return false;
         }
+
+        self.make_room();
+        self.bytes += key.byte_size() + value.byte_size();
+        let last_used = self.next_tick();
+        self.recency.insert(last_used, key.clone());
+        self.data.insert(
+            key,
+            Entry {
+                last_used,
+                ..Entry::new(value, expires_at)
+            },
+        );
+        true
     }
 
-    pub fn get(&self, key: &str) -> Option<String> {
+    pub fn get<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_entry(key).map(|entry| entry.value)
+    }
+
+    /// Same as `get`, returning a copy of the whole entry.
+    pub fn get_entry<Q>(&mut self, key: &Q) -> Option<Entry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.ops.gets += 1;
+        if self.remove_if_expired(key) {
+            return None;
+        }
+        self.touch(key);
         self.data.get(key).cloned()
     }
 
-    pub fn set(&mut self, key: String, value: String) {
-        self.data.insert(key, value);
+    /// Same as `get`, along with the type of the value.
+    pub fn get_typed<Q>(&mut self, key: &Q) -> Option<(V, ValueType)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_entry(key)
+            .map(|entry| (entry.value, entry.value_type))
+    }
+
+    /// Same as `get`, along with the version of the value.
+    pub fn get_versioned<Q>(&mut self, key: &Q) -> Option<(V, u64)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_entry(key)
+            .map(|entry| (entry.value, entry.version))
+    }
+
+    /// Version of the value of the key, None if it's missing.
+    pub fn version<Q>(&self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.data.get(key) {
+            Some(entry) if !entry.is_expired() => Some(entry.version),
+            _ => None,
+        }
+    }
+
+    /// Type of the value of the key, None if it's missing.
+    pub fn value_type<Q>(&self, key: &Q) -> Option<ValueType>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.data.get(key) {
+            Some(entry) if !entry.is_expired() => Some(entry.value_type),
+            _ => None,
+        }
+    }
+
+    /// Tags the value of the key with a type, returning false if the key is missing.
+    pub fn set_value_type<Q>(&mut self, key: &Q, value_type: ValueType) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                entry.value_type = value_type;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Tags the value of the key with an encoding, None clearing it, returning false if the key
+    /// is missing.
+    pub fn set_encoding<Q>(&mut self, key: &Q, encoding: Option<String>) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                entry.encoding = encoding;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Writes the value under the key, reads it back and removes it again, straight in the map
+    /// so the size, recency and eviction bookkeeping are left untouched. Returns whether the
+    /// value read back is the one written. A key the shard already holds is never overwritten,
+    /// the probe then passing as is.
+    pub fn probe(&mut self, key: K, value: V) -> bool
+    where
+        V: PartialEq,
+    {
+        if self.data.contains_key(&key) {
+            return true;
+        }
+        self.data
+            .insert(key.clone(), Entry::new(value.clone(), None));
+        let read_back = self
+            .data
+            .get(&key)
+            .is_some_and(|entry| entry.value == value);
+        self.data.remove(&key).is_some() && read_back
+    }
+
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        matches!(self.data.get(key), Some(entry) if !entry.is_expired())
+    }
+
+    /// Remaining lifetime of the key: None if it's missing, Some(None) if it never expires.
+    pub fn ttl<Q>(&self, key: &Q) -> Option<Option<Duration>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.data.get(key) {
+            Some(entry) if !entry.is_expired() => Some(
+                entry
+                    .expires_at
+                    .map(|expires_at| expires_at.saturating_duration_since(Instant::now())),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Live entry of the key along with the key as it was stored, without counting as an
+    /// access.
+    pub fn entry<Q>(&self, key: &Q) -> Option<(&K, &Entry<V>)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.data
+            .get_key_value(key)
+            .filter(|(_, entry)| !entry.is_expired())
+    }
+
+    /// Tick of the last access to the shard. Entries used from now on get later ticks.
+    pub fn last_tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Keys of up to `limit` entries last used at a tick in `from..until`, least recently used
+    /// first, along with the tick to carry on from. Walks the entries in chunks: those used
+    /// again in between move past `until`, so every entry is listed at most once.
+    pub fn keys_used_between(&self, from: u64, until: u64, limit: usize) -> (Vec<K>, u64) {
+        let keys: Vec<(u64, K)> = self
+            .recency
+            .range(from..until)
+            .take(limit)
+            .map(|(&tick, key)| (tick, key.clone()))
+            .collect();
+        let next = keys.last().map_or(until, |&(tick, _)| tick + 1);
+        (keys.into_iter().map(|(_, key)| key).collect(), next)
+    }
+
+    /// Iterates over the live entries of the shard, skipping expired ones.
+    pub fn entries(&self) -> impl Iterator<Item = (&K, &Entry<V>)> {
+        self.data.iter().filter(|(_, entry)| !entry.is_expired())
+    }
+
+    /// Operations served by the shard so far.
+    pub fn ops(&self) -> ShardOps {
+        self.ops
+    }
+
+    /// Summed size of the keys and values in the shard, see `ByteSize`. Expired entries count
+    /// until they're removed.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Approximate memory used by the shard: the size of its keys and values, plus for every
+    /// entry the map slot holding it and the copy of its key tracking recency. The spare
+    /// capacity of the maps isn't counted.
+    pub fn estimated_bytes(&self) -> usize {
+        let per_entry =
+            2 * mem::size_of::<K>() + mem::size_of::<Entry<V>>() + mem::size_of::<u64>();
+        self.bytes + self.data.len() * per_entry
+    }
+
+    /// Removes the least recently used entry, expired or not, returning its key. None if the
+    /// shard is empty.
+    pub fn evict_lru(&mut self) -> Option<K> {
+        let (_, key) = self.recency.pop_first()?;
+        if let Some(entry) = self.data.remove(&key) {
+            self.bytes -= key.byte_size() + entry.value.byte_size();
+        }
+        Some(key)
+    }
+
+    /// Number of entries in the shard, not counting expired ones.
+    pub fn len(&self) -> usize {
+        self.data
+            .values()
+            .filter(|entry| !entry.is_expired())
+            .count()
+    }
+
+    /// Stores the value, returning true if the key was created and false if it was overwritten.
+    pub fn set(&mut self, key: K, value: V) -> bool {
+        self.ops.sets += 1;
+        self.insert(key, value, None)
+    }
+
+    /// Same as `set`, with the key expiring after `ttl`.
+    pub fn set_with_ttl(&mut self, key: K, value: V, ttl: Duration) -> bool {
+        self.ops.sets += 1;
+        self.insert(key, value, Some(Instant::now() + ttl))
+    }
+
+    /// Stores the value only if the key is absent, optionally expiring after `ttl`. Returns
+    /// whether the value was stored.
+    pub fn set_if_absent(&mut self, key: K, value: V, ttl: Option<Duration>) -> bool {
+        self.ops.sets += 1;
+        self.remove_if_expired(&key);
+        if self.data.contains_key(&key) {
+            return false;
+        }
+        self.insert(key, value, ttl.map(|ttl| Instant::now() + ttl))
+    }
+
+    /// Stores the value without expiry, like `set`, and returns the previous one.
+    pub fn get_set(&mut self, key: K, value: V) -> Option<V> {
+        self.ops.sets += 1;
+        self.remove_if_expired(&key);
+        let previous = self.data.get(&key).map(|entry| entry.value.clone());
+        self.insert(key, value, None);
+        previous
+    }
+
+    /// Inserts an entry as is, keeping its expiry, type, version and modification time. Used
+    /// to move entries between shards.
+    pub fn insert_entry(&mut self, key: K, entry: Entry<V>) {
+        self.insert(key.clone(), entry.value, entry.expires_at);
+        if let Some(stored) = self.data.get_mut(&key) {
+            stored.value_type = entry.value_type;
+            stored.encoding = entry.encoding;
+            stored.version = entry.version;
+            stored.modified_at = entry.modified_at;
+        }
+    }
+
+    /// Removes and returns every live entry whose key matches the predicate.
+    /// Expired entries that match are dropped instead of being returned.
+    pub fn drain_matching<F: FnMut(&K) -> bool>(&mut self, mut predicate: F) -> Vec<(K, Entry<V>)> {
+        let keys: Vec<K> = self
+            .data
+            .keys()
+            .filter(|key| predicate(key))
+            .cloned()
+            .collect();
+
+        keys.into_iter()
+            .filter_map(|key| self.remove(&key))
+            .filter(|(_, entry)| !entry.is_expired())
+            .collect()
+    }
+
+    pub fn update<Q>(&mut self, key: &Q, value: V) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.ops.sets += 1;
+        if self.remove_if_expired(key) {
+            return None;
+        }
+        self.touch(key);
+        self.data.get_mut(key).map(|entry| {
+            self.bytes = self.bytes - entry.value.byte_size() + value.byte_size();
+            entry.version += 1;
+            entry.modified_at = SystemTime::now();
+            std::mem::replace(&mut entry.value, value)
+        })
+    }
+
+    /// Makes the key expire after `ttl`, or never when None, without changing its value or
+    /// version. Returns false if the key is missing.
+    pub fn expire<Q>(&mut self, key: &Q, ttl: Option<Duration>) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.remove_if_expired(key) || !self.data.contains_key(key) {
+            return false;
+        }
+        self.touch(key);
+        if let Some(entry) = self.data.get_mut(key) {
+            entry.expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        }
+        true
+    }
+
+    /// Removes every entry, returning how many live ones there were.
+    pub fn clear(&mut self) -> usize {
+        let removed = self.len();
+        self.data.clear();
+        self.recency.clear();
+        self.bytes = 0;
+        removed
+    }
+
+    /// Removes every expired entry, returning how many were removed.
+    pub fn sweep_expired(&mut self) -> usize {
+        let expired: Vec<K> = self
+            .data
+            .iter()
+            .filter(|(_, entry)| entry.is_expired())
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired {
+            self.remove(key);
+        }
+        expired.len()
+    }
+
+    /// Removes and returns the entry of the key, keeping its expiry so it can be stored under
+    /// another key.
+    pub fn take<Q>(&mut self, key: &Q) -> Option<Entry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.remove_if_expired(key) {
+            return None;
+        }
+        self.remove(key).map(|(_, entry)| entry)
+    }
+
+    pub fn delete<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.delete_entry(key).map(|(_, value)| value)
+    }
+
+    /// Same as `delete`, also returning the key as it was stored.
+    pub fn delete_entry<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.ops.deletes += 1;
+        if self.remove_if_expired(key) {
+            return None;
+        }
+        self.remove(key).map(|(key, entry)| (key, entry.value))
+    }
+}
+
+impl Shard {
+    /// Length of the value of the key in bytes, None if it's missing.
+    pub fn value_len(&self, key: &str) -> Option<usize> {
+        match self.data.get(key) {
+            Some(entry) if !entry.is_expired() => Some(entry.value.len()),
+            _ => None,
+        }
+    }
+
+    /// Adds `by` to the integer stored at `key`, a missing key counting as 0. The key keeps
+    /// its TTL, if any.
+    pub fn increment(&mut self, key: &str, by: i64) -> Result<i64, IncrError> {
+        self.ops.sets += 1;
+        self.remove_if_expired(key);
+        let (current, expires_at) = match self.data.get(key) {
+            Some(entry) => (
+                entry
+                    .value
+                    .parse::<i64>()
+                    .map_err(|_| IncrError::NotAnInteger)?,
+                entry.expires_at,
+            ),
+            None => (0, None),
+        };
+
+        let new_value = current.checked_add(by).ok_or(IncrError::Overflow)?;
+        self.insert(key.to_string(), new_value.to_string(), expires_at);
+        Ok(new_value)
+    }
+
+    /// Appends `suffix` to the value of the key, a missing key counting as empty. The key keeps
+    /// its TTL, if any. Returns the length of the new value in bytes.
+    pub fn append(&mut self, key: &str, suffix: &str) -> usize {
+        self.ops.sets += 1;
+        self.remove_if_expired(key);
+        let (mut value, expires_at) = match self.data.get(key) {
+            Some(entry) => (entry.value.clone(), entry.expires_at),
+            None => (String::new(), None),
+        };
+
+        value.push_str(suffix);
+        let len = value.len();
+        self.insert(key.to_string(), value, expires_at);
+        len
     }
 
-    pub fn delete(&mut self, key: &str) -> Option<String> {
-        self.data.remove(key)
+    /// Sets the key to `new` only if its current value is `expected`, `None` meaning the key
+    /// must be absent. The key keeps its TTL, if any.
+    pub fn compare_and_swap(&mut self, key: &str, expected: Option<&str>, new: String) -> bool {
+        self.ops.sets += 1;
+        self.remove_if_expired(key);
+        let current = self.data.get(key);
+        if current.map(|entry| entry.value.as_str()) != expected {
+            return false;
+        }
+
+        let expires_at = current.and_then(|entry| entry.expires_at);
+        self.insert(key.to_string(), new, expires_at);
+        true
     }
 }
 
@@ -30,7 +688,7 @@ mod tests {
 
     #[test]
     fn test_shard_new() {
-        let shard = Shard::new();
+        let shard: Shard = Shard::new();
         assert!(shard.data.is_empty());
     }
 
@@ -41,12 +699,358 @@ mod tests {
         assert_eq!(shard.get("key1"), Some("value1".to_string()));
     }
 
+    #[test]
+    fn test_shard_set_reports_creation() {
+        let mut shard = Shard::new();
+        assert!(shard.set("key1".to_string(), "value1".to_string()));
+        assert!(!shard.set("key1".to_string(), "value2".to_string()));
+
+        shard.set_with_ttl("key2".to_string(), "value1".to_string(), Duration::ZERO);
+        // An expired key counts as absent
+        assert!(shard.set("key2".to_string(), "value2".to_string()));
+    }
+
+    #[test]
+    fn test_shard_set_if_absent() {
+        let mut shard = Shard::new();
+        assert!(shard.set_if_absent("key1".to_string(), "value1".to_string(), None));
+        assert!(!shard.set_if_absent("key1".to_string(), "value2".to_string(), None));
+        assert_eq!(shard.get("key1"), Some("value1".to_string()));
+
+        shard.set_with_ttl("key2".to_string(), "value1".to_string(), Duration::ZERO);
+        // An expired key counts as absent
+        assert!(shard.set_if_absent(
+            "key2".to_string(),
+            "value2".to_string(),
+            Some(Duration::from_secs(60))
+        ));
+        assert_eq!(shard.get("key2"), Some("value2".to_string()));
+        assert!(shard.data["key2"].expires_at.is_some());
+    }
+
+    #[test]
+    fn test_shard_versions() {
+        let mut shard = Shard::new();
+        shard.set("key1".to_string(), "value1".to_string());
+        assert_eq!(shard.get_versioned("key1"), Some(("value1".to_string(), 1)));
+
+        shard.set("key1".to_string(), "value2".to_string());
+        assert_eq!(shard.get_versioned("key1"), Some(("value2".to_string(), 2)));
+        shard.update("key1", "value3".to_string());
+        shard.increment("counter", 1).unwrap();
+        assert_eq!(shard.get_versioned("key1"), Some(("value3".to_string(), 3)));
+        assert_eq!(shard.get_versioned("counter"), Some(("1".to_string(), 1)));
+
+        // Moving an entry keeps its version
+        let entry = shard.take("key1").unwrap();
+        shard.insert_entry("key2".to_string(), entry);
+        assert_eq!(shard.get_versioned("key2"), Some(("value3".to_string(), 3)));
+
+        // A deleted key starts over
+        shard.delete("key2");
+        shard.set("key2".to_string(), "value".to_string());
+        assert_eq!(shard.get_versioned("key2"), Some(("value".to_string(), 1)));
+        assert_eq!(shard.get_versioned("missing"), None);
+    }
+
+    #[test]
+    fn test_shard_append() {
+        let mut shard = Shard::new();
+        shard.set_with_ttl(
+            "key1".to_string(),
+            "hello".to_string(),
+            Duration::from_secs(60),
+        );
+        assert_eq!(shard.append("key1", " world"), 11);
+        assert_eq!(shard.get("key1"), Some("hello world".to_string()));
+        assert_eq!(shard.value_len("key1"), Some(11));
+        assert!(matches!(shard.ttl("key1"), Some(Some(_))));
+
+        assert_eq!(shard.append("key2", "fresh"), 5);
+        assert_eq!(shard.get("key2"), Some("fresh".to_string()));
+        assert_eq!(shard.ttl("key2"), Some(None));
+        assert_eq!(shard.value_len("missing"), None);
+    }
+
+    #[test]
+    fn test_shard_get_set() {
+        let mut shard = Shard::new();
+        assert_eq!(
+            shard.get_set("key1".to_string(), "value1".to_string()),
+            None
+        );
+        assert_eq!(
+            shard.get_set("key1".to_string(), "value2".to_string()),
+            Some("value1".to_string())
+        );
+        assert_eq!(shard.get("key1"), Some("value2".to_string()));
+
+        shard.set_with_ttl("key2".to_string(), "value1".to_string(), Duration::ZERO);
+        assert_eq!(
+            shard.get_set("key2".to_string(), "value2".to_string()),
+            None
+        );
+    }
+
     #[test]
     fn test_shard_get_non_existent() {
-        let shard = Shard::new();
+        let mut shard: Shard = Shard::new();
+        assert_eq!(shard.get("non_existent_key"), None);
+    }
+
+    #[test]
+    fn test_shard_set_with_ttl() {
+        let mut shard = Shard::new();
+        shard.set_with_ttl(
+            "key1".to_string(),
+            "value1".to_string(),
+            Duration::from_millis(50),
+        );
+        assert_eq!(shard.get("key1"), Some("value1".to_string()));
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!shard.contains("key1"));
+        assert_eq!(shard.get("key1"), None);
+        assert!(shard.data.is_empty());
+    }
+
+    #[test]
+    fn test_shard_contains() {
+        let mut shard = Shard::new();
+        shard.set("key1".to_string(), "value1".to_string());
+        assert!(shard.contains("key1"));
+        assert!(!shard.contains("non_existent_key"));
+    }
+
+    #[test]
+    fn test_shard_ttl() {
+        let mut shard = Shard::new();
+        shard.set("key1".to_string(), "value1".to_string());
+        shard.set_with_ttl(
+            "key2".to_string(),
+            "value2".to_string(),
+            Duration::from_secs(60),
+        );
+        shard.set_with_ttl("key3".to_string(), "value3".to_string(), Duration::ZERO);
+
+        assert_eq!(shard.ttl("key1"), Some(None));
+        let ttl = shard.ttl("key2").unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(59) && ttl <= Duration::from_secs(60));
+        assert_eq!(shard.ttl("key3"), None);
+        assert_eq!(shard.ttl("missing"), None);
+    }
+
+    #[test]
+    fn test_shard_expire() {
+        let mut shard = Shard::new();
+        shard.set_with_ttl(
+            "key1".to_string(),
+            "value1".to_string(),
+            Duration::from_millis(50),
+        );
+        assert!(shard.expire("key1", Some(Duration::from_secs(60))));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(shard.get("key1"), Some("value1".to_string()));
+        assert_eq!(shard.version("key1"), Some(1));
+
+        assert!(shard.expire("key1", None));
+        assert_eq!(shard.ttl("key1"), Some(None));
+        assert!(!shard.expire("missing", None));
+    }
+
+    #[test]
+    fn test_shard_len() {
+        let mut shard = Shard::new();
+        assert_eq!(shard.len(), 0);
+        shard.set("key1".to_string(), "value1".to_string());
+        shard.set("key2".to_string(), "value2".to_string());
+        shard.set_with_ttl("key3".to_string(), "value3".to_string(), Duration::ZERO);
+        assert_eq!(shard.len(), 2);
+    }
+
+    #[test]
+    fn test_shard_with_integer_keys() {
+        let mut shard: Shard<u64, String> = Shard::with_max_entries(2);
+        shard.set(1, "one".to_string());
+        shard.set(2, "two".to_string());
+        assert_eq!(shard.get(&1), Some("one".to_string()));
+
+        // 2 is now the least recently used key
+        shard.set(3, "three".to_string());
+        assert_eq!(shard.get(&2), None);
+        assert_eq!(shard.delete_entry(&3), Some((3, "three".to_string())));
+        assert_eq!(shard.len(), 1);
+    }
+
+    #[test]
+    fn test_shard_drain_matching() {
+        let mut shard = Shard::new();
+        shard.set("a1".to_string(), "value1".to_string());
+        shard.set("a2".to_string(), "value2".to_string());
+        shard.set("b1".to_string(), "value3".to_string());
+
+        let mut drained = shard.drain_matching(|key| key.starts_with('a'));
+        drained.sort_by(|a, b| a.0.cmp(&b.0));
+        let drained_values: Vec<(&str, &str)> = drained
+            .iter()
+            .map(|(key, entry)| (key.as_str(), entry.value.as_str()))
+            .collect();
+        assert_eq!(drained_values, vec![("a1", "value1"), ("a2", "value2")]);
+        assert_eq!(shard.len(), 1);
+
+        let mut other = Shard::new();
+        for (key, entry) in drained {
+            other.insert_entry(key, entry);
+        }
+        assert_eq!(other.get("a1"), Some("value1".to_string()));
+    }
+
+    #[test]
+    fn test_shard_evicts_least_recently_used() {
+        let mut shard = Shard::with_max_entries(3);
+        shard.set("key1".to_string(), "value1".to_string());
+        shard.set("key2".to_string(), "value2".to_string());
+        shard.set("key3".to_string(), "value3".to_string());
+
+        // key1 becomes the most recently used, leaving key2 as the oldest
+        assert_eq!(shard.get("key1"), Some("value1".to_string()));
+        shard.set("key4".to_string(), "value4".to_string());
+
+        assert_eq!(shard.len(), 3);
+        assert!(!shard.contains("key2"));
+        assert!(shard.contains("key1"));
+        assert!(shard.contains("key3"));
+        assert!(shard.contains("key4"));
+    }
+
+    #[test]
+    fn test_shard_tracks_bytes() {
+        let mut shard = Shard::new();
+        shard.set("key1".to_string(), "value1".to_string());
+        shard.set("key2".to_string(), "v".to_string());
+        assert_eq!(shard.bytes(), 15);
+
+        shard.set("key1".to_string(), "longer value".to_string());
+        assert_eq!(shard.bytes(), 21);
+        shard.update("key2", "value2".to_string());
+        assert_eq!(shard.bytes(), 26);
+        shard.append("key2", "!");
+        assert_eq!(shard.bytes(), 27);
+
+        assert_eq!(shard.evict_lru(), Some("key1".to_string()));
+        assert_eq!(shard.bytes(), 11);
+        shard.delete("key2");
+        assert_eq!(shard.bytes(), 0);
+        assert_eq!(shard.evict_lru(), None);
+
+        let mut shard: Shard<u64, i64> = Shard::new();
+        shard.set(1, 10);
+        assert_eq!(shard.bytes(), 16);
+        shard.clear();
+        assert_eq!(shard.bytes(), 0);
+    }
+
+    #[test]
+    fn test_shard_estimated_bytes() {
+        let mut shard = Shard::new();
+        assert_eq!(shard.estimated_bytes(), 0);
+        shard.set("key1".to_string(), "value1".to_string());
+        let one = shard.estimated_bytes();
+        assert!(one > shard.bytes());
+
+        shard.set("key2".to_string(), "value2".to_string());
+        assert_eq!(shard.estimated_bytes(), 2 * one);
+        shard.delete("key1");
+        shard.delete("key2");
+        assert_eq!(shard.estimated_bytes(), 0);
+    }
+
+    #[test]
+    fn test_shard_overwrite_does_not_evict() {
+        let mut shard = Shard::with_max_entries(2);
+        shard.set("key1".to_string(), "value1".to_string());
+        shard.set("key2".to_string(), "value2".to_string());
+        shard.set("key1".to_string(), "value3".to_string());
+
+        assert_eq!(shard.len(), 2);
+        assert_eq!(shard.get("key1"), Some("value3".to_string()));
+        assert_eq!(shard.get("key2"), Some("value2".to_string()));
+    }
+
+    #[test]
+    fn test_shard_update() {
+        let mut shard = Shard::new();
+        shard.set("key1".to_string(), "value1".to_string());
+        let previous = shard.update("key1", "value2".to_string());
+        assert_eq!(previous, Some("value1".to_string()));
+        assert_eq!(shard.get("key1"), Some("value2".to_string()));
+    }
+
+    #[test]
+    fn test_shard_update_non_existent() {
+        let mut shard: Shard = Shard::new();
+        let previous = shard.update("non_existent_key", "value".to_string());
+        assert_eq!(previous, None);
         assert_eq!(shard.get("non_existent_key"), None);
     }
 
+    #[test]
+    fn test_shard_increment() {
+        let mut shard = Shard::new();
+        assert_eq!(shard.increment("counter", 1), Ok(1));
+        assert_eq!(shard.increment("counter", 5), Ok(6));
+        assert_eq!(shard.increment("counter", -10), Ok(-4));
+        assert_eq!(shard.get("counter"), Some("-4".to_string()));
+    }
+
+    #[test]
+    fn test_shard_increment_errors() {
+        let mut shard = Shard::new();
+        shard.set("text".to_string(), "abc".to_string());
+        assert_eq!(shard.increment("text", 1), Err(IncrError::NotAnInteger));
+        assert_eq!(shard.get("text"), Some("abc".to_string()));
+
+        shard.set("max".to_string(), i64::MAX.to_string());
+        assert_eq!(shard.increment("max", 1), Err(IncrError::Overflow));
+    }
+
+    #[test]
+    fn test_shard_compare_and_swap() {
+        let mut shard = Shard::new();
+        assert!(shard.compare_and_swap("key1", None, "value1".to_string()));
+        assert!(!shard.compare_and_swap("key1", None, "value2".to_string()));
+        assert!(!shard.compare_and_swap("key1", Some("other"), "value2".to_string()));
+        assert_eq!(shard.get("key1"), Some("value1".to_string()));
+
+        assert!(shard.compare_and_swap("key1", Some("value1"), "value2".to_string()));
+        assert_eq!(shard.get("key1"), Some("value2".to_string()));
+        assert!(!shard.compare_and_swap("missing", Some("value"), "value".to_string()));
+    }
+
+    #[test]
+    fn test_shard_clear() {
+        let mut shard = Shard::new();
+        shard.set("key1".to_string(), "value1".to_string());
+        shard.set("key2".to_string(), "value2".to_string());
+        assert_eq!(shard.clear(), 2);
+        assert_eq!(shard.len(), 0);
+        assert_eq!(shard.get("key1"), None);
+    }
+
+    #[test]
+    fn test_shard_sweep_expired() {
+        let mut shard = Shard::new();
+        shard.set("key1".to_string(), "value1".to_string());
+        shard.set_with_ttl("key2".to_string(), "value2".to_string(), Duration::ZERO);
+        shard.set_with_ttl("key3".to_string(), "value3".to_string(), Duration::ZERO);
+        assert_eq!(shard.data.len(), 3);
+
+        assert_eq!(shard.sweep_expired(), 2);
+        assert_eq!(shard.data.len(), 1);
+        assert!(shard.recency.values().all(|key| key == "key1"));
+        assert_eq!(shard.sweep_expired(), 0);
+    }
+
     #[test]
     fn test_shard_delete() {
         let mut shard = Shard::new();
@@ -57,9 +1061,128 @@ mod tests {
     }
 
     #[test]
-    fn test_shard_delete_non_existent() {
+    fn test_shard_take() {
         let mut shard = Shard::new();
+        shard.set_with_ttl(
+            "key1".to_string(),
+            "value1".to_string(),
+            Duration::from_secs(60),
+        );
+        shard.set_with_ttl("key2".to_string(), "value2".to_string(), Duration::ZERO);
+
+        let entry = shard.take("key1").unwrap();
+        assert_eq!(entry.value, "value1");
+        assert!(entry.expires_at.is_some());
+        assert!(!shard.contains("key1"));
+        assert_eq!(shard.take("key2"), None);
+        assert!(shard.data.is_empty());
+    }
+
+    #[test]
+    fn test_shard_delete_non_existent() {
+        let mut shard: Shard = Shard::new();
         let deleted_value = shard.delete("non_existent_key");
         assert_eq!(deleted_value, None);
     }
+
+    #[test]
+    fn test_shard_value_type() {
+        let mut shard = Shard::new();
+        shard.set("key1".to_string(), r#"{"a":1}"#.to_string());
+        assert_eq!(shard.value_type("key1"), Some(ValueType::String));
+        assert!(shard.set_value_type("key1", ValueType::Json));
+        assert_eq!(
+            shard.get_typed("key1"),
+            Some((r#"{"a":1}"#.to_string(), ValueType::Json))
+        );
+
+        let entry = shard.take("key1").unwrap();
+        shard.insert_entry("key2".to_string(), entry);
+        assert_eq!(shard.value_type("key2"), Some(ValueType::Json));
+
+        // Overwriting the value makes it a plain string again
+        shard.set("key2".to_string(), "value".to_string());
+        assert_eq!(shard.value_type("key2"), Some(ValueType::String));
+        assert!(!shard.set_value_type("missing", ValueType::Json));
+        assert_eq!(shard.value_type("missing"), None);
+    }
+
+    #[test]
+    fn test_shard_encoding() {
+        let mut shard = Shard::new();
+        shard.set("key1".to_string(), "H4sI".to_string());
+        assert_eq!(shard.get_entry("key1").unwrap().encoding, None);
+        assert!(shard.set_encoding("key1", Some("gzip".to_string())));
+        assert_eq!(
+            shard.get_entry("key1").unwrap().encoding.as_deref(),
+            Some("gzip")
+        );
+
+        let entry = shard.take("key1").unwrap();
+        shard.insert_entry("key2".to_string(), entry);
+        assert_eq!(
+            shard.get_entry("key2").unwrap().encoding.as_deref(),
+            Some("gzip")
+        );
+
+        // Overwriting the value drops its encoding
+        shard.set("key2".to_string(), "value".to_string());
+        assert_eq!(shard.get_entry("key2").unwrap().encoding, None);
+        assert!(!shard.set_encoding("missing", Some("gzip".to_string())));
+    }
+
+    #[test]
+    fn test_shard_probe() {
+        let mut shard = Shard::with_max_entries(1);
+        shard.set("key1".to_string(), "value1".to_string());
+        let bytes = shard.bytes();
+        assert!(shard.probe("probe".to_string(), "ok".to_string()));
+        assert_eq!(shard.len(), 1);
+        assert_eq!(shard.bytes(), bytes);
+        assert_eq!(shard.get("key1"), Some("value1".to_string()));
+
+        // A key that's already stored is left as is
+        assert!(shard.probe("key1".to_string(), "ok".to_string()));
+        assert_eq!(shard.get("key1"), Some("value1".to_string()));
+    }
+
+    #[test]
+    fn test_shard_keys_used_between() {
+        let mut shard = Shard::new();
+        for i in 0..5 {
+            shard.set(format!("key{}", i), "value".to_string());
+        }
+        let until = shard.last_tick() + 1;
+
+        let (keys, next) = shard.keys_used_between(0, until, 2);
+        assert_eq!(keys, vec!["key0".to_string(), "key1".to_string()]);
+        // A key used in between moves past the end of the walk
+        shard.get("key2");
+        let (keys, next) = shard.keys_used_between(next, until, 2);
+        assert_eq!(keys, vec!["key3".to_string(), "key4".to_string()]);
+        let (keys, _) = shard.keys_used_between(next, until, 2);
+        assert!(keys.is_empty());
+    }
+
+    #[test]
+    fn test_shard_ops() {
+        let mut shard = Shard::new();
+        shard.set("key1".to_string(), "value1".to_string());
+        shard.update("key1", "value2".to_string());
+        shard.get("key1");
+        shard.get("missing");
+        shard.delete("key1");
+        // Internal bookkeeping isn't counted
+        shard.sweep_expired();
+        shard.len();
+
+        assert_eq!(
+            shard.ops(),
+            ShardOps {
+                gets: 2,
+                sets: 2,
+                deletes: 1,
+            }
+        );
+    }
 }
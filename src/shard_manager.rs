@@ -1,108 +1,4057 @@
 // shard_manager.rs
 
-use crate::shard::Shard;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use crate::audit::{AuditEntry, AuditLog, AuditOp};
+use crate::hash_algo::HashAlgo;
+use crate::shard::{ByteSize, Entry, IncrError, Shard, ShardOps, ValueType};
+use crate::wal::{Wal, WalOp};
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub struct ShardManager {
-    shards: Vec<Shard>,
+/// Number of positions each shard occupies on the hash ring, unless set with
+/// `ShardManager::with_virtual_nodes`.
+const DEFAULT_VIRTUAL_NODES: usize = 100;
+
+/// Key written, read back and removed in every shard by `ShardManager::check_shards`.
+const HEALTH_CHECK_KEY: &str = "__hashmaprs_health_check__";
+
+/// On-disk representation of an entry. The expiry is stored as a Unix timestamp in
+/// milliseconds, since an `Instant` means nothing once the process restarts.
+#[derive(Deserialize, Serialize)]
+pub struct PersistedEntry {
+    key: String,
+    value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "ValueType::is_string")]
+    value_type: ValueType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
+    #[serde(default = "first_version")]
+    version: u64,
+}
+
+fn first_version() -> u64 {
+    1
+}
+
+impl PersistedEntry {
+    pub(crate) fn new(key: &str, entry: &Entry) -> Self {
+        let expires_at_ms = entry.expires_at.map(|expires_at| {
+            let remaining = expires_at.saturating_duration_since(Instant::now());
+            (SystemTime::now() + remaining)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64
+        });
+
+        PersistedEntry {
+            key: key.to_string(),
+            value: entry.value.clone(),
+            expires_at_ms,
+            value_type: entry.value_type,
+            encoding: entry.encoding.clone(),
+            version: entry.version,
+        }
+    }
+
+    /// Time left before the entry expires, or None if it never does.
+    fn ttl(&self) -> Option<Duration> {
+        self.expires_at_ms.map(|expires_at_ms| {
+            (UNIX_EPOCH + Duration::from_millis(expires_at_ms))
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        })
+    }
+
+    /// Rebuilds the in-memory entry, None if its TTL elapsed while it was on disk.
+    fn into_entry(self) -> Option<Entry> {
+        let expires_at = match self.ttl() {
+            Some(ttl) if ttl.is_zero() => return None,
+            ttl => ttl.map(|ttl| Instant::now() + ttl),
+        };
+        let mut entry = Entry::new(self.value, expires_at);
+        entry.value_type = self.value_type;
+        entry.encoding = self.encoding;
+        entry.version = self.version;
+        Some(entry)
+    }
+}
+
+/// Outcome of `ShardManager::delete_if`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeleteIfResult {
+    Deleted,
+    /// The key holds another value, it was left untouched.
+    Mismatch,
+    NotFound,
+}
+
+/// Error of a write that would create a key while the store already holds its maximum number
+/// of keys, see `ShardManager::set_max_keys`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StoreFull;
+
+impl fmt::Display for StoreFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the store holds its maximum number of keys")
+    }
+}
+
+impl std::error::Error for StoreFull {}
+
+/// Error of a write that was refused, or undone because the WAL couldn't record it. Either way
+/// the keys it was about to change are left as they were.
+#[derive(Debug)]
+pub enum WriteError {
+    /// See `StoreFull`.
+    StoreFull,
+    /// The WAL still failed to record the write after retrying, see `WalOptions::retries`.
+    Wal(io::Error),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::StoreFull => write!(f, "{}", StoreFull),
+            WriteError::Wal(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+impl From<StoreFull> for WriteError {
+    fn from(_: StoreFull) -> Self {
+        WriteError::StoreFull
+    }
+}
+
+impl From<io::Error> for WriteError {
+    fn from(err: io::Error) -> Self {
+        WriteError::Wal(err)
+    }
+}
+
+impl From<WriteError> for io::Error {
+    fn from(err: WriteError) -> Self {
+        match err {
+            WriteError::StoreFull => io::Error::other(StoreFull),
+            WriteError::Wal(err) => err,
+        }
+    }
+}
+
+/// Keys checked and moved by a call to `ShardManager::migrate_keys`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MigratedKeys {
+    pub checked: usize,
+    pub moved: usize,
+}
+
+/// Type and encoding a value is tagged with when it's written, see `ShardManager::set_tagged`.
+/// The default tags plain strings with no encoding, like `set`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ValueTags {
+    pub value_type: ValueType,
+    pub encoding: Option<String>,
+}
+
+/// On-disk representation of the whole store, one list of entries per shard.
+#[derive(Deserialize, Serialize)]
+struct PersistedStore {
+    shards: Vec<Vec<PersistedEntry>>,
+    #[serde(default)]
+    hash_algo: HashAlgo,
+}
+
+/// Routes keys to their shard. Each shard sits behind its own read-write lock, so operations on
+/// keys living in different shards never wait on each other. Lookups take the write lock too,
+/// as they record which entry was used last, while stats and scans only take the read lock.
+///
+/// Keys are placed with consistent hashing: every shard owns several virtual nodes on a hash
+/// ring, and a key belongs to the first virtual node found clockwise from its hash. Adding or
+/// removing a shard only moves the keys of the ring segments that change owner.
+///
+/// Keys are of type `K` and values of type `V`, both `String` unless stated otherwise. Any
+/// hashable key can be placed on the ring, but counters, appends, compare-and-swap and
+/// persistence are only available for `String` keys and values.
+pub struct ShardManager<K = String, V = String> {
+    shards: Vec<ShardLock<K, V>>,
+    ring: BTreeMap<u64, usize>,
+    /// Keys placed in a given shard rather than the one they hash to, see `pin`.
+    pinned: RwLock<HashMap<K, usize>>,
+    virtual_nodes: usize,
+    max_entries_per_shard: Option<usize>,
+    hash_algo: HashAlgo,
+    /// TTL of the keys stored without one, None leaving them without expiry.
+    default_ttl: Option<Duration>,
+    /// Largest summed size of the keys and values across all shards, see `set_max_bytes`.
+    max_bytes: Option<usize>,
+    /// Largest number of keys across all shards, see `set_max_keys`.
+    max_keys: Option<usize>,
+    /// Whether keys are lowercased before they're stored, see `fold_key`.
+    case_insensitive_keys: bool,
+    /// Last writes applied, kept for inspection when enabled with `enable_audit`.
+    audit: Option<AuditLog<K>>,
+    /// Log every write is recorded to, if any.
+    wal: Option<AttachedWal<K, V>>,
+    /// Keys still to be moved to the shards the ring now places them in, see
+    /// `start_migration`.
+    migration: Option<Migration<K>>,
+}
+
+/// Placement the keys had before `ShardManager::start_migration` changed the ring. Until a key
+/// is moved, it stays in the shard this placement gives.
+struct Migration<K> {
+    ring: BTreeMap<u64, usize>,
+    /// Pins to the shards being removed, dropped from the current placement.
+    unpinned: HashMap<K, usize>,
+    /// Number of shards the keys are placed on, those after being removed once they're empty.
+    shard_count: usize,
+    scan: Mutex<MigrationScan>,
+}
+
+/// How far `ShardManager::migrate_keys` went through the shards, which it walks in the order
+/// their entries were last used. Entries used since the migration started were moved then.
+struct MigrationScan {
+    shard_index: usize,
+    /// Tick of the next entry of the shard to check.
+    next_tick: u64,
+    /// Tick every shard had reached when the migration started, the walk stopping there.
+    until: Vec<u64>,
+}
+
+/// Lock of a shard, counting the write locks that had to wait for another thread to release it.
+struct ShardLock<K, V> {
+    shard: RwLock<Shard<K, V>>,
+    contended: AtomicU64,
+    /// Number of entries the shard held when its write lock was last released, readable
+    /// without locking the shard.
+    stored: AtomicUsize,
+}
+
+/// Write lock on a shard, updating the shard's stored count once released.
+struct ShardWriteGuard<'a, K, V> {
+    shard: RwLockWriteGuard<'a, Shard<K, V>>,
+    stored: &'a AtomicUsize,
+}
+
+impl<K, V> Deref for ShardWriteGuard<'_, K, V> {
+    type Target = Shard<K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.shard
+    }
+}
+
+impl<K, V> DerefMut for ShardWriteGuard<'_, K, V> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.shard
+    }
+}
+
+impl<K, V> Drop for ShardWriteGuard<'_, K, V> {
+    fn drop(&mut self) {
+        self.stored
+            .store(self.shard.stored_len(), Ordering::Relaxed);
+    }
+}
+
+impl<K, V> ShardLock<K, V> {
+    fn new(shard: Shard<K, V>) -> Self {
+        ShardLock {
+            stored: AtomicUsize::new(shard.stored_len()),
+            shard: RwLock::new(shard),
+            contended: AtomicU64::new(0),
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Shard<K, V>> {
+        self.shard.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> ShardWriteGuard<'_, K, V> {
+        let shard = match self.shard.try_write() {
+            Ok(shard) => shard,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                self.contended.fetch_add(1, Ordering::Relaxed);
+                self.shard.write().unwrap_or_else(PoisonError::into_inner)
+            }
+        };
+        ShardWriteGuard {
+            shard,
+            stored: &self.stored,
+        }
+    }
+
+    fn into_inner(self) -> Shard<K, V> {
+        self.shard
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Keys a write changed in one of the shards it locked, each along with the entry it held
+/// before, see `ShardManager::backup`.
+type Written<K, V> = Vec<(K, Option<Entry<V>>)>;
+
+/// WAL attached to a manager, along with how to record the state of a key, None meaning it
+/// was removed. How depends on the types of the keys and values.
+struct AttachedWal<K, V> {
+    wal: Wal,
+    to_op: fn(&K, Option<&Entry<V>>) -> WalOp,
+}
+
+impl<K: Hash + Eq + Clone + ByteSize, V: Clone + ByteSize> ShardManager<K, V> {
+    pub fn new(shard_count: usize) -> Self {
+        Self::with_virtual_nodes(shard_count, DEFAULT_VIRTUAL_NODES)
+    }
+
+    /// Creates a manager placing `virtual_nodes` ring positions per shard. More virtual nodes
+    /// spread keys more evenly across shards, at the cost of a bigger ring.
+    pub fn with_virtual_nodes(shard_count: usize, virtual_nodes: usize) -> Self {
+        Self::build(shard_count, virtual_nodes, None, HashAlgo::Default)
+    }
+
+    /// Creates a manager whose shards hold at most `max_entries_per_shard` entries each,
+    /// evicting their least recently used entry when full.
+    pub fn with_max_entries_per_shard(shard_count: usize, max_entries_per_shard: usize) -> Self {
+        Self::build(
+            shard_count,
+            DEFAULT_VIRTUAL_NODES,
+            Some(max_entries_per_shard),
+            HashAlgo::Default,
+        )
+    }
+
+    /// Creates a manager placing keys with the given hash algorithm. Pick a non-default one
+    /// for key placement to stay the same across Rust versions and processes.
+    pub fn with_hash_algo(shard_count: usize, hash_algo: HashAlgo) -> Self {
+        Self::build(shard_count, DEFAULT_VIRTUAL_NODES, None, hash_algo)
+    }
+
+    /// Creates a manager placing keys with the given hash algorithm, whose shards hold at most
+    /// `max_entries_per_shard` entries each if set, like `with_max_entries_per_shard`.
+    pub fn with_max_entries_and_hash_algo(
+        shard_count: usize,
+        max_entries_per_shard: Option<usize>,
+        hash_algo: HashAlgo,
+    ) -> Self {
+        Self::build(
+            shard_count,
+            DEFAULT_VIRTUAL_NODES,
+            max_entries_per_shard,
+            hash_algo,
+        )
+    }
+
+    fn build(
+        shard_count: usize,
+        virtual_nodes: usize,
+        max_entries_per_shard: Option<usize>,
+        hash_algo: HashAlgo,
+    ) -> Self {
+        let mut shard_manager = ShardManager {
+            shards: Vec::with_capacity(shard_count),
+            ring: BTreeMap::new(),
+            pinned: RwLock::new(HashMap::new()),
+            virtual_nodes: virtual_nodes.max(1),
+            max_entries_per_shard,
+            hash_algo,
+            default_ttl: None,
+            max_bytes: None,
+            max_keys: None,
+            case_insensitive_keys: false,
+            audit: None,
+            wal: None,
+            migration: None,
+        };
+        for shard_index in 0..shard_count {
+            let shard = shard_manager.new_shard();
+            shard_manager.shards.push(ShardLock::new(shard));
+            shard_manager.add_virtual_nodes(shard_index, 1);
+        }
+
+        shard_manager
+    }
+
+    fn new_shard(&self) -> Shard<K, V> {
+        match self.max_entries_per_shard {
+            Some(max_entries) => Shard::with_max_entries(max_entries),
+            None => Shard::new(),
+        }
+    }
+
+    /// Places `weight` times the usual number of virtual nodes of the shard on the ring.
+    fn add_virtual_nodes(&mut self, shard_index: usize, weight: u32) {
+        for i in 0..self.virtual_nodes * weight as usize {
+            let position = self.hash(&format!("{}-{}", shard_index, i));
+            self.ring.insert(position, shard_index);
+        }
+    }
+
+    /// Adds a new shard to the ring and moves over the keys of the segments it now owns,
+    /// every other key stays where it is.
+    ///
+    /// Returns the index of the new shard.
+    pub fn add_shard(&mut self) -> usize {
+        self.add_weighted_shard(1)
+    }
+
+    /// Same as `add_shard`, with the new shard owning `weight` times as many virtual nodes as
+    /// the shards created along with the manager, and so receiving about `weight` times as many
+    /// keys. A weight of 0 counts as 1. Weights are dropped by `reshard`.
+    ///
+    /// Returns the index of the new shard.
+    pub fn add_weighted_shard(&mut self, weight: u32) -> usize {
+        self.finish_migration();
+        let new_index = self.shards.len();
+        let shard = self.new_shard();
+        self.shards.push(ShardLock::new(shard));
+        self.add_virtual_nodes(new_index, weight.max(1));
+
+        let mut moved = Vec::new();
+        for shard in &self.shards[..new_index] {
+            let mut shard = shard.write();
+            moved.extend(shard.drain_matching(|key| self.get_shard_index(key) == new_index));
+        }
+
+        let mut new_shard = self.shards[new_index].write();
+        for (key, entry) in moved {
+            self.place_entry(&mut new_shard, key, entry);
+        }
+        new_index
+    }
+
+    /// Removes the last shard from the ring and hands its keys over to the shards now owning
+    /// their segments, every other key stays where it is.
+    ///
+    /// Returns false without doing anything if there is only one shard left.
+    pub fn remove_shard(&mut self) -> bool {
+        self.finish_migration();
+        if self.shards.len() <= 1 {
+            return false;
+        }
+
+        let removed_index = self.shards.len() - 1;
+        self.ring
+            .retain(|_, shard_index| *shard_index != removed_index);
+        self.pinned
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, shard_index| *shard_index != removed_index);
+        let mut removed = self.shards.pop().unwrap().into_inner();
+
+        for (key, entry) in removed.drain_matching(|_| true) {
+            let mut shard = self.get_shard(&key);
+            self.place_entry(&mut shard, key, entry);
+        }
+        true
+    }
+
+    /// Rebuilds the manager with `new_count` shards and reinserts every entry into the shard
+    /// now owning it, keeping its expiry and type. The per-shard operation counters start over,
+    /// and every shard gets a weight of 1.
+    ///
+    /// Returns false without doing anything if `new_count` is 0.
+    pub fn reshard(&mut self, new_count: usize) -> bool {
+        if new_count == 0 {
+            return false;
+        }
+        self.finish_migration();
+
+        let mut resharded = Self::build(
+            new_count,
+            self.virtual_nodes,
+            self.max_entries_per_shard,
+            self.hash_algo,
+        );
+        // Pins to shards that no longer exist are dropped, their keys going back to hashing
+        let mut pinned = std::mem::take(
+            self.pinned
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        pinned.retain(|_, shard_index| *shard_index < new_count);
+        *resharded
+            .pinned
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner) = pinned;
+        // Logged once the WAL and the audit log are handed over to the rebuilt manager
+        let mut evicted = Vec::new();
+        for shard in self.shards.drain(..) {
+            let mut shard = shard.into_inner();
+            for (key, entry) in shard.drain_matching(|_| true) {
+                let mut new_shard = resharded.get_shard(&key);
+                new_shard.insert_entry(key, entry);
+                evicted.extend(new_shard.take_evicted());
+            }
+        }
+        resharded.default_ttl = self.default_ttl;
+        resharded.max_bytes = self.max_bytes;
+        resharded.max_keys = self.max_keys;
+        resharded.case_insensitive_keys = self.case_insensitive_keys;
+        resharded.audit = self.audit.take();
+        resharded.wal = self.wal.take();
+        *self = resharded;
+        self.log_evicted(&evicted);
+        true
+    }
+
+    /// Places the keys on `shard_count` shards without moving any yet, for `migrate_keys` to
+    /// move them a few at a time. Shards are added or removed like with `add_shard` and
+    /// `remove_shard`, and weights are kept. Until a key is moved, any operation on it first
+    /// moves it to its new shard, so it's never missed, and the shards being removed stay in
+    /// place until `finish_migration`. A migration still running is finished first.
+    ///
+    /// Returns the number of entries `migrate_keys` has to check, none if `shard_count` is 0 or
+    /// the current shard count.
+    pub fn start_migration(&mut self, shard_count: usize) -> usize {
+        self.finish_migration();
+        let current = self.shards.len();
+        if shard_count == 0 || shard_count == current {
+            return 0;
+        }
+
+        let ring = self.ring.clone();
+        for shard_index in current..shard_count {
+            let shard = self.new_shard();
+            self.shards.push(ShardLock::new(shard));
+            self.add_virtual_nodes(shard_index, 1);
+        }
+        self.ring
+            .retain(|_, shard_index| *shard_index < shard_count);
+        let pinned = self
+            .pinned
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let unpinned = pinned
+            .iter()
+            .filter(|(_, shard_index)| **shard_index >= shard_count)
+            .map(|(key, shard_index)| (key.clone(), *shard_index))
+            .collect();
+        pinned.retain(|_, shard_index| *shard_index < shard_count);
+
+        let old_shards = &self.shards[..current];
+        let until = old_shards
+            .iter()
+            .map(|shard| shard.read().last_tick() + 1)
+            .collect();
+        let entries = old_shards
+            .iter()
+            .map(|shard| shard.stored.load(Ordering::Relaxed))
+            .sum();
+        self.migration = Some(Migration {
+            ring,
+            unpinned,
+            shard_count,
+            scan: Mutex::new(MigrationScan {
+                shard_index: 0,
+                next_tick: 0,
+                until,
+            }),
+        });
+        entries
+    }
+
+    /// Moves the keys of up to `limit` entries to the shard they're now placed in, if they
+    /// aren't there already, locking the shards of a single key at a time. Requests are served
+    /// in between, along with other calls.
+    ///
+    /// Returns None once every entry has been checked, or if no migration is running.
+    pub fn migrate_keys(&self, limit: usize) -> Option<MigratedKeys> {
+        let migration = self.migration.as_ref()?;
+        let (shard_index, keys) = {
+            let mut scan = migration
+                .scan
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            loop {
+                let shard_index = scan.shard_index;
+                let until = *scan.until.get(shard_index)?;
+                let (keys, next_tick) =
+                    self.shards[shard_index]
+                        .read()
+                        .keys_used_between(scan.next_tick, until, limit);
+                if keys.is_empty() {
+                    scan.shard_index += 1;
+                    scan.next_tick = 0;
+                    continue;
+                }
+                scan.next_tick = next_tick;
+                break (shard_index, keys);
+            }
+        };
+
+        let moved = keys
+            .iter()
+            .filter(|key| {
+                let to = self.get_shard_index(*key);
+                to != shard_index && self.move_key(*key, shard_index, to)
+            })
+            .count();
+        Some(MigratedKeys {
+            checked: keys.len(),
+            moved,
+        })
+    }
+
+    /// Moves the keys `migrate_keys` hasn't reached yet and removes the shards left empty, ending
+    /// the migration. Does nothing if none is running.
+    pub fn finish_migration(&mut self) {
+        let Some(migration) = self.migration.take() else {
+            return;
+        };
+        let mut moved = Vec::new();
+        for (shard_index, shard) in self.shards.iter().enumerate() {
+            let mut shard = shard.write();
+            moved.extend(shard.drain_matching(|key| self.get_shard_index(key) != shard_index));
+        }
+        self.shards.truncate(migration.shard_count);
+        for (key, entry) in moved {
+            let mut shard = self.get_shard(&key);
+            self.place_entry(&mut shard, key, entry);
+        }
+    }
+
+    /// Whether keys are still being moved by a migration, see `start_migration`.
+    pub fn is_migrating(&self) -> bool {
+        self.migration.is_some()
+    }
+
+    /// Same as `get_shard_index`, first moving the key to that shard if a migration is running
+    /// and hasn't moved it yet. Called by every operation on a given key before it locks its
+    /// shard.
+    fn route<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let shard_index = self.get_shard_index(key);
+        if let Some(from) = self.previous_index(key).filter(|&from| from != shard_index) {
+            self.move_key(key, from, shard_index);
+        }
+        shard_index
+    }
+
+    /// Shard the key was placed in before the running migration, None if there is none.
+    fn previous_index<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let migration = self.migration.as_ref()?;
+        Some(match migration.unpinned.get(key) {
+            Some(&shard_index) => shard_index,
+            None => self
+                .pinned_index(key)
+                .unwrap_or_else(|| Self::ring_owner(&migration.ring, self.hash(key))),
+        })
+    }
+
+    /// Moves the entry of the key from one shard to another, with both locked, unless the
+    /// destination already holds the key. Returns whether an entry was moved.
+    fn move_key<Q>(&self, key: &Q, from: usize, to: usize) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (mut from_shard, mut to_shard) = if from < to {
+            let from_shard = self.shards[from].write();
+            (from_shard, self.shards[to].write())
+        } else {
+            let to_shard = self.shards[to].write();
+            (self.shards[from].write(), to_shard)
+        };
+        let Some(stored_key) = from_shard.entry(key).map(|(key, _)| key.clone()) else {
+            return false;
+        };
+        if to_shard.contains(key) {
+            return false;
+        }
+        let Some(entry) = from_shard.take(key) else {
+            return false;
+        };
+        self.place_entry(&mut to_shard, stored_key, entry);
+        true
+    }
+
+    /// Inserts an entry moved from another shard or replayed, which isn't logged itself, logging
+    /// the removal of the entries the shard evicted to make room for it.
+    fn place_entry(&self, shard: &mut Shard<K, V>, key: K, entry: Entry<V>) {
+        shard.insert_entry(key, entry);
+        let evicted = shard.take_evicted();
+        self.log_evicted(&evicted);
+    }
+
+    /// Pins the key to the given shard, bypassing its hash: from then on the key is read from
+    /// and written to that shard, and any value it holds moves there. Pins last until the store
+    /// is cleared or the shard removed, and aren't persisted.
+    ///
+    /// Returns false without doing anything if there is no such shard.
+    pub fn pin(&self, key: K, shard_index: usize) -> bool {
+        let shard_count = self
+            .migration
+            .as_ref()
+            .map_or(self.shards.len(), |migration| migration.shard_count);
+        if shard_index >= shard_count {
+            return false;
+        }
+
+        let current_index = self.route(&key);
+        if current_index != shard_index {
+            let entry = self.shards[current_index].write().take(&key);
+            if let Some(entry) = entry {
+                self.place_entry(&mut self.shards[shard_index].write(), key.clone(), entry);
+            }
+        }
+        self.pinned
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, shard_index);
+        true
+    }
+
+    /// Makes `set`, `set_if_absent`, `set_many` and `import` store keys expiring after
+    /// `default_ttl`, or without expiry when None, the default.
+    pub fn set_default_ttl(&mut self, default_ttl: Option<Duration>) {
+        self.default_ttl = default_ttl;
+    }
+
+    /// TTL of the keys stored by `set` and the like, None if they don't expire.
+    pub fn default_ttl(&self) -> Option<Duration> {
+        self.default_ttl
+    }
+
+    /// Caps the summed size of the keys and values across all shards at `max_bytes`, or lifts
+    /// the cap when None, the default. Writes going over it evict the least recently used
+    /// entries of the largest shard until the store fits again, starting with the entries
+    /// already held when the cap is lowered.
+    pub fn set_max_bytes(&mut self, max_bytes: Option<usize>) {
+        self.max_bytes = max_bytes;
+        self.evict_over_budget();
+    }
+
+    /// Caps the number of keys across all shards at `max_keys`, or lifts the cap when None, the
+    /// default. Once it's reached, `set` and every other write creating keys refuse to while
+    /// existing keys can still be overwritten, `rename` and `swap` only ever moving keys.
+    /// Expired keys count until they're swept, and concurrent writes to different shards may
+    /// take the store slightly past the cap.
+    pub fn set_max_keys(&mut self, max_keys: Option<usize>) {
+        self.max_keys = max_keys;
+    }
+
+    /// Whether the key can be written to the shard without going over `max_keys`.
+    fn has_room_for<Q>(&self, shard: &Shard<K, V>, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.max_keys {
+            Some(max_keys) => shard.contains(key) || self.stored_keys() < max_keys,
+            None => true,
+        }
+    }
+
+    /// Whether the key can be written without going over `max_keys`, eg. to check a write
+    /// before doing anything else for it.
+    pub fn has_room_for_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.has_room_for(&self.read_shard(key), key)
+    }
+
+    /// Same as `has_room_for` for several keys written at once, the keys already held not
+    /// counting. Locks the shards one at a time, so the answer may be stale by the time the
+    /// keys are written.
+    fn has_room_for_all<'a>(&self, keys: impl IntoIterator<Item = &'a K>) -> bool
+    where
+        K: 'a,
+    {
+        let Some(max_keys) = self.max_keys else {
+            return true;
+        };
+        let new_keys: HashSet<&K> = keys
+            .into_iter()
+            .filter(|key| !self.read_shard(*key).contains(*key))
+            .collect();
+        self.stored_keys() + new_keys.len() <= max_keys
+    }
+
+    /// Number of entries held across all shards, counting expired ones not swept yet. Read
+    /// without locking any shard.
+    fn stored_keys(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.stored.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Summed size of the keys and values across all shards, see `ByteSize`.
+    pub fn total_bytes(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().bytes()).sum()
+    }
+
+    /// Approximate memory used by each shard, see `Shard::estimated_bytes`.
+    pub fn estimated_shard_bytes(&self) -> Vec<usize> {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().estimated_bytes())
+            .collect()
+    }
+
+    /// Approximate memory used by the whole store, for capacity planning. Counts the keys and
+    /// values along with the bookkeeping of every entry, see `Shard::estimated_bytes`.
+    pub fn estimated_bytes(&self) -> usize {
+        self.estimated_shard_bytes().iter().sum()
+    }
+
+    /// Evicts entries until the store fits in `max_bytes`, if set. Must be called without any
+    /// shard locked.
+    fn evict_over_budget(&self) {
+        let Some(max_bytes) = self.max_bytes else {
+            return;
+        };
+        loop {
+            let sizes: Vec<usize> = self
+                .shards
+                .iter()
+                .map(|shard| shard.read().bytes())
+                .collect();
+            if sizes.iter().sum::<usize>() <= max_bytes {
+                return;
+            }
+            let largest = (0..sizes.len())
+                .max_by_key(|&i| sizes[i])
+                .unwrap_or_default();
+            let mut shard = self.shards[largest].write();
+            match shard.evict_lru() {
+                Some(key) => self.log_evicted(&[key]),
+                None => return,
+            }
+        }
+    }
+
+    /// Keeps the last `capacity` writes applied from now on, see `audit_log`. A capacity of 0
+    /// stops recording them.
+    pub fn enable_audit(&mut self, capacity: usize) {
+        self.audit = (capacity > 0).then(|| AuditLog::new(capacity));
+    }
+
+    /// Last writes applied since `enable_audit` was called, oldest first: keys set, deleted or
+    /// evicted and stores cleared. Empty if auditing isn't enabled.
+    pub fn audit_log(&self) -> Vec<AuditEntry<K>> {
+        self.audit
+            .as_ref()
+            .map(|audit| audit.entries())
+            .unwrap_or_default()
+    }
+
+    /// Syncs the writes buffered by the WAL to disk. Does nothing if no WAL is attached.
+    pub fn flush_wal(&self) -> io::Result<()> {
+        match &self.wal {
+            Some(attached) => attached.wal.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Copy of the entry of the key before a write, for `commit` to put back if the WAL can't
+    /// record the write. Only taken when a WAL is attached, since writes can't fail otherwise.
+    fn backup<Q>(&self, shard: &Shard<K, V>, key: &Q) -> Option<Entry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.wal.as_ref()?;
+        shard.entry(key).map(|(_, entry)| entry.clone())
+    }
+
+    /// Records the state the written keys were left in to the WAL and the audit log, if any,
+    /// after the removal of the entries their shard evicted to make room for them. Called with
+    /// the shards still locked, so the logs see the writes to a key in the order they were
+    /// applied, and the ops of every shard are synced together.
+    ///
+    /// If the WAL can't record them, every written key is put back to the entry it held before
+    /// and the error returned: the write is undone rather than left applied without being
+    /// durable. Evicted entries stay removed, their removal staying buffered for the next sync.
+    fn commit<S>(&self, shards: &mut [(S, Written<K, V>)]) -> io::Result<()>
+    where
+        S: DerefMut<Target = Shard<K, V>>,
+    {
+        let mut evicted = Vec::new();
+        for (shard, _) in shards.iter_mut() {
+            evicted.extend(shard.take_evicted());
+        }
+        if self.wal.is_none() && self.audit.is_none() {
+            return Ok(());
+        }
+
+        let mut ops: Vec<WalOp> = Vec::new();
+        if let Some(attached) = &self.wal {
+            ops.extend(evicted.iter().map(|key| (attached.to_op)(key, None)));
+            for (shard, written) in shards.iter() {
+                ops.extend(
+                    written
+                        .iter()
+                        .map(|(key, _)| (attached.to_op)(key, shard.entry(key).map(|(_, e)| e))),
+                );
+            }
+        }
+        let logged = self.log(&ops);
+        self.audit_removed(&evicted);
+        if let Err(err) = logged {
+            for (shard, written) in shards.iter_mut() {
+                for (key, backup) in written.drain(..).rev() {
+                    self.restore(shard, key, backup);
+                }
+            }
+            return Err(err);
+        }
+
+        if let Some(audit) = &self.audit {
+            for (shard, written) in shards.iter() {
+                for (key, _) in written {
+                    let op = match shard.entry(key) {
+                        Some(_) => AuditOp::Set,
+                        None => AuditOp::Delete,
+                    };
+                    audit.record(op, Some(key.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Same as `commit` for a single key of a single shard, found as it's stored. Only the
+    /// evicted entries are recorded if the key is missing.
+    fn log_key<Q>(
+        &self,
+        shard: &mut Shard<K, V>,
+        key: &Q,
+        backup: Option<Entry<V>>,
+    ) -> io::Result<()>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let written = shard
+            .entry(key)
+            .map(|(key, _)| (key.clone(), backup))
+            .into_iter()
+            .collect();
+        self.commit(&mut [(shard, written)])
+    }
+
+    /// Same as `commit` for a single key removed from the shard.
+    fn log_removed(
+        &self,
+        shard: &mut Shard<K, V>,
+        key: K,
+        backup: Option<Entry<V>>,
+    ) -> io::Result<()> {
+        self.commit(&mut [(shard, vec![(key, backup)])])
+    }
+
+    /// Records the removal of entries evicted to keep the store within its limits. The evictions
+    /// stand even if the WAL can't record them, the ops staying buffered for the next sync.
+    fn log_evicted(&self, keys: &[K]) {
+        if let Some(attached) = &self.wal {
+            let ops: Vec<WalOp> = keys.iter().map(|key| (attached.to_op)(key, None)).collect();
+            // Already reported by `log`, and there's no write to fail
+            let _ = self.log(&ops);
+        }
+        self.audit_removed(keys);
+    }
+
+    fn audit_removed(&self, keys: &[K]) {
+        if let Some(audit) = &self.audit {
+            for key in keys {
+                audit.record(AuditOp::Delete, Some(key.clone()));
+            }
+        }
+    }
+
+    /// Puts the key back to the entry it held before a write the WAL couldn't record, None
+    /// meaning it was missing, and buffers the op recording it after those of the entries evicted
+    /// to make room for it. The op of the write stays buffered in the WAL, which may get it to
+    /// disk later, so this op must follow it.
+    fn restore(&self, shard: &mut Shard<K, V>, key: K, backup: Option<Entry<V>>) {
+        shard.take(&key);
+        if let Some(entry) = backup {
+            shard.insert_entry(key.clone(), entry);
+        }
+        let evicted = shard.take_evicted();
+        if let Some(attached) = &self.wal {
+            let ops = evicted
+                .iter()
+                .map(|key| (attached.to_op)(key, None))
+                .chain([(attached.to_op)(
+                    &key,
+                    shard.entry(&key).map(|(_, entry)| entry),
+                )]);
+            for op in ops {
+                if let Err(err) = attached.wal.record(&op) {
+                    log::error!("Failed to record an undone write to the WAL: {}", err);
+                }
+            }
+        }
+        self.audit_removed(&evicted);
+    }
+
+    /// Appends the ops to the WAL, if any, synced together.
+    fn log(&self, ops: &[WalOp]) -> io::Result<()> {
+        let Some(attached) = &self.wal else {
+            return Ok(());
+        };
+        if ops.is_empty() {
+            return Ok(());
+        }
+        attached.wal.append_all(ops).map_err(|err| {
+            log::error!("Failed to append to the WAL: {}", err);
+            io::Error::new(err.kind(), format!("Failed to append to the WAL: {}", err))
+        })
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn hash_algo(&self) -> HashAlgo {
+        self.hash_algo
+    }
+
+    fn hash<T: Hash + ?Sized>(&self, t: &T) -> u64 {
+        self.hash_algo.hash(t)
+    }
+
+    /// Locks and returns the shard owning the key, leaving every other shard available.
+    fn get_shard<Q>(&self, key: &Q) -> ShardWriteGuard<'_, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let shard_index = self.route(key);
+        self.shards[shard_index].write()
+    }
+
+    /// Same as `get_shard`, only locking the shard for reading.
+    fn read_shard<Q>(&self, key: &Q) -> RwLockReadGuard<'_, Shard<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let shard_index = self.route(key);
+        self.shards[shard_index].read()
+    }
+
+    /// Finds the shard owning the key: the shard it's pinned to if any, the first virtual node
+    /// clockwise from the key's hash otherwise, wrapping around to the start of the ring.
+    pub fn get_shard_index<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.pinned_index(key)
+            .unwrap_or_else(|| Self::ring_owner(&self.ring, self.hash(key)))
+    }
+
+    fn pinned_index<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.pinned
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key)
+            .copied()
+    }
+
+    /// Shard owning the first virtual node of the ring at or after the hash, wrapping around to
+    /// the start of the ring.
+    fn ring_owner(ring: &BTreeMap<u64, usize>, hash: u64) -> usize {
+        ring.range(hash..)
+            .next()
+            .or_else(|| ring.iter().next())
+            .map(|(_, &shard_index)| shard_index)
+            .expect("the hash ring has no shards")
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut shard = self.get_shard(key);
+        shard.get(key)
+    }
+
+    /// Same as `get`, returning a copy of the whole entry.
+    pub fn get_entry<Q>(&self, key: &Q) -> Option<Entry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut shard = self.get_shard(key);
+        shard.get_entry(key)
+    }
+
+    /// Same as `get`, along with the version of the value, which goes up by one on every
+    /// write to the key.
+    pub fn get_versioned<Q>(&self, key: &Q) -> Option<(V, u64)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut shard = self.get_shard(key);
+        shard.get_versioned(key)
+    }
+
+    /// Same as `get`, along with the type of the value.
+    pub fn get_typed<Q>(&self, key: &Q) -> Option<(V, ValueType)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut shard = self.get_shard(key);
+        shard.get_typed(key)
+    }
+
+    /// Version of the value of the key, None if it's missing.
+    pub fn version<Q>(&self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let shard = self.read_shard(key);
+        shard.version(key)
+    }
+
+    /// Type of the value of the key, None if it's missing.
+    pub fn value_type<Q>(&self, key: &Q) -> Option<ValueType>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let shard = self.read_shard(key);
+        shard.value_type(key)
+    }
+
+    /// Tags the value of the key with a type, returning false if the key is missing. The change
+    /// isn't recorded to the WAL or the audit log, writes of typed values go through
+    /// `set_tagged` instead.
+    pub fn set_value_type<Q>(&self, key: &Q, value_type: ValueType) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut shard = self.get_shard(key);
+        shard.set_value_type(key, value_type)
+    }
+
+    /// Tags the value of the key with an encoding, None clearing it, returning false if the key
+    /// is missing. Like the type, the encoding is dropped whenever the value is overwritten, and
+    /// the change isn't logged, see `set_value_type`.
+    pub fn set_encoding<Q>(&self, key: &Q, encoding: Option<String>) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut shard = self.get_shard(key);
+        shard.set_encoding(key, encoding)
+    }
+
+    /// Groups keys by the index of the shard owning them.
+    fn group_by_shard<'a>(&self, keys: &'a [K]) -> Vec<Vec<&'a K>> {
+        let mut groups = vec![Vec::new(); self.shards.len()];
+        for key in keys {
+            groups[self.route(key)].push(key);
+        }
+        groups
+    }
+
+    /// Looks up several keys at once, locking each shard a single time.
+    /// Keys that don't exist are left out of the returned map.
+    pub fn get_many(&self, keys: &[K]) -> HashMap<K, V> {
+        let mut values = HashMap::new();
+        for (shard_index, group) in self.group_by_shard(keys).into_iter().enumerate() {
+            if group.is_empty() {
+                continue;
+            }
+            let mut shard = self.shards[shard_index].write();
+            for key in group {
+                if let Some(value) = shard.get(key) {
+                    values.insert(key.clone(), value);
+                }
+            }
+        }
+        values
+    }
+
+    /// Checks several keys for presence at once, locking each shard a single time. Every key
+    /// is in the returned map, true if it exists.
+    pub fn contains_many(&self, keys: &[K]) -> HashMap<K, bool> {
+        let mut presence = HashMap::new();
+        for (shard_index, group) in self.group_by_shard(keys).into_iter().enumerate() {
+            if group.is_empty() {
+                continue;
+            }
+            let shard = self.shards[shard_index].read();
+            for key in group {
+                presence.insert(key.clone(), shard.contains(key));
+            }
+        }
+        presence
+    }
+
+    /// Remaining lifetime of the key in seconds, rounded up, or -1 if it never expires.
+    /// Returns None if the key is missing.
+    pub fn ttl<Q>(&self, key: &Q) -> Option<i64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let shard = self.read_shard(key);
+        shard.ttl(key).map(|ttl| match ttl {
+            Some(ttl) => ttl.as_millis().div_ceil(1000) as i64,
+            None => -1,
+        })
+    }
+
+    /// Merges the live entries of every shard into a single map of keys to values.
+    pub fn export(&self) -> HashMap<K, V> {
+        let mut data = HashMap::new();
+        for shard in &self.shards {
+            let shard = shard.read();
+            data.extend(
+                shard
+                    .entries()
+                    .map(|(key, entry)| (key.clone(), entry.value.clone())),
+            );
+        }
+        data
+    }
+
+    /// Live entries of the shard at `shard_index`, empty if there is no such shard. Lets the
+    /// store be walked one shard at a time, without copying it whole like `export`.
+    pub fn shard_entries(&self, shard_index: usize) -> Vec<(K, V)> {
+        match self.shards.get(shard_index) {
+            Some(shard) => shard
+                .read()
+                .entries()
+                .map(|(key, entry)| (key.clone(), entry.value.clone()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Keys of the live entries written to after `since`.
+    pub fn changed_since(&self, since: SystemTime) -> Vec<K> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .entries()
+                    .filter(|(_, entry)| entry.modified_at > since)
+                    .map(|(key, _)| key.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Returns up to `limit` live entries starting at position `cursor`, along with the cursor
+    /// of the next page, or None once every entry has been returned.
+    ///
+    /// Entries are walked shard by shard, ordered by key within each shard, so successive pages
+    /// cover the store exactly once as long as it isn't modified in between.
+    pub fn scan(&self, cursor: usize, limit: usize) -> (Vec<(K, V)>, Option<usize>)
+    where
+        K: Ord,
+    {
+        let mut page = Vec::new();
+        // Number of entries in the shards before the current one
+        let mut position = 0;
+        for shard in &self.shards {
+            let shard = shard.read();
+            let len = shard.len();
+            if position + len > cursor && page.len() < limit {
+                let mut entries: Vec<(K, V)> = shard
+                    .entries()
+                    .map(|(key, entry)| (key.clone(), entry.value.clone()))
+                    .collect();
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                let start = cursor.saturating_sub(position);
+                let remaining = limit - page.len();
+                page.extend(entries.into_iter().skip(start).take(remaining));
+            }
+            position += len;
+        }
+
+        let next_cursor = cursor + page.len();
+        if next_cursor < position {
+            (page, Some(next_cursor))
+        } else {
+            (page, None)
+        }
+    }
+
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let shard = self.read_shard(key);
+        shard.contains(key)
+    }
+
+    /// Total number of entries across all shards.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.read().len() == 0)
+    }
+
+    /// Number of entries in each shard, indexed by shard index.
+    pub fn shard_sizes(&self) -> Vec<usize> {
+        self.shards.iter().map(|shard| shard.read().len()).collect()
+    }
+
+    /// Picks a live entry at random, each one being as likely to come up: the shard is picked
+    /// with a probability proportional to its size, then the entry within it. None if the store
+    /// is empty.
+    pub fn random_entry(&self) -> Option<(K, V)> {
+        let sizes = self.shard_sizes();
+        let total: usize = sizes.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut index = random_below(total);
+        for (shard, size) in self.shards.iter().zip(sizes) {
+            if index < size {
+                let shard = shard.read();
+                // Entries may have expired since the sizes were taken
+                let entry = shard
+                    .entries()
+                    .nth(index)
+                    .or_else(|| shard.entries().next());
+                return entry.map(|(key, entry)| (key.clone(), entry.value.clone()));
+            }
+            index -= size;
+        }
+        None
+    }
+
+    /// Operations served by each shard, index i holding the counters of shard i.
+    pub fn shard_ops(&self) -> Vec<ShardOps> {
+        self.shards.iter().map(|shard| shard.read().ops()).collect()
+    }
+
+    /// Number of times a write lock on each shard had to wait for another thread to release the
+    /// shard, index i holding the count of shard i. Counts growing fast mean threads keep
+    /// queueing for the same shards, which more shards would spread out.
+    pub fn lock_contention(&self) -> Vec<u64> {
+        self.shards
+            .iter()
+            .map(|shard| shard.contended.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Positions of the virtual nodes on the hash ring, each with the index of the shard owning
+    /// it, sorted by position. A key belongs to the first node at or after its hash.
+    pub fn ring(&self) -> Vec<(u64, usize)> {
+        self.ring
+            .iter()
+            .map(|(&position, &shard_index)| (position, shard_index))
+            .collect()
+    }
+
+    /// Keys stored in a shard other than the one they hash to, which reads and writes can't
+    /// reach anymore. Always empty unless placement is broken, eg. by a faulty reshard. Keys a
+    /// migration hasn't moved yet are reachable in the shard they were placed in before.
+    pub fn verify_placement(&self) -> Vec<K> {
+        let mut misplaced = Vec::new();
+        for (shard_index, shard) in self.shards.iter().enumerate() {
+            let shard = shard.read();
+            misplaced.extend(
+                shard
+                    .entries()
+                    .map(|(key, _)| key)
+                    .filter(|key| {
+                        self.get_shard_index(*key) != shard_index
+                            && self.previous_index(*key) != Some(shard_index)
+                    })
+                    .cloned(),
+            );
+        }
+        misplaced
+    }
+
+    /// Stores the key in the given shard regardless of its hash, to break placement in tests.
+    #[cfg(test)]
+    fn set_in_shard(&self, shard_index: usize, key: K, value: V) {
+        self.shards[shard_index].write().set(key, value);
+    }
+
+    /// Stores the value, returning the index of the shard it went to and whether the key was
+    /// created (false if an existing value was overwritten). The key expires after the default
+    /// TTL, if one is set. Fails without storing anything if the key is new and the store
+    /// already holds `max_keys` keys, or if the WAL can't record the write.
+    pub fn set(&self, key: K, value: V) -> Result<(usize, bool), WriteError> {
+        self.set_with_expiry(key, value, self.default_ttl)
+    }
+
+    /// Same as `set`, with the key expiring after `ttl`.
+    pub fn set_with_ttl(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<(usize, bool), WriteError> {
+        self.set_with_expiry(key, value, Some(ttl))
+    }
+
+    /// Same as `set`, with the key expiring after `ttl`, or never when None whatever the
+    /// default TTL.
+    pub fn set_with_expiry(
+        &self,
+        key: K,
+        value: V,
+        ttl: Option<Duration>,
+    ) -> Result<(usize, bool), WriteError> {
+        self.set_tagged(key, value, ttl, ValueTags::default())
+    }
+
+    /// Same as `set_with_expiry`, tagging the value with `tags` in the same write so it's
+    /// logged once, type and encoding included.
+    pub fn set_tagged(
+        &self,
+        key: K,
+        value: V,
+        ttl: Option<Duration>,
+        tags: ValueTags,
+    ) -> Result<(usize, bool), WriteError> {
+        let shard_index = self.route(&key);
+        let mut shard = self.shards[shard_index].write();
+        if !self.has_room_for(&shard, &key) {
+            return Err(WriteError::StoreFull);
+        }
+        let backup = self.backup(&shard, &key);
+        let created = Self::store(&mut shard, key.clone(), value, ttl);
+        Self::tag(&mut shard, &key, tags);
+        self.log_key(&mut shard, &key, backup)?;
+        drop(shard);
+        self.evict_over_budget();
+        Ok((shard_index, created))
+    }
+
+    fn store(shard: &mut Shard<K, V>, key: K, value: V, ttl: Option<Duration>) -> bool {
+        match ttl {
+            Some(ttl) => shard.set_with_ttl(key, value, ttl),
+            None => shard.set(key, value),
+        }
+    }
+
+    /// Tags the value just stored under the key, before it's logged.
+    fn tag(shard: &mut Shard<K, V>, key: &K, tags: ValueTags) {
+        shard.set_value_type(key, tags.value_type);
+        shard.set_encoding(key, tags.encoding);
+    }
+
+    /// Stores the value only if the key is absent, returning whether it was stored. The key
+    /// expires after the default TTL, if one is set. Fails without storing anything if the
+    /// store already holds `max_keys` keys, or if the WAL can't record the write.
+    pub fn set_if_absent(&self, key: K, value: V) -> Result<bool, WriteError> {
+        self.set_if_absent_with_expiry(key, value, self.default_ttl)
+    }
+
+    /// Same as `set_if_absent`, with the key expiring after `ttl`.
+    pub fn set_if_absent_with_ttl(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<bool, WriteError> {
+        self.set_if_absent_with_expiry(key, value, Some(ttl))
+    }
+
+    /// Same as `set_if_absent`, with the key expiring after `ttl`, or never when None whatever
+    /// the default TTL.
+    pub fn set_if_absent_with_expiry(
+        &self,
+        key: K,
+        value: V,
+        ttl: Option<Duration>,
+    ) -> Result<bool, WriteError> {
+        self.set_if_absent_tagged(key, value, ttl, ValueTags::default())
+    }
+
+    /// Same as `set_if_absent_with_expiry`, tagging the value with `tags` in the same write,
+    /// see `set_tagged`.
+    pub fn set_if_absent_tagged(
+        &self,
+        key: K,
+        value: V,
+        ttl: Option<Duration>,
+        tags: ValueTags,
+    ) -> Result<bool, WriteError> {
+        let mut shard = self.get_shard(&key);
+        if !self.has_room_for(&shard, &key) {
+            return Err(WriteError::StoreFull);
+        }
+        let backup = self.backup(&shard, &key);
+        let stored = shard.set_if_absent(key.clone(), value, ttl);
+        if stored {
+            Self::tag(&mut shard, &key, tags);
+            self.log_key(&mut shard, &key, backup)?;
+        }
+        drop(shard);
+        self.evict_over_budget();
+        Ok(stored)
+    }
+
+    /// Stores the value and returns the previous one, None if the key was absent. Fails like
+    /// `set` if the key is new and the store is full, or if the WAL can't record the write.
+    pub fn get_set(&self, key: K, value: V) -> Result<Option<V>, WriteError> {
+        let mut shard = self.get_shard(&key);
+        if !self.has_room_for(&shard, &key) {
+            return Err(WriteError::StoreFull);
+        }
+        let backup = self.backup(&shard, &key);
+        let previous = shard.get_set(key.clone(), value);
+        self.log_key(&mut shard, &key, backup)?;
+        drop(shard);
+        self.evict_over_budget();
+        Ok(previous)
+    }
+
+    /// Inserts several key-value pairs at once, locking each shard a single time. None of them
+    /// are stored if the new keys would take the store over `max_keys`, or if the WAL can't
+    /// record them, the shards staying locked until they're recorded.
+    ///
+    /// Returns how many pairs were written to each shard index.
+    pub fn set_many(&self, pairs: Vec<(K, V)>) -> Result<HashMap<usize, usize>, WriteError> {
+        self.set_many_tagged(
+            pairs
+                .into_iter()
+                .map(|(key, value)| (key, value, ValueTags::default()))
+                .collect(),
+        )
+    }
+
+    /// Same as `set_many`, tagging each value with its tags in the same write, see
+    /// `set_tagged`.
+    pub fn set_many_tagged(
+        &self,
+        pairs: Vec<(K, V, ValueTags)>,
+    ) -> Result<HashMap<usize, usize>, WriteError> {
+        if !self.has_room_for_all(pairs.iter().map(|(key, _, _)| key)) {
+            return Err(WriteError::StoreFull);
+        }
+        let mut groups = vec![Vec::new(); self.shards.len()];
+        for (key, value, tags) in pairs {
+            groups[self.route(&key)].push((key, value, tags));
+        }
+
+        let mut counts = HashMap::new();
+        // Shards are locked in index order, so concurrent batches can't deadlock
+        let mut locked = Vec::new();
+        for (shard_index, group) in groups.into_iter().enumerate() {
+            if group.is_empty() {
+                continue;
+            }
+            counts.insert(shard_index, group.len());
+            let mut shard = self.shards[shard_index].write();
+            let mut written = Vec::with_capacity(group.len());
+            for (key, value, tags) in group {
+                let backup = self.backup(&shard, &key);
+                Self::store(&mut shard, key.clone(), value, self.default_ttl);
+                Self::tag(&mut shard, &key, tags);
+                written.push((key, backup));
+            }
+            locked.push((shard, written));
+        }
+        self.commit(&mut locked)?;
+        drop(locked);
+        self.evict_over_budget();
+        Ok(counts)
+    }
+
+    /// Inserts every pair of `data`, returning how many were stored. When `overwrite` is
+    /// false, keys already holding a live value are left untouched and not counted. Like
+    /// `set_many`, nothing is stored if the new keys would take the store over `max_keys` or
+    /// if the WAL can't record them.
+    pub fn import(&self, data: HashMap<K, V>, overwrite: bool) -> Result<usize, WriteError> {
+        if !self.has_room_for_all(data.keys()) {
+            return Err(WriteError::StoreFull);
+        }
+        let mut groups = vec![Vec::new(); self.shards.len()];
+        for (key, value) in data {
+            groups[self.route(&key)].push((key, value));
+        }
+
+        let mut imported = 0;
+        let mut locked = Vec::new();
+        for (shard_index, group) in groups.into_iter().enumerate() {
+            if group.is_empty() {
+                continue;
+            }
+            let mut shard = self.shards[shard_index].write();
+            let mut written = Vec::new();
+            for (key, value) in group {
+                if overwrite || !shard.contains(&key) {
+                    let backup = self.backup(&shard, &key);
+                    Self::store(&mut shard, key.clone(), value, self.default_ttl);
+                    written.push((key, backup));
+                    imported += 1;
+                }
+            }
+            locked.push((shard, written));
+        }
+        self.commit(&mut locked)?;
+        drop(locked);
+        self.evict_over_budget();
+        Ok(imported)
+    }
+
+    /// Replaces the value of an existing key, returning the previous one, or None without
+    /// writing anything if the key is missing. Fails if the WAL can't record the write.
+    pub fn update<Q>(&self, key: &Q, value: V) -> io::Result<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut shard = self.get_shard(key);
+        let backup = self.backup(&shard, key);
+        let previous = shard.update(key, value);
+        if previous.is_some() {
+            self.log_key(&mut shard, key, backup)?;
+        }
+        drop(shard);
+        self.evict_over_budget();
+        Ok(previous)
+    }
+
+    /// Replaces the value of the key with the one `patch` makes out of the current value, the
+    /// shard staying locked in between so no other write can slip in. Nothing is written if
+    /// `patch` fails.
+    ///
+    /// Returns None if the key is missing, otherwise the previous value or the error of `patch`,
+    /// or an error if the WAL can't record the write.
+    pub fn update_with<Q, E>(
+        &self,
+        key: &Q,
+        patch: impl FnOnce(V) -> Result<V, E>,
+    ) -> io::Result<Option<Result<V, E>>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut shard = self.get_shard(key);
+        let Some(current) = shard.get(key) else {
+            return Ok(None);
+        };
+        let backup = self.backup(&shard, key);
+        let updated = match patch(current) {
+            Ok(value) => match shard.update(key, value) {
+                Some(previous) => Ok(previous),
+                None => return Ok(None),
+            },
+            Err(err) => Err(err),
+        };
+        if updated.is_ok() {
+            self.log_key(&mut shard, key, backup)?;
+        }
+        drop(shard);
+        self.evict_over_budget();
+        Ok(Some(updated))
+    }
+
+    /// Resets the expiry of the key to `ttl` from now, or removes it when None, keeping its
+    /// value. Returns false if the key is missing, or an error if the WAL can't record the
+    /// write.
+    pub fn touch<Q>(&self, key: &Q, ttl: Option<Duration>) -> io::Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut shard = self.get_shard(key);
+        let backup = self.backup(&shard, key);
+        let touched = shard.expire(key, ttl);
+        if touched {
+            self.log_key(&mut shard, key, backup)?;
+        }
+        Ok(touched)
+    }
+
+    /// Moves the value of `from` to `to`, overwriting any value `to` held. The value keeps its
+    /// TTL, if any. Both shards are locked for the whole move, so no one sees the value under
+    /// both keys or under neither.
+    ///
+    /// Returns false without doing anything if `from` doesn't exist, or an error if the WAL
+    /// can't record the move, which is then undone.
+    pub fn rename<Q>(&self, from: &Q, to: &Q) -> io::Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        let from_index = self.route(from);
+        let to_index = self.route(to);
+
+        if from_index == to_index {
+            let mut shard = self.shards[from_index].write();
+            let (from_backup, to_backup) = (self.backup(&shard, from), self.backup(&shard, to));
+            let Some(entry) = shard.take(from) else {
+                return Ok(false);
+            };
+            shard.insert_entry(to.to_owned(), entry);
+            let written = vec![(from.to_owned(), from_backup), (to.to_owned(), to_backup)];
+            self.commit(&mut [(shard, written)])?;
+            return Ok(true);
+        }
+
+        // Lock in index order so concurrent renames between the same shards can't deadlock
+        let (mut from_shard, mut to_shard) = if from_index < to_index {
+            let from_shard = self.shards[from_index].write();
+            (from_shard, self.shards[to_index].write())
+        } else {
+            let to_shard = self.shards[to_index].write();
+            (self.shards[from_index].write(), to_shard)
+        };
+        let from_backup = self.backup(&from_shard, from);
+        let to_backup = self.backup(&to_shard, to);
+        let Some(entry) = from_shard.take(from) else {
+            return Ok(false);
+        };
+        to_shard.insert_entry(to.to_owned(), entry);
+        self.commit(&mut [
+            (from_shard, vec![(from.to_owned(), from_backup)]),
+            (to_shard, vec![(to.to_owned(), to_backup)]),
+        ])?;
+        Ok(true)
+    }
+
+    /// Exchanges the values of `a` and `b` along with their TTLs, atomically like `rename`. A
+    /// missing key takes the other one's value, the other one being removed.
+    ///
+    /// Returns false without doing anything if both keys are missing, or an error if the WAL
+    /// can't record the exchange, which is then undone.
+    pub fn swap<Q>(&self, a: &Q, b: &Q) -> io::Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        let a_index = self.route(a);
+        let b_index = self.route(b);
+
+        if a_index == b_index {
+            let mut shard = self.shards[a_index].write();
+            let (a_backup, b_backup) = (self.backup(&shard, a), self.backup(&shard, b));
+            let (a_entry, b_entry) = (shard.take(a), shard.take(b));
+            if a_entry.is_none() && b_entry.is_none() {
+                return Ok(false);
+            }
+            Self::put_taken(&mut shard, a, b_entry);
+            Self::put_taken(&mut shard, b, a_entry);
+            let written = vec![(a.to_owned(), a_backup), (b.to_owned(), b_backup)];
+            self.commit(&mut [(shard, written)])?;
+            return Ok(true);
+        }
+
+        // Lock in index order so concurrent swaps between the same shards can't deadlock
+        let (mut a_shard, mut b_shard) = if a_index < b_index {
+            let a_shard = self.shards[a_index].write();
+            (a_shard, self.shards[b_index].write())
+        } else {
+            let b_shard = self.shards[b_index].write();
+            (self.shards[a_index].write(), b_shard)
+        };
+        let (a_backup, b_backup) = (self.backup(&a_shard, a), self.backup(&b_shard, b));
+        let (a_entry, b_entry) = (a_shard.take(a), b_shard.take(b));
+        if a_entry.is_none() && b_entry.is_none() {
+            return Ok(false);
+        }
+        Self::put_taken(&mut a_shard, a, b_entry);
+        Self::put_taken(&mut b_shard, b, a_entry);
+        self.commit(&mut [
+            (a_shard, vec![(a.to_owned(), a_backup)]),
+            (b_shard, vec![(b.to_owned(), b_backup)]),
+        ])?;
+        Ok(true)
+    }
+
+    /// Stores an entry taken from another key under `key`, leaving `key` removed when there
+    /// was none.
+    fn put_taken<Q>(shard: &mut Shard<K, V>, key: &Q, entry: Option<Entry<V>>)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        if let Some(entry) = entry {
+            shard.insert_entry(key.to_owned(), entry);
+        }
+    }
+
+    /// Removes the key, returning its value, None if it was missing. Fails if the WAL can't
+    /// record the removal, which is then undone.
+    pub fn delete<Q>(&self, key: &Q) -> io::Result<Option<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut shard = self.get_shard(key);
+        let backup = self.backup(&shard, key);
+        let Some((key, value)) = shard.delete_entry(key) else {
+            return Ok(None);
+        };
+        self.log_removed(&mut shard, key, backup)?;
+        Ok(Some(value))
+    }
+
+    /// Deletes the key only if its current value equals `expected`, so a value changed by
+    /// someone else since it was read isn't lost. Fails if the WAL can't record the removal,
+    /// which is then undone.
+    pub fn delete_if<Q>(&self, key: &Q, expected: &V) -> io::Result<DeleteIfResult>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: PartialEq,
+    {
+        let mut shard = self.get_shard(key);
+        match shard.entry(key) {
+            None => return Ok(DeleteIfResult::NotFound),
+            Some((_, entry)) if entry.value != *expected => return Ok(DeleteIfResult::Mismatch),
+            Some(_) => {}
+        }
+        let backup = self.backup(&shard, key);
+        if let Some((key, _)) = shard.delete_entry(key) {
+            self.log_removed(&mut shard, key, backup)?;
+        }
+        Ok(DeleteIfResult::Deleted)
+    }
+
+    /// Deletes several keys at once, locking each shard a single time. Returns how many keys
+    /// were actually removed, missing ones not being counted, or an error if the WAL can't
+    /// record the removals, none of which is then made.
+    pub fn delete_many(&self, keys: &[K]) -> io::Result<usize> {
+        let mut removed = 0;
+        let mut locked = Vec::new();
+        for (shard_index, group) in self.group_by_shard(keys).into_iter().enumerate() {
+            if group.is_empty() {
+                continue;
+            }
+            let mut shard = self.shards[shard_index].write();
+            let mut written = Vec::new();
+            for key in group {
+                let backup = self.backup(&shard, key);
+                if shard.delete(key).is_some() {
+                    written.push((key.clone(), backup));
+                    removed += 1;
+                }
+            }
+            locked.push((shard, written));
+        }
+        self.commit(&mut locked)?;
+        Ok(removed)
+    }
+
+    /// Empties every shard, returning how many entries were removed. The shards are all locked
+    /// until the WAL has recorded the clear, and left untouched if it can't.
+    pub fn clear(&self) -> io::Result<usize> {
+        let mut shards: Vec<_> = self.shards.iter().map(ShardLock::write).collect();
+        if let Err(err) = self.log(&[WalOp::Clear]) {
+            // The clear stays buffered, so the entries are recorded again after it
+            if let Some(attached) = &self.wal {
+                for shard in &shards {
+                    for (key, entry) in shard.entries() {
+                        let _ = attached.wal.record(&(attached.to_op)(key, Some(entry)));
+                    }
+                }
+            }
+            return Err(err);
+        }
+        let removed = shards.iter_mut().map(|shard| shard.clear()).sum();
+        drop(shards);
+        self.pinned
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        if let Some(audit) = &self.audit {
+            audit.record(AuditOp::Clear, None);
+        }
+        Ok(removed)
+    }
+
+    /// Removes the expired entries of every shard, returning how many were removed. Expired
+    /// entries are otherwise only dropped when their key is accessed again.
+    pub fn sweep_expired(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.write().sweep_expired())
+            .sum()
+    }
+}
+
+impl ShardManager {
+    /// Makes keys case-insensitive when enabled, off by default. Keys aren't folded by the
+    /// manager itself: callers pass the keys they get through `fold_key` before using them, and
+    /// keys stored before it was enabled keep their case.
+    pub fn set_case_insensitive_keys(&mut self, enabled: bool) {
+        self.case_insensitive_keys = enabled;
+    }
+
+    /// The key as it's stored: lowercased when keys are case-insensitive, as is otherwise.
+    pub fn fold_key(&self, key: String) -> String {
+        if self.case_insensitive_keys {
+            key.to_lowercase()
+        } else {
+            key
+        }
+    }
+
+    /// Whether each shard still serves writes and reads, found by writing, reading back and
+    /// removing a reserved key in it, without going through the WAL, the audit log or eviction.
+    /// A shard whose lock was poisoned by a writer that panicked is reported as failing, even
+    /// though later requests recover the lock.
+    pub fn check_shards(&self) -> Vec<bool> {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let poisoned = shard.shard.is_poisoned();
+                let probed = shard
+                    .write()
+                    .probe(HEALTH_CHECK_KEY.to_string(), "ok".to_string());
+                !poisoned && probed
+            })
+            .collect()
+    }
+
+    /// Number of live keys starting with `prefix`, across all shards.
+    pub fn count_prefix(&self, prefix: &str) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .read()
+                    .entries()
+                    .filter(|(key, _)| key.starts_with(prefix))
+                    .count()
+            })
+            .sum()
+    }
+
+    /// Removes every key starting with `prefix`, returning how many were removed. The matching
+    /// keys of a shard are collected before any is removed, and every shard stays locked until
+    /// the WAL has recorded the removals: if it can't, none is made and the error is returned.
+    pub fn delete_prefix(&self, prefix: &str) -> io::Result<usize> {
+        let mut removed = 0;
+        let mut locked = Vec::new();
+        for shard in &self.shards {
+            let mut shard = shard.write();
+            let written: Written<String, String> = shard
+                .drain_matching(|key| key.starts_with(prefix))
+                .into_iter()
+                .map(|(key, entry)| (key, self.wal.as_ref().map(|_| entry)))
+                .collect();
+            removed += written.len();
+            locked.push((shard, written));
+        }
+        self.commit(&mut locked)?;
+        Ok(removed)
+    }
+
+    /// Records every write from now on to `wal`.
+    pub fn attach_wal(&mut self, wal: Wal) {
+        self.wal = Some(AttachedWal {
+            wal,
+            to_op: |key, entry| match entry {
+                Some(entry) => WalOp::Set(PersistedEntry::new(key, entry)),
+                None => WalOp::Delete { key: key.clone() },
+            },
+        });
+    }
+
+    /// Applies the ops of the WAL at `path` in order, returning how many there were. The
+    /// replayed ops aren't recorded again.
+    pub fn replay_wal(&self, path: &Path) -> io::Result<usize> {
+        let ops = Wal::replay(path)?;
+        let replayed = ops.len();
+        for op in ops {
+            match op {
+                WalOp::Set(persisted) => {
+                    let key = persisted.key.clone();
+                    let mut shard = self.get_shard(&key);
+                    match persisted.into_entry() {
+                        Some(entry) => self.place_entry(&mut shard, key, entry),
+                        None => {
+                            shard.take(&key);
+                        }
+                    }
+                }
+                WalOp::Delete { key } => {
+                    self.get_shard(&key).take(&key);
+                }
+                WalOp::Clear => {
+                    for shard in &self.shards {
+                        shard.write().clear();
+                    }
+                }
+            }
+        }
+        Ok(replayed)
+    }
+
+    /// Length of the value of the key in bytes, None if it's missing.
+    pub fn value_len(&self, key: &str) -> Option<usize> {
+        let shard = self.read_shard(key);
+        shard.value_len(key)
+    }
+
+    /// Atomically adds `by` to the integer stored at `key` and returns the new value.
+    /// A missing key counts as 0, failing like `set` when the store is full or the WAL can't
+    /// record the write.
+    pub fn increment(&self, key: &str, by: i64) -> Result<Result<i64, IncrError>, WriteError> {
+        let mut shard = self.get_shard(key);
+        if !self.has_room_for(&shard, key) {
+            return Err(WriteError::StoreFull);
+        }
+        let backup = self.backup(&shard, key);
+        let incremented = shard.increment(key, by);
+        if incremented.is_ok() {
+            self.log_key(&mut shard, key, backup)?;
+        }
+        drop(shard);
+        self.evict_over_budget();
+        Ok(incremented)
+    }
+
+    /// Atomically appends `suffix` to the value of the key, creating it if it's missing, and
+    /// returns the length of the new value in bytes. Fails like `set` if the key is new and the
+    /// store is full, or if the WAL can't record the write.
+    pub fn append(&self, key: &str, suffix: &str) -> Result<usize, WriteError> {
+        let mut shard = self.get_shard(key);
+        if !self.has_room_for(&shard, key) {
+            return Err(WriteError::StoreFull);
+        }
+        let backup = self.backup(&shard, key);
+        let len = shard.append(key, suffix);
+        self.log_key(&mut shard, key, backup)?;
+        drop(shard);
+        self.evict_over_budget();
+        Ok(len)
+    }
+
+    /// Sets the key to `new` only if its current value equals `expected`, `None` meaning the
+    /// key must be absent. Returns whether the swap happened, failing like `set` if it would
+    /// create the key while the store is full, or if the WAL can't record the write.
+    pub fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<String>,
+        new: String,
+    ) -> Result<bool, WriteError> {
+        let mut shard = self.get_shard(key);
+        if !self.has_room_for(&shard, key) {
+            return Err(WriteError::StoreFull);
+        }
+        let backup = self.backup(&shard, key);
+        let swapped = shard.compare_and_swap(key, expected.as_deref(), new);
+        if swapped {
+            self.log_key(&mut shard, key, backup)?;
+        }
+        drop(shard);
+        self.evict_over_budget();
+        Ok(swapped)
+    }
+
+    /// Saves the store to `path` like `save_to_file`, then truncates the WAL, whose ops the
+    /// snapshot now holds. Recovering takes loading the snapshot and replaying the WAL, which
+    /// only holds the writes made after it.
+    ///
+    /// Takes `&mut self` so no write can slip in between the dump and the truncation.
+    pub fn snapshot(&mut self, path: &Path) -> io::Result<()> {
+        self.save_to_file(path)?;
+        match &self.wal {
+            Some(attached) => attached.wal.truncate(),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes every shard to `path` as JSON. The data is written to a temporary file first and
+    /// then moved over `path`, so a crash mid-save never leaves a truncated file behind.
+    pub fn save_to_file(&self, path: &Path) -> io::Result<()> {
+        let store = PersistedStore {
+            shards: self
+                .shards
+                .iter()
+                .map(|shard| {
+                    let shard = shard.read();
+                    shard
+                        .entries()
+                        .map(|(key, entry)| PersistedEntry::new(key, entry))
+                        .collect()
+                })
+                .collect(),
+            hash_algo: self.hash_algo,
+        };
+
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, &store)?;
+        writer.flush()?;
+        fs::rename(tmp_path, path)
+    }
+
+    /// Stores the pairs of a JSON file mapping keys to values, eg. to start from a known data
+    /// set. Keys the store already holds keep their value. Unlike the file of `save_to_file`,
+    /// the seed file is only ever read.
+    ///
+    /// Returns the number of keys seeded, or an error if they'd take the store over `max_keys`.
+    pub fn seed_from_file(&self, path: &Path) -> io::Result<usize> {
+        let reader = BufReader::new(File::open(path)?);
+        let data: HashMap<String, String> = serde_json::from_reader(reader)?;
+        let data = data
+            .into_iter()
+            .map(|(key, value)| (self.fold_key(key), value))
+            .collect();
+        Ok(self.import(data, false)?)
+    }
+
+    /// Rebuilds a manager from a file written by `save_to_file`, with the same number of
+    /// shards. Entries whose TTL elapsed while the store was on disk are skipped.
+    pub fn load_from_file(path: &Path) -> io::Result<Self> {
+        let store = Self::read_persisted(path)?;
+        let shard_manager =
+            ShardManager::with_hash_algo(store.shards.len().max(1), store.hash_algo);
+        shard_manager.insert_persisted(store);
+        Ok(shard_manager)
+    }
+
+    /// Adds the entries of a file written by `save_to_file` to this manager, which keeps its
+    /// own shards, hash algorithm and limits: each entry goes to the shard its key belongs to
+    /// here, and full shards evict their least recently used entries. Entries whose TTL elapsed
+    /// while the store was on disk are skipped.
+    ///
+    /// Returns the number of entries loaded.
+    pub fn load_entries_from_file(&self, path: &Path) -> io::Result<usize> {
+        let loaded = self.insert_persisted(Self::read_persisted(path)?);
+        self.evict_over_budget();
+        Ok(loaded)
+    }
+
+    fn read_persisted(path: &Path) -> io::Result<PersistedStore> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Inserts the live entries of the store as they are, without logging them, returning how
+    /// many there were.
+    fn insert_persisted(&self, store: PersistedStore) -> usize {
+        let mut inserted = 0;
+        for persisted in store.shards.into_iter().flatten() {
+            let key = persisted.key.clone();
+            if let Some(entry) = persisted.into_entry() {
+                let mut shard = self.get_shard(&key);
+                shard.insert_entry(key, entry);
+                // The file stays the record of what was loaded, so evictions aren't logged
+                shard.take_evicted();
+                inserted += 1;
+            }
+        }
+        inserted
+    }
+}
+
+thread_local! {
+    /// State of the xorshift generator behind `random_below`, seeded once per thread from the
+    /// random keys of a `RandomState` so no random number generator crate is needed. Never 0,
+    /// which xorshift would keep returning.
+    static RANDOM_STATE: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
 }
 
-impl ShardManager {
-    pub fn new(shard_count: usize) -> Self {
-        let mut shards = Vec::with_capacity(shard_count);
-        for _ in 0..shard_count {
-            shards.push(Shard::new());
+/// Random number below `bound`, which must not be 0. Only used to sample entries, so neither
+/// the predictability of xorshift nor the slight bias of the modulo matter.
+fn random_below(bound: usize) -> usize {
+    RANDOM_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        (x % bound as u64) as usize
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::WalOptions;
+    use std::collections::HashSet;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    #[test]
+    fn test_shard_manager_new() {
+        let shard_count = 4;
+        let shard_manager: ShardManager = ShardManager::new(shard_count);
+        assert_eq!(shard_manager.shards.len(), shard_count);
+        assert_eq!(shard_manager.shard_count(), shard_count);
+    }
+
+    #[test]
+    fn test_shard_manager_with_integer_values() {
+        let shard_manager: ShardManager<String, i64> = ShardManager::new(4);
+        assert_eq!(
+            shard_manager.set("key1".to_string(), 1).unwrap(),
+            (shard_manager.get_shard_index("key1"), true)
+        );
+        assert_eq!(shard_manager.get("key1"), Some(1));
+        assert_eq!(shard_manager.update("key1", -7).unwrap(), Some(1));
+        assert_eq!(shard_manager.get_many(&["key1".to_string()])["key1"], -7);
+        assert_eq!(shard_manager.delete("key1").unwrap(), Some(-7));
+        assert_eq!(shard_manager.get("key1"), None);
+        assert!(shard_manager.is_empty());
+    }
+
+    #[test]
+    fn test_shard_manager_with_integer_keys() {
+        let shard_manager: ShardManager<u64, String> = ShardManager::new(4);
+        for key in 0..100 {
+            shard_manager.set(key, format!("value{}", key)).unwrap();
+        }
+        assert_eq!(shard_manager.get(&42), Some("value42".to_string()));
+        assert_eq!(shard_manager.len(), 100);
+
+        // Placement only depends on the key, so another manager agrees on it
+        let other: ShardManager<u64, String> = ShardManager::new(4);
+        for key in 0..100 {
+            assert_eq!(
+                shard_manager.get_shard_index(&key),
+                other.get_shard_index(&key)
+            );
+        }
+        assert!(shard_manager.rename(&42, &1000).unwrap());
+        assert_eq!(shard_manager.get(&1000), Some("value42".to_string()));
+    }
+
+    #[test]
+    fn test_shard_manager_random_entry() {
+        let shard_manager: ShardManager = ShardManager::new(4);
+        assert_eq!(shard_manager.random_entry(), None);
+
+        for i in 0..20 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        let mut seen = HashSet::new();
+        for _ in 0..200 {
+            let (key, value) = shard_manager.random_entry().unwrap();
+            assert_eq!(shard_manager.get(&key), Some(value));
+            seen.insert(key);
+        }
+        // Not every pick lands on the same few keys
+        assert!(seen.len() > 10);
+    }
+
+    #[test]
+    fn test_shard_manager_set_encoding() {
+        let mut shard_manager = ShardManager::new(2);
+        for i in 0..20 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            assert!(shard_manager.set_encoding(&format!("key{}", i), Some("br".to_string())));
+        }
+        assert!(!shard_manager.set_encoding("missing", Some("br".to_string())));
+
+        // Entries keep their encoding when they move to another shard
+        shard_manager.add_shard();
+        for i in 0..20 {
+            let entry = shard_manager.get_entry(&format!("key{}", i)).unwrap();
+            assert_eq!(entry.encoding.as_deref(), Some("br"));
+        }
+
+        shard_manager
+            .set("key0".to_string(), "value".to_string())
+            .unwrap();
+        assert_eq!(shard_manager.get_entry("key0").unwrap().encoding, None);
+    }
+
+    #[test]
+    fn test_shard_manager_ring() {
+        let mut shard_manager: ShardManager = ShardManager::with_virtual_nodes(3, 10);
+        let ring = shard_manager.ring();
+        assert_eq!(ring.len(), 30);
+        assert!(ring.windows(2).all(|nodes| nodes[0].0 < nodes[1].0));
+        for shard_index in 0..3 {
+            let owned = ring.iter().filter(|(_, owner)| *owner == shard_index);
+            assert_eq!(owned.count(), 10);
+        }
+
+        let new_index = shard_manager.add_weighted_shard(2);
+        let ring = shard_manager.ring();
+        assert_eq!(ring.len(), 50);
+        let owned = ring.iter().filter(|(_, owner)| *owner == new_index);
+        assert_eq!(owned.count(), 20);
+    }
+
+    #[test]
+    fn test_shard_manager_verify_placement() {
+        let mut shard_manager: ShardManager = ShardManager::new(4);
+        for i in 0..100 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        assert!(shard_manager.verify_placement().is_empty());
+        shard_manager.reshard(7);
+        assert!(shard_manager.verify_placement().is_empty());
+
+        let shard_index = shard_manager.get_shard_index("stray");
+        let wrong_index = (shard_index + 1) % shard_manager.shard_count();
+        shard_manager.set_in_shard(wrong_index, "stray".to_string(), "value".to_string());
+        assert_eq!(shard_manager.verify_placement(), vec!["stray".to_string()]);
+        assert_eq!(shard_manager.get("stray"), None);
+    }
+
+    #[test]
+    fn test_shard_manager_count_prefix() {
+        let shard_manager: ShardManager = ShardManager::new(4);
+        for i in 0..10 {
+            shard_manager
+                .set(format!("user:{}", i), "value".to_string())
+                .unwrap();
+        }
+        for i in 0..5 {
+            shard_manager
+                .set(format!("order:{}", i), "value".to_string())
+                .unwrap();
+        }
+        shard_manager
+            .set("user".to_string(), "value".to_string())
+            .unwrap();
+
+        assert_eq!(shard_manager.count_prefix("user:"), 10);
+        assert_eq!(shard_manager.count_prefix("order:"), 5);
+        assert_eq!(shard_manager.count_prefix("user"), 11);
+        assert_eq!(shard_manager.count_prefix("missing:"), 0);
+        assert_eq!(shard_manager.count_prefix(""), 16);
+    }
+
+    #[test]
+    fn test_shard_manager_delete_prefix() {
+        let shard_manager: ShardManager = ShardManager::new(4);
+        for i in 0..10 {
+            shard_manager
+                .set(format!("session:{}", i), "value".to_string())
+                .unwrap();
+            shard_manager
+                .set(format!("user:{}", i), "value".to_string())
+                .unwrap();
+        }
+        shard_manager
+            .set("session".to_string(), "value".to_string())
+            .unwrap();
+
+        assert_eq!(shard_manager.delete_prefix("session:").unwrap(), 10);
+        assert_eq!(shard_manager.count_prefix("session:"), 0);
+        assert_eq!(shard_manager.count_prefix("user:"), 10);
+        assert!(shard_manager.contains("session"));
+        assert_eq!(shard_manager.delete_prefix("session:").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_shard_manager_default_ttl() {
+        let mut shard_manager: ShardManager = ShardManager::new(4);
+        shard_manager.set_default_ttl(Some(Duration::from_secs(60)));
+
+        shard_manager
+            .set("default".to_string(), "value".to_string())
+            .unwrap();
+        let ttl = shard_manager.ttl("default").unwrap();
+        assert!(ttl > 55 && ttl <= 60);
+        shard_manager
+            .set_if_absent("absent".to_string(), "value".to_string())
+            .unwrap();
+        assert!(shard_manager.ttl("absent").unwrap() > 55);
+
+        // An explicit TTL, or explicitly no expiry, wins over the default
+        shard_manager
+            .set_with_ttl(
+                "explicit".to_string(),
+                "value".to_string(),
+                Duration::from_secs(600),
+            )
+            .unwrap();
+        assert!(shard_manager.ttl("explicit").unwrap() > 595);
+        shard_manager
+            .set_with_expiry("forever".to_string(), "value".to_string(), None)
+            .unwrap();
+        assert_eq!(shard_manager.ttl("forever"), Some(-1));
+
+        // Resharding keeps the default
+        shard_manager.reshard(2);
+        shard_manager
+            .set("after_reshard".to_string(), "value".to_string())
+            .unwrap();
+        assert!(shard_manager.ttl("after_reshard").unwrap() > 55);
+    }
+
+    #[test]
+    fn test_shard_manager_estimated_bytes() {
+        let shard_manager: ShardManager = ShardManager::new(4);
+        assert_eq!(shard_manager.estimated_bytes(), 0);
+
+        // 100 keys of 8 bytes with values of 100 bytes
+        for i in 0..100 {
+            shard_manager
+                .set(format!("key{:05}", i), "x".repeat(100))
+                .unwrap();
+        }
+        let raw = 100 * (8 + 100);
+        assert_eq!(shard_manager.total_bytes(), raw);
+        let estimated = shard_manager.estimated_bytes();
+        assert!(
+            (raw + 100 * 64..=raw + 100 * 512).contains(&estimated),
+            "{}",
+            estimated
+        );
+        assert_eq!(
+            shard_manager.estimated_shard_bytes().iter().sum::<usize>(),
+            estimated
+        );
+    }
+
+    #[test]
+    fn test_shard_manager_max_bytes() {
+        let mut shard_manager: ShardManager = ShardManager::new(4);
+        shard_manager.set_max_bytes(Some(10_000));
+
+        for i in 0..100 {
+            let value = "x".repeat(if i % 10 == 0 { 3_000 } else { 100 });
+            shard_manager.set(format!("key{:02}", i), value).unwrap();
+            assert!(shard_manager.total_bytes() <= 10_000);
+        }
+        assert!(shard_manager.total_bytes() > 5_000);
+        assert!(shard_manager.len() < 100);
+
+        // Lowering the cap evicts right away
+        shard_manager.set_max_bytes(Some(2_000));
+        assert!(shard_manager.total_bytes() <= 2_000);
+
+        shard_manager.set_max_bytes(None);
+        shard_manager
+            .set("big".to_string(), "x".repeat(20_000))
+            .unwrap();
+        assert!(shard_manager.total_bytes() > 20_000);
+
+        // Within a shard, the least recently used entries go first
+        let mut shard_manager: ShardManager = ShardManager::new(1);
+        shard_manager.set_max_bytes(Some(1_000));
+        for i in 0..4 {
+            shard_manager
+                .set(format!("key{}", i), "x".repeat(200))
+                .unwrap();
+        }
+        shard_manager.get("key0");
+        shard_manager.append("key3", &"y".repeat(300)).unwrap();
+        assert!(shard_manager.total_bytes() <= 1_000);
+        assert!(!shard_manager.contains("key1"));
+        assert!(shard_manager.contains("key0"));
+        assert_eq!(shard_manager.value_len("key3"), Some(500));
+    }
+
+    #[test]
+    fn test_shard_manager_pin() {
+        let mut shard_manager: ShardManager = ShardManager::new(4);
+        shard_manager
+            .set("moved".to_string(), "value".to_string())
+            .unwrap();
+        let hashed_index = shard_manager.get_shard_index("moved");
+        let pinned_index = (hashed_index + 1) % 4;
+
+        assert!(shard_manager.pin("moved".to_string(), pinned_index));
+        assert!(shard_manager.pin("new".to_string(), pinned_index));
+        assert!(!shard_manager.pin("other".to_string(), 4));
+        assert_eq!(shard_manager.get_shard_index("moved"), pinned_index);
+        assert_eq!(shard_manager.get("moved"), Some("value".to_string()));
+        assert_eq!(
+            shard_manager
+                .set("new".to_string(), "value".to_string())
+                .unwrap(),
+            (pinned_index, true)
+        );
+        let mut sizes = vec![0; 4];
+        sizes[pinned_index] = 2;
+        assert_eq!(shard_manager.shard_sizes(), sizes);
+        assert!(shard_manager.verify_placement().is_empty());
+
+        // Resharding keeps the pins to shards that still exist
+        shard_manager.reshard(8);
+        assert_eq!(shard_manager.get_shard_index("moved"), pinned_index);
+        assert_eq!(shard_manager.get("new"), Some("value".to_string()));
+
+        shard_manager.clear().unwrap();
+        assert_eq!(shard_manager.get_shard_index("moved"), {
+            let unpinned: ShardManager = ShardManager::new(8);
+            unpinned.get_shard_index("moved")
+        });
+    }
+
+    #[test]
+    fn test_shard_manager_set_and_get() {
+        let shard_manager = ShardManager::new(4);
+        shard_manager
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        assert_eq!(shard_manager.get("key1"), Some("value1".to_string()));
+    }
+
+    #[test]
+    fn test_shard_manager_set_reports_shard_and_creation() {
+        let shard_manager = ShardManager::new(4);
+        let (shard_index, created) = shard_manager
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        assert_eq!(shard_index, shard_manager.get_shard_index("key1"));
+        assert!(created);
+
+        let (_, created) = shard_manager
+            .set("key1".to_string(), "value2".to_string())
+            .unwrap();
+        assert!(!created);
+    }
+
+    #[test]
+    fn test_shard_manager_set_if_absent() {
+        let shard_manager = ShardManager::new(4);
+        assert!(shard_manager
+            .set_if_absent("key1".to_string(), "value1".to_string())
+            .unwrap());
+        assert!(!shard_manager
+            .set_if_absent("key1".to_string(), "value2".to_string())
+            .unwrap());
+        assert!(!shard_manager
+            .set_if_absent_with_ttl(
+                "key1".to_string(),
+                "value3".to_string(),
+                Duration::from_secs(60)
+            )
+            .unwrap());
+        assert_eq!(shard_manager.get("key1"), Some("value1".to_string()));
+    }
+
+    #[test]
+    fn test_shard_manager_get_set() {
+        let shard_manager = ShardManager::new(4);
+        assert_eq!(
+            shard_manager
+                .get_set("key1".to_string(), "value1".to_string())
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            shard_manager
+                .get_set("key1".to_string(), "value2".to_string())
+                .unwrap(),
+            Some("value1".to_string())
+        );
+        assert_eq!(shard_manager.get("key1"), Some("value2".to_string()));
+    }
+
+    #[test]
+    fn test_shard_manager_get_versioned() {
+        let shard_manager = ShardManager::new(4);
+        assert_eq!(shard_manager.get_versioned("key1"), None);
+        for version in 1..=3 {
+            shard_manager
+                .set("key1".to_string(), format!("value{}", version))
+                .unwrap();
+            assert_eq!(
+                shard_manager.get_versioned("key1"),
+                Some((format!("value{}", version), version))
+            );
+        }
+
+        shard_manager.rename("key1", "key2").unwrap();
+        assert_eq!(
+            shard_manager.get_versioned("key2"),
+            Some(("value3".to_string(), 3))
+        );
+    }
+
+    #[test]
+    fn test_shard_manager_append() {
+        let shard_manager = ShardManager::new(4);
+        shard_manager
+            .set("key1".to_string(), "abc".to_string())
+            .unwrap();
+        assert_eq!(shard_manager.append("key1", "def").unwrap(), 6);
+        assert_eq!(shard_manager.get("key1"), Some("abcdef".to_string()));
+
+        assert_eq!(shard_manager.append("key2", "xyz").unwrap(), 3);
+        assert_eq!(shard_manager.get("key2"), Some("xyz".to_string()));
+        assert_eq!(shard_manager.value_len("key2"), Some(3));
+    }
+
+    #[test]
+    fn test_shard_manager_delete_many() {
+        let shard_manager = ShardManager::new(4);
+        for i in 0..10 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+
+        let keys: Vec<String> = ["key1", "key5", "key9", "missing", "key1"]
+            .iter()
+            .map(|key| key.to_string())
+            .collect();
+        assert_eq!(shard_manager.delete_many(&keys).unwrap(), 3);
+        assert_eq!(shard_manager.len(), 7);
+        assert_eq!(shard_manager.get("key5"), None);
+        assert_eq!(shard_manager.get("key0"), Some("value0".to_string()));
+    }
+
+    #[test]
+    fn test_shard_manager_get_non_existent() {
+        let shard_manager: ShardManager = ShardManager::new(4);
+        assert_eq!(shard_manager.get("non_existent_key"), None);
+    }
+
+    #[test]
+    fn test_shard_manager_shard_entries() {
+        let shard_manager = ShardManager::new(4);
+        for i in 0..20 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+
+        let mut data = HashMap::new();
+        for shard_index in 0..4 {
+            let entries = shard_manager.shard_entries(shard_index);
+            assert_eq!(entries.len(), shard_manager.shard_sizes()[shard_index]);
+            for (key, value) in entries {
+                assert_eq!(shard_manager.get_shard_index(&key), shard_index);
+                data.insert(key, value);
+            }
+        }
+        assert_eq!(data, shard_manager.export());
+        assert!(shard_manager.shard_entries(4).is_empty());
+    }
+
+    #[test]
+    fn test_shard_manager_changed_since() {
+        let shard_manager: ShardManager = ShardManager::new(4);
+        shard_manager
+            .set("old1".to_string(), "value".to_string())
+            .unwrap();
+        shard_manager
+            .set("old2".to_string(), "value".to_string())
+            .unwrap();
+        thread::sleep(Duration::from_millis(5));
+        let since = SystemTime::now();
+        thread::sleep(Duration::from_millis(5));
+        shard_manager
+            .set("new1".to_string(), "value".to_string())
+            .unwrap();
+        shard_manager
+            .set("old2".to_string(), "updated".to_string())
+            .unwrap();
+
+        let mut changed = shard_manager.changed_since(since);
+        changed.sort();
+        assert_eq!(changed, vec!["new1".to_string(), "old2".to_string()]);
+        assert_eq!(shard_manager.changed_since(UNIX_EPOCH).len(), 3);
+        assert!(shard_manager
+            .changed_since(SystemTime::now() + Duration::from_secs(60))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_shard_manager_get_many() {
+        let shard_manager = ShardManager::new(4);
+        for i in 0..10 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+
+        let keys: Vec<String> = vec!["key1", "key5", "key9", "missing1", "missing2"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let values = shard_manager.get_many(&keys);
+
+        assert_eq!(values.len(), 3);
+        assert_eq!(values.get("key1"), Some(&"value1".to_string()));
+        assert_eq!(values.get("key5"), Some(&"value5".to_string()));
+        assert_eq!(values.get("key9"), Some(&"value9".to_string()));
+        assert!(!values.contains_key("missing1"));
+    }
+
+    #[test]
+    fn test_shard_manager_contains_many() {
+        let shard_manager = ShardManager::new(4);
+        for i in 0..10 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        shard_manager
+            .set_with_ttl(
+                "expired".to_string(),
+                "value".to_string(),
+                Duration::from_millis(1),
+            )
+            .unwrap();
+        thread::sleep(Duration::from_millis(5));
+
+        let keys: Vec<String> = vec!["key1", "key9", "missing", "expired"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let presence = shard_manager.contains_many(&keys);
+
+        assert_eq!(presence.len(), 4);
+        assert_eq!(presence.get("key1"), Some(&true));
+        assert_eq!(presence.get("key9"), Some(&true));
+        assert_eq!(presence.get("missing"), Some(&false));
+        assert_eq!(presence.get("expired"), Some(&false));
+    }
+
+    #[test]
+    fn test_shard_manager_set_many() {
+        let shard_manager = ShardManager::new(4);
+        let pairs: Vec<(String, String)> = (0..20)
+            .map(|i| (format!("key{}", i), format!("value{}", i)))
+            .collect();
+
+        let counts = shard_manager.set_many(pairs).unwrap();
+
+        assert_eq!(counts.values().sum::<usize>(), 20);
+        for (shard_index, count) in counts {
+            let expected = (0..20)
+                .filter(|i| shard_manager.get_shard_index(&format!("key{}", i)) == shard_index)
+                .count();
+            assert_eq!(count, expected);
+        }
+        for i in 0..20 {
+            assert_eq!(
+                shard_manager.get(&format!("key{}", i)),
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    #[test]
+    fn test_shard_manager_max_entries_per_shard() {
+        let shard_manager = ShardManager::with_max_entries_per_shard(1, 2);
+        shard_manager
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        shard_manager
+            .set("key2".to_string(), "value2".to_string())
+            .unwrap();
+
+        // key1 becomes the most recently used, leaving key2 as the oldest
+        assert_eq!(shard_manager.get("key1"), Some("value1".to_string()));
+        shard_manager
+            .set("key3".to_string(), "value3".to_string())
+            .unwrap();
+
+        assert_eq!(shard_manager.len(), 2);
+        assert_eq!(shard_manager.get("key2"), None);
+        assert_eq!(shard_manager.get("key1"), Some("value1".to_string()));
+        assert_eq!(shard_manager.get("key3"), Some("value3".to_string()));
+    }
+
+    #[test]
+    fn test_shard_manager_set_with_ttl() {
+        let shard_manager = ShardManager::new(4);
+        shard_manager
+            .set_with_ttl(
+                "key1".to_string(),
+                "value1".to_string(),
+                Duration::from_millis(50),
+            )
+            .unwrap();
+        assert_eq!(shard_manager.get("key1"), Some("value1".to_string()));
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!shard_manager.contains("key1"));
+        assert_eq!(shard_manager.get("key1"), None);
+    }
+
+    #[test]
+    fn test_shard_manager_ttl() {
+        let shard_manager = ShardManager::new(4);
+        shard_manager
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        shard_manager
+            .set_with_ttl(
+                "key2".to_string(),
+                "value2".to_string(),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+
+        assert_eq!(shard_manager.ttl("key1"), Some(-1));
+        assert_eq!(shard_manager.ttl("key2"), Some(60));
+        assert_eq!(shard_manager.ttl("missing"), None);
+    }
+
+    #[test]
+    fn test_shard_manager_contains() {
+        let shard_manager = ShardManager::new(4);
+        shard_manager
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        assert!(shard_manager.contains("key1"));
+        assert!(!shard_manager.contains("non_existent_key"));
+    }
+
+    #[test]
+    fn test_shard_manager_is_empty() {
+        let shard_manager = ShardManager::new(4);
+        assert!(shard_manager.is_empty());
+        shard_manager
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        assert!(!shard_manager.is_empty());
+    }
+
+    #[test]
+    fn test_shard_manager_len() {
+        let shard_manager = ShardManager::new(4);
+        assert_eq!(shard_manager.len(), 0);
+        for i in 0..10 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        assert_eq!(shard_manager.len(), 10);
+    }
+
+    #[test]
+    fn test_shard_manager_shard_sizes() {
+        let shard_manager = ShardManager::new(4);
+        for i in 0..20 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+
+        let sizes = shard_manager.shard_sizes();
+        assert_eq!(sizes.len(), 4);
+        assert_eq!(sizes.iter().sum::<usize>(), 20);
+        for (shard_index, size) in sizes.into_iter().enumerate() {
+            assert_eq!(size, shard_manager.shards[shard_index].read().len());
+        }
+    }
+
+    #[test]
+    fn test_shard_manager_update() {
+        let shard_manager = ShardManager::new(4);
+        shard_manager
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        let previous = shard_manager.update("key1", "value2".to_string()).unwrap();
+        assert_eq!(previous, Some("value1".to_string()));
+        assert_eq!(shard_manager.get("key1"), Some("value2".to_string()));
+    }
+
+    #[test]
+    fn test_shard_manager_update_non_existent() {
+        let shard_manager: ShardManager = ShardManager::new(4);
+        let previous = shard_manager
+            .update("non_existent_key", "value".to_string())
+            .unwrap();
+        assert_eq!(previous, None);
+        assert_eq!(shard_manager.get("non_existent_key"), None);
+    }
+
+    #[test]
+    fn test_shard_manager_update_with() {
+        let shard_manager: ShardManager = ShardManager::new(4);
+        assert_eq!(
+            shard_manager
+                .update_with("key1", |value| Ok::<_, ()>(value + "!"))
+                .unwrap(),
+            None
+        );
+
+        shard_manager
+            .set("key1".to_string(), "0".to_string())
+            .unwrap();
+        assert_eq!(
+            shard_manager
+                .update_with("key1", |_| Err("rejected"))
+                .unwrap(),
+            Some(Err("rejected"))
+        );
+        assert_eq!(shard_manager.version("key1"), Some(1));
+
+        // Concurrent read-modify-writes of the same key don't lose any update
+        let shard_manager = Arc::new(shard_manager);
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let shard_manager = shard_manager.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        shard_manager
+                            .update_with("key1", |value| {
+                                Ok::<_, ()>((value.parse::<u32>().unwrap() + 1).to_string())
+                            })
+                            .unwrap()
+                            .unwrap()
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(shard_manager.get("key1"), Some("800".to_string()));
+    }
+
+    #[test]
+    fn test_shard_manager_increment() {
+        let shard_manager = ShardManager::new(4);
+        assert_eq!(shard_manager.increment("counter", 1).unwrap(), Ok(1));
+        assert_eq!(shard_manager.increment("counter", 2).unwrap(), Ok(3));
+        assert_eq!(shard_manager.get("counter"), Some("3".to_string()));
+
+        shard_manager
+            .set("text".to_string(), "abc".to_string())
+            .unwrap();
+        assert_eq!(
+            shard_manager.increment("text", 1).unwrap(),
+            Err(IncrError::NotAnInteger)
+        );
+    }
+
+    #[test]
+    fn test_shard_manager_delete_if() {
+        let shard_manager: ShardManager = ShardManager::new(4);
+        shard_manager
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+
+        assert_eq!(
+            shard_manager
+                .delete_if("key1", &"other".to_string())
+                .unwrap(),
+            DeleteIfResult::Mismatch
+        );
+        assert_eq!(shard_manager.get("key1"), Some("value1".to_string()));
+        assert_eq!(
+            shard_manager
+                .delete_if("key1", &"value1".to_string())
+                .unwrap(),
+            DeleteIfResult::Deleted
+        );
+        assert!(!shard_manager.contains("key1"));
+        assert_eq!(
+            shard_manager
+                .delete_if("key1", &"value1".to_string())
+                .unwrap(),
+            DeleteIfResult::NotFound
+        );
+    }
+
+    #[test]
+    fn test_shard_manager_compare_and_swap() {
+        let shard_manager = ShardManager::new(4);
+        assert!(shard_manager
+            .compare_and_swap("key1", None, "value1".to_string())
+            .unwrap());
+        assert!(!shard_manager
+            .compare_and_swap("key1", Some("other".to_string()), "value2".to_string())
+            .unwrap());
+        assert!(shard_manager
+            .compare_and_swap("key1", Some("value1".to_string()), "value2".to_string())
+            .unwrap());
+        assert_eq!(shard_manager.get("key1"), Some("value2".to_string()));
+    }
+
+    #[test]
+    fn test_shard_manager_delete() {
+        let shard_manager = ShardManager::new(4);
+        shard_manager
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        let deleted_value = shard_manager.delete("key1").unwrap();
+        assert_eq!(deleted_value, Some("value1".to_string()));
+        assert_eq!(shard_manager.get("key1"), None);
+    }
+
+    #[test]
+    fn test_shard_manager_clear() {
+        let shard_manager = ShardManager::new(4);
+        for i in 0..10 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
         }
+        assert_eq!(shard_manager.clear().unwrap(), 10);
+        assert!(shard_manager.is_empty());
+    }
+
+    #[test]
+    fn test_shard_manager_import() {
+        let shard_manager = ShardManager::new(4);
+        shard_manager
+            .set("key0".to_string(), "old".to_string())
+            .unwrap();
 
-        ShardManager { shards }
+        let data: HashMap<String, String> = (0..10)
+            .map(|i| (format!("key{}", i), format!("value{}", i)))
+            .collect();
+        assert_eq!(shard_manager.import(data.clone(), false).unwrap(), 9);
+        assert_eq!(shard_manager.get("key0"), Some("old".to_string()));
+        assert_eq!(shard_manager.len(), 10);
+
+        assert_eq!(shard_manager.import(data, true).unwrap(), 10);
+        assert_eq!(shard_manager.get("key0"), Some("value0".to_string()));
+        assert_eq!(shard_manager.len(), 10);
     }
 
-    fn hash<T: Hash + Sized>(t: &T) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        t.hash(&mut hasher);
-        hasher.finish()
+    #[test]
+    fn test_shard_manager_export() {
+        let shard_manager = ShardManager::new(4);
+        let data: HashMap<String, String> = (0..20)
+            .map(|i| (format!("key{}", i), format!("value{}", i)))
+            .collect();
+        shard_manager.import(data.clone(), true).unwrap();
+        shard_manager
+            .set_with_ttl("expired".to_string(), "value".to_string(), Duration::ZERO)
+            .unwrap();
+
+        assert_eq!(shard_manager.export(), data);
     }
 
-    pub fn get_shard(&mut self, key: &str) -> &mut Shard {
-        let shard_index = (Self::hash(&key) as usize) % self.shards.len();
-        &mut self.shards[shard_index]
+    #[test]
+    fn test_shard_manager_scan() {
+        let shard_manager = ShardManager::new(4);
+        for i in 0..25 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+
+        let mut seen = HashMap::new();
+        let mut cursor = Some(0);
+        let mut pages = 0;
+        while let Some(current) = cursor {
+            let (page, next_cursor) = shard_manager.scan(current, 10);
+            assert!(page.len() <= 10);
+            for (key, value) in page {
+                assert!(seen.insert(key, value).is_none());
+            }
+            cursor = next_cursor;
+            pages += 1;
+        }
+
+        assert_eq!(pages, 3);
+        assert_eq!(seen, shard_manager.export());
+        assert_eq!(shard_manager.scan(25, 10), (Vec::new(), None));
     }
 
-    pub fn get_shard_index(&self, key: &str) -> usize {
-        let shard_index = (Self::hash(&key) as usize) % self.shards.len();
-        shard_index
+    #[test]
+    fn test_shard_manager_sweep_expired() {
+        let shard_manager = ShardManager::new(4);
+        for i in 0..10 {
+            shard_manager
+                .set_with_ttl(format!("key{}", i), format!("value{}", i), Duration::ZERO)
+                .unwrap();
+        }
+        shard_manager
+            .set("kept".to_string(), "value".to_string())
+            .unwrap();
+
+        assert_eq!(shard_manager.sweep_expired(), 10);
+        assert_eq!(shard_manager.sweep_expired(), 0);
+        assert_eq!(shard_manager.len(), 1);
     }
 
-    pub fn get(&self, key: &str) -> Option<String> {
-        let shard_index = self.get_shard_index(key);
-        self.shards[shard_index].get(key)
+    /// Finds a key living in (or out of) the same shard as `key`.
+    fn find_key(shard_manager: &ShardManager, key: &str, same_shard: bool) -> String {
+        let shard_index = shard_manager.get_shard_index(key);
+        (0..)
+            .map(|i| format!("other{}", i))
+            .find(|other| (shard_manager.get_shard_index(other) == shard_index) == same_shard)
+            .unwrap()
     }
 
-    pub fn set(&mut self, key: String, value: String) -> usize {
-        let shard_index = (Self::hash(&key) as usize) % self.shards.len();
-        let shard = self.get_shard(&key);
-        shard.set(key, value);
-        shard_index
+    #[test]
+    fn test_shard_manager_rename() {
+        let shard_manager = ShardManager::new(4);
+        for same_shard in [true, false] {
+            shard_manager
+                .set_with_ttl(
+                    "from".to_string(),
+                    "value".to_string(),
+                    Duration::from_secs(60),
+                )
+                .unwrap();
+            let to = find_key(&shard_manager, "from", same_shard);
+            shard_manager.set(to.clone(), "old".to_string()).unwrap();
+
+            assert!(shard_manager.rename("from", &to).unwrap());
+            assert!(!shard_manager.contains("from"));
+            assert_eq!(shard_manager.get(&to), Some("value".to_string()));
+            assert!(shard_manager.ttl(&to).unwrap() > 0);
+            shard_manager.delete(&to).unwrap();
+        }
+
+        assert!(!shard_manager.rename("missing", "to").unwrap());
+        assert!(!shard_manager.contains("to"));
     }
 
-    pub fn delete(&mut self, key: &str) -> Option<String> {
-        let shard = self.get_shard(key);
-        shard.delete(key)
+    #[test]
+    fn test_shard_manager_swap() {
+        let shard_manager = ShardManager::new(4);
+        for same_shard in [true, false] {
+            let b = find_key(&shard_manager, "a", same_shard);
+            shard_manager
+                .set_with_ttl("a".to_string(), "1".to_string(), Duration::from_secs(60))
+                .unwrap();
+            shard_manager.set(b.clone(), "2".to_string()).unwrap();
+
+            assert!(shard_manager.swap("a", &b).unwrap());
+            assert_eq!(shard_manager.get("a"), Some("2".to_string()));
+            assert_eq!(shard_manager.get(&b), Some("1".to_string()));
+            assert_eq!(shard_manager.ttl("a"), Some(-1));
+            assert!(shard_manager.ttl(&b).unwrap() > 0);
+
+            // A missing key takes the other one's value
+            shard_manager.delete("a").unwrap();
+            assert!(shard_manager.swap("a", &b).unwrap());
+            assert_eq!(shard_manager.get("a"), Some("1".to_string()));
+            assert!(!shard_manager.contains(&b));
+            assert!(shard_manager.swap("a", &b).unwrap());
+            assert!(!shard_manager.contains("a"));
+            assert_eq!(shard_manager.get(&b), Some("1".to_string()));
+            shard_manager.delete(&b).unwrap();
+        }
+
+        assert!(!shard_manager.swap("missing", "other").unwrap());
+        assert!(shard_manager.is_empty());
+
+        shard_manager
+            .set("same".to_string(), "value".to_string())
+            .unwrap();
+        assert!(shard_manager.swap("same", "same").unwrap());
+        assert_eq!(shard_manager.get("same"), Some("value".to_string()));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_shard_manager_audit_log() {
+        let mut shard_manager: ShardManager = ShardManager::new(4);
+        shard_manager
+            .set("before".to_string(), "value".to_string())
+            .unwrap();
+        assert!(shard_manager.audit_log().is_empty());
+
+        shard_manager.enable_audit(4);
+        shard_manager
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        shard_manager
+            .set("key2".to_string(), "value2".to_string())
+            .unwrap();
+        shard_manager.delete("key1").unwrap();
+        // Missing keys aren't recorded
+        shard_manager.delete("missing").unwrap();
+        shard_manager
+            .update("missing", "value".to_string())
+            .unwrap();
+        shard_manager.append("key2", "!").unwrap();
+        shard_manager.clear().unwrap();
+
+        let ops: Vec<(AuditOp, Option<String>)> = shard_manager
+            .audit_log()
+            .into_iter()
+            .map(|entry| (entry.op, entry.key))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                (AuditOp::Set, Some("key2".to_string())),
+                (AuditOp::Delete, Some("key1".to_string())),
+                (AuditOp::Set, Some("key2".to_string())),
+                (AuditOp::Clear, None),
+            ]
+        );
+
+        shard_manager.enable_audit(0);
+        shard_manager
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        assert!(shard_manager.audit_log().is_empty());
+    }
 
     #[test]
-    fn test_shard_manager_new() {
-        let shard_count = 4;
-        let shard_manager = ShardManager::new(shard_count);
-        assert_eq!(shard_manager.shards.len(), shard_count);
+    fn test_shard_manager_delete_non_existent() {
+        let shard_manager: ShardManager = ShardManager::new(4);
+        let deleted_value = shard_manager.delete("non_existent_key").unwrap();
+        assert_eq!(deleted_value, None);
     }
 
     #[test]
-    fn test_shard_manager_set_and_get() {
-        let mut shard_manager = ShardManager::new(4);
-        shard_manager.set("key1".to_string(), "value1".to_string());
+    fn test_shard_manager_consistent_hashing() {
+        let shard_manager = ShardManager::new(4);
+        let key = "consistent_key";
+        let value = "consistent_value";
+        shard_manager
+            .set(key.to_string(), value.to_string())
+            .unwrap();
+
+        let hash = shard_manager.hash(key);
+        let (_, &shard_index) = shard_manager
+            .ring
+            .range(hash..)
+            .next()
+            .unwrap_or_else(|| shard_manager.ring.iter().next().unwrap());
+        assert_eq!(
+            shard_manager.shards[shard_index].write().get(key),
+            Some(value.to_string())
+        );
+    }
+
+    #[test]
+    fn test_shard_manager_hash_algo_is_reproducible() {
+        for hash_algo in [HashAlgo::Fnv, HashAlgo::XxHash] {
+            let first: ShardManager = ShardManager::with_hash_algo(4, hash_algo);
+            let second: ShardManager = ShardManager::with_hash_algo(4, hash_algo);
+            assert_eq!(first.hash_algo(), hash_algo);
+
+            for i in 0..100 {
+                let key = format!("key{}", i);
+                assert_eq!(first.get_shard_index(&key), second.get_shard_index(&key));
+            }
+        }
+
+        // Different algorithms lay out the ring differently
+        let fnv: ShardManager = ShardManager::with_hash_algo(4, HashAlgo::Fnv);
+        let xxhash: ShardManager = ShardManager::with_hash_algo(4, HashAlgo::XxHash);
+        assert_ne!(fnv.ring, xxhash.ring);
+    }
+
+    #[test]
+    fn test_shard_manager_concurrent_access_to_different_shards() {
+        let shard_manager = Arc::new(ShardManager::new(4));
+        let blocked_index = shard_manager.get_shard_index("blocked_key");
+        let keys: Vec<String> = (0..100)
+            .map(|i| format!("key{}", i))
+            .filter(|key| shard_manager.get_shard_index(key) != blocked_index)
+            .collect();
+
+        // Hold one shard's lock for the whole test, writes to the other shards must not wait on it
+        let _guard = shard_manager.shards[blocked_index].write();
+
+        let (sender, receiver) = mpsc::channel();
+        for key in keys.clone() {
+            let shard_manager = Arc::clone(&shard_manager);
+            let sender = sender.clone();
+            thread::spawn(move || {
+                shard_manager.set(key.clone(), key.clone()).unwrap();
+                sender.send(()).unwrap();
+            });
+        }
+
+        for _ in 0..keys.len() {
+            receiver
+                .recv_timeout(Duration::from_secs(5))
+                .expect("a write to an unlocked shard was blocked");
+        }
+        for key in &keys {
+            assert_eq!(shard_manager.get(key), Some(key.clone()));
+        }
+    }
+
+    #[test]
+    fn test_shard_manager_max_keys() {
+        let mut shard_manager: ShardManager = ShardManager::new(4);
+        shard_manager.set_max_keys(Some(10));
+        for i in 0..10 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        assert_eq!(shard_manager.len(), 10);
+
+        assert!(matches!(
+            shard_manager.set("key10".to_string(), "value10".to_string()),
+            Err(WriteError::StoreFull)
+        ));
+        assert_eq!(shard_manager.get("key10"), None);
+        assert_eq!(shard_manager.len(), 10);
+
+        // Existing keys can still be overwritten, and deleting one makes room again
+        let (_, created) = shard_manager
+            .set("key3".to_string(), "updated".to_string())
+            .unwrap();
+        assert!(!created);
+        assert_eq!(shard_manager.get("key3"), Some("updated".to_string()));
+        shard_manager.delete("key0").unwrap();
+        assert!(shard_manager
+            .set("key10".to_string(), "value10".to_string())
+            .is_ok());
+
+        shard_manager.set_max_keys(None);
+        assert!(shard_manager
+            .set("key11".to_string(), "value11".to_string())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_shard_manager_max_keys_on_every_write() {
+        let mut shard_manager: ShardManager = ShardManager::new(4);
+        shard_manager.set_max_keys(Some(2));
+        shard_manager
+            .set("key1".to_string(), "1".to_string())
+            .unwrap();
+        shard_manager
+            .set("key2".to_string(), "2".to_string())
+            .unwrap();
+
+        let new_key = "new".to_string();
+        assert!(matches!(
+            shard_manager.set_if_absent(new_key.clone(), "value".to_string()),
+            Err(WriteError::StoreFull)
+        ));
+        assert!(matches!(
+            shard_manager.get_set(new_key.clone(), "value".to_string()),
+            Err(WriteError::StoreFull)
+        ));
+        assert!(matches!(
+            shard_manager.increment(&new_key, 1),
+            Err(WriteError::StoreFull)
+        ));
+        assert!(matches!(
+            shard_manager.append(&new_key, "!"),
+            Err(WriteError::StoreFull)
+        ));
+        assert!(matches!(
+            shard_manager.compare_and_swap(&new_key, None, "value".to_string()),
+            Err(WriteError::StoreFull)
+        ));
+        assert!(matches!(
+            shard_manager.set_many(vec![(new_key.clone(), "value".to_string())]),
+            Err(WriteError::StoreFull)
+        ));
+        let data = HashMap::from([(new_key.clone(), "value".to_string())]);
+        assert!(matches!(
+            shard_manager.import(data, true),
+            Err(WriteError::StoreFull)
+        ));
+        assert_eq!(shard_manager.get(&new_key), None);
+
+        // Existing keys can still be written, and moving keys around doesn't add any
+        assert_eq!(shard_manager.increment("key1", 1).unwrap(), Ok(2));
+        assert_eq!(shard_manager.append("key2", "!").unwrap(), 2);
+        assert!(shard_manager.rename("key1", &new_key).unwrap());
+        assert!(shard_manager.swap("key2", "other").unwrap());
+        assert_eq!(shard_manager.len(), 2);
+    }
+
+    #[test]
+    fn test_shard_manager_recovers_poisoned_shard_lock() {
+        let shard_manager: ShardManager = ShardManager::new(1);
+        shard_manager
+            .set("key1".to_string(), "value1".to_string())
+            .unwrap();
+        std::thread::scope(|scope| {
+            let poisoning = scope.spawn(|| {
+                let _shard = shard_manager.shards[0].write();
+                panic!("panicking while holding the shard lock");
+            });
+            assert!(poisoning.join().is_err());
+        });
+        assert!(shard_manager.shards[0].shard.is_poisoned());
+
         assert_eq!(shard_manager.get("key1"), Some("value1".to_string()));
+        shard_manager
+            .set("key2".to_string(), "value2".to_string())
+            .unwrap();
+        assert_eq!(shard_manager.len(), 2);
     }
 
     #[test]
-    fn test_shard_manager_get_non_existent() {
-        let shard_manager = ShardManager::new(4);
-        assert_eq!(shard_manager.get("non_existent_key"), None);
+    fn test_shard_manager_check_shards() {
+        let shard_manager: ShardManager = ShardManager::new(4);
+        assert_eq!(shard_manager.check_shards(), vec![true; 4]);
+        assert_eq!(shard_manager.len(), 0);
+        assert_eq!(shard_manager.get(HEALTH_CHECK_KEY), None);
+
+        std::thread::scope(|scope| {
+            let poisoning = scope.spawn(|| {
+                let _shard = shard_manager.shards[2].write();
+                panic!("panicking while holding the shard lock");
+            });
+            assert!(poisoning.join().is_err());
+        });
+        assert_eq!(shard_manager.check_shards(), vec![true, true, false, true]);
     }
 
     #[test]
-    fn test_shard_manager_delete() {
+    fn test_shard_manager_lock_contention() {
+        let shard_manager: Arc<ShardManager> = Arc::new(ShardManager::new(4));
+        let hot_index = shard_manager.get_shard_index("hot_key");
+        assert_eq!(shard_manager.lock_contention(), vec![0; 4]);
+
+        // Writers started while the shard is locked have to wait for it
+        let guard = shard_manager.shards[hot_index].write();
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let shard_manager = Arc::clone(&shard_manager);
+                thread::spawn(move || {
+                    for j in 0..100 {
+                        shard_manager
+                            .set("hot_key".to_string(), format!("{}-{}", i, j))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let contention = shard_manager.lock_contention();
+        assert!(contention[hot_index] > 0);
+        for (shard_index, &count) in contention.iter().enumerate() {
+            if shard_index != hot_index {
+                assert_eq!(count, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shard_manager_ring_has_virtual_nodes_for_every_shard() {
+        let shard_manager: ShardManager = ShardManager::new(4);
+        for shard_index in 0..4 {
+            let nodes = shard_manager
+                .ring
+                .values()
+                .filter(|&&owner| owner == shard_index)
+                .count();
+            assert!(nodes > 0);
+        }
+    }
+
+    fn shard_sizes_std_dev(shard_manager: &ShardManager) -> f64 {
+        let sizes = shard_manager.shard_sizes();
+        let mean = sizes.iter().sum::<usize>() as f64 / sizes.len() as f64;
+        let variance = sizes
+            .iter()
+            .map(|&size| (size as f64 - mean).powi(2))
+            .sum::<f64>()
+            / sizes.len() as f64;
+        variance.sqrt()
+    }
+
+    #[test]
+    fn test_shard_manager_with_virtual_nodes() {
+        let shard_manager: ShardManager = ShardManager::with_virtual_nodes(4, 10);
+        assert_eq!(shard_manager.shard_count(), 4);
+        assert_eq!(shard_manager.ring.len(), 40);
+    }
+
+    #[test]
+    fn test_shard_manager_more_virtual_nodes_balance_better() {
+        let single = ShardManager::with_virtual_nodes(4, 1);
+        let many = ShardManager::with_virtual_nodes(4, 100);
+        for i in 0..4000 {
+            single
+                .set(format!("key{}", i), "value".to_string())
+                .unwrap();
+            many.set(format!("key{}", i), "value".to_string()).unwrap();
+        }
+
+        assert!(shard_sizes_std_dev(&many) < shard_sizes_std_dev(&single));
+    }
+
+    #[test]
+    fn test_shard_manager_add_shard_keeps_most_assignments() {
         let mut shard_manager = ShardManager::new(4);
-        shard_manager.set("key1".to_string(), "value1".to_string());
-        let deleted_value = shard_manager.delete("key1");
-        assert_eq!(deleted_value, Some("value1".to_string()));
-        assert_eq!(shard_manager.get("key1"), None);
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            shard_manager.set(key.clone(), key.clone()).unwrap();
+        }
+        let before: Vec<usize> = keys
+            .iter()
+            .map(|key| shard_manager.get_shard_index(key))
+            .collect();
+
+        let new_index = shard_manager.add_shard();
+        assert_eq!(new_index, 4);
+        assert_eq!(shard_manager.shard_count(), 5);
+
+        let mut unchanged = 0;
+        for (key, old_index) in keys.iter().zip(before) {
+            let index = shard_manager.get_shard_index(key);
+            if index == old_index {
+                unchanged += 1;
+            } else {
+                // Keys only ever move to the new shard
+                assert_eq!(index, new_index);
+            }
+            assert_eq!(shard_manager.get(key), Some(key.clone()));
+        }
+        assert!(unchanged > keys.len() / 2);
+        assert_eq!(shard_manager.len(), keys.len());
     }
 
     #[test]
-    fn test_shard_manager_delete_non_existent() {
+    fn test_shard_manager_add_weighted_shard() {
+        let mut shard_manager = ShardManager::new(1);
+        let heavy_index = shard_manager.add_weighted_shard(3);
+        assert_eq!(heavy_index, 1);
+        for i in 0..10000 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+
+        let sizes = shard_manager.shard_sizes();
+        assert_eq!(sizes[0] + sizes[1], 10000);
+        let ratio = sizes[1] as f64 / sizes[0] as f64;
+        assert!((2.5..3.5).contains(&ratio), "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_shard_manager_remove_shard() {
         let mut shard_manager = ShardManager::new(4);
-        let deleted_value = shard_manager.delete("non_existent_key");
-        assert_eq!(deleted_value, None);
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            shard_manager.set(key.clone(), key.clone()).unwrap();
+        }
+        let before: Vec<usize> = keys
+            .iter()
+            .map(|key| shard_manager.get_shard_index(key))
+            .collect();
+
+        assert!(shard_manager.remove_shard());
+        assert_eq!(shard_manager.shard_count(), 3);
+
+        for (key, old_index) in keys.iter().zip(before) {
+            // Only the keys of the removed shard move
+            if old_index != 3 {
+                assert_eq!(shard_manager.get_shard_index(key), old_index);
+            }
+            assert_eq!(shard_manager.get(key), Some(key.clone()));
+        }
+        assert_eq!(shard_manager.len(), keys.len());
     }
 
     #[test]
-    fn test_shard_manager_consistent_hashing() {
+    fn test_shard_manager_migration_moves_keys_in_chunks() {
         let mut shard_manager = ShardManager::new(4);
-        let key = "consistent_key";
-        let value = "consistent_value";
-        shard_manager.set(key.to_string(), value.to_string());
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            shard_manager.set(key.clone(), key.clone()).unwrap();
+        }
+
+        assert_eq!(shard_manager.start_migration(8), 1000);
+        assert!(shard_manager.is_migrating());
+        assert_eq!(shard_manager.shard_count(), 8);
+        assert!(shard_manager.verify_placement().is_empty());
+        // Keys used before the migration reaches them are moved first
+        shard_manager
+            .set("key0".to_string(), "updated".to_string())
+            .unwrap();
+        assert_eq!(shard_manager.get("key1"), Some("key1".to_string()));
+
+        let mut checked = 0;
+        while let Some(migrated) = shard_manager.migrate_keys(100) {
+            assert!(migrated.checked <= 100 && migrated.moved <= migrated.checked);
+            checked += migrated.checked;
+            assert!(shard_manager.verify_placement().is_empty());
+        }
+        assert!(checked <= 1000);
+        shard_manager.finish_migration();
+        assert!(!shard_manager.is_migrating());
+
+        assert_eq!(shard_manager.shard_count(), 8);
+        assert!(shard_manager.verify_placement().is_empty());
+        assert_eq!(shard_manager.len(), keys.len());
+        assert!(shard_manager.shard_sizes()[4..]
+            .iter()
+            .all(|&size| size > 0));
+        assert_eq!(shard_manager.get("key0"), Some("updated".to_string()));
+        for key in &keys[1..] {
+            assert_eq!(shard_manager.get(key), Some(key.clone()));
+        }
+    }
+
+    #[test]
+    fn test_shard_manager_migration_removes_shards() {
+        let mut shard_manager = ShardManager::new(4);
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            shard_manager.set(key.clone(), key.clone()).unwrap();
+        }
+        shard_manager
+            .set("pinned".to_string(), "value".to_string())
+            .unwrap();
+        assert!(shard_manager.pin("pinned".to_string(), 3));
+
+        shard_manager.start_migration(2);
+        // The removed shards stay until their keys are moved, no key being pinned to them
+        assert_eq!(shard_manager.shard_count(), 4);
+        assert!(!shard_manager.pin("key0".to_string(), 3));
+        for key in &keys[..500] {
+            assert_eq!(shard_manager.get(key), Some(key.clone()));
+        }
+        assert_eq!(shard_manager.get("pinned"), Some("value".to_string()));
+
+        shard_manager.finish_migration();
+        assert_eq!(shard_manager.shard_count(), 2);
+        assert!(shard_manager.verify_placement().is_empty());
+        assert_eq!(shard_manager.len(), keys.len() + 1);
+        for key in &keys {
+            assert_eq!(shard_manager.get(key), Some(key.clone()));
+        }
+        assert_eq!(shard_manager.get("pinned"), Some("value".to_string()));
+    }
+
+    #[test]
+    fn test_shard_manager_reshard() {
+        let mut shard_manager = ShardManager::new(4);
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            shard_manager.set(key.clone(), key.clone()).unwrap();
+        }
+        shard_manager
+            .set_with_ttl(
+                "ttl_key".to_string(),
+                "ttl_value".to_string(),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+
+        assert!(shard_manager.reshard(8));
+        assert_eq!(shard_manager.shard_count(), 8);
+        assert_eq!(shard_manager.len(), keys.len() + 1);
+        for key in &keys {
+            assert_eq!(shard_manager.get(key), Some(key.clone()));
+        }
+        assert!(shard_manager.shard_sizes().iter().all(|&size| size > 0));
+        assert_eq!(shard_manager.ttl("ttl_key"), Some(60));
+
+        assert!(shard_manager.reshard(2));
+        assert_eq!(shard_manager.shard_count(), 2);
+        assert_eq!(shard_manager.len(), keys.len() + 1);
+
+        assert!(!shard_manager.reshard(0));
+        assert_eq!(shard_manager.shard_count(), 2);
+    }
+
+    #[test]
+    fn test_shard_manager_remove_last_shard() {
+        let mut shard_manager: ShardManager = ShardManager::new(1);
+        assert!(!shard_manager.remove_shard());
+        assert_eq!(shard_manager.shard_count(), 1);
+    }
+
+    #[test]
+    fn test_shard_manager_fold_key() {
+        let mut shard_manager: ShardManager = ShardManager::new(4);
+        assert_eq!(shard_manager.fold_key("Key1".to_string()), "Key1");
+
+        shard_manager.set_case_insensitive_keys(true);
+        assert_eq!(shard_manager.fold_key("Key1".to_string()), "key1");
+        assert_eq!(shard_manager.fold_key("KEY1".to_string()), "key1");
+
+        // The setting survives a reshard
+        shard_manager.reshard(8);
+        assert_eq!(shard_manager.fold_key("Key1".to_string()), "key1");
+    }
+
+    #[test]
+    fn test_shard_manager_seed_from_file() {
+        let path = std::env::temp_dir().join(format!("hashmaprs_seed_{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"key1": "seeded1", "key2": "seeded2"}"#).unwrap();
+
+        let shard_manager: ShardManager = ShardManager::new(4);
+        shard_manager
+            .set("key2".to_string(), "existing".to_string())
+            .unwrap();
+        let seeded = shard_manager.seed_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(seeded, 1);
+        assert_eq!(shard_manager.get("key1"), Some("seeded1".to_string()));
+        assert_eq!(shard_manager.get("key2"), Some("existing".to_string()));
+        assert!(shard_manager
+            .seed_from_file(Path::new("/nonexistent/seed.json"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_shard_manager_save_and_load_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("hashmaprs_round_trip_{}.json", std::process::id()));
+
+        let shard_manager = ShardManager::with_hash_algo(6, HashAlgo::Fnv);
+        for i in 0..50 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        shard_manager
+            .set_with_ttl(
+                "ttl_key".to_string(),
+                "ttl_value".to_string(),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        shard_manager
+            .set_with_ttl(
+                "expired_key".to_string(),
+                "expired_value".to_string(),
+                Duration::ZERO,
+            )
+            .unwrap();
+        shard_manager
+            .set("json_key".to_string(), "[1, 2]".to_string())
+            .unwrap();
+        shard_manager.set_value_type("json_key", ValueType::Json);
+        shard_manager.set_encoding("key1", Some("gzip".to_string()));
+        shard_manager
+            .set("key0".to_string(), "value0".to_string())
+            .unwrap();
+        shard_manager.save_to_file(&path).unwrap();
+
+        let loaded = ShardManager::load_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.shard_count(), 6);
+        assert_eq!(loaded.hash_algo(), HashAlgo::Fnv);
+        assert_eq!(loaded.len(), 52);
+        for i in 0..50 {
+            assert_eq!(
+                loaded.get(&format!("key{}", i)),
+                Some(format!("value{}", i))
+            );
+        }
+        assert_eq!(loaded.get("ttl_key"), Some("ttl_value".to_string()));
+        assert_eq!(loaded.get("expired_key"), None);
+        assert_eq!(
+            loaded.get_typed("json_key"),
+            Some(("[1, 2]".to_string(), ValueType::Json))
+        );
+        assert_eq!(loaded.value_type("key0"), Some(ValueType::String));
+        assert_eq!(
+            loaded.get_versioned("key0"),
+            Some(("value0".to_string(), 2))
+        );
+        assert_eq!(
+            loaded.get_versioned("key1"),
+            Some(("value1".to_string(), 1))
+        );
+        assert_eq!(
+            loaded.get_entry("key1").unwrap().encoding.as_deref(),
+            Some("gzip")
+        );
+        assert_eq!(loaded.get_entry("key2").unwrap().encoding, None);
+    }
+
+    // Writes to /dev/full always fail with "No space left on device"
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_shard_manager_undoes_writes_the_wal_cannot_record() {
+        let mut shard_manager: ShardManager = ShardManager::new(4);
+        shard_manager
+            .set("key1".to_string(), "a".to_string())
+            .unwrap();
+        shard_manager
+            .set("key2".to_string(), "b".to_string())
+            .unwrap();
 
-        let shard_index = (ShardManager::hash(&key) as usize) % shard_manager.shards.len();
+        let options = WalOptions {
+            retries: 2,
+            retry_delay: Duration::from_millis(1),
+            ..WalOptions::default()
+        };
+        shard_manager.attach_wal(Wal::open(Path::new("/dev/full"), options).unwrap());
+        let err = shard_manager
+            .set("key1".to_string(), "c".to_string())
+            .unwrap_err();
+        assert!(err.to_string().contains("WAL"));
+        assert!(shard_manager.increment("key3", 1).is_err());
+        assert!(shard_manager.delete("key2").is_err());
+        assert!(shard_manager.rename("key1", "key4").is_err());
+        assert!(shard_manager
+            .set_many(vec![
+                ("key1".to_string(), "d".to_string()),
+                ("key5".to_string(), "e".to_string()),
+            ])
+            .is_err());
+        assert!(shard_manager.clear().is_err());
+
+        // None of the writes is applied in memory
+        assert_eq!(shard_manager.len(), 2);
         assert_eq!(
-            shard_manager.shards[shard_index].get(key),
-            Some(value.to_string())
+            shard_manager.get_versioned("key1"),
+            Some(("a".to_string(), 1))
+        );
+        assert_eq!(shard_manager.get("key2"), Some("b".to_string()));
+        for key in ["key3", "key4", "key5"] {
+            assert_eq!(shard_manager.get(key), None);
+        }
+    }
+
+    #[test]
+    fn test_shard_manager_wal_replay() {
+        let path =
+            std::env::temp_dir().join(format!("hashmaprs_wal_replay_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut shard_manager = ShardManager::new(4);
+        let options = WalOptions {
+            batch_size: 64,
+            ..WalOptions::default()
+        };
+        shard_manager.attach_wal(Wal::open(&path, options).unwrap());
+        for i in 0..200 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        shard_manager.delete("key0").unwrap();
+        shard_manager.increment("counter", 5).unwrap().unwrap();
+        shard_manager.rename("key1", "renamed").unwrap();
+        shard_manager.flush_wal().unwrap();
+
+        let recovered = ShardManager::new(4);
+        let replayed = recovered.replay_wal(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(replayed, 204);
+        assert_eq!(recovered.export(), shard_manager.export());
+        assert_eq!(recovered.get("key0"), None);
+        assert_eq!(recovered.get("key1"), None);
+        assert_eq!(recovered.get("renamed"), Some("value1".to_string()));
+        assert_eq!(recovered.version("counter"), Some(1));
+    }
+
+    #[test]
+    fn test_shard_manager_logs_keys_evicted_by_moves() {
+        // Two full shards of 2 keys each, so moving a key into either one evicts another
+        let full_shard_manager = || {
+            let mut shard_manager: ShardManager = ShardManager::with_max_entries_per_shard(2, 2);
+            let mut counts = [0; 2];
+            for i in 0.. {
+                let key = format!("key{}", i);
+                let shard_index = shard_manager.get_shard_index(&key);
+                if counts[shard_index] < 2 {
+                    counts[shard_index] += 1;
+                    shard_manager.set(key, "value".to_string()).unwrap();
+                }
+                if counts == [2, 2] {
+                    break;
+                }
+            }
+            shard_manager.enable_audit(8);
+            shard_manager
+        };
+        let evicted = |shard_manager: &ShardManager| {
+            shard_manager
+                .audit_log()
+                .into_iter()
+                .filter(|entry| entry.op == AuditOp::Delete)
+                .count()
+        };
+
+        let mut shard_manager = full_shard_manager();
+        assert!(shard_manager.remove_shard());
+        assert_eq!(shard_manager.len(), 2);
+        assert_eq!(evicted(&shard_manager), 2);
+
+        let mut shard_manager = full_shard_manager();
+        assert!(shard_manager.reshard(1));
+        assert_eq!(shard_manager.len(), 2);
+        assert_eq!(evicted(&shard_manager), 2);
+
+        let shard_manager = full_shard_manager();
+        let key = (0..)
+            .map(|i| format!("key{}", i))
+            .find(|key| shard_manager.contains(key) && shard_manager.get_shard_index(key) == 1)
+            .unwrap();
+        assert!(shard_manager.pin(key.clone(), 0));
+        assert_eq!(shard_manager.get(&key), Some("value".to_string()));
+        assert_eq!(shard_manager.len(), 3);
+        assert_eq!(evicted(&shard_manager), 1);
+    }
+
+    #[test]
+    fn test_shard_manager_set_tagged() {
+        let path =
+            std::env::temp_dir().join(format!("hashmaprs_wal_tagged_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut shard_manager = ShardManager::new(4);
+        shard_manager.attach_wal(Wal::open(&path, WalOptions::default()).unwrap());
+        shard_manager.enable_audit(8);
+        let tags = ValueTags {
+            value_type: ValueType::Json,
+            encoding: Some("gzip".to_string()),
+        };
+        shard_manager
+            .set_tagged("json".to_string(), "{}".to_string(), None, tags.clone())
+            .unwrap();
+        assert!(!shard_manager
+            .set_if_absent_tagged(
+                "json".to_string(),
+                "[]".to_string(),
+                None,
+                ValueTags::default(),
+            )
+            .unwrap());
+        shard_manager
+            .set_many_tagged(vec![("list".to_string(), "[]".to_string(), tags)])
+            .unwrap();
+        shard_manager.flush_wal().unwrap();
+
+        // Each write is logged once, along with its tags
+        assert_eq!(shard_manager.audit_log().len(), 2);
+        let recovered = ShardManager::new(4);
+        assert_eq!(recovered.replay_wal(&path).unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+        for key in ["json", "list"] {
+            let entry = recovered.get_entry(key).unwrap();
+            assert_eq!(entry.value_type, ValueType::Json);
+            assert_eq!(entry.encoding.as_deref(), Some("gzip"));
+        }
+    }
+
+    #[test]
+    fn test_shard_manager_wal_replay_drops_evicted_keys() {
+        let path =
+            std::env::temp_dir().join(format!("hashmaprs_wal_evicted_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut shard_manager = ShardManager::with_max_entries_per_shard(1, 2);
+        shard_manager.attach_wal(Wal::open(&path, WalOptions::default()).unwrap());
+        for i in 0..4 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        shard_manager.flush_wal().unwrap();
+
+        // The keys evicted to make room are replayed as deletions
+        let recovered = ShardManager::new(1);
+        assert_eq!(recovered.replay_wal(&path).unwrap(), 6);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(recovered.get("key0"), None);
+        assert_eq!(recovered.get("key1"), None);
+        assert_eq!(recovered.export(), shard_manager.export());
+    }
+
+    #[test]
+    fn test_shard_manager_recovers_from_snapshot_and_wal() {
+        let dir = std::env::temp_dir();
+        let snapshot_path = dir.join(format!("hashmaprs_snapshot_{}.json", std::process::id()));
+        let wal_path = dir.join(format!("hashmaprs_snapshot_wal_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&wal_path);
+
+        let mut shard_manager = ShardManager::new(4);
+        shard_manager.attach_wal(Wal::open(&wal_path, WalOptions::default()).unwrap());
+        for i in 0..50 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        shard_manager.snapshot(&snapshot_path).unwrap();
+
+        for i in 50..60 {
+            shard_manager
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        shard_manager.delete("key0").unwrap();
+        shard_manager.update("key1", "updated".to_string()).unwrap();
+
+        let recovered = ShardManager::load_from_file(&snapshot_path).unwrap();
+        let replayed = recovered.replay_wal(&wal_path).unwrap();
+        std::fs::remove_file(&snapshot_path).unwrap();
+        std::fs::remove_file(&wal_path).unwrap();
+
+        // Only the writes made after the snapshot are left in the WAL
+        assert_eq!(replayed, 12);
+        assert_eq!(recovered.len(), 59);
+        assert_eq!(recovered.export(), shard_manager.export());
+        assert_eq!(recovered.get("key1"), Some("updated".to_string()));
+        assert_eq!(recovered.version("key1"), Some(2));
+    }
+
+    #[test]
+    fn test_shard_manager_shard_ops() {
+        let shard_manager = ShardManager::new(4);
+        let key1 = "key1".to_string();
+        let shard1 = shard_manager.get_shard_index(&key1);
+        let key2 = find_key(&shard_manager, &key1, false);
+        let shard2 = shard_manager.get_shard_index(&key2);
+
+        shard_manager
+            .set(key1.clone(), "value".to_string())
+            .unwrap();
+        shard_manager.get(&key1);
+        shard_manager.get(&key1);
+        shard_manager.get_many(&[key1.clone(), key2.clone()]);
+        shard_manager.delete(&key2).unwrap();
+
+        let ops = shard_manager.shard_ops();
+        assert_eq!(ops.len(), 4);
+        assert_eq!(
+            ops[shard1],
+            ShardOps {
+                gets: 3,
+                sets: 1,
+                deletes: 0,
+            }
+        );
+        assert_eq!(
+            ops[shard2],
+            ShardOps {
+                gets: 1,
+                sets: 0,
+                deletes: 1,
+            }
+        );
+        assert_eq!(
+            ops.iter().map(|ops| ops.gets).sum::<u64>(),
+            4,
+            "no other shard should have been read"
         );
     }
 }
@@ -0,0 +1,418 @@
+// wal.rs
+
+use crate::shard_manager::PersistedEntry;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A write recorded in the log. Ops hold the state a key was left in rather than the request
+/// that changed it, so replaying them in order always rebuilds the same store.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum WalOp {
+    Set(PersistedEntry),
+    Delete { key: String },
+    Clear,
+}
+
+/// When buffered ops are written out to disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WalOptions {
+    /// Number of ops buffered before the log is synced, 1 syncing every op.
+    pub batch_size: usize,
+    /// Longest time an op stays buffered, None leaving it to the batch size and `Wal::flush`.
+    pub flush_interval: Option<Duration>,
+    /// Number of times a failed write to disk is retried before the error is returned.
+    pub retries: usize,
+    /// Wait before the first retry, doubled before each following one.
+    pub retry_delay: Duration,
+}
+
+impl Default for WalOptions {
+    fn default() -> Self {
+        WalOptions {
+            batch_size: 1,
+            flush_interval: None,
+            retries: 3,
+            retry_delay: Duration::from_millis(10),
+        }
+    }
+}
+
+/// Storage the log is written to: its file, or a stand-in in tests.
+trait LogFile: Write + Send {
+    fn sync_data(&self) -> io::Result<()>;
+    fn set_len(&self, size: u64) -> io::Result<()>;
+}
+
+impl LogFile for File {
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+}
+
+struct WalWriter {
+    file: Box<dyn LogFile>,
+    /// Serialized ops not written to the file yet.
+    buffer: Vec<u8>,
+    /// Number of ops appended since the log was opened.
+    appended: u64,
+    /// Number of the first appended ops known to be on disk.
+    synced: u64,
+    last_flush: Instant,
+}
+
+impl WalWriter {
+    fn push(&mut self, op: &WalOp) -> io::Result<()> {
+        serde_json::to_writer(&mut self.buffer, op)?;
+        self.buffer.push(b'\n');
+        self.appended += 1;
+        Ok(())
+    }
+
+    /// Number of ops not known to be on disk yet.
+    fn pending(&self) -> u64 {
+        self.appended - self.synced
+    }
+
+    /// Writes the buffered ops out and syncs them to disk.
+    fn sync(&mut self) -> io::Result<()> {
+        self.write_out()?;
+        self.synced = self.appended;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    /// Writes the buffer out and syncs it to disk. Bytes written before a failure are taken
+    /// out of the buffer, so trying again picks up where this attempt stopped.
+    fn write_out(&mut self) -> io::Result<()> {
+        while !self.buffer.is_empty() {
+            let written = self.file.write(&self.buffer)?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.buffer.drain(..written);
+        }
+        self.file.flush()?;
+        self.file.sync_data()
+    }
+}
+
+/// Append-only log of the writes applied to a store, one JSON op per line. Ops are buffered
+/// and synced to disk in batches, so only the ops of the last unflushed batch can be lost in
+/// a crash. Failed writes to disk are retried with exponential backoff, the log staying
+/// available to other writers in between, and ops that still couldn't be written stay buffered
+/// for the next sync.
+pub struct Wal {
+    options: WalOptions,
+    writer: Mutex<WalWriter>,
+}
+
+impl Wal {
+    /// Opens the log at `path` for appending, creating it if it doesn't exist.
+    pub fn open(path: &Path, options: WalOptions) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::with_file(Box::new(file), options))
+    }
+
+    fn with_file(file: Box<dyn LogFile>, options: WalOptions) -> Self {
+        Wal {
+            options,
+            writer: Mutex::new(WalWriter {
+                file,
+                buffer: Vec::new(),
+                appended: 0,
+                synced: 0,
+                last_flush: Instant::now(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, WalWriter> {
+        self.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Appends an op to the log, syncing the batch to disk once it holds `batch_size` ops or
+    /// its oldest op has waited for `flush_interval`. An error means the sync still failed
+    /// after `retries` retries, the op staying buffered for the next sync.
+    pub fn append(&self, op: &WalOp) -> io::Result<()> {
+        self.append_all(std::slice::from_ref(op))
+    }
+
+    /// Same as `append` for several ops, synced together.
+    pub fn append_all(&self, ops: &[WalOp]) -> io::Result<()> {
+        let mut writer = self.lock();
+        for op in ops {
+            writer.push(op)?;
+        }
+
+        let interval_elapsed = matches!(
+            self.options.flush_interval,
+            Some(interval) if writer.last_flush.elapsed() >= interval
+        );
+        if writer.pending() < self.options.batch_size as u64 && !interval_elapsed {
+            return Ok(());
+        }
+        let last_op = writer.appended;
+        drop(writer);
+        self.sync_through(last_op)
+    }
+
+    /// Buffers an op without syncing it, so it's only written along with the next batch. Used
+    /// to undo ops whose append failed, which stay buffered.
+    pub fn record(&self, op: &WalOp) -> io::Result<()> {
+        self.lock().push(op)
+    }
+
+    /// Syncs the buffered ops to disk, eg. before shutting down or on a timer.
+    pub fn flush(&self) -> io::Result<()> {
+        let writer = self.lock();
+        if writer.pending() == 0 {
+            return Ok(());
+        }
+        let last_op = writer.appended;
+        drop(writer);
+        self.sync_through(last_op)
+    }
+
+    /// Empties the log, dropping the buffered ops too. Used once a snapshot holds every op
+    /// logged so far. It's only tried once, as the store stays locked for the snapshot.
+    pub fn truncate(&self) -> io::Result<()> {
+        let mut writer = self.lock();
+        writer.buffer.clear();
+        writer.file.set_len(0)?;
+        writer.sync()
+    }
+
+    /// Syncs the log until the op numbered `last_op` is on disk, retrying up to `retries`
+    /// times while it fails. The log isn't locked while waiting to retry, so other writers can
+    /// append in the meantime, their sync taking the waiting ops along.
+    fn sync_through(&self, last_op: u64) -> io::Result<()> {
+        let mut delay = self.options.retry_delay;
+        let mut retries_left = self.options.retries;
+        loop {
+            let mut writer = self.lock();
+            if writer.synced >= last_op {
+                return Ok(());
+            }
+            match writer.sync() {
+                Ok(()) => return Ok(()),
+                Err(err) if retries_left == 0 => return Err(err),
+                Err(_) => {}
+            }
+            drop(writer);
+            thread::sleep(delay);
+            delay *= 2;
+            retries_left -= 1;
+        }
+    }
+
+    /// Reads back every op of the log at `path`, in the order they were appended. A truncated
+    /// last line, left by a crash in the middle of a write, is ignored, while any other line
+    /// that isn't a valid op fails the replay with `InvalidData`.
+    pub fn replay(path: &Path) -> io::Result<Vec<WalOp>> {
+        let mut ops = Vec::new();
+        let mut lines = BufReader::new(File::open(path)?).lines().peekable();
+        let mut line_number = 0;
+        while let Some(line) = lines.next() {
+            line_number += 1;
+            match serde_json::from_str(&line?) {
+                Ok(op) => ops.push(op),
+                Err(_) if lines.peek().is_none() => break,
+                Err(err) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Corrupt WAL line {}: {}", line_number, err),
+                    ))
+                }
+            }
+        }
+        Ok(ops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_wal_batched_ops_are_replayed_after_flush() {
+        let path =
+            std::env::temp_dir().join(format!("hashmaprs_wal_batched_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let wal = Wal::open(
+            &path,
+            WalOptions {
+                batch_size: 1000,
+                flush_interval: Some(Duration::from_secs(60)),
+                ..WalOptions::default()
+            },
+        )
+        .unwrap();
+        for i in 0..500 {
+            wal.append(&WalOp::Delete {
+                key: format!("key{}", i),
+            })
+            .unwrap();
+        }
+        wal.append(&WalOp::Clear).unwrap();
+        wal.flush().unwrap();
+
+        let ops = Wal::replay(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(ops.len(), 501);
+        for (i, op) in ops[..500].iter().enumerate() {
+            assert!(matches!(op, WalOp::Delete { key } if *key == format!("key{}", i)));
+        }
+        assert!(matches!(ops[500], WalOp::Clear));
+    }
+
+    #[test]
+    fn test_wal_replay_ignores_only_a_truncated_last_line() {
+        let path =
+            std::env::temp_dir().join(format!("hashmaprs_wal_corrupt_{}.log", std::process::id()));
+
+        std::fs::write(&path, "{\"op\":\"clear\"}\n{\"op\":\"delete\",\"key\":\"ke").unwrap();
+        let ops = Wal::replay(&path).unwrap();
+        assert_eq!(ops.len(), 1);
+        assert!(matches!(ops[0], WalOp::Clear));
+
+        std::fs::write(
+            &path,
+            "{\"op\":\"clear\"}\n{\"op\":\"del\n{\"op\":\"delete\",\"key\":\"key1\"}\n",
+        )
+        .unwrap();
+        let err = Wal::replay(&path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("line 2"));
+    }
+
+    /// Log file failing its first `failures` writes, keeping what's written in memory.
+    struct FlakyFile {
+        failures: usize,
+        data: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Write for FlakyFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::other("disk unavailable"));
+            }
+            self.data.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LogFile for FlakyFile {
+        fn sync_data(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_len(&self, size: u64) -> io::Result<()> {
+            self.data.lock().unwrap().truncate(size as usize);
+            Ok(())
+        }
+    }
+
+    fn flaky_wal(failures: usize, retries: usize) -> (Wal, Arc<Mutex<Vec<u8>>>) {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let file = FlakyFile {
+            failures,
+            data: data.clone(),
+        };
+        let options = WalOptions {
+            retries,
+            retry_delay: Duration::from_millis(1),
+            ..WalOptions::default()
+        };
+        (Wal::with_file(Box::new(file), options), data)
+    }
+
+    fn logged_ops(data: &Mutex<Vec<u8>>) -> Vec<WalOp> {
+        let data = data.lock().unwrap();
+        data.split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_wal_retries_failed_writes() {
+        let (wal, data) = flaky_wal(2, 3);
+        wal.append(&WalOp::Delete {
+            key: "key1".to_string(),
+        })
+        .unwrap();
+
+        let ops = logged_ops(&data);
+        assert_eq!(ops.len(), 1);
+        assert!(matches!(&ops[0], WalOp::Delete { key } if key == "key1"));
+    }
+
+    #[test]
+    fn test_wal_other_writers_append_while_a_sync_waits_to_retry() {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        let file = FlakyFile {
+            failures: 1,
+            data: data.clone(),
+        };
+        let options = WalOptions {
+            retries: 1,
+            retry_delay: Duration::from_millis(500),
+            ..WalOptions::default()
+        };
+        let wal = Arc::new(Wal::with_file(Box::new(file), options));
+
+        let waiting = {
+            let wal = wal.clone();
+            std::thread::spawn(move || wal.append(&WalOp::Clear))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        let started = Instant::now();
+        wal.append(&WalOp::Delete {
+            key: "key1".to_string(),
+        })
+        .unwrap();
+        // Synced both ops without waiting for the first append's retry
+        assert!(started.elapsed() < Duration::from_millis(250));
+        assert_eq!(logged_ops(&data).len(), 2);
+
+        waiting.join().unwrap().unwrap();
+        let ops = logged_ops(&data);
+        assert_eq!(ops.len(), 2);
+        assert!(matches!(ops[0], WalOp::Clear));
+    }
+
+    #[test]
+    fn test_wal_keeps_ops_that_failed_every_retry() {
+        let (wal, data) = flaky_wal(3, 2);
+        assert!(wal.append(&WalOp::Clear).is_err());
+        assert!(logged_ops(&data).is_empty());
+
+        wal.append(&WalOp::Delete {
+            key: "key1".to_string(),
+        })
+        .unwrap();
+        let ops = logged_ops(&data);
+        assert_eq!(ops.len(), 2);
+        assert!(matches!(ops[0], WalOp::Clear));
+        assert!(matches!(&ops[1], WalOp::Delete { key } if key == "key1"));
+    }
+}
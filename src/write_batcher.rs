@@ -0,0 +1,156 @@
+// write_batcher.rs
+
+use crate::error::ApiError;
+use crate::shard::ValueType;
+use crate::shard_manager::{ShardManager, ValueTags};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// Number of full batches the queue holds before writers wait for room.
+const QUEUED_BATCHES: usize = 4;
+
+/// How a queued write was applied to the store.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AppliedWrite {
+    pub shard: usize,
+    pub created: bool,
+    pub version: u64,
+}
+
+/// A write waiting in the queue, along with where to report it once applied.
+struct QueuedWrite {
+    key: String,
+    value: String,
+    /// Requested TTL, zero meaning no expiry and None the default TTL.
+    ttl: Option<Duration>,
+    value_type: ValueType,
+    encoding: Option<String>,
+    applied: oneshot::Sender<Result<AppliedWrite, ApiError>>,
+}
+
+/// Queue of writes applied to the store by a single background task, which takes the store's
+/// lock once per batch rather than once per write.
+pub struct WriteBatcher {
+    sender: mpsc::Sender<QueuedWrite>,
+}
+
+impl WriteBatcher {
+    /// Spawns the task applying the queued writes, up to `batch_size` of them under each lock.
+    /// Must be called from a Tokio runtime.
+    pub fn spawn(shard_manager: Arc<RwLock<ShardManager>>, batch_size: usize) -> Self {
+        let batch_size = batch_size.max(1);
+        let (sender, mut receiver) = mpsc::channel(batch_size * QUEUED_BATCHES);
+        tokio::spawn(async move {
+            let mut batch = Vec::with_capacity(batch_size);
+            while let Some(write) = receiver.recv().await {
+                batch.push(write);
+                // Take whatever else is already queued, without waiting for more
+                while batch.len() < batch_size {
+                    match receiver.try_recv() {
+                        Ok(write) => batch.push(write),
+                        Err(_) => break,
+                    }
+                }
+                apply_batch(
+                    &shard_manager.read().unwrap_or_else(PoisonError::into_inner),
+                    &mut batch,
+                );
+            }
+        });
+        WriteBatcher { sender }
+    }
+
+    /// Queues a write and waits until it's applied.
+    ///
+    /// # Returns
+    ///
+    /// The shard the key was stored in, whether it was created and its new version, an error if
+    /// the store is full or the WAL couldn't record the write, or None if the task applying the
+    /// writes has stopped
+    pub async fn set(
+        &self,
+        key: String,
+        value: String,
+        ttl: Option<Duration>,
+        value_type: ValueType,
+        encoding: Option<String>,
+    ) -> Option<Result<AppliedWrite, ApiError>> {
+        let (applied, receiver) = oneshot::channel();
+        let write = QueuedWrite {
+            key,
+            value,
+            ttl,
+            value_type,
+            encoding,
+            applied,
+        };
+        self.sender.send(write).await.ok()?;
+        receiver.await.ok()
+    }
+}
+
+fn apply_batch(shard_manager: &ShardManager, batch: &mut Vec<QueuedWrite>) {
+    for write in batch.drain(..) {
+        let applied = shard_manager
+            .set_tagged(
+                write.key.clone(),
+                write.value,
+                crate::write_expiry(shard_manager, write.ttl),
+                ValueTags {
+                    value_type: write.value_type,
+                    encoding: write.encoding,
+                },
+            )
+            .map(|(shard, created)| AppliedWrite {
+                shard,
+                created,
+                version: shard_manager.version(&write.key).unwrap_or_default(),
+            })
+            .map_err(ApiError::from);
+        // The writer may have stopped waiting, the write stands regardless
+        let _ = write.applied.send(applied);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_batcher_applies_concurrent_writes() {
+        let shard_manager: ShardManager = ShardManager::new(4);
+        let shard_manager = Arc::new(RwLock::new(shard_manager));
+        let write_batcher = Arc::new(WriteBatcher::spawn(shard_manager.clone(), 16));
+
+        let mut handles = Vec::new();
+        for i in 0..500 {
+            let write_batcher = write_batcher.clone();
+            handles.push(tokio::spawn(async move {
+                write_batcher
+                    .set(
+                        format!("key{}", i),
+                        format!("value{}", i),
+                        None,
+                        ValueType::String,
+                        None,
+                    )
+                    .await
+            }));
+        }
+        for handle in handles {
+            let applied = handle.await.unwrap().unwrap().unwrap();
+            assert!(applied.created);
+            assert_eq!(applied.version, 1);
+        }
+
+        let shard_manager = shard_manager.read().unwrap();
+        assert_eq!(shard_manager.len(), 500);
+        for i in 0..500 {
+            assert_eq!(
+                shard_manager.get(&format!("key{}", i)),
+                Some(format!("value{}", i))
+            );
+        }
+    }
+}